@@ -0,0 +1,186 @@
+// Fixed-size relay cells
+// Every message on a circuit is cut into cells of exactly CELL_LEN bytes
+// before it is layered, so relays and observers see a count of cells
+// rather than the length of what the client sent.
+//
+// Layout: command (1) | stream id (2, BE) | length (2, BE) | data | padding
+
+/// Size of a cell before any onion layers are added
+pub const CELL_LEN: usize = 512;
+
+pub const CELL_HEADER_LEN: usize = 5;
+
+/// Payload bytes that fit in one cell
+pub const CELL_DATA_LEN: usize = CELL_LEN - CELL_HEADER_LEN;
+
+/// Largest message a hop will reassemble from cells
+pub const MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CellCommand {
+    /// Filler with no payload; dropped by the hop that opens it
+    Padding = 0,
+    /// Part of a message, more cells follow
+    Fragment = 1,
+    /// Last (or only) cell of a message
+    End = 2,
+}
+
+impl CellCommand {
+    fn from_byte(byte: u8) -> Result<Self, String> {
+        match byte {
+            0 => Ok(CellCommand::Padding),
+            1 => Ok(CellCommand::Fragment),
+            2 => Ok(CellCommand::End),
+            other => Err(format!("Unknown cell command {}", other)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cell {
+    pub command: CellCommand,
+    pub stream_id: u16,
+    pub data: Vec<u8>,
+}
+
+impl Cell {
+    pub fn padding() -> Self {
+        Cell { command: CellCommand::Padding, stream_id: 0, data: Vec::new() }
+    }
+
+    /// Always exactly CELL_LEN bytes
+    pub fn encode(&self) -> Vec<u8> {
+        assert!(self.data.len() <= CELL_DATA_LEN, "cell data is {} bytes", self.data.len());
+        let mut out = Vec::with_capacity(CELL_LEN);
+        out.push(self.command as u8);
+        out.extend_from_slice(&self.stream_id.to_be_bytes());
+        out.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+        out.extend_from_slice(&self.data);
+        out.resize(CELL_LEN, 0);
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() != CELL_LEN {
+            return Err(format!("Cell must be {} bytes, got {}", CELL_LEN, bytes.len()));
+        }
+        let command = CellCommand::from_byte(bytes[0])?;
+        let stream_id = u16::from_be_bytes([bytes[1], bytes[2]]);
+        let len = u16::from_be_bytes([bytes[3], bytes[4]]) as usize;
+        if len > CELL_DATA_LEN {
+            return Err(format!("Cell claims {} bytes of data, at most {} fit", len, CELL_DATA_LEN));
+        }
+        let data = bytes[CELL_HEADER_LEN..CELL_HEADER_LEN + len].to_vec();
+        Ok(Cell { command, stream_id, data })
+    }
+}
+
+/// Cut `message` into as many cells as it needs; an empty message still
+/// takes one cell
+pub fn fragment(stream_id: u16, message: &[u8]) -> Vec<Cell> {
+    let mut chunks: Vec<&[u8]> = message.chunks(CELL_DATA_LEN).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    let last = chunks.len() - 1;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| Cell {
+            command: if i == last { CellCommand::End } else { CellCommand::Fragment },
+            stream_id,
+            data: chunk.to_vec(),
+        })
+        .collect()
+}
+
+/// Collects fragments until a message is complete
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    stream_id: Option<u16>,
+    buffer: Vec<u8>,
+}
+
+impl Reassembler {
+    /// Add a cell; returns the stream id and message once its last cell arrives
+    pub fn push(&mut self, cell: Cell) -> Result<Option<(u16, Vec<u8>)>, String> {
+        if cell.command == CellCommand::Padding {
+            return Ok(None);
+        }
+        if let Some(stream_id) = self.stream_id {
+            if stream_id != cell.stream_id {
+                self.reset();
+                return Err(format!("Stream {} cell arrived in the middle of stream {}", cell.stream_id, stream_id));
+            }
+        }
+        if self.buffer.len() + cell.data.len() > MAX_MESSAGE_LEN {
+            self.reset();
+            return Err(format!("Message exceeds {} bytes", MAX_MESSAGE_LEN));
+        }
+
+        self.stream_id = Some(cell.stream_id);
+        self.buffer.extend_from_slice(&cell.data);
+        if cell.command == CellCommand::End {
+            self.stream_id = None;
+            return Ok(Some((cell.stream_id, std::mem::take(&mut self.buffer))));
+        }
+        Ok(None)
+    }
+
+    fn reset(&mut self) {
+        self.stream_id = None;
+        self.buffer.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cells_are_fixed_size() {
+        for len in [0, 1, CELL_DATA_LEN - 1, CELL_DATA_LEN] {
+            let cells = fragment(7, &vec![0xAB; len]);
+            assert_eq!(cells.len(), 1);
+            let bytes = cells[0].encode();
+            assert_eq!(bytes.len(), CELL_LEN);
+            assert_eq!(Cell::decode(&bytes).unwrap(), cells[0]);
+        }
+        assert_eq!(Cell::padding().encode().len(), CELL_LEN);
+        assert!(Cell::decode(&[0u8; CELL_LEN - 1]).is_err());
+    }
+
+    #[test]
+    fn test_split_and_reassemble() {
+        let message: Vec<u8> = (0..3 * CELL_DATA_LEN + 10).map(|i| i as u8).collect();
+        let cells = fragment(3, &message);
+        assert_eq!(cells.len(), 4);
+        assert!(cells[..3].iter().all(|c| c.command == CellCommand::Fragment));
+
+        let mut reassembler = Reassembler::default();
+        for cell in &cells[..3] {
+            assert_eq!(reassembler.push(cell.clone()).unwrap(), None);
+        }
+        // Padding in between doesn't disturb the message
+        assert_eq!(reassembler.push(Cell::padding()).unwrap(), None);
+        assert_eq!(reassembler.push(cells[3].clone()).unwrap(), Some((3, message)));
+    }
+
+    #[test]
+    fn test_reassembly_rejects_bad_input() {
+        let mut reassembler = Reassembler::default();
+        let cells = fragment(1, &[0u8; CELL_DATA_LEN + 1]);
+        reassembler.push(cells[0].clone()).unwrap();
+        let other = Cell { command: CellCommand::End, stream_id: 2, data: vec![1] };
+        assert!(reassembler.push(other).is_err());
+
+        let huge = Cell { command: CellCommand::Fragment, stream_id: 0, data: vec![0; CELL_DATA_LEN] };
+        let result = (0..=MAX_MESSAGE_LEN / CELL_DATA_LEN).try_for_each(|_| reassembler.push(huge.clone()).map(|_| ()));
+        assert!(result.is_err());
+
+        let mut bad_len = Cell::padding().encode();
+        bad_len[3..5].copy_from_slice(&(CELL_DATA_LEN as u16 + 1).to_be_bytes());
+        assert!(Cell::decode(&bad_len).is_err());
+    }
+}
@@ -0,0 +1,179 @@
+// Circuit lifecycle
+// A background task that keeps a few clean circuits built ahead of need,
+// tears down circuits that have failed, expired or been used too long, and
+// backs off when building keeps failing.
+
+use std::sync::Arc;
+use std::time::Duration;
+use serde::Serialize;
+use tokio::sync::RwLock;
+use crate::onion::{CircuitTransport, OnionRouter};
+
+/// How often the pool is checked
+pub const MAINTAIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Longest wait between build attempts while they keep failing
+pub const MAX_BUILD_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Debug)]
+pub struct ManagerSettings {
+    /// Clean circuits to keep ready
+    pub pool_size: usize,
+    pub num_hops: usize,
+    /// What pooled circuits' exits must allow (host:port)
+    pub destination: String,
+}
+
+/// What the manager has done, for the dashboard
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ManagerStats {
+    pub built: u64,
+    pub failed: u64,
+    pub retired: u64,
+    /// Builds that have failed since the last one succeeded
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+}
+
+/// Wait before the next pass: the normal interval, doubled for each
+/// consecutive failure up to MAX_BUILD_BACKOFF
+pub fn backoff(consecutive_failures: u32) -> Duration {
+    MAINTAIN_INTERVAL
+        .saturating_mul(1 << consecutive_failures.min(16))
+        .min(MAX_BUILD_BACKOFF)
+}
+
+pub struct CircuitManager<T> {
+    router: Arc<OnionRouter>,
+    transport: Arc<T>,
+    settings: ManagerSettings,
+    stats: Arc<RwLock<ManagerStats>>,
+}
+
+impl<T: CircuitTransport + 'static> CircuitManager<T> {
+    pub fn new(router: Arc<OnionRouter>, transport: Arc<T>, settings: ManagerSettings) -> Self {
+        CircuitManager { router, transport, settings, stats: Arc::new(RwLock::new(ManagerStats::default())) }
+    }
+
+    /// Read-only view for the dashboard
+    pub fn status(&self) -> CircuitStatus {
+        CircuitStatus { router: self.router.clone(), settings: self.settings.clone(), stats: self.stats.clone() }
+    }
+
+    /// One pass: tear down circuits that are done with, then build until
+    /// the pool is full or a build fails
+    pub async fn maintain(&self) {
+        for circuit_id in self.router.retirable_circuits().await {
+            if self.router.close_circuit(self.transport.as_ref(), &circuit_id).await.is_ok() {
+                self.stats.write().await.retired += 1;
+            }
+        }
+        self.router.prune_closed().await;
+
+        let destination = self.settings.destination.as_str();
+        while self.router.clean_circuits(destination).await < self.settings.pool_size {
+            let built = self
+                .router
+                .build_circuit(self.transport.clone(), self.settings.num_hops, Some(destination))
+                .await;
+            let mut stats = self.stats.write().await;
+            match built {
+                Ok(circuit_id) => {
+                    println!("🧅 Circuit {} ready", &circuit_id[..8]);
+                    stats.built += 1;
+                    stats.consecutive_failures = 0;
+                }
+                Err(e) => {
+                    eprintln!("🧅 Circuit build failed: {}", e);
+                    stats.failed += 1;
+                    stats.consecutive_failures += 1;
+                    stats.last_error = Some(e);
+                    break;
+                }
+            }
+        }
+    }
+
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                self.maintain().await;
+                let failures = self.stats.read().await.consecutive_failures;
+                tokio::time::sleep(backoff(failures)).await;
+            }
+        })
+    }
+}
+
+/// The pool's circuits and the manager's counters
+#[derive(Clone)]
+pub struct CircuitStatus {
+    router: Arc<OnionRouter>,
+    settings: ManagerSettings,
+    stats: Arc<RwLock<ManagerStats>>,
+}
+
+impl CircuitStatus {
+    pub async fn to_json(&self) -> serde_json::Value {
+        let circuits = self.router.circuit_summaries().await;
+        let stats = self.stats.read().await.clone();
+        serde_json::json!({
+            "pool_size": self.settings.pool_size,
+            "clean": self.router.clean_circuits(&self.settings.destination).await,
+            "circuits": circuits,
+            "stats": stats,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::testing::{loopback_router, spawn_node, spawn_relays};
+    use crate::onion::testing::with_max_dirtiness;
+
+    #[test]
+    fn test_backoff_doubles_up_to_a_cap() {
+        assert_eq!(backoff(0), MAINTAIN_INTERVAL);
+        assert_eq!(backoff(1), MAINTAIN_INTERVAL * 2);
+        assert_eq!(backoff(3), MAINTAIN_INTERVAL * 8);
+        assert_eq!(backoff(10), MAX_BUILD_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BUILD_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_manager_keeps_a_fresh_pool() {
+        let (_, client, _) = spawn_node().await;
+        let router = loopback_router(&spawn_relays(3).await).await;
+        let router = Arc::new(with_max_dirtiness(router, Duration::from_millis(200)));
+        let settings = ManagerSettings { pool_size: 2, num_hops: 3, destination: "*:443".to_string() };
+        let manager = CircuitManager::new(router.clone(), client.clone(), settings.clone());
+        manager.maintain().await;
+        assert_eq!(router.clean_circuits("*:443").await, 2);
+
+        // A circuit handed out for a stream is no longer clean, so another is built
+        assert!(router.ready_streams("example.com:443").await.is_some());
+        assert_eq!(router.clean_circuits("*:443").await, 1);
+        manager.maintain().await;
+        assert_eq!(router.clean_circuits("*:443").await, 2);
+
+        // Dirty for too long with no streams open, it is torn down
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        manager.maintain().await;
+        let circuits = router.circuit_summaries().await;
+        assert_eq!(circuits.len(), 2);
+        assert!(circuits.iter().all(|circuit| circuit.dirty_secs.is_none()));
+        let status = manager.status().to_json().await;
+        assert_eq!(status["stats"]["built"], 3);
+        assert_eq!(status["stats"]["retired"], 1);
+
+        // Builds that can't succeed are counted so the manager backs off
+        let greedy = ManagerSettings { pool_size: 3, num_hops: 4, ..settings };
+        let manager = CircuitManager::new(router.clone(), client, greedy);
+        manager.maintain().await;
+        let status = manager.status().to_json().await;
+        assert_eq!(status["stats"]["failed"], 1);
+        assert_eq!(status["stats"]["consecutive_failures"], 1);
+        assert!(status["stats"]["last_error"].as_str().unwrap().contains("Not enough nodes"));
+    }
+}
@@ -1,9 +1,24 @@
 // Freedom Network Client - used by browser to fetch from .freedom sites
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::net::SocketAddr;
+use std::time::Duration;
 use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+use crate::http::{BodyLength, Headers, HttpReader, RequestHead};
+use crate::lookup::DhtTransport;
+use crate::onion::{CircuitTransport, OnionRouter};
+use crate::rendezvous;
+use crate::resolver::{FreedomResolver, FreedomSiteMetadata};
+use crate::stream::StreamMux;
+
+/// Largest response body a fetch buffers
+pub const MAX_RESPONSE_LEN: u64 = 16 * 1024 * 1024;
+
+/// How long a site may take to start answering a request
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FreedomRequest {
@@ -18,63 +33,140 @@ pub struct FreedomResponse {
     pub body: Vec<u8>,
 }
 
-pub struct FreedomClient {
-    resolver: Arc<crate::resolver::FreedomResolver>,
+pub struct FreedomClient<T> {
+    resolver: Arc<FreedomResolver<T>>,
+    router: Arc<OnionRouter>,
+    transport: Arc<T>,
+    num_hops: usize,
+    /// Rendezvous circuits kept open per site, so later requests skip the
+    /// introduction
+    sessions: Mutex<HashMap<String, StreamMux>>,
 }
 
-impl FreedomClient {
-    pub fn new(resolver: Arc<crate::resolver::FreedomResolver>) -> Self {
-        Self { resolver }
+impl<T: CircuitTransport + DhtTransport + 'static> FreedomClient<T> {
+    pub fn new(resolver: Arc<FreedomResolver<T>>, router: Arc<OnionRouter>, transport: Arc<T>, num_hops: usize) -> Self {
+        Self {
+            resolver,
+            router,
+            transport,
+            num_hops,
+            sessions: Mutex::new(HashMap::new()),
+        }
     }
 
-    /// Fetch content from a .freedom site
-    pub async fn fetch(&self, domain: &str, path: &str) -> Result<FreedomResponse> {
-        // Resolve the domain
+    /// Send `method` for `path` to a .freedom site, with `headers` from the
+    /// client, and read back the whole response
+    pub async fn fetch(&self, domain: &str, method: &str, path: &str, headers: Headers) -> Result<FreedomResponse> {
+        // Resolve the domain, and refuse a site whose key doesn't match its name
         let metadata = self.resolver.resolve(domain).await?;
+        crate::resolver::verify_site_key(&metadata)?;
 
-        // Build the socket address
-        let addr_str = format!("{}:{}", 
-            metadata.ipv4.clone().unwrap_or_else(|| "127.0.0.1".to_string()),
-            metadata.port
-        );
-        let _addr: SocketAddr = addr_str.parse()?;
-
-        // For now, return a mock response
-        // In the full implementation, this would make a real QUIC connection
-        let mut headers = std::collections::HashMap::new();
-        headers.insert("Content-Type".to_string(), "text/html".to_string());
+        let mut stream = self.open(&metadata).await?;
+        // HTTP/1.0 keeps the server from chunking the body, and it closes
+        // the stream when it is done
+        let mut fields = vec![("Host".to_string(), metadata.domain.clone())];
+        fields.extend(headers.0);
+        let request = RequestHead {
+            method: method.to_string(),
+            target: path.to_string(),
+            version: "HTTP/1.0".to_string(),
+            headers: Headers(fields),
+        };
+        stream.write_all(&request.encode()).await?;
 
-        let body = format!(
-            "<html><body><h1>Content from {}</h1><p>Path: {}</p></body></html>",
-            domain, path
-        ).into_bytes();
+        let mut reader = HttpReader::new(stream);
+        let head = tokio::time::timeout(RESPONSE_TIMEOUT, reader.read_response())
+            .await
+            .map_err(|_| anyhow!("{} sent no response within {}s", metadata.domain, RESPONSE_TIMEOUT.as_secs()))??;
+        let mut body = Vec::new();
+        match head.body(method)? {
+            BodyLength::Fixed(len) if len > MAX_RESPONSE_LEN => {
+                return Err(anyhow!("{} sent a {} byte response; the limit is {}", metadata.domain, len, MAX_RESPONSE_LEN));
+            }
+            BodyLength::Chunked => return Err(anyhow!("{} chunked its answer to an HTTP/1.0 request", metadata.domain)),
+            BodyLength::UntilClose => {
+                // Read at most one byte past the limit, to tell a full body
+                // from an oversized one
+                body = reader.take_buffered();
+                let room = (MAX_RESPONSE_LEN + 1).saturating_sub(body.len() as u64);
+                reader.get_mut().take(room).read_to_end(&mut body).await?;
+                if body.len() as u64 > MAX_RESPONSE_LEN {
+                    return Err(anyhow!("{} sent more than {} bytes", metadata.domain, MAX_RESPONSE_LEN));
+                }
+            }
+            body_length => {
+                reader.copy_body(body_length, &mut body).await?;
+            }
+        }
 
+        let headers = head.headers.0.into_iter().map(|(name, value)| (name.to_ascii_lowercase(), value)).collect();
         Ok(FreedomResponse {
-            status: 200,
+            status: head.status,
             headers,
             body,
         })
     }
 
-    /// Fetch with fallback path
-    pub async fn fetch_with_index(&self, domain: &str, path: &str) -> Result<FreedomResponse> {
-        let clean_path = if path.is_empty() || path == "/" {
-            "/index.html".to_string()
-        } else {
-            path.to_string()
-        };
+    /// A stream to the site, over its open rendezvous if there is one
+    async fn open(&self, metadata: &FreedomSiteMetadata) -> Result<tokio::io::DuplexStream> {
+        let session = self.sessions.lock().await.get(&metadata.domain).cloned();
+        if let Some(session) = session.filter(|session| !session.is_closed()) {
+            if let Ok(stream) = session.open(&format!("{}:80", metadata.domain)).await {
+                return Ok(stream);
+            }
+        }
 
-        self.fetch(domain, &clean_path).await
+        let session = rendezvous::connect(&self.router, self.transport.clone(), &metadata.service, self.num_hops).await?;
+        let stream = session.open(&format!("{}:80", metadata.domain)).await?;
+        self.sessions.lock().await.insert(metadata.domain.clone(), session);
+        Ok(stream)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::identity::generate_identity;
+    use crate::resolver::testing::cache_site;
+    use crate::protocol::{generate_node_id, DHTMessage, RoutingMessage, ServiceDescriptor, DEFAULT_RECORD_TTL, DHT};
+
+    struct NoTransport;
+
+    impl DhtTransport for NoTransport {
+        async fn request(&self, addr: &str, _message: DHTMessage) -> Result<DHTMessage> {
+            Err(anyhow!("{} unreachable", addr))
+        }
+    }
+
+    impl CircuitTransport for NoTransport {
+        async fn send(&self, addr: &str, _message: RoutingMessage) -> Result<Option<RoutingMessage>> {
+            Err(anyhow!("{} unreachable", addr))
+        }
+    }
+
+    fn client() -> (Arc<FreedomResolver<NoTransport>>, FreedomClient<NoTransport>) {
+        let transport = Arc::new(NoTransport);
+        let dht = Arc::new(DHT::new(generate_node_id(b"client")));
+        let resolver = Arc::new(FreedomResolver::new(dht, transport.clone()));
+        let client = FreedomClient::new(resolver.clone(), Arc::new(OnionRouter::new()), transport, 3);
+        (resolver, client)
+    }
 
     #[tokio::test]
-    async fn test_client_creation() {
-        let resolver = Arc::new(crate::resolver::FreedomResolver::new(vec![]));
-        let _client = FreedomClient::new(resolver);
+    async fn test_fetch_needs_a_published_site() {
+        let (_, client) = client();
+        let owner = generate_identity().public();
+        assert!(client.fetch(&owner.to_domain(), "GET", "/index.html", Headers::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_checks_site_key() {
+        let (resolver, client) = client();
+        let domain = generate_identity().public().to_domain();
+
+        let forged = ServiceDescriptor::sign(&generate_identity(), [2u8; 32], Vec::new(), DEFAULT_RECORD_TTL);
+        cache_site(&resolver, &domain, FreedomSiteMetadata::from_descriptor(&domain, forged)).await;
+        let error = client.fetch(&domain, "GET", "/index.html", Headers::default()).await.unwrap_err();
+        assert!(error.to_string().contains("Site key does not match"), "{}", error);
     }
 }
@@ -0,0 +1,501 @@
+// Node configuration
+// Built from defaults, then an optional TOML file, then command-line flags
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use crate::exit::{ExitPolicy, DEFAULT_EXIT_POLICY};
+use crate::identity::PublicIdentity;
+use crate::path::DEFAULT_BANDWIDTH;
+use crate::privacy::PrivacyPolicy;
+use crate::protocol::{unpin_addr, NodeId};
+
+/// Config file read when `--config` is not given
+pub const DEFAULT_CONFIG_FILE: &str = "freedom-node.toml";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HostedSite {
+    pub domain: String,
+    pub path: PathBuf,
+    #[serde(default = "default_index")]
+    pub index: String,
+}
+
+fn default_index() -> String {
+    "index.fdom".to_string()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    pub quic_address: SocketAddr,
+    /// Where peers reach this node, as its descriptor says; defaults to
+    /// `quic_address`, which then can't be a wildcard
+    pub advertised_address: Option<SocketAddr>,
+    pub proxy_address: SocketAddr,
+    pub dashboard_address: SocketAddr,
+    /// SOCKS5 listen address; no SOCKS listener when unset
+    pub socks_address: Option<SocketAddr>,
+    /// "USER:PASS" logins the SOCKS listener requires; when empty it takes
+    /// any login, using it only to give each application its own circuits
+    pub socks_users: Vec<String>,
+    /// What the HTTP proxy scrubs from requests before they leave an exit
+    pub privacy_policy: PrivacyPolicy,
+    pub bootstrap_nodes: Vec<String>,
+    pub hop_count: usize,
+    /// Clean circuits kept built ahead of need
+    pub circuit_pool: usize,
+    pub data_dir: PathBuf,
+    pub sites: Vec<HostedSite>,
+    /// Open streams out of the network for circuits that end here
+    pub exit_relay: bool,
+    /// Exit policy rules, first match wins; only used by exit relays
+    pub exit_policy: Vec<String>,
+    /// Hex node IDs of other relays run by the same operator
+    pub family: Vec<String>,
+    /// Bytes per second this relay advertises it can carry
+    pub relay_bandwidth: u64,
+    /// Never build circuits through two relays in one /16 (or IPv6 /32)
+    pub distinct_subnets: bool,
+    /// Local address to serve as a hidden service, reached only through
+    /// rendezvous circuits
+    pub hidden_service: Option<SocketAddr>,
+    /// Human-readable names mapped onto key-derived .freedom names
+    pub pet_names: HashMap<String, String>,
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            quic_address: "127.0.0.1:5000".parse().unwrap(),
+            advertised_address: None,
+            proxy_address: "127.0.0.1:8080".parse().unwrap(),
+            dashboard_address: "127.0.0.1:9090".parse().unwrap(),
+            socks_address: None,
+            socks_users: Vec::new(),
+            privacy_policy: PrivacyPolicy::default(),
+            bootstrap_nodes: Vec::new(),
+            hop_count: 3,
+            circuit_pool: 2,
+            data_dir: PathBuf::from("freedom-data"),
+            sites: Vec::new(),
+            exit_relay: false,
+            exit_policy: DEFAULT_EXIT_POLICY.iter().map(|rule| rule.to_string()).collect(),
+            family: Vec::new(),
+            relay_bandwidth: DEFAULT_BANDWIDTH,
+            distinct_subnets: true,
+            hidden_service: None,
+            pet_names: HashMap::new(),
+        }
+    }
+}
+
+/// Command-line flags. Anything given here overrides the config file.
+#[derive(Debug, Default, Parser)]
+#[command(name = "freedom-node", version, about = "Freedom Network node")]
+pub struct Cli {
+    /// Path to a TOML config file
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// QUIC listen address for peer traffic
+    #[arg(long, value_name = "ADDR")]
+    pub quic_addr: Option<SocketAddr>,
+
+    /// Address peers reach this node at, when it differs from the QUIC one
+    #[arg(long, value_name = "ADDR")]
+    pub advertised_addr: Option<SocketAddr>,
+
+    /// HTTP proxy listen address
+    #[arg(long, value_name = "ADDR")]
+    pub proxy_addr: Option<SocketAddr>,
+
+    /// Web dashboard listen address
+    #[arg(long, value_name = "ADDR")]
+    pub dashboard_addr: Option<SocketAddr>,
+
+    /// SOCKS5 listen address
+    #[arg(long, value_name = "ADDR")]
+    pub socks_addr: Option<SocketAddr>,
+
+    /// Login the SOCKS5 listener accepts, as USER:PASS (repeatable; replaces the file's list)
+    #[arg(long = "socks-user", value_name = "USER:PASS")]
+    pub socks_users: Vec<String>,
+
+    /// Headers the HTTP proxy scrubs from requests
+    #[arg(long, value_name = "POLICY")]
+    pub privacy_policy: Option<PrivacyPolicy>,
+
+    /// Bootstrap peer to join through (repeatable; replaces the file's list)
+    #[arg(short, long = "bootstrap", value_name = "ADDR")]
+    pub bootstrap: Vec<String>,
+
+    /// Number of onion hops per circuit
+    #[arg(long, value_name = "N")]
+    pub hops: Option<usize>,
+
+    /// Directory for persistent node state
+    #[arg(long, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Host a site, as DOMAIN=PATH (repeatable; added to the file's list)
+    #[arg(long = "site", value_name = "DOMAIN=PATH", value_parser = parse_site)]
+    pub sites: Vec<HostedSite>,
+
+    /// Act as an exit relay
+    #[arg(long)]
+    pub exit: bool,
+
+    /// Exit policy rule such as "accept *:443" (repeatable; replaces the file's policy)
+    #[arg(long = "exit-policy", value_name = "RULE")]
+    pub exit_policy: Vec<String>,
+
+    /// Serve the local address ADDR as a hidden service
+    #[arg(long, value_name = "ADDR")]
+    pub hidden_service: Option<SocketAddr>,
+
+    /// Call a site by a name of your own, as NAME=ADDRESS (repeatable; added to the file's names)
+    #[arg(long = "pet-name", value_name = "NAME=ADDRESS", value_parser = parse_pet_name)]
+    pub pet_names: Vec<(String, String)>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Manage the node's long-lived identity key
+    #[command(subcommand)]
+    Identity(IdentityCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum IdentityCommand {
+    /// Print the node ID and public key
+    Show,
+    /// Write an encrypted copy of the identity to FILE
+    Export { file: PathBuf },
+    /// Install an exported identity as this node's identity
+    Import {
+        file: PathBuf,
+        /// Replace an existing identity (the old one is kept as a backup)
+        #[arg(long)]
+        force: bool,
+    },
+    /// Replace the identity with a new keypair (the old one is kept as a backup)
+    Rotate,
+}
+
+fn parse_site(arg: &str) -> Result<HostedSite, String> {
+    let (domain, path) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected DOMAIN=PATH, got '{}'", arg))?;
+    if domain.is_empty() || path.is_empty() {
+        return Err(format!("expected DOMAIN=PATH, got '{}'", arg));
+    }
+    Ok(HostedSite {
+        domain: domain.to_string(),
+        path: PathBuf::from(path),
+        index: default_index(),
+    })
+}
+
+fn parse_pet_name(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, address)) if !name.is_empty() && !address.is_empty() => Ok((name.to_string(), address.to_string())),
+        _ => Err(format!("expected NAME=ADDRESS, got '{}'", arg)),
+    }
+}
+
+impl NodeConfig {
+    /// Parse a TOML config file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("Invalid config {}", path.display()))
+    }
+
+    /// Resolve the effective config for a command line. An explicit
+    /// `--config` must exist; the default file is optional.
+    pub fn load(cli: &Cli) -> Result<Self> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Self::default(),
+        };
+        config.apply_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn apply_cli(&mut self, cli: &Cli) {
+        if let Some(addr) = cli.quic_addr {
+            self.quic_address = addr;
+        }
+        if let Some(addr) = cli.advertised_addr {
+            self.advertised_address = Some(addr);
+        }
+        if let Some(addr) = cli.proxy_addr {
+            self.proxy_address = addr;
+        }
+        if let Some(addr) = cli.dashboard_addr {
+            self.dashboard_address = addr;
+        }
+        if let Some(addr) = cli.socks_addr {
+            self.socks_address = Some(addr);
+        }
+        if !cli.socks_users.is_empty() {
+            self.socks_users = cli.socks_users.clone();
+        }
+        if let Some(policy) = cli.privacy_policy {
+            self.privacy_policy = policy;
+        }
+        if !cli.bootstrap.is_empty() {
+            self.bootstrap_nodes = cli.bootstrap.clone();
+        }
+        if let Some(hops) = cli.hops {
+            self.hop_count = hops;
+        }
+        if let Some(dir) = &cli.data_dir {
+            self.data_dir = dir.clone();
+        }
+        self.sites.extend(cli.sites.iter().cloned());
+        if cli.exit {
+            self.exit_relay = true;
+        }
+        if !cli.exit_policy.is_empty() {
+            self.exit_policy = cli.exit_policy.clone();
+        }
+        if let Some(addr) = cli.hidden_service {
+            self.hidden_service = Some(addr);
+        }
+        self.pet_names.extend(cli.pet_names.iter().cloned());
+    }
+
+    /// The address this node's descriptor gives peers
+    pub fn advertised_address(&self) -> SocketAddr {
+        self.advertised_address.unwrap_or(self.quic_address)
+    }
+
+    /// The parsed exit policy if this node is an exit relay
+    pub fn exit_policy(&self) -> Result<Option<ExitPolicy>> {
+        if !self.exit_relay {
+            return Ok(None);
+        }
+        ExitPolicy::parse(&self.exit_policy).map(Some)
+    }
+
+    /// The declared family as node IDs
+    pub fn family(&self) -> Result<Vec<NodeId>> {
+        self.family
+            .iter()
+            .map(|id| NodeId::from_hex(id).ok_or_else(|| anyhow!("Invalid family node ID '{}'", id)))
+            .collect()
+    }
+
+    /// SOCKS logins as username to password
+    pub fn socks_users(&self) -> Result<HashMap<String, String>> {
+        self.socks_users
+            .iter()
+            .map(|login| {
+                // RFC 1929 carries each field behind a one-byte length
+                let (user, pass) = login
+                    .split_once(':')
+                    .filter(|(user, pass)| !user.is_empty() && user.len() <= 255 && pass.len() <= 255)
+                    .ok_or_else(|| anyhow!("Invalid SOCKS login '{}', expected USER:PASS", login))?;
+                Ok((user.to_string(), pass.to_string()))
+            })
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.hop_count == 0 {
+            return Err(anyhow!("hop_count must be at least 1"));
+        }
+        if self.circuit_pool == 0 {
+            return Err(anyhow!("circuit_pool must be at least 1"));
+        }
+        if self.advertised_address().ip().is_unspecified() {
+            return Err(anyhow!(
+                "{} is not an address peers can reach; set advertised_address",
+                self.advertised_address()
+            ));
+        }
+        for addr in &self.bootstrap_nodes {
+            // Either host:port, or node_id@host:port to pin the node's key
+            unpin_addr(addr)
+                .map_err(|e| anyhow!(e))
+                .and_then(|(_, plain)| Ok(plain.parse::<SocketAddr>()?))
+                .with_context(|| format!("Invalid bootstrap address '{}'", addr))?;
+        }
+        // A site is this node's hidden service, so it has the name the
+        // service key derives, and there is only the one
+        if self.sites.len() > 1 {
+            return Err(anyhow!("Only one site can be hosted, as the node's hidden service"));
+        }
+        for site in &self.sites {
+            PublicIdentity::from_domain(&site.domain.to_ascii_lowercase())
+                .with_context(|| format!("Hosted site '{}' must be named after the hidden service key", site.domain))?;
+        }
+        if !self.sites.is_empty() && self.hidden_service.is_some() {
+            return Err(anyhow!("Hosted sites are served as the hidden service; set either sites or hidden_service"));
+        }
+        ExitPolicy::parse(&self.exit_policy)?;
+        self.family()?;
+        self.socks_users()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let config = NodeConfig::default();
+        assert_eq!(config.quic_address.to_string(), "127.0.0.1:5000");
+        assert_eq!(config.proxy_address.to_string(), "127.0.0.1:8080");
+        assert_eq!(config.dashboard_address.to_string(), "127.0.0.1:9090");
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_file_then_cli_overrides() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("node.toml");
+        std::fs::write(&path, r#"
+quic_address = "0.0.0.0:6000"
+advertised_address = "192.0.2.7:6000"
+bootstrap_nodes = ["10.0.0.1:5000"]
+hop_count = 2
+
+"#).unwrap();
+
+        let site = format!("{}=sites/chat-site", crate::identity::generate_identity().public().to_domain());
+        let cli = Cli::try_parse_from([
+            "freedom-node",
+            "--config", path.to_str().unwrap(),
+            "--proxy-addr", "127.0.0.1:8181",
+            "--site", &site,
+        ]).unwrap();
+        let config = NodeConfig::load(&cli).unwrap();
+
+        assert_eq!(config.quic_address.to_string(), "0.0.0.0:6000");
+        assert_eq!(config.advertised_address().to_string(), "192.0.2.7:6000");
+        assert_eq!(config.proxy_address.to_string(), "127.0.0.1:8181");
+        assert_eq!(config.dashboard_address.to_string(), "127.0.0.1:9090");
+        assert_eq!(config.bootstrap_nodes, vec!["10.0.0.1:5000".to_string()]);
+        assert_eq!(config.hop_count, 2);
+        assert_eq!(config.sites.len(), 1);
+        assert_eq!(config.sites[0].index, "index.fdom");
+        assert_eq!(config.sites[0].path, PathBuf::from("sites/chat-site"));
+    }
+
+    #[test]
+    fn test_exit_relay_settings() {
+        let config = NodeConfig::default();
+        assert_eq!(config.exit_policy().unwrap(), None);
+
+        let cli = Cli::try_parse_from(["freedom-node", "--exit", "--exit-policy", "accept *:22", "--exit-policy", "reject *:*"]).unwrap();
+        let config = NodeConfig::load(&cli).unwrap();
+        let policy = config.exit_policy().unwrap().unwrap();
+        assert!(policy.allows(&"192.0.2.1:22".parse().unwrap()));
+        assert!(!policy.allows(&"192.0.2.1:443".parse().unwrap()));
+
+        // An exit without its own policy gets the default one
+        let config: NodeConfig = toml::from_str("exit_relay = true").unwrap();
+        assert_eq!(config.exit_policy().unwrap(), Some(ExitPolicy::default()));
+
+        let cli = Cli::try_parse_from(["freedom-node", "--exit-policy", "allow *:80"]).unwrap();
+        assert!(NodeConfig::load(&cli).is_err());
+
+        let mut config = NodeConfig { family: vec!["ab".repeat(32)], ..NodeConfig::default() };
+        assert_eq!(config.family().unwrap(), vec![NodeId([0xab; 32])]);
+        config.family.push("abcd".to_string());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_socks_settings() {
+        assert_eq!(NodeConfig::default().socks_address, None);
+
+        let cli = Cli::try_parse_from(["freedom-node", "--socks-addr", "127.0.0.1:1080", "--socks-user", "alice:s3cret:x"]).unwrap();
+        let config = NodeConfig::load(&cli).unwrap();
+        assert_eq!(config.socks_address, Some("127.0.0.1:1080".parse().unwrap()));
+        assert_eq!(config.socks_users().unwrap()["alice"], "s3cret:x");
+        // Passwords may contain colons, but a login needs one
+        let cli = Cli::try_parse_from(["freedom-node", "--socks-user", "nopassword"]).unwrap();
+        assert!(NodeConfig::load(&cli).is_err());
+    }
+
+    #[test]
+    fn test_privacy_policy() {
+        assert_eq!(NodeConfig::default().privacy_policy, PrivacyPolicy::Standard);
+
+        let config: NodeConfig = toml::from_str("privacy_policy = \"strict\"").unwrap();
+        assert_eq!(config.privacy_policy, PrivacyPolicy::Strict);
+        let cli = Cli::try_parse_from(["freedom-node", "--privacy-policy", "off"]).unwrap();
+        assert_eq!(NodeConfig::load(&cli).unwrap().privacy_policy, PrivacyPolicy::Off);
+
+        assert!(toml::from_str::<NodeConfig>("privacy_policy = \"paranoid\"").is_err());
+        assert!(Cli::try_parse_from(["freedom-node", "--privacy-policy", "paranoid"]).is_err());
+    }
+
+    #[test]
+    fn test_pet_names() {
+        let config: NodeConfig = toml::from_str("[pet_names]\nblog = \"abc.freedom\"").unwrap();
+        assert_eq!(config.pet_names["blog"], "abc.freedom");
+
+        let cli = Cli::try_parse_from(["freedom-node", "--pet-name", "chat=def.freedom"]).unwrap();
+        assert_eq!(NodeConfig::load(&cli).unwrap().pet_names["chat"], "def.freedom");
+        assert!(Cli::try_parse_from(["freedom-node", "--pet-name", "chat"]).is_err());
+    }
+
+    #[test]
+    fn test_identity_subcommands() {
+        let cli = Cli::try_parse_from(["freedom-node", "--data-dir", "/tmp/n1", "identity", "import", "k.key", "--force"]).unwrap();
+        assert_eq!(cli.data_dir, Some(PathBuf::from("/tmp/n1")));
+        match cli.command {
+            Some(Command::Identity(IdentityCommand::Import { file, force })) => {
+                assert_eq!(file, PathBuf::from("k.key"));
+                assert!(force);
+            }
+            other => panic!("unexpected command: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_rejects_bad_config() {
+        let cli = Cli::try_parse_from(["freedom-node", "--config", "/nonexistent/node.toml"]).unwrap();
+        assert!(NodeConfig::load(&cli).is_err());
+
+        assert!(Cli::try_parse_from(["freedom-node", "--site", "no-path"]).is_err());
+        assert!(toml::from_str::<NodeConfig>("unknown_key = 1").is_err());
+
+        let mut config = NodeConfig::default();
+        config.bootstrap_nodes.push(format!("{}@10.0.0.1:5000", "ab".repeat(32)));
+        assert!(config.validate().is_ok());
+        config.bootstrap_nodes.push("abcd@10.0.0.1:5000".to_string());
+        assert!(config.validate().is_err());
+        config.bootstrap_nodes = vec!["not-an-address".to_string()];
+        assert!(config.validate().is_err());
+
+        // A wildcard bind needs an address to advertise
+        let cli = Cli::try_parse_from(["freedom-node", "--quic-addr", "0.0.0.0:5000"]).unwrap();
+        assert!(NodeConfig::load(&cli).is_err());
+        let cli = Cli::try_parse_from(["freedom-node", "--quic-addr", "[::]:5000", "--advertised-addr", "[2001:db8::1]:5000"]).unwrap();
+        assert_eq!(NodeConfig::load(&cli).unwrap().advertised_address().to_string(), "[2001:db8::1]:5000");
+
+        // Sites are the hidden service: one, named after its key, and no other service
+        let cli = Cli::try_parse_from(["freedom-node", "--site", "blog.freedom=sites/blog"]).unwrap();
+        assert!(NodeConfig::load(&cli).is_err());
+        let cli = Cli::try_parse_from(["freedom-node", "--hidden-service", "127.0.0.1:8000"]).unwrap();
+        assert_eq!(NodeConfig::load(&cli).unwrap().hidden_service, Some("127.0.0.1:8000".parse().unwrap()));
+        let cli = Cli::try_parse_from(["freedom-node", "--hidden-service", "127.0.0.1:8000", "--site", "blog.freedom=sites/blog"]).unwrap();
+        assert!(NodeConfig::load(&cli).is_err());
+    }
+}
@@ -0,0 +1,233 @@
+// Request dispatcher for incoming QUIC streams
+// Decodes each frame and hands it to the DHT or the circuit relay
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use anyhow::Result;
+use quinn::Connection;
+use crate::lookup::{self, DhtTransport, QUERY_TIMEOUT};
+use crate::onion::CircuitTransport;
+use crate::protocol::{DHTMessage, NodeId, PeerInfo, DHT};
+use crate::relay::Relay;
+use crate::wire::{self, ErrorCode, Frame, WireError};
+
+pub struct Dispatcher<T: DhtTransport + CircuitTransport> {
+    dht: Arc<DHT>,
+    relay: Arc<Relay<T>>,
+    transport: Arc<T>,
+    /// Senders being pinged before they may join the routing table
+    verifying: Arc<Mutex<HashSet<NodeId>>>,
+}
+
+impl<T: DhtTransport + CircuitTransport + 'static> Dispatcher<T> {
+    pub fn new(dht: Arc<DHT>, relay: Arc<Relay<T>>, transport: Arc<T>) -> Self {
+        Self { dht, relay, transport, verifying: Arc::default() }
+    }
+
+    /// Serve every stream a peer opens on this connection
+    pub async fn serve_connection(self: Arc<Self>, conn: Connection) {
+        let remote = conn.remote_address();
+        while let Ok((mut send, mut recv)) = conn.accept_bi().await {
+            let dispatcher = self.clone();
+            tokio::spawn(async move {
+                let result: Result<()> = async {
+                    // A stream may carry several requests; each gets one reply
+                    while let Some(raw) = wire::read_frame(&mut recv).await? {
+                        let reply = match raw.decode() {
+                            Ok(frame) => dispatcher.handle(frame, remote).await,
+                            Err(error) => Frame::Error(error),
+                        };
+                        wire::write_frame(&mut send, &reply).await?;
+                    }
+                    send.finish().await?;
+                    Ok(())
+                }
+                .await;
+
+                if let Err(e) = result {
+                    eprintln!("❌ Stream from {} failed: {}", remote, e);
+                }
+            });
+        }
+    }
+
+    /// Route one request frame and build its reply
+    pub async fn handle(&self, frame: Frame, remote: SocketAddr) -> Frame {
+        match frame {
+            Frame::Dht(message) => self.handle_dht(message, remote).await,
+            Frame::Routing(message) => self.relay.handle(message, remote).await,
+            // Sites are hidden services: a peer asking for their files
+            // directly would learn which node hosts them
+            Frame::Content(_) => Frame::Error(WireError::new(
+                ErrorCode::NotFound,
+                "Sites are only served over rendezvous circuits",
+            )),
+            Frame::Ack | Frame::Error(_) => Frame::Error(WireError::new(
+                ErrorCode::UnexpectedMessage,
+                "Replies are not accepted as requests",
+            )),
+        }
+    }
+
+    async fn handle_dht(&self, message: DHTMessage, remote: SocketAddr) -> Frame {
+        // The endpoint dials out from its listening socket, so the remote
+        // address is also where the sender can be reached
+        let sender = match &message {
+            DHTMessage::FindNode { requesting_node, .. } => Some(requesting_node.clone()),
+            DHTMessage::Ping { sender } => Some(sender.clone()),
+            _ => None,
+        };
+
+        let reply = match self.dht.handle_message(message) {
+            Some(reply) => Frame::Dht(reply),
+            None => Frame::Ack,
+        };
+
+        if let Some(node_id) = sender {
+            let peer = PeerInfo { node_id, addr: remote.to_string() };
+            if self.dht.contains_peer(&peer) {
+                self.dht.insert_peer(peer);
+            } else {
+                self.verify_peer(peer);
+            }
+        }
+
+        reply
+    }
+
+    /// Anyone can claim any NodeId, so a new sender only joins the routing
+    /// table once a ping pinned to that ID at its address comes back. The
+    /// ping runs in the background so the reply isn't held up, and a sender
+    /// already being checked isn't pinged again (its own check may be what
+    /// is pinging us).
+    fn verify_peer(&self, peer: PeerInfo) {
+        if !self.verifying.lock().unwrap().insert(peer.node_id.clone()) {
+            return;
+        }
+        let dht = self.dht.clone();
+        let transport = self.transport.clone();
+        let verifying = self.verifying.clone();
+        tokio::spawn(async move {
+            let local_id = dht.local_id().clone();
+            if lookup::ping(transport.as_ref(), local_id.clone(), peer.clone(), QUERY_TIMEOUT).await {
+                dht.add_peer(peer.clone(), |oldest| lookup::ping(transport.as_ref(), local_id, oldest, QUERY_TIMEOUT))
+                    .await;
+            }
+            verifying.lock().unwrap().remove(&peer.node_id);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::ClientHandshake;
+    use crate::identity::{generate_identity, Identity};
+    use crate::protocol::{ContentMessage, DomainRecord, NodeId, RoutingMessage, DEFAULT_RECORD_TTL};
+    use anyhow::anyhow;
+
+    struct NoTransport;
+
+    impl DhtTransport for NoTransport {
+        async fn request(&self, addr: &str, _message: DHTMessage) -> Result<DHTMessage> {
+            Err(anyhow!("{} unreachable", addr))
+        }
+    }
+
+    impl CircuitTransport for NoTransport {
+        async fn send(&self, addr: &str, _message: RoutingMessage) -> Result<Option<RoutingMessage>> {
+            Err(anyhow!("{} unreachable", addr))
+        }
+    }
+
+    fn dispatcher_for(identity: Arc<Identity>) -> Dispatcher<NoTransport> {
+        let transport = Arc::new(NoTransport);
+        Dispatcher::new(
+            Arc::new(DHT::new(identity.node_id())),
+            Arc::new(Relay::new(identity, transport.clone())),
+            transport,
+        )
+    }
+
+    fn dispatcher() -> Dispatcher<NoTransport> {
+        dispatcher_for(Arc::new(generate_identity()))
+    }
+
+    fn remote() -> SocketAddr {
+        "127.0.0.1:6000".parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_dht_requests() {
+        let dispatcher = dispatcher();
+        let reply = dispatcher.handle(Frame::Dht(DHTMessage::Ping { sender: NodeId([1u8; 32]) }), remote()).await;
+        assert!(matches!(reply, Frame::Dht(DHTMessage::Pong { .. })));
+
+        // The sender can't be pinged back, so it never joins the routing table
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(dispatcher.dht.peer_count(), 0);
+        assert!(dispatcher.verifying.lock().unwrap().is_empty());
+
+        let owner = generate_identity();
+        let record = DomainRecord::sign(&owner, "example.freedom", NodeId([1u8; 32]), 1, DEFAULT_RECORD_TTL);
+        let store = DHTMessage::StoreFreedomDomain { record: record.clone() };
+        let reply = dispatcher.handle(Frame::Dht(store), remote()).await;
+        assert!(matches!(reply, Frame::Dht(DHTMessage::Stored { accepted: true, .. })));
+        assert_eq!(dispatcher.dht.lookup_domain("example.freedom"), Some(record));
+    }
+
+    #[tokio::test]
+    async fn test_routing_requests() {
+        let identity = Arc::new(generate_identity());
+        let dispatcher = dispatcher_for(identity.clone());
+
+        let onion_key = dispatcher.relay.onion_keys().public();
+        let (client, onion_skin) = ClientHandshake::start(&identity.node_id(), &onion_key);
+        let build = RoutingMessage::BuildCircuit { circuit_id: 42, handshake: onion_skin };
+        match dispatcher.handle(Frame::Routing(build.clone()), remote()).await {
+            Frame::Routing(RoutingMessage::CircuitCreated { circuit_id: 42, handshake }) => {
+                assert!(client.complete(&handshake).is_ok());
+            }
+            other => panic!("unexpected reply: {:?}", other),
+        }
+        assert!(matches!(dispatcher.handle(Frame::Routing(build), remote()).await, Frame::Error(_)));
+
+        // A cell that doesn't open under the hop's key is rejected
+        let relay = RoutingMessage::RelayData { circuit_id: 42, cells: vec![vec![1; 64]] };
+        match dispatcher.handle(Frame::Routing(relay), remote()).await {
+            Frame::Error(e) => assert_eq!(e.code, ErrorCode::Malformed),
+            other => panic!("unexpected reply: {:?}", other),
+        }
+
+        let destroy = RoutingMessage::DestroyCircuit { circuit_id: 42 };
+        assert!(matches!(dispatcher.handle(Frame::Routing(destroy), remote()).await, Frame::Ack));
+
+        let relay = RoutingMessage::RelayData { circuit_id: 42, cells: vec![vec![1]] };
+        match dispatcher.handle(Frame::Routing(relay), remote()).await {
+            Frame::Error(e) => assert_eq!(e.code, ErrorCode::NotFound),
+            other => panic!("unexpected reply: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_content_requests_are_refused() {
+        let dispatcher = dispatcher();
+        let get = ContentMessage::GetContent {
+            domain: "test.freedom".to_string(),
+            path: "/index.fdom".to_string(),
+            circuit_id: 0,
+        };
+        match dispatcher.handle(Frame::Content(get), remote()).await {
+            Frame::Error(e) => assert_eq!(e.code, ErrorCode::NotFound),
+            other => panic!("unexpected reply: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejects_replies_as_requests() {
+        let dispatcher = dispatcher();
+        let reply = dispatcher.handle(Frame::Ack, remote()).await;
+        assert!(matches!(reply, Frame::Error(e) if e.code == ErrorCode::UnexpectedMessage));
+    }
+}
@@ -1,9 +1,11 @@
 use chacha20poly1305::{aead::{Aead, KeyInit}, ChaCha20Poly1305, Key, Nonce};
 use rand::RngCore;
 
+const NONCE_LEN: usize = 12;
+
 pub fn encrypt(data: &[u8], key_bytes: &[u8;32]) -> Vec<u8> {
     let cipher = ChaCha20Poly1305::new(Key::from_slice(key_bytes));
-    let mut nonce_bytes = [0u8;12];
+    let mut nonce_bytes = [0u8;NONCE_LEN];
     rand::thread_rng().fill_bytes(&mut nonce_bytes);
     let nonce = Nonce::from_slice(&nonce_bytes);
 
@@ -13,9 +15,13 @@ pub fn encrypt(data: &[u8], key_bytes: &[u8;32]) -> Vec<u8> {
     result
 }
 
-pub fn decrypt(ciphertext: &[u8], key_bytes: &[u8;32]) -> Vec<u8> {
+/// Fails if the ciphertext is truncated, was tampered with, or the key is wrong
+pub fn decrypt(ciphertext: &[u8], key_bytes: &[u8;32]) -> Result<Vec<u8>, chacha20poly1305::Error> {
+    if ciphertext.len() < NONCE_LEN {
+        return Err(chacha20poly1305::Error);
+    }
     let cipher = ChaCha20Poly1305::new(Key::from_slice(key_bytes));
-    let (nonce_bytes, ct) = ciphertext.split_at(12);
+    let (nonce_bytes, ct) = ciphertext.split_at(NONCE_LEN);
     let nonce = Nonce::from_slice(nonce_bytes);
-    cipher.decrypt(nonce, ct).unwrap()
-}
\ No newline at end of file
+    cipher.decrypt(nonce, ct)
+}
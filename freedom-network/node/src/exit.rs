@@ -0,0 +1,254 @@
+// Exit policies
+// Decide which destinations an exit relay will open connections to. Rules
+// are checked in order and the first one that matches wins; a destination
+// no rule matches is refused. Rules use Tor's syntax:
+//
+//   accept|reject ADDR[/BITS]:PORTS
+//
+// where ADDR is `*`, an IPv4 address or a bracketed IPv6 address, and PORTS
+// is `*`, a single port or a range such as 6660-6669.
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Web traffic only, and never into private or local networks
+pub const DEFAULT_EXIT_POLICY: &[&str] = &[
+    "reject 0.0.0.0/8:*",
+    "reject 10.0.0.0/8:*",
+    "reject 100.64.0.0/10:*",
+    "reject 127.0.0.0/8:*",
+    "reject 169.254.0.0/16:*",
+    "reject 172.16.0.0/12:*",
+    "reject 192.168.0.0/16:*",
+    "reject [::]/128:*",
+    "reject [::1]/128:*",
+    "reject [fc00::]/7:*",
+    "reject [fe80::]/10:*",
+    "accept *:80",
+    "accept *:443",
+    "reject *:*",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExitRule {
+    pub accept: bool,
+    /// Network and prefix length; `None` matches every address
+    pub network: Option<(IpAddr, u8)>,
+    /// Inclusive port range
+    pub ports: (u16, u16),
+}
+
+impl ExitRule {
+    pub fn parse(rule: &str) -> Result<Self> {
+        let (action, pattern) = rule
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("expected 'accept|reject ADDR:PORTS'"))?;
+        let accept = match action {
+            "accept" => true,
+            "reject" => false,
+            other => return Err(anyhow!("unknown action '{}'", other)),
+        };
+        let (addr, ports) = pattern
+            .trim()
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("missing ':PORTS' in '{}'", pattern.trim()))?;
+        Ok(ExitRule { accept, network: parse_network(addr)?, ports: parse_ports(ports)? })
+    }
+
+    fn matches_port(&self, port: u16) -> bool {
+        self.ports.0 <= port && port <= self.ports.1
+    }
+
+    fn matches(&self, ip: IpAddr, port: u16) -> bool {
+        if !self.matches_port(port) {
+            return false;
+        }
+        match self.network {
+            None => true,
+            Some((network, bits)) => in_network(ip, network, bits),
+        }
+    }
+}
+
+impl fmt::Display for ExitRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.accept { "accept " } else { "reject " })?;
+        match self.network {
+            None => f.write_str("*")?,
+            Some((IpAddr::V4(ip), bits)) => write!(f, "{}/{}", ip, bits)?,
+            Some((IpAddr::V6(ip), bits)) => write!(f, "[{}]/{}", ip, bits)?,
+        }
+        match self.ports {
+            (0, u16::MAX) => f.write_str(":*"),
+            (low, high) if low == high => write!(f, ":{}", low),
+            (low, high) => write!(f, ":{}-{}", low, high),
+        }
+    }
+}
+
+fn parse_network(addr: &str) -> Result<Option<(IpAddr, u8)>> {
+    if addr == "*" {
+        return Ok(None);
+    }
+    let (ip, bits) = match addr.split_once('/') {
+        Some((ip, bits)) => (ip, Some(bits)),
+        None => (addr, None),
+    };
+    let ip: IpAddr = ip
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .with_context(|| format!("bad address '{}'", ip))?;
+    let max = if ip.is_ipv4() { 32 } else { 128 };
+    let bits = match bits {
+        Some(bits) => bits.parse::<u8>().ok().filter(|&b| b <= max).ok_or_else(|| anyhow!("bad prefix length '{}'", bits))?,
+        None => max,
+    };
+    Ok(Some((ip, bits)))
+}
+
+fn parse_ports(ports: &str) -> Result<(u16, u16)> {
+    if ports == "*" {
+        return Ok((0, u16::MAX));
+    }
+    let parse = |port: &str| port.parse::<u16>().with_context(|| format!("bad port '{}'", port));
+    let (low, high) = match ports.split_once('-') {
+        Some((low, high)) => (parse(low)?, parse(high)?),
+        None => (parse(ports)?, parse(ports)?),
+    };
+    if low > high {
+        return Err(anyhow!("empty port range '{}'", ports));
+    }
+    Ok((low, high))
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, bits: u8) -> bool {
+    match (ip.to_canonical(), network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - bits as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - bits as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExitPolicy {
+    rules: Vec<ExitRule>,
+}
+
+impl Default for ExitPolicy {
+    fn default() -> Self {
+        Self::parse(DEFAULT_EXIT_POLICY).expect("default exit policy parses")
+    }
+}
+
+impl ExitPolicy {
+    pub fn parse<S: AsRef<str>>(rules: &[S]) -> Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| ExitRule::parse(rule.as_ref()).with_context(|| format!("Invalid exit rule '{}'", rule.as_ref())))
+            .collect::<Result<_>>()?;
+        Ok(ExitPolicy { rules })
+    }
+
+    pub fn rules(&self) -> &[ExitRule] {
+        &self.rules
+    }
+
+    /// Whether the exit will connect to `addr`
+    pub fn allows(&self, addr: &SocketAddr) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(addr.ip(), addr.port()))
+            .is_some_and(|rule| rule.accept)
+    }
+
+    /// Whether some address on `port` could be allowed; used for destinations
+    /// only known by hostname until the exit resolves them
+    pub fn allows_port(&self, port: u16) -> bool {
+        for rule in self.rules.iter().filter(|rule| rule.matches_port(port)) {
+            if rule.accept {
+                return true;
+            }
+            if rule.network.is_none() {
+                return false;
+            }
+        }
+        false
+    }
+
+    /// Check a "host:port" target as far as is possible before resolving it
+    pub fn may_allow(&self, target: &str) -> bool {
+        if let Ok(addr) = target.parse::<SocketAddr>() {
+            return self.allows(&addr);
+        }
+        target
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok())
+            .is_some_and(|port| self.allows_port(port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_first_match_wins() {
+        let policy = ExitPolicy::parse(&["reject 10.0.0.0/8:*", "accept *:80", "accept [2001:db8::]/32:22", "reject *:*"]).unwrap();
+        assert!(policy.allows(&addr("93.184.216.34:80")));
+        assert!(!policy.allows(&addr("10.1.2.3:80")));
+        assert!(!policy.allows(&addr("93.184.216.34:22")));
+        assert!(policy.allows(&addr("[2001:db8::1]:22")));
+        assert!(!policy.allows(&addr("[2001:db9::1]:22")));
+
+        // Nothing matched means refused
+        assert!(!ExitPolicy::parse(&["accept *:443"]).unwrap().allows(&addr("1.1.1.1:80")));
+    }
+
+    #[test]
+    fn test_default_policy_keeps_private_networks_out() {
+        let policy = ExitPolicy::default();
+        assert!(policy.allows(&addr("93.184.216.34:443")));
+        assert!(!policy.allows(&addr("93.184.216.34:25")));
+        for private in ["127.0.0.1:80", "192.168.1.1:443", "172.20.0.5:80", "[::1]:443", "[fe80::1]:80", "[::ffff:10.0.0.1]:80"] {
+            assert!(!policy.allows(&addr(private)), "{}", private);
+        }
+    }
+
+    #[test]
+    fn test_hostname_targets() {
+        let policy = ExitPolicy::default();
+        assert!(policy.may_allow("example.com:443"));
+        assert!(!policy.may_allow("example.com:25"));
+        assert!(!policy.may_allow("127.0.0.1:443"));
+        assert!(!policy.may_allow("no-port"));
+
+        // A port accepted only for some network might still be reachable
+        let narrow = ExitPolicy::parse(&["accept 1.2.3.0/24:22", "reject *:*"]).unwrap();
+        assert!(narrow.allows_port(22));
+        assert!(!narrow.allows_port(23));
+    }
+
+    #[test]
+    fn test_rule_syntax() {
+        for rule in ["accept *:*", "reject 10.0.0.0/8:6660-6669", "accept [2001:db8::]/32:443", "accept 1.2.3.4/32:80"] {
+            assert_eq!(ExitRule::parse(rule).unwrap().to_string(), rule);
+        }
+        for bad in ["allow *:80", "accept *", "accept 1.2.3.4/33:80", "accept *:80-70", "accept *:http", "accept"] {
+            assert!(ExitRule::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,390 @@
+// Circuit handshake, ntor-style over X25519
+// The client sends an ephemeral key to a relay it knows by NodeId and onion
+// key; the relay answers with its own ephemeral key and proof that it holds
+// the onion key. Hop keys come from the ephemeral-ephemeral exchange, so a
+// later leak of a relay's long-term key does not expose recorded circuits.
+// Onion keys are X25519 keys of their own, published in signed descriptors
+// and replaced every ONION_KEY_LIFETIME.
+
+use std::fmt;
+use std::sync::RwLock;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use sha3::Sha3_256;
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+use crate::protocol::{unix_now, NodeId};
+
+const PROTOCOL_ID: &[u8] = b"freedom-ntor-x25519-sha3-256-1";
+const T_KEY: &[u8] = b"freedom-ntor-x25519-sha3-256-1:key_extract";
+const T_VERIFY: &[u8] = b"freedom-ntor-x25519-sha3-256-1:verify";
+const T_MAC: &[u8] = b"freedom-ntor-x25519-sha3-256-1:mac";
+const M_EXPAND: &[u8] = b"freedom-ntor-x25519-sha3-256-1:key_expand";
+const T_SEAL: &[u8] = b"freedom-seal-x25519-sha3-256-1";
+
+/// Client request: relay NodeId, relay onion key, client ephemeral key
+pub const ONION_SKIN_LEN: usize = 96;
+
+/// Relay reply: relay ephemeral key, then the authenticator
+pub const HANDSHAKE_REPLY_LEN: usize = 64;
+
+/// How long a node advertises one onion key before it makes a new one
+pub const ONION_KEY_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+type HmacSha3 = Hmac<Sha3_256>;
+
+/// A node's onion keys, newest first. Only the newest is advertised; the
+/// one it replaced is still answered until the next rotation, a lifetime
+/// later, by which time every descriptor naming it has expired.
+pub struct OnionKeys {
+    keys: RwLock<Vec<OnionKey>>,
+}
+
+struct OnionKey {
+    secret: StaticSecret,
+    public: PublicKey,
+    created_at: u64,
+}
+
+impl OnionKey {
+    fn generate(now: u64) -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        OnionKey { public: PublicKey::from(&secret), secret, created_at: now }
+    }
+}
+
+impl OnionKeys {
+    pub fn generate() -> Self {
+        Self::generate_at(unix_now())
+    }
+
+    pub fn generate_at(now: u64) -> Self {
+        OnionKeys { keys: RwLock::new(vec![OnionKey::generate(now)]) }
+    }
+
+    /// The key to advertise
+    pub fn public(&self) -> [u8; 32] {
+        self.keys.read().unwrap()[0].public.to_bytes()
+    }
+
+    /// Replace the advertised key once it is ONION_KEY_LIFETIME old and
+    /// forget the one before it. True if the advertised key changed.
+    pub fn rotate(&self, now: u64) -> bool {
+        let mut keys = self.keys.write().unwrap();
+        if now.saturating_sub(keys[0].created_at) < ONION_KEY_LIFETIME.as_secs() {
+            return false;
+        }
+        keys.truncate(1);
+        keys.insert(0, OnionKey::generate(now));
+        true
+    }
+
+    /// Secrets still answered, with their public halves
+    fn secrets(&self) -> Vec<(StaticSecret, PublicKey)> {
+        self.keys.read().unwrap().iter().map(|key| (key.secret.clone(), key.public)).collect()
+    }
+}
+
+/// Keys for one hop of a circuit, one per direction
+#[derive(Clone, PartialEq, Eq)]
+pub struct HopKeys {
+    pub forward: [u8; 32],
+    pub backward: [u8; 32],
+}
+
+impl fmt::Debug for HopKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HopKeys(..)")
+    }
+}
+
+/// Client state between sending the onion skin and reading the reply
+pub struct ClientHandshake {
+    node_id: NodeId,
+    onion_key: PublicKey,
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl ClientHandshake {
+    /// Begin a handshake with the relay `node_id` under its advertised
+    /// `onion_key`; the returned onion skin goes in the `BuildCircuit` or
+    /// `Extend` message
+    pub fn start(node_id: &NodeId, onion_key: &[u8; 32]) -> (Self, Vec<u8>) {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let handshake = ClientHandshake {
+            node_id: node_id.clone(),
+            onion_key: PublicKey::from(*onion_key),
+            public: PublicKey::from(&secret),
+            secret,
+        };
+
+        let mut onion_skin = Vec::with_capacity(ONION_SKIN_LEN);
+        onion_skin.extend_from_slice(&handshake.node_id.0);
+        onion_skin.extend_from_slice(handshake.onion_key.as_bytes());
+        onion_skin.extend_from_slice(handshake.public.as_bytes());
+        (handshake, onion_skin)
+    }
+
+    /// Check the relay's reply and derive the hop keys
+    pub fn complete(self, reply: &[u8]) -> Result<HopKeys> {
+        if reply.len() != HANDSHAKE_REPLY_LEN {
+            return Err(anyhow!("Handshake reply must be {} bytes, got {}", HANDSHAKE_REPLY_LEN, reply.len()));
+        }
+        let relay_public = PublicKey::from(<[u8; 32]>::try_from(&reply[..32]).unwrap());
+        let auth = &reply[32..];
+
+        let transcript = Transcript {
+            exp_xy: contributory(self.secret.diffie_hellman(&relay_public))?,
+            exp_xb: contributory(self.secret.diffie_hellman(&self.onion_key))?,
+            node_id: &self.node_id,
+            onion_key: &self.onion_key,
+            client: &self.public,
+            relay: &relay_public,
+        };
+
+        let mut mac = HmacSha3::new_from_slice(T_MAC).expect("HMAC accepts any key length");
+        mac.update(&transcript.auth_input());
+        mac.verify_slice(auth).map_err(|_| anyhow!("Relay failed to prove its onion key"))?;
+        Ok(transcript.hop_keys())
+    }
+}
+
+/// Relay side: answer an onion skin addressed to `node_id` under one of
+/// `keys`
+pub fn respond(node_id: &NodeId, keys: &OnionKeys, onion_skin: &[u8]) -> Result<(Vec<u8>, HopKeys)> {
+    if onion_skin.len() != ONION_SKIN_LEN {
+        return Err(anyhow!("Onion skin must be {} bytes, got {}", ONION_SKIN_LEN, onion_skin.len()));
+    }
+    if onion_skin[..32] != node_id.0 {
+        return Err(anyhow!("Onion skin is addressed to a different relay"));
+    }
+    let (onion_secret, onion_key) = keys
+        .secrets()
+        .into_iter()
+        .find(|(_, public)| onion_skin[32..64] == *public.as_bytes())
+        .ok_or_else(|| anyhow!("Onion skin is for an onion key this relay doesn't hold"))?;
+    let client = PublicKey::from(<[u8; 32]>::try_from(&onion_skin[64..]).unwrap());
+
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+    let transcript = Transcript {
+        exp_xy: contributory(secret.diffie_hellman(&client))?,
+        exp_xb: contributory(onion_secret.diffie_hellman(&client))?,
+        node_id,
+        onion_key: &onion_key,
+        client: &client,
+        relay: &public,
+    };
+
+    let mut mac = HmacSha3::new_from_slice(T_MAC).expect("HMAC accepts any key length");
+    mac.update(&transcript.auth_input());
+
+    let mut reply = Vec::with_capacity(HANDSHAKE_REPLY_LEN);
+    reply.extend_from_slice(public.as_bytes());
+    reply.extend_from_slice(&mac.finalize().into_bytes());
+    Ok((reply, transcript.hop_keys()))
+}
+
+/// Reject low-order points, which would make the shared secret predictable
+fn contributory(shared: SharedSecret) -> Result<[u8; 32]> {
+    if !shared.was_contributory() {
+        return Err(anyhow!("Handshake key is a low-order point"));
+    }
+    Ok(shared.to_bytes())
+}
+
+/// Encrypt `plaintext` so only the holder of the onion key `recipient` can
+/// read it: an ephemeral X25519 key, then the ciphertext under the shared
+/// secret. Nothing in it identifies the sender.
+pub fn seal_to(recipient: &[u8; 32], plaintext: &[u8]) -> Vec<u8> {
+    let recipient = PublicKey::from(*recipient);
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+    let key = seal_key(&secret.diffie_hellman(&recipient), &public, &recipient);
+    let mut sealed = public.as_bytes().to_vec();
+    sealed.extend(crate::encrypt::encrypt(plaintext, &key));
+    sealed
+}
+
+/// Open a message sealed with `seal_to` to one of `keys`
+pub fn open_sealed(keys: &OnionKeys, sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < 32 {
+        return Err(anyhow!("Sealed message is too short"));
+    }
+    let (public, ciphertext) = sealed.split_at(32);
+    let public = PublicKey::from(<[u8; 32]>::try_from(public).unwrap());
+    for (secret, recipient) in keys.secrets() {
+        let shared = secret.diffie_hellman(&public);
+        if !shared.was_contributory() {
+            return Err(anyhow!("Sealed message key is a low-order point"));
+        }
+        let key = seal_key(&shared, &public, &recipient);
+        if let Ok(plaintext) = crate::encrypt::decrypt(ciphertext, &key) {
+            return Ok(plaintext);
+        }
+    }
+    Err(anyhow!("Sealed message failed authentication"))
+}
+
+fn seal_key(shared: &SharedSecret, sender: &PublicKey, recipient: &PublicKey) -> [u8; 32] {
+    let mut info = sender.as_bytes().to_vec();
+    info.extend_from_slice(recipient.as_bytes());
+    let hkdf = Hkdf::<Sha3_256>::new(Some(T_SEAL), shared.as_bytes());
+    let mut key = [0u8; 32];
+    hkdf.expand(&info, &mut key).expect("32 bytes is a valid HKDF-SHA3-256 length");
+    key
+}
+
+/// Everything both sides agree on once the exchange is done
+struct Transcript<'a> {
+    exp_xy: [u8; 32],
+    exp_xb: [u8; 32],
+    node_id: &'a NodeId,
+    onion_key: &'a PublicKey,
+    client: &'a PublicKey,
+    relay: &'a PublicKey,
+}
+
+impl Transcript<'_> {
+    fn secret_input(&self) -> Vec<u8> {
+        let mut input = Vec::with_capacity(32 * 6 + PROTOCOL_ID.len());
+        input.extend_from_slice(&self.exp_xy);
+        input.extend_from_slice(&self.exp_xb);
+        input.extend_from_slice(&self.node_id.0);
+        input.extend_from_slice(self.onion_key.as_bytes());
+        input.extend_from_slice(self.client.as_bytes());
+        input.extend_from_slice(self.relay.as_bytes());
+        input.extend_from_slice(PROTOCOL_ID);
+        input
+    }
+
+    fn auth_input(&self) -> Vec<u8> {
+        let mut verify = HmacSha3::new_from_slice(T_VERIFY).expect("HMAC accepts any key length");
+        verify.update(&self.secret_input());
+
+        let mut input = verify.finalize().into_bytes().to_vec();
+        input.extend_from_slice(&self.node_id.0);
+        input.extend_from_slice(self.onion_key.as_bytes());
+        input.extend_from_slice(self.relay.as_bytes());
+        input.extend_from_slice(self.client.as_bytes());
+        input.extend_from_slice(PROTOCOL_ID);
+        input.extend_from_slice(b"Server");
+        input
+    }
+
+    fn hop_keys(&self) -> HopKeys {
+        let hkdf = Hkdf::<Sha3_256>::new(Some(T_KEY), &self.secret_input());
+        let mut okm = [0u8; 64];
+        hkdf.expand(M_EXPAND, &mut okm).expect("64 bytes is a valid HKDF-SHA3-256 length");
+
+        let mut keys = HopKeys { forward: [0u8; 32], backward: [0u8; 32] };
+        keys.forward.copy_from_slice(&okm[..32]);
+        keys.backward.copy_from_slice(&okm[32..]);
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::generate_node_id;
+
+    fn relay() -> (NodeId, OnionKeys) {
+        (generate_node_id(&rand::random::<[u8; 32]>()), OnionKeys::generate())
+    }
+
+    #[test]
+    fn test_handshake_agrees_on_keys() {
+        let (node_id, keys) = relay();
+        let (client, onion_skin) = ClientHandshake::start(&node_id, &keys.public());
+        assert_eq!(onion_skin.len(), ONION_SKIN_LEN);
+
+        let (reply, relay_keys) = respond(&node_id, &keys, &onion_skin).unwrap();
+        assert_eq!(reply.len(), HANDSHAKE_REPLY_LEN);
+        let client_keys = client.complete(&reply).unwrap();
+
+        assert_eq!(client_keys, relay_keys);
+        assert_ne!(client_keys.forward, client_keys.backward);
+    }
+
+    #[test]
+    fn test_every_handshake_gets_fresh_keys() {
+        let (node_id, keys) = relay();
+        let run = || {
+            let (client, onion_skin) = ClientHandshake::start(&node_id, &keys.public());
+            let (reply, _) = respond(&node_id, &keys, &onion_skin).unwrap();
+            client.complete(&reply).unwrap()
+        };
+        assert_ne!(run(), run());
+    }
+
+    #[test]
+    fn test_rejects_wrong_relay() {
+        let (node_id, keys) = relay();
+        let (impostor_id, impostor_keys) = relay();
+        let (client, onion_skin) = ClientHandshake::start(&node_id, &keys.public());
+        assert!(respond(&impostor_id, &impostor_keys, &onion_skin).is_err());
+        assert!(respond(&node_id, &impostor_keys, &onion_skin).is_err());
+
+        // An impostor that rewrites the skin can't produce a valid reply
+        let mut rewritten = onion_skin.clone();
+        rewritten[..32].copy_from_slice(&impostor_id.0);
+        rewritten[32..64].copy_from_slice(&impostor_keys.public());
+        let (reply, _) = respond(&impostor_id, &impostor_keys, &rewritten).unwrap();
+        assert!(client.complete(&reply).is_err());
+    }
+
+    #[test]
+    fn test_rejects_tampered_reply() {
+        let (node_id, keys) = relay();
+        let (client, onion_skin) = ClientHandshake::start(&node_id, &keys.public());
+        let (mut reply, _) = respond(&node_id, &keys, &onion_skin).unwrap();
+        reply[40] ^= 1;
+        assert!(client.complete(&reply).is_err());
+
+        let (client, onion_skin) = ClientHandshake::start(&node_id, &keys.public());
+        let mut low_order = onion_skin;
+        low_order[64..].copy_from_slice(&[0u8; 32]);
+        assert!(respond(&node_id, &keys, &low_order).is_err());
+        assert!(client.complete(&[0u8; 10]).is_err());
+    }
+
+    #[test]
+    fn test_onion_key_rotation() {
+        let node_id = generate_node_id(b"relay");
+        let keys = OnionKeys::generate_at(1000);
+        let first = keys.public();
+        assert!(!keys.rotate(1000 + ONION_KEY_LIFETIME.as_secs() - 1));
+        let (_, stale_skin) = ClientHandshake::start(&node_id, &first);
+
+        // The replaced key is still answered until the next rotation
+        assert!(keys.rotate(1000 + ONION_KEY_LIFETIME.as_secs()));
+        let second = keys.public();
+        assert_ne!(first, second);
+        assert!(respond(&node_id, &keys, &stale_skin).is_ok());
+        let sealed = seal_to(&first, b"sealed before the rotation");
+        assert_eq!(open_sealed(&keys, &sealed).unwrap(), b"sealed before the rotation");
+
+        assert!(keys.rotate(1000 + 2 * ONION_KEY_LIFETIME.as_secs()));
+        assert!(respond(&node_id, &keys, &stale_skin).is_err());
+        assert!(open_sealed(&keys, &sealed).is_err());
+        let (_, skin) = ClientHandshake::start(&node_id, &second);
+        assert!(respond(&node_id, &keys, &skin).is_ok());
+    }
+
+    #[test]
+    fn test_sealed_messages() {
+        let recipient = OnionKeys::generate();
+        let sealed = seal_to(&recipient.public(), b"meet at the rendezvous");
+        assert_eq!(open_sealed(&recipient, &sealed).unwrap(), b"meet at the rendezvous");
+
+        assert!(open_sealed(&OnionKeys::generate(), &sealed).is_err());
+        let mut tampered = sealed.clone();
+        tampered[40] ^= 1;
+        assert!(open_sealed(&recipient, &tampered).is_err());
+        assert!(open_sealed(&recipient, &sealed[..20]).is_err());
+    }
+}
@@ -0,0 +1,597 @@
+// HTTP/1.1 message framing for the proxy (RFC 7230)
+// Heads are read incrementally, however the bytes arrive, up to a size
+// limit. Bodies are passed through as they are, chunked framing included;
+// the parser only tracks where each message ends so a connection can carry
+// the next one.
+
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Longest request or response head accepted
+pub const MAX_HEAD_LEN: usize = 64 * 1024;
+/// Most header fields accepted in one head
+pub const MAX_HEADERS: usize = 100;
+/// Longest chunk-size or trailer line accepted
+const MAX_LINE_LEN: usize = 8 * 1024;
+const READ_CHUNK: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub enum HttpError {
+    /// Not valid HTTP/1.x; answered with 400
+    Malformed(String),
+    /// Head or framing line over its limit; answered with 400
+    TooLarge(&'static str),
+    /// The peer closed the connection in the middle of a message
+    Closed,
+    Io(std::io::Error),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::Malformed(reason) => write!(f, "Malformed HTTP message: {}", reason),
+            HttpError::TooLarge(what) => write!(f, "HTTP {} is too large", what),
+            HttpError::Closed => write!(f, "Connection closed in the middle of a message"),
+            HttpError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<std::io::Error> for HttpError {
+    fn from(e: std::io::Error) -> Self {
+        HttpError::Io(e)
+    }
+}
+
+fn malformed(reason: impl Into<String>) -> HttpError {
+    HttpError::Malformed(reason.into())
+}
+
+/// Fields that describe one connection rather than the message. Bodies are
+/// passed on with their framing intact, so Transfer-Encoding isn't here.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+const FRAMING: &[&str] = &["content-length", "transfer-encoding", "host"];
+
+/// Header fields in the order they arrived
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Headers(pub Vec<(String, String)>);
+
+impl Headers {
+    /// The first value of `name`, compared case-insensitively
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(field, _)| field.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    /// Every value of `name`, in order
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0.iter().filter(move |(field, _)| field.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    /// Whether a comma-separated field such as Connection lists `token`
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name).flat_map(|value| value.split(',')).any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(field, _)| !field.eq_ignore_ascii_case(name));
+    }
+
+    /// Drop the fields that only apply to one connection (RFC 7230 section
+    /// 6.1): the standard set and whatever Connection names. An upgrade the
+    /// sender asked for is kept so it reaches the other end.
+    pub fn strip_hop_by_hop(&mut self) {
+        let upgrade = match self.has_token("connection", "upgrade") {
+            true => self.get("upgrade").map(str::to_string),
+            false => None,
+        };
+        let listed: Vec<String> = self
+            .get_all("connection")
+            .flat_map(|value| value.split(','))
+            .map(|token| token.trim().to_ascii_lowercase())
+            .collect();
+        self.0.retain(|(field, _)| {
+            let field = field.to_ascii_lowercase();
+            // Connection can't take away the fields that frame the message
+            let listed = listed.contains(&field) && !FRAMING.contains(&field.as_str());
+            !HOP_BY_HOP.contains(&field.as_str()) && !listed
+        });
+        if let Some(protocol) = upgrade {
+            self.0.push(("Connection".to_string(), "Upgrade".to_string()));
+            self.0.push(("Upgrade".to_string(), protocol));
+        }
+    }
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        for (name, value) in &self.0 {
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestHead {
+    pub method: String,
+    /// The request-target as sent: absolute-form from a proxy client, or
+    /// authority-form for CONNECT
+    pub target: String,
+    pub version: String,
+    pub headers: Headers,
+}
+
+impl RequestHead {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = format!("{} {} {}\r\n", self.method, self.target, self.version).into_bytes();
+        self.headers.encode_into(&mut out);
+        out
+    }
+
+    /// Whether the client wants the connection kept open after this exchange
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(&self.version, &self.headers)
+    }
+
+    /// How much body follows the head (RFC 7230 section 3.3.3). A request
+    /// with both Transfer-Encoding and Content-Length is refused outright,
+    /// since the two could be read differently further along.
+    pub fn body(&self) -> Result<BodyLength, HttpError> {
+        if self.headers.get("transfer-encoding").is_some() {
+            if self.headers.get("content-length").is_some() {
+                return Err(malformed("both Transfer-Encoding and Content-Length"));
+            }
+            return match final_encoding_is_chunked(&self.headers) {
+                true => Ok(BodyLength::Chunked),
+                false => Err(malformed("request body is not chunked")),
+            };
+        }
+        match content_length(&self.headers)? {
+            Some(0) | None => Ok(BodyLength::Empty),
+            Some(len) => Ok(BodyLength::Fixed(len)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResponseHead {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl ResponseHead {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = format!("{} {} {}\r\n", self.version, self.status, self.reason).into_bytes();
+        self.headers.encode_into(&mut out);
+        out
+    }
+
+    pub fn keep_alive(&self) -> bool {
+        keep_alive(&self.version, &self.headers)
+    }
+
+    /// How much body follows the head, given the request method
+    pub fn body(&self, method: &str) -> Result<BodyLength, HttpError> {
+        if method.eq_ignore_ascii_case("HEAD") || (100..200).contains(&self.status) || self.status == 204 || self.status == 304 {
+            return Ok(BodyLength::Empty);
+        }
+        if self.headers.get("transfer-encoding").is_some() {
+            return match final_encoding_is_chunked(&self.headers) {
+                true => Ok(BodyLength::Chunked),
+                false => Ok(BodyLength::UntilClose),
+            };
+        }
+        match content_length(&self.headers)? {
+            Some(0) => Ok(BodyLength::Empty),
+            Some(len) => Ok(BodyLength::Fixed(len)),
+            None => Ok(BodyLength::UntilClose),
+        }
+    }
+}
+
+/// Where a message body ends
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyLength {
+    Empty,
+    Fixed(u64),
+    Chunked,
+    /// Only the connection closing ends it
+    UntilClose,
+}
+
+fn keep_alive(version: &str, headers: &Headers) -> bool {
+    if version == "HTTP/1.0" {
+        headers.has_token("connection", "keep-alive")
+    } else {
+        !headers.has_token("connection", "close")
+    }
+}
+
+fn final_encoding_is_chunked(headers: &Headers) -> bool {
+    let last = headers.get_all("transfer-encoding").flat_map(|value| value.split(',')).last();
+    last.is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Content-Length, which may repeat only with the same value
+fn content_length(headers: &Headers) -> Result<Option<u64>, HttpError> {
+    let mut length = None;
+    for value in headers.get_all("content-length").flat_map(|value| value.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(malformed(format!("bad Content-Length '{}'", value)));
+        }
+        let value: u64 = value.parse().map_err(|_| malformed("Content-Length out of range"))?;
+        if length.is_some_and(|length| length != value) {
+            return Err(malformed("conflicting Content-Length values"));
+        }
+        length = Some(value);
+    }
+    Ok(length)
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Split a head into its start line and header fields
+fn parse_head(head: &[u8]) -> Result<(&str, Headers), HttpError> {
+    let head = std::str::from_utf8(head).map_err(|_| malformed("head is not UTF-8"))?;
+    let mut lines = head.split('\n').map(|line| line.strip_suffix('\r').unwrap_or(line));
+    let start = lines.next().unwrap_or("");
+
+    let mut headers = Vec::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        if line.starts_with([' ', '\t']) {
+            return Err(malformed("obsolete line folding"));
+        }
+        let (name, value) = line.split_once(':').ok_or_else(|| malformed(format!("header line '{}'", line)))?;
+        if !is_token(name) {
+            return Err(malformed(format!("header name '{}'", name)));
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(HttpError::TooLarge("header count"));
+        }
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+    Ok((start, Headers(headers)))
+}
+
+fn check_version(version: &str) -> Result<(), HttpError> {
+    match version {
+        "HTTP/1.1" | "HTTP/1.0" => Ok(()),
+        _ => Err(malformed(format!("unsupported version '{}'", version))),
+    }
+}
+
+/// Reads HTTP messages off a connection, keeping whatever arrived past the
+/// end of one message for the next
+pub struct HttpReader<R> {
+    inner: R,
+    buffer: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> HttpReader<R> {
+    pub fn new(inner: R) -> Self {
+        HttpReader { inner, buffer: Vec::new() }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Bytes read past the last message, e.g. the start of a tunnel
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    /// Read more into the buffer; false at end of stream
+    async fn fill(&mut self) -> Result<bool, HttpError> {
+        let mut chunk = [0u8; READ_CHUNK];
+        let n = self.inner.read(&mut chunk).await?;
+        self.buffer.extend_from_slice(&chunk[..n]);
+        Ok(n > 0)
+    }
+
+    /// Read up to the blank line ending a head and return the head. `None`
+    /// if the stream ended cleanly before a new message began.
+    async fn read_head(&mut self) -> Result<Option<Vec<u8>>, HttpError> {
+        let mut scanned: usize = 0;
+        loop {
+            // Empty lines before a request line are ignored (RFC 7230 section 3.5)
+            let blank = self.buffer.iter().take_while(|b| **b == b'\r' || **b == b'\n').count();
+            self.buffer.drain(..blank);
+            scanned = scanned.saturating_sub(blank);
+
+            if let Some(end) = find_head_end(&self.buffer, scanned) {
+                let head = self.buffer.drain(..end).collect();
+                return Ok(Some(head));
+            }
+            if self.buffer.len() > MAX_HEAD_LEN {
+                return Err(HttpError::TooLarge("head"));
+            }
+            scanned = self.buffer.len().saturating_sub(3);
+            if !self.fill().await? {
+                return match self.buffer.is_empty() {
+                    true => Ok(None),
+                    false => Err(HttpError::Closed),
+                };
+            }
+        }
+    }
+
+    /// The next request on a client connection, or `None` once it closes
+    pub async fn read_request(&mut self) -> Result<Option<RequestHead>, HttpError> {
+        let Some(head) = self.read_head().await? else {
+            return Ok(None);
+        };
+        let (start, headers) = parse_head(&head)?;
+        let mut parts = start.split(' ');
+        let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(malformed(format!("request line '{}'", start)));
+        };
+        if !is_token(method) || target.is_empty() {
+            return Err(malformed(format!("request line '{}'", start)));
+        }
+        check_version(version)?;
+        Ok(Some(RequestHead {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: version.to_string(),
+            headers,
+        }))
+    }
+
+    /// The next response from a server
+    pub async fn read_response(&mut self) -> Result<ResponseHead, HttpError> {
+        let head = self.read_head().await?.ok_or(HttpError::Closed)?;
+        let (start, headers) = parse_head(&head)?;
+        let mut parts = start.splitn(3, ' ');
+        let (Some(version), Some(status)) = (parts.next(), parts.next()) else {
+            return Err(malformed(format!("status line '{}'", start)));
+        };
+        check_version(version)?;
+        let status = status
+            .parse()
+            .ok()
+            .filter(|status| (100..1000).contains(status))
+            .ok_or_else(|| malformed(format!("status '{}'", status)))?;
+        Ok(ResponseHead {
+            version: version.to_string(),
+            status,
+            reason: parts.next().unwrap_or("").to_string(),
+            headers,
+        })
+    }
+
+    /// Pass a body through to `out` unchanged; returns the bytes written
+    pub async fn copy_body<W: AsyncWrite + Unpin>(&mut self, body: BodyLength, out: &mut W) -> Result<u64, HttpError> {
+        match body {
+            BodyLength::Empty => Ok(0),
+            BodyLength::Fixed(len) => self.copy_exact(len, out).await,
+            BodyLength::Chunked => self.copy_chunked(out).await,
+            BodyLength::UntilClose => {
+                let buffered = self.take_buffered();
+                out.write_all(&buffered).await?;
+                let copied = tokio::io::copy(&mut self.inner, out).await?;
+                Ok(buffered.len() as u64 + copied)
+            }
+        }
+    }
+
+    async fn copy_exact<W: AsyncWrite + Unpin>(&mut self, len: u64, out: &mut W) -> Result<u64, HttpError> {
+        let mut remaining = len;
+        while remaining > 0 {
+            if self.buffer.is_empty() && !self.fill().await? {
+                return Err(HttpError::Closed);
+            }
+            let take = self.buffer.len().min(remaining.try_into().unwrap_or(usize::MAX));
+            out.write_all(&self.buffer[..take]).await?;
+            self.buffer.drain(..take);
+            remaining -= take as u64;
+        }
+        Ok(len)
+    }
+
+    /// One line, CRLF included
+    async fn read_line(&mut self) -> Result<Vec<u8>, HttpError> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+                return Ok(self.buffer.drain(..=end).collect());
+            }
+            if self.buffer.len() > MAX_LINE_LEN {
+                return Err(HttpError::TooLarge("chunk line"));
+            }
+            if !self.fill().await? {
+                return Err(HttpError::Closed);
+            }
+        }
+    }
+
+    async fn copy_chunked<W: AsyncWrite + Unpin>(&mut self, out: &mut W) -> Result<u64, HttpError> {
+        let mut written = 0;
+        loop {
+            let line = self.read_line().await?;
+            out.write_all(&line).await?;
+            written += line.len() as u64;
+            let size = std::str::from_utf8(&line)
+                .ok()
+                .map(|line| line.split(';').next().unwrap_or("").trim())
+                .and_then(|size| u64::from_str_radix(size, 16).ok())
+                .ok_or_else(|| malformed("chunk size"))?;
+            if size == 0 {
+                break;
+            }
+            written += self.copy_exact(size, out).await?;
+            let end = self.read_line().await?;
+            if end != b"\r\n" && end != b"\n" {
+                return Err(malformed("chunk is longer than its size"));
+            }
+            out.write_all(&end).await?;
+            written += end.len() as u64;
+        }
+        // Trailer fields, then the blank line that ends the body
+        loop {
+            let line = self.read_line().await?;
+            out.write_all(&line).await?;
+            written += line.len() as u64;
+            if line == b"\r\n" || line == b"\n" {
+                return Ok(written);
+            }
+        }
+    }
+}
+
+/// End of the blank line closing a head, searching from `from`
+fn find_head_end(buffer: &[u8], from: usize) -> Option<usize> {
+    (from..buffer.len()).find_map(|i| {
+        let rest = &buffer[i..];
+        if rest.starts_with(b"\r\n\r\n") {
+            Some(i + 4)
+        } else if rest.starts_with(b"\n\n") {
+            Some(i + 2)
+        } else if rest.starts_with(b"\n\r\n") {
+            Some(i + 3)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A reader that hands out `bytes` a few at a time, as a slow network would
+    fn trickle(bytes: &[u8]) -> HttpReader<tokio::io::DuplexStream> {
+        let (mut writer, reader) = tokio::io::duplex(7);
+        let bytes = bytes.to_vec();
+        tokio::spawn(async move {
+            let _ = writer.write_all(&bytes).await;
+        });
+        HttpReader::new(reader)
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests_in_pieces() {
+        let mut reader = trickle(
+            b"\r\nPOST http://example.com/form HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\nhello\
+              GET http://example.com/chunks HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: gzip, chunked\r\n\r\n\
+              4\r\nwiki\r\n5;ext=1\r\npedia\r\n0\r\nExpires: never\r\n\r\n\
+              GET /last HTTP/1.0\r\n\r\n",
+        );
+
+        let first = reader.read_request().await.unwrap().unwrap();
+        assert_eq!((first.method.as_str(), first.target.as_str()), ("POST", "http://example.com/form"));
+        assert_eq!(first.headers.get("HOST"), Some("example.com"));
+        assert!(first.keep_alive());
+        let mut body = Vec::new();
+        assert_eq!(reader.copy_body(first.body().unwrap(), &mut body).await.unwrap(), 5);
+        assert_eq!(body, b"hello");
+
+        let second = reader.read_request().await.unwrap().unwrap();
+        assert_eq!(second.body().unwrap(), BodyLength::Chunked);
+        let mut body = Vec::new();
+        reader.copy_body(BodyLength::Chunked, &mut body).await.unwrap();
+        assert_eq!(body, b"4\r\nwiki\r\n5;ext=1\r\npedia\r\n0\r\nExpires: never\r\n\r\n");
+
+        let third = reader.read_request().await.unwrap().unwrap();
+        assert_eq!(third.body().unwrap(), BodyLength::Empty);
+        assert!(!third.keep_alive());
+        assert!(reader.read_request().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_bad_requests() {
+        let oversized = format!("GET / HTTP/1.1\r\nX-Big: {}\r\n\r\n", "a".repeat(MAX_HEAD_LEN));
+        let many = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: 1\r\n".repeat(MAX_HEADERS + 1));
+        let cases: [&[u8]; 8] = [
+            oversized.as_bytes(),
+            many.as_bytes(),
+            b"GET / HTTP/2.0\r\n\r\n",
+            b"GET /\r\n\r\n",
+            b"GET / HTTP/1.1\r\nBad Header: x\r\n\r\n",
+            b"GET / HTTP/1.1\r\nX-A: 1\r\n folded\r\n\r\n",
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n",
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n",
+        ];
+        for case in cases {
+            let mut reader = trickle(case);
+            let result = match reader.read_request().await {
+                Ok(head) => head.unwrap().body().map(|_| ()),
+                Err(e) => Err(e),
+            };
+            assert!(matches!(result, Err(HttpError::Malformed(_) | HttpError::TooLarge(_))), "{:?}", String::from_utf8_lossy(case));
+        }
+
+        let mut reader = trickle(b"GET / HTTP/1.1\r\nHost: a");
+        assert!(matches!(reader.read_request().await, Err(HttpError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn test_response_framing() {
+        let mut reader = trickle(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nokHTTP/1.0 200 OK\r\n\r\nrest of it");
+        let interim = reader.read_response().await.unwrap();
+        assert_eq!(interim.status, 100);
+        assert_eq!(interim.body("POST").unwrap(), BodyLength::Empty);
+
+        let ok = reader.read_response().await.unwrap();
+        assert_eq!(ok.body("GET").unwrap(), BodyLength::Fixed(2));
+        assert_eq!(ok.body("HEAD").unwrap(), BodyLength::Empty);
+        let mut body = Vec::new();
+        reader.copy_body(BodyLength::Fixed(2), &mut body).await.unwrap();
+
+        let legacy = reader.read_response().await.unwrap();
+        assert!(!legacy.keep_alive());
+        assert_eq!(legacy.body("GET").unwrap(), BodyLength::UntilClose);
+        let mut body = Vec::new();
+        reader.copy_body(BodyLength::UntilClose, &mut body).await.unwrap();
+        assert_eq!(body, b"rest of it");
+        assert_eq!(legacy.encode(), b"HTTP/1.0 200 OK\r\n\r\n");
+    }
+
+    #[test]
+    fn test_strips_hop_by_hop_fields() {
+        let fields = [
+            ("Host", "example.com"),
+            ("Connection", "keep-alive, X-Session, Content-Length"),
+            ("Keep-Alive", "timeout=5"),
+            ("Proxy-Connection", "keep-alive"),
+            ("Proxy-Authorization", "Basic c2VjcmV0"),
+            ("TE", "trailers"),
+            ("Trailer", "Expires"),
+            ("X-Session", "abc"),
+            ("Content-Length", "2"),
+            ("Accept", "*/*"),
+        ];
+        let mut headers = Headers(fields.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect());
+        headers.strip_hop_by_hop();
+        let left: Vec<&str> = headers.0.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(left, ["Host", "Content-Length", "Accept"]);
+
+        // A requested upgrade survives, and nothing else from Connection
+        let mut headers = Headers(vec![
+            ("connection".to_string(), "Upgrade, Keep-Alive".to_string()),
+            ("upgrade".to_string(), "websocket".to_string()),
+            ("Sec-WebSocket-Key".to_string(), "x3JJHMbDL1EzLkh9GBhXDw==".to_string()),
+        ]);
+        headers.strip_hop_by_hop();
+        assert_eq!(headers.get("connection"), Some("Upgrade"));
+        assert_eq!(headers.get("upgrade"), Some("websocket"));
+        assert!(headers.get("sec-websocket-key").is_some());
+    }
+}
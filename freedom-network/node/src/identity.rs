@@ -1,25 +1,554 @@
+// Long-lived node identity
+// An Ed25519 keypair kept encrypted in the data directory. The node's NodeId
+// and its TLS certificate are both derived from it, so they survive restarts.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, Context, Result};
+use argon2::Argon2;
+use data_encoding::BASE32_NOPAD;
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
 use rand::RngCore;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha3::{Digest, Sha3_256};
-use hex::encode;
+use crate::encrypt;
+use crate::protocol::{generate_node_id, NodeId};
+
+/// Identity file name inside the data directory
+pub const IDENTITY_FILE: &str = "identity.key";
+
+/// Key for a hidden service hosted by this node. Kept apart from the node
+/// identity so the service's name doesn't tie it to the relay.
+pub const SERVICE_IDENTITY_FILE: &str = "service.key";
+
+/// Environment variable holding the passphrase for the identity file
+pub const PASSPHRASE_ENV: &str = "FREEDOM_IDENTITY_PASSPHRASE";
+
+const FILE_MAGIC: &[u8; 4] = b"FNID";
+const FILE_VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+
+/// Version byte embedded in every address
+pub const ADDRESS_VERSION: u8 = 1;
+
+/// Length of an encoded address: base32 of key (32) + checksum (2) + version (1)
+pub const ADDRESS_LEN: usize = 56;
+
+const ADDRESS_CHECKSUM_PREFIX: &[u8] = b".freedom checksum";
+
+/// Suffix shared by every Freedom Network name
+pub const FREEDOM_TLD: &str = ".freedom";
+
+/// PKCS#8 v1 wrapper for a raw Ed25519 seed (RFC 8410); the 32 seed bytes follow
+const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06,
+    0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+/// An Ed25519 signature
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Signature(pub [u8; 64]);
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Signature({})", hex::encode(self.0))
+    }
+}
+
+impl Serialize for Signature {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Signature {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = <Vec<u8>>::deserialize(deserializer)?;
+        let bytes: [u8; 64] = bytes
+            .try_into()
+            .map_err(|_| serde::de::Error::custom("signature must be 64 bytes"))?;
+        Ok(Signature(bytes))
+    }
+}
+
+/// The public half of an identity: what peers see, store and verify against
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "Vec<u8>", into = "Vec<u8>")]
+pub struct PublicIdentity([u8; 32]);
+
+impl PublicIdentity {
+    /// Accepts only bytes that decode to a valid Ed25519 point
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let bytes: [u8; 32] = bytes
+            .try_into()
+            .map_err(|_| anyhow!("Ed25519 public key must be 32 bytes, got {}", bytes.len()))?;
+        VerifyingKey::from_bytes(&bytes).map_err(|_| anyhow!("Invalid Ed25519 public key"))?;
+        Ok(PublicIdentity(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    pub fn to_vec(self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        generate_node_id(&self.0)
+    }
+
+    pub fn verify(&self, message: &[u8], signature: &Signature) -> bool {
+        let key = match VerifyingKey::from_bytes(&self.0) {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        key.verify(message, &ed25519_dalek::Signature::from_bytes(&signature.0)).is_ok()
+    }
+
+    /// Human-readable address: lowercase base32 of key, checksum and version,
+    /// always `ADDRESS_LEN` characters
+    pub fn to_address(self) -> String {
+        let mut raw = self.0.to_vec();
+        raw.extend_from_slice(&address_checksum(&self.0, ADDRESS_VERSION));
+        raw.push(ADDRESS_VERSION);
+        BASE32_NOPAD.encode(&raw).to_ascii_lowercase()
+    }
+
+    /// Parse an address, rejecting bad lengths, checksums and versions
+    pub fn from_address(address: &str) -> Result<Self> {
+        if address.len() != ADDRESS_LEN {
+            return Err(anyhow!("Address must be {} characters, got {}", ADDRESS_LEN, address.len()));
+        }
+        let raw = BASE32_NOPAD
+            .decode(address.to_ascii_uppercase().as_bytes())
+            .map_err(|_| anyhow!("Address is not valid base32"))?;
+
+        let (key, rest) = raw.split_at(32);
+        let (checksum, version) = (&rest[..2], rest[2]);
+        if version != ADDRESS_VERSION {
+            return Err(anyhow!("Unsupported address version {}", version));
+        }
+        if checksum != address_checksum(key, version) {
+            return Err(anyhow!("Address checksum mismatch"));
+        }
+        Self::from_bytes(key)
+    }
+
+    /// Self-authenticating name: `<address>.freedom`
+    pub fn to_domain(self) -> String {
+        format!("{}{}", self.to_address(), FREEDOM_TLD)
+    }
 
+    /// Recover the key a self-authenticating name was derived from
+    pub fn from_domain(domain: &str) -> Result<Self> {
+        let address = domain
+            .strip_suffix(FREEDOM_TLD)
+            .ok_or_else(|| anyhow!("'{}' is not a {} name", domain, FREEDOM_TLD))?;
+        Self::from_address(address)
+    }
+}
+
+impl TryFrom<Vec<u8>> for PublicIdentity {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: Vec<u8>) -> Result<Self> {
+        Self::from_bytes(&bytes)
+    }
+}
+
+impl From<PublicIdentity> for Vec<u8> {
+    fn from(public: PublicIdentity) -> Self {
+        public.to_vec()
+    }
+}
+
+impl fmt::Debug for PublicIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicIdentity({})", self.to_address())
+    }
+}
+
+impl fmt::Display for PublicIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_address())
+    }
+}
+
+fn address_checksum(key: &[u8], version: u8) -> [u8; 2] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(ADDRESS_CHECKSUM_PREFIX);
+    hasher.update(key);
+    hasher.update([version]);
+    let digest = hasher.finalize();
+    [digest[0], digest[1]]
+}
+
+/// A node's signing identity. The secret key never leaves this type except
+/// through the encrypted file format and the TLS key export.
 pub struct Identity {
-    pub private_key: [u8;32],
-    pub public_key: [u8;32],
-    pub address: String,
+    signing_key: SigningKey,
+    public: PublicIdentity,
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity").field("public", &self.public).finish_non_exhaustive()
+    }
 }
 
 pub fn generate_identity() -> Identity {
     let mut private_key = [0u8;32];
     rand::thread_rng().fill_bytes(&mut private_key);
+    Identity::from_private_key(private_key)
+}
 
-    let public_key = Sha3_256::digest(&private_key);
-    let mut hasher = Sha3_256::new();
-    hasher.update(&public_key);
-    let address = encode(hasher.finalize());
+impl Identity {
+    pub fn from_private_key(private_key: [u8;32]) -> Self {
+        let signing_key = SigningKey::from_bytes(&private_key);
+        let public = PublicIdentity(signing_key.verifying_key().to_bytes());
+        Identity { signing_key, public }
+    }
+
+    pub fn public(&self) -> PublicIdentity {
+        self.public
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.public.node_id()
+    }
+
+    pub fn address(&self) -> String {
+        self.public.to_address()
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        Signature(self.signing_key.sign(message).to_bytes())
+    }
+
+    /// Private key in PKCS#8 DER form, as TLS libraries expect it
+    pub fn pkcs8_der(&self) -> Vec<u8> {
+        let mut der = ED25519_PKCS8_PREFIX.to_vec();
+        der.extend_from_slice(self.signing_key.as_bytes());
+        der
+    }
+
+    /// Self-signed TLS certificate whose key is this identity's key.
+    /// Returns `(certificate_der, private_key_der)`.
+    pub fn tls_certificate(&self) -> Result<(Vec<u8>, Vec<u8>)> {
+        let key_pair = rcgen::KeyPair::from_der_and_sign_algo(&self.pkcs8_der(), &rcgen::PKCS_ED25519)?;
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_string()]);
+        params.alg = &rcgen::PKCS_ED25519;
+        params.key_pair = Some(key_pair);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, hex::encode(self.node_id().0));
+
+        let cert = rcgen::Certificate::from_params(params)?;
+        Ok((cert.serialize_der()?, cert.serialize_private_key_der()))
+    }
+
+    /// Encrypt the private key under a passphrase-derived key.
+    /// Layout: magic, version, salt, then nonce + ChaCha20-Poly1305 ciphertext.
+    pub fn to_encrypted_bytes(&self, passphrase: &str) -> Result<Vec<u8>> {
+        let mut salt = [0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let key = derive_file_key(passphrase, &salt)?;
+
+        let mut out = FILE_MAGIC.to_vec();
+        out.push(FILE_VERSION);
+        out.extend_from_slice(&salt);
+        out.extend(encrypt::encrypt(self.signing_key.as_bytes(), &key));
+        Ok(out)
+    }
+
+    pub fn from_encrypted_bytes(bytes: &[u8], passphrase: &str) -> Result<Self> {
+        let header_len = FILE_MAGIC.len() + 1 + SALT_LEN;
+        if bytes.len() < header_len || &bytes[..4] != FILE_MAGIC {
+            return Err(anyhow!("Not a Freedom Network identity file"));
+        }
+        if bytes[4] != FILE_VERSION {
+            return Err(anyhow!("Unsupported identity file version {}", bytes[4]));
+        }
+
+        let key = derive_file_key(passphrase, &bytes[5..header_len])?;
+        let plaintext = encrypt::decrypt(&bytes[header_len..], &key)
+            .map_err(|_| anyhow!("Wrong passphrase or corrupted identity file"))?;
+        let private_key: [u8; 32] = plaintext
+            .try_into()
+            .map_err(|_| anyhow!("Identity file holds a key of the wrong length"))?;
+        Ok(Self::from_private_key(private_key))
+    }
+
+    pub fn save(&self, path: &Path, passphrase: &str) -> Result<()> {
+        let bytes = self.to_encrypted_bytes(passphrase)?;
+        // Write then rename so a crash never leaves a half-written key behind
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, bytes).with_context(|| format!("Failed to write {}", tmp.display()))?;
+        restrict_permissions(&tmp)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path, passphrase: &str) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::from_encrypted_bytes(&bytes, passphrase)
+            .with_context(|| format!("Failed to open identity {}", path.display()))
+    }
+}
+
+fn derive_file_key(passphrase: &str, salt: &[u8]) -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+#[cfg(unix)]
+fn restrict_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn restrict_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+pub fn identity_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(IDENTITY_FILE)
+}
+
+/// Passphrase from the environment. An unset variable means an empty
+/// passphrase, which still encrypts the file but protects nothing.
+pub fn passphrase_from_env() -> String {
+    std::env::var(PASSPHRASE_ENV).unwrap_or_default()
+}
+
+/// Load the node identity, generating and saving one on first start
+pub fn load_or_create(data_dir: &Path, passphrase: &str) -> Result<Identity> {
+    load_or_create_at(&identity_path(data_dir), passphrase)
+}
 
-    Identity {
-        private_key,
-        public_key: public_key.into(),
-        address,
+/// Load the hidden service key, generating and saving one on first start
+pub fn load_or_create_service(data_dir: &Path, passphrase: &str) -> Result<Identity> {
+    load_or_create_at(&data_dir.join(SERVICE_IDENTITY_FILE), passphrase)
+}
+
+fn load_or_create_at(path: &Path, passphrase: &str) -> Result<Identity> {
+    if path.exists() {
+        return Identity::load(path, passphrase);
     }
-}
\ No newline at end of file
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let identity = generate_identity();
+    identity.save(path, passphrase)?;
+    Ok(identity)
+}
+
+/// Copy the encrypted identity file to `dest`, checking it opens first
+pub fn export(data_dir: &Path, dest: &Path, passphrase: &str) -> Result<Identity> {
+    let identity = Identity::load(&identity_path(data_dir), passphrase)?;
+    if dest.exists() {
+        return Err(anyhow!("{} already exists; export to a new file", dest.display()));
+    }
+    identity.save(dest, passphrase)?;
+    Ok(identity)
+}
+
+/// Install an exported identity file as this node's identity. An existing
+/// identity is only replaced with `force`, and is kept as a backup.
+pub fn import(data_dir: &Path, src: &Path, passphrase: &str, force: bool) -> Result<Identity> {
+    let identity = Identity::load(src, passphrase)?;
+    let path = identity_path(data_dir);
+    if path.exists() {
+        if !force {
+            return Err(anyhow!("{} already exists; pass --force to replace it", path.display()));
+        }
+        backup(&path)?;
+    }
+
+    std::fs::create_dir_all(data_dir)?;
+    identity.save(&path, passphrase)?;
+    Ok(identity)
+}
+
+/// Replace the identity with a fresh keypair, keeping the old file as a backup.
+/// Returns the new identity and the backup path.
+pub fn rotate(data_dir: &Path, passphrase: &str) -> Result<(Identity, Option<PathBuf>)> {
+    let path = identity_path(data_dir);
+    let backup_path = if path.exists() {
+        // Refuse to rotate away a key we cannot open
+        Identity::load(&path, passphrase)?;
+        Some(backup(&path)?)
+    } else {
+        None
+    };
+
+    std::fs::create_dir_all(data_dir)?;
+    let identity = generate_identity();
+    identity.save(&path, passphrase)?;
+    Ok((identity, backup_path))
+}
+
+/// Copy `path` to a new backup file beside it. The name carries the time to
+/// the millisecond, and a counter if that is taken; no file is overwritten.
+fn backup(path: &Path) -> Result<PathBuf> {
+    let contents = std::fs::read(path)?;
+    let stamp = chrono::Utc::now().format("%Y%m%d%H%M%S%3f");
+    for attempt in 0u32.. {
+        let backup_path = match attempt {
+            0 => path.with_extension(format!("key.{}.bak", stamp)),
+            n => path.with_extension(format!("key.{}-{}.bak", stamp, n)),
+        };
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(&backup_path) {
+            Ok(mut file) => {
+                std::io::Write::write_all(&mut file, &contents)?;
+                return Ok(backup_path);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    unreachable!("a backup name is free before the counter runs out")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let identity = generate_identity();
+        let signature = identity.sign(b"example.freedom");
+        assert!(identity.public().verify(b"example.freedom", &signature));
+        assert!(!identity.public().verify(b"evil.freedom", &signature));
+        assert!(!generate_identity().public().verify(b"example.freedom", &signature));
+
+        let mut tampered = signature;
+        tampered.0[0] ^= 1;
+        assert!(!identity.public().verify(b"example.freedom", &tampered));
+    }
+
+    #[test]
+    fn test_public_key_is_ed25519() {
+        let identity = Identity::from_private_key([7u8; 32]);
+        let expected = SigningKey::from_bytes(&[7u8; 32]).verifying_key().to_bytes();
+        assert_eq!(identity.public().as_bytes(), &expected);
+        assert!(PublicIdentity::from_bytes(&[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_address_roundtrip() {
+        let public = generate_identity().public();
+        let address = public.to_address();
+        assert_eq!(address.len(), ADDRESS_LEN);
+        assert_eq!(address, address.to_ascii_lowercase());
+        assert_eq!(PublicIdentity::from_address(&address).unwrap(), public);
+        assert_eq!(PublicIdentity::from_address(&address.to_ascii_uppercase()).unwrap(), public);
+    }
+
+    #[test]
+    fn test_address_rejects_typos() {
+        let address = generate_identity().address();
+        let mut chars: Vec<char> = address.chars().collect();
+        chars[10] = if chars[10] == 'a' { 'b' } else { 'a' };
+        let typo: String = chars.into_iter().collect();
+        assert!(PublicIdentity::from_address(&typo).is_err());
+        assert!(PublicIdentity::from_address(&address[..40]).is_err());
+        assert!(PublicIdentity::from_address(&"1".repeat(ADDRESS_LEN)).is_err());
+    }
+
+    #[test]
+    fn test_domain_roundtrip() {
+        let public = generate_identity().public();
+        let domain = public.to_domain();
+        assert!(domain.ends_with(".freedom"));
+        assert_eq!(PublicIdentity::from_domain(&domain).unwrap(), public);
+        assert!(PublicIdentity::from_domain(&public.to_address()).is_err());
+        assert!(PublicIdentity::from_domain("example.freedom").is_err());
+    }
+
+    #[test]
+    fn test_serialization() {
+        let identity = generate_identity();
+        let signature = identity.sign(b"hello");
+
+        let json = serde_json::to_string(&(identity.public(), signature)).unwrap();
+        let (public, restored): (PublicIdentity, Signature) = serde_json::from_str(&json).unwrap();
+        assert_eq!(public, identity.public());
+        assert!(public.verify(b"hello", &restored));
+
+        let bytes = bincode::serialize(&identity.public()).unwrap();
+        assert_eq!(bincode::deserialize::<PublicIdentity>(&bytes).unwrap(), identity.public());
+        assert!(serde_json::from_str::<PublicIdentity>("[1,2,3]").is_err());
+    }
+
+    #[test]
+    fn test_encrypted_roundtrip() {
+        let identity = generate_identity();
+        let bytes = identity.to_encrypted_bytes("hunter2").unwrap();
+        assert!(!bytes.windows(32).any(|w| w == identity.signing_key.as_bytes()));
+
+        let restored = Identity::from_encrypted_bytes(&bytes, "hunter2").unwrap();
+        assert_eq!(restored.public(), identity.public());
+        assert_eq!(restored.node_id(), identity.node_id());
+
+        assert!(Identity::from_encrypted_bytes(&bytes, "wrong").is_err());
+        assert!(Identity::from_encrypted_bytes(&bytes[..10], "hunter2").is_err());
+    }
+
+    #[test]
+    fn test_load_or_create_is_stable() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let first = load_or_create(temp_dir.path(), "").unwrap();
+        let second = load_or_create(temp_dir.path(), "").unwrap();
+        assert_eq!(first.node_id(), second.node_id());
+
+        // The service key is its own keypair, just as stable
+        let service = load_or_create_service(temp_dir.path(), "").unwrap();
+        assert_ne!(service.node_id(), first.node_id());
+        assert_eq!(load_or_create_service(temp_dir.path(), "").unwrap().node_id(), service.node_id());
+    }
+
+    #[test]
+    fn test_tls_certificate_binds_identity_key() {
+        let identity = generate_identity();
+        let (cert_der, key_der) = identity.tls_certificate().unwrap();
+        assert_eq!(key_der, identity.pkcs8_der());
+        // The certificate's SubjectPublicKeyInfo carries the raw Ed25519 key
+        assert!(cert_der.windows(32).any(|w| w == identity.public().as_bytes()));
+    }
+
+    #[test]
+    fn test_export_import_rotate() {
+        let node_dir = tempfile::tempdir().unwrap();
+        let other_dir = tempfile::tempdir().unwrap();
+        let original = load_or_create(node_dir.path(), "pw").unwrap();
+
+        let exported = other_dir.path().join("backup.key");
+        export(node_dir.path(), &exported, "pw").unwrap();
+
+        assert!(export(node_dir.path(), &exported, "pw").is_err());
+
+        let (rotated, backup_path) = rotate(node_dir.path(), "pw").unwrap();
+        assert_ne!(rotated.node_id(), original.node_id());
+        let backup_path = backup_path.unwrap();
+        assert!(backup_path.exists());
+
+        // Rotating again at once keeps both backups
+        let (_, second_backup) = rotate(node_dir.path(), "pw").unwrap();
+        assert_ne!(second_backup.as_ref(), Some(&backup_path));
+        assert_eq!(Identity::load(&backup_path, "pw").unwrap().node_id(), original.node_id());
+        assert_eq!(Identity::load(&second_backup.unwrap(), "pw").unwrap().node_id(), rotated.node_id());
+
+        assert!(import(node_dir.path(), &exported, "pw", false).is_err());
+        let imported = import(node_dir.path(), &exported, "pw", true).unwrap();
+        assert_eq!(imported.node_id(), original.node_id());
+        assert_eq!(load_or_create(node_dir.path(), "pw").unwrap().node_id(), original.node_id());
+    }
+}
@@ -0,0 +1,611 @@
+// Iterative Kademlia lookups (FIND_NODE / FIND_VALUE / STORE)
+// Queries ALPHA peers at a time and converges on the K closest nodes to a key
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use futures::stream::{FuturesUnordered, StreamExt};
+use crate::protocol::{domain_key, DHTMessage, Distance, DomainRecord, NodeId, PeerInfo, NodeDescriptor, ServiceDescriptor, unpin_addr, DHT, K_BUCKET_SIZE};
+
+/// Number of queries kept in flight during a lookup
+pub const ALPHA: usize = 3;
+
+/// How long a single peer gets to answer before it is marked failed
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Carries one DHT request to a peer and returns its reply
+pub trait DhtTransport: Send + Sync {
+    fn request(&self, addr: &str, message: DHTMessage) -> impl Future<Output = Result<DHTMessage>> + Send;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QueryState {
+    Pending,
+    InFlight,
+    Responded,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+struct Candidate {
+    peer: PeerInfo,
+    state: QueryState,
+}
+
+/// Outcome of an iterative lookup
+#[derive(Debug, Clone)]
+pub struct LookupResult {
+    /// Closest peers that answered, nearest first
+    pub closest: Vec<PeerInfo>,
+    /// Set when a FIND_VALUE lookup reached a node holding a valid record
+    pub owner: Option<DomainRecord>,
+    /// Set when a service lookup reached a node holding a valid descriptor
+    pub service: Option<ServiceDescriptor>,
+}
+
+pub struct Lookup<'a, T: DhtTransport> {
+    dht: &'a DHT,
+    transport: &'a T,
+    alpha: usize,
+    k: usize,
+    query_timeout: Duration,
+}
+
+impl<'a, T: DhtTransport> Lookup<'a, T> {
+    pub fn new(dht: &'a DHT, transport: &'a T) -> Self {
+        Self {
+            dht,
+            transport,
+            alpha: ALPHA,
+            k: K_BUCKET_SIZE,
+            query_timeout: QUERY_TIMEOUT,
+        }
+    }
+
+    /// Find the K nodes closest to `target`
+    pub async fn find_node(&self, target: &NodeId) -> Vec<PeerInfo> {
+        let request = DHTMessage::FindNode {
+            target: target.clone(),
+            requesting_node: self.dht.local_id().clone(),
+        };
+        self.run(target, request, None).await.closest
+    }
+
+    /// Find the owner record of a .freedom domain: the highest sequence
+    /// among the valid records the closest nodes and the local table hold.
+    /// A newer record than the local one is cached locally and on the
+    /// closest node.
+    pub async fn find_domain(&self, domain: &str) -> LookupResult {
+        let local = self.dht.lookup_domain(domain);
+        let request = DHTMessage::FindFreedomDomain { domain: domain.to_string() };
+        let mut result = self.run(&domain_key(domain), request, Some(domain)).await;
+
+        let local_sequence = local.as_ref().map(|record| record.sequence);
+        match &result.owner {
+            Some(owner) if local_sequence.is_none_or(|sequence| owner.sequence > sequence) => {
+                let _ = self.dht.register_domain(owner.clone());
+                if let Some(nearest) = result.closest.first() {
+                    self.store_on(nearest.clone(), DHTMessage::StoreFreedomDomain { record: owner.clone() }).await;
+                }
+            }
+            _ => result.owner = local.or(result.owner),
+        }
+        result
+    }
+
+    /// Find a hidden service's descriptor by its domain. Like domain
+    /// records, one found remotely is cached locally.
+    pub async fn find_service(&self, domain: &str) -> Option<ServiceDescriptor> {
+        if let Some(descriptor) = self.dht.lookup_service(domain) {
+            return Some(descriptor);
+        }
+        let request = DHTMessage::FindService { domain: domain.to_string() };
+        let descriptor = self.run(&domain_key(domain), request, Some(domain)).await.service?;
+        let _ = self.dht.register_service(descriptor.clone());
+        Some(descriptor)
+    }
+
+    /// Replicate a record to the K nodes closest to its key; returns how
+    /// many of them accepted it
+    pub async fn publish(&self, record: &DomainRecord) -> usize {
+        let request = DHTMessage::StoreFreedomDomain { record: record.clone() };
+        self.replicate(&domain_key(&record.address.domain), request).await
+    }
+
+    /// Replicate a service descriptor the same way, keyed by its domain
+    pub async fn publish_service(&self, descriptor: &ServiceDescriptor) -> usize {
+        let request = DHTMessage::StoreService { descriptor: descriptor.clone() };
+        self.replicate(&domain_key(&descriptor.domain()), request).await
+    }
+
+    async fn replicate(&self, key: &NodeId, request: DHTMessage) -> usize {
+        let closest = self.find_node(key).await;
+        let mut stores: FuturesUnordered<_> = closest
+            .into_iter()
+            .map(|peer| self.store_on(peer, request.clone()))
+            .collect();
+
+        let mut accepted = 0;
+        while let Some(stored) = stores.next().await {
+            if stored {
+                accepted += 1;
+            }
+        }
+        accepted
+    }
+
+    async fn store_on(&self, peer: PeerInfo, request: DHTMessage) -> bool {
+        let (_, reply) = self.query(peer, request).await;
+        matches!(reply, Ok(DHTMessage::Stored { accepted: true, .. }))
+    }
+
+    async fn run(&self, target: &NodeId, request: DHTMessage, expected_domain: Option<&str>) -> LookupResult {
+        let mut shortlist: BTreeMap<Distance, Candidate> = BTreeMap::new();
+        for peer in self.dht.find_closest_peers(target, self.k) {
+            self.offer(&mut shortlist, target, peer);
+        }
+
+        // Every valid answer is kept until the lookup ends, so a stale copy
+        // that happens to arrive first can't win over a newer one
+        let mut owner: Option<DomainRecord> = None;
+        let mut service: Option<ServiceDescriptor> = None;
+        let mut in_flight = FuturesUnordered::new();
+        loop {
+            // Top up to ALPHA outstanding queries from the K closest live candidates
+            while in_flight.len() < self.alpha {
+                let next = shortlist
+                    .values_mut()
+                    .filter(|c| c.state != QueryState::Failed)
+                    .take(self.k)
+                    .find(|c| c.state == QueryState::Pending);
+                match next {
+                    Some(candidate) => {
+                        candidate.state = QueryState::InFlight;
+                        in_flight.push(self.query(candidate.peer.clone(), request.clone()));
+                    }
+                    None => break,
+                }
+            }
+
+            // Nothing outstanding and nothing left to ask: the K closest have all been queried
+            let (peer, result) = match in_flight.next().await {
+                Some(reply) => reply,
+                None => break,
+            };
+
+            let distance = Distance::between(&peer.node_id, target);
+            let state = match result {
+                Ok(DHTMessage::PeersFound { peers }) => {
+                    for found in peers {
+                        self.offer(&mut shortlist, target, found);
+                    }
+                    QueryState::Responded
+                }
+                Ok(DHTMessage::DomainOwner { domain, owner: Some(found) })
+                    if Some(domain.as_str()) == expected_domain
+                        && found.address.domain == domain
+                        && found.verify().is_ok() =>
+                {
+                    if owner.as_ref().is_none_or(|best| found.sequence > best.sequence) {
+                        owner = Some(found);
+                    }
+                    QueryState::Responded
+                }
+                Ok(DHTMessage::DomainOwner { owner: None, .. }) => QueryState::Responded,
+                Ok(DHTMessage::DomainOwner { domain, .. }) => {
+                    // Forged, expired or mismatched records count as a failed query
+                    eprintln!("⚠️  {} returned an invalid record for {}", peer.addr, domain);
+                    QueryState::Failed
+                }
+                Ok(DHTMessage::Service { domain, descriptor: Some(descriptor) })
+                    if Some(domain.as_str()) == expected_domain
+                        && descriptor.domain() == domain
+                        && descriptor.verify().is_ok() =>
+                {
+                    if service.as_ref().is_none_or(|best| descriptor.published_at > best.published_at) {
+                        service = Some(descriptor);
+                    }
+                    QueryState::Responded
+                }
+                Ok(DHTMessage::Service { descriptor: None, .. }) => QueryState::Responded,
+                Ok(DHTMessage::Service { domain, .. }) => {
+                    eprintln!("⚠️  {} returned an invalid service descriptor for {}", peer.addr, domain);
+                    QueryState::Failed
+                }
+                Ok(other) => {
+                    eprintln!("⚠️  Unexpected lookup reply from {}: {:?}", peer.addr, other);
+                    QueryState::Failed
+                }
+                Err(_) => QueryState::Failed,
+            };
+
+            if let Some(candidate) = shortlist.get_mut(&distance) {
+                candidate.state = state;
+            }
+            if state == QueryState::Responded {
+                self.learn(peer).await;
+            }
+        }
+
+        LookupResult {
+            closest: Self::responded(&shortlist, self.k),
+            owner,
+            service,
+        }
+    }
+
+    async fn query(&self, peer: PeerInfo, request: DHTMessage) -> (PeerInfo, Result<DHTMessage>) {
+        let result = tokio::time::timeout(self.query_timeout, self.transport.request(&peer.pinned_addr(), request))
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("Query to {} timed out", peer.addr)));
+        (peer, result)
+    }
+
+    fn offer(&self, shortlist: &mut BTreeMap<Distance, Candidate>, target: &NodeId, peer: PeerInfo) {
+        if &peer.node_id == self.dht.local_id() {
+            return;
+        }
+        shortlist
+            .entry(Distance::between(&peer.node_id, target))
+            .or_insert(Candidate { peer, state: QueryState::Pending });
+    }
+
+    /// Add a peer that answered us to the routing table
+    async fn learn(&self, peer: PeerInfo) {
+        let local_id = self.dht.local_id().clone();
+        let query_timeout = self.query_timeout;
+        self.dht
+            .add_peer(peer, |oldest| ping(self.transport, local_id, oldest, query_timeout))
+            .await;
+    }
+
+    fn responded(shortlist: &BTreeMap<Distance, Candidate>, k: usize) -> Vec<PeerInfo> {
+        shortlist
+            .values()
+            .filter(|c| c.state == QueryState::Responded)
+            .take(k)
+            .map(|c| c.peer.clone())
+            .collect()
+    }
+}
+
+/// Check that a peer is alive and still owns the NodeId we know it by
+pub async fn ping<T: DhtTransport>(transport: &T, local_id: NodeId, peer: PeerInfo, query_timeout: Duration) -> bool {
+    let addr = peer.pinned_addr();
+    let request = transport.request(&addr, DHTMessage::Ping { sender: local_id });
+    match tokio::time::timeout(query_timeout, request).await {
+        Ok(Ok(DHTMessage::Pong { sender })) => sender == peer.node_id,
+        _ => false,
+    }
+}
+
+/// Fetch a peer's own descriptor. It must verify and be signed by the
+/// key the peer's NodeId was derived from.
+pub async fn fetch_descriptor<T: DhtTransport>(transport: &T, peer: &PeerInfo) -> Result<NodeDescriptor> {
+    let addr = peer.pinned_addr();
+    let request = transport.request(&addr, DHTMessage::FindDescriptor { node_id: peer.node_id.clone() });
+    match tokio::time::timeout(QUERY_TIMEOUT, request).await {
+        Ok(Ok(DHTMessage::Descriptor { descriptor: Some(descriptor) })) => {
+            descriptor.verify().map_err(|e| anyhow!("Descriptor from {}: {}", peer.addr, e))?;
+            if descriptor.node_id() != peer.node_id {
+                return Err(anyhow!("{} sent a descriptor for another node", peer.addr));
+            }
+            Ok(descriptor)
+        }
+        Ok(Ok(DHTMessage::Descriptor { descriptor: None })) => Err(anyhow!("{} has no descriptor", peer.addr)),
+        Ok(Ok(other)) => Err(anyhow!("{} answered FindDescriptor with {:?}", peer.addr, other)),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(anyhow!("{} timed out", peer.addr)),
+    }
+}
+
+/// Join the network: learn the bootstrap nodes' IDs, then look up our own ID
+/// so that the peers closest to us (and they to us) fill the routing table
+pub async fn bootstrap<T: DhtTransport>(dht: &DHT, transport: &T, addrs: &[String]) -> Result<usize> {
+    for addr in addrs {
+        // A pinned bootstrap address has the transport check the node's key
+        let (pinned, plain) = match unpin_addr(addr) {
+            Ok(parts) => parts,
+            Err(e) => {
+                eprintln!("⚠️  {}", e);
+                continue;
+            }
+        };
+        let request = transport.request(addr, DHTMessage::Ping { sender: dht.local_id().clone() });
+        match tokio::time::timeout(QUERY_TIMEOUT, request).await {
+            Ok(Ok(DHTMessage::Pong { sender })) if pinned.as_ref().is_some_and(|id| *id != sender) => {
+                eprintln!("⚠️  Bootstrap node {} answered as {}", addr, sender);
+            }
+            Ok(Ok(DHTMessage::Pong { sender })) => {
+                dht.insert_peer(PeerInfo { node_id: sender, addr: plain.to_string() });
+            }
+            Ok(Ok(other)) => eprintln!("⚠️  Bootstrap node {} sent {:?}", addr, other),
+            Ok(Err(e)) => eprintln!("⚠️  Bootstrap node {} unreachable: {}", addr, e),
+            Err(_) => eprintln!("⚠️  Bootstrap node {} timed out", addr),
+        }
+    }
+
+    if dht.peer_count() == 0 {
+        return Err(anyhow!("No bootstrap node answered"));
+    }
+
+    Lookup::new(dht, transport).find_node(dht.local_id()).await;
+    Ok(dht.peer_count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::generate_identity;
+    use crate::protocol::{Capabilities, DEFAULT_RECORD_TTL, DESCRIPTOR_TTL};
+    use std::collections::{HashMap, HashSet};
+    use std::sync::Arc;
+
+    /// In-process network: each address maps straight to a node's DHT
+    #[derive(Default)]
+    struct MemoryNetwork {
+        nodes: HashMap<String, Arc<DHT>>,
+        offline: HashSet<String>,
+    }
+
+    /// One node's view of the network; like the QUIC server, the receiving
+    /// node learns the sender of FIND_NODE and PING requests
+    struct MemoryTransport<'a> {
+        network: &'a MemoryNetwork,
+        from: String,
+    }
+
+    impl DhtTransport for MemoryTransport<'_> {
+        async fn request(&self, addr: &str, message: DHTMessage) -> Result<DHTMessage> {
+            let (_, addr) = unpin_addr(addr).map_err(|e| anyhow!(e))?;
+            if self.network.offline.contains(addr) {
+                return Err(anyhow!("{} is offline", addr));
+            }
+            let dht = self.network.nodes.get(addr).ok_or_else(|| anyhow!("Unknown peer {}", addr))?;
+            if let DHTMessage::FindNode { requesting_node: sender, .. } | DHTMessage::Ping { sender } = &message {
+                dht.insert_peer(PeerInfo { node_id: sender.clone(), addr: self.from.clone() });
+            }
+            dht.handle_message(message).ok_or_else(|| anyhow!("No reply"))
+        }
+    }
+
+    impl MemoryNetwork {
+        fn node(&self, n: u16) -> Arc<DHT> {
+            self.nodes[&addr(n)].clone()
+        }
+
+        fn transport(&self, n: u16) -> MemoryTransport<'_> {
+            MemoryTransport { network: self, from: addr(n) }
+        }
+    }
+
+    fn id(n: u16) -> NodeId {
+        crate::protocol::generate_node_id(&n.to_be_bytes())
+    }
+
+    fn addr(n: u16) -> String {
+        format!("10.0.0.1:{}", n)
+    }
+
+    /// Nodes join one at a time by bootstrapping off node 0
+    async fn joined(size: u16) -> MemoryNetwork {
+        let mut network = MemoryNetwork::default();
+        network.nodes.insert(addr(0), Arc::new(DHT::new(id(0))));
+        for n in 1..size {
+            let dht = Arc::new(DHT::new(id(n)));
+            network.nodes.insert(addr(n), dht.clone());
+            bootstrap(&dht, &network.transport(n), &[addr(0)]).await.unwrap();
+        }
+        network
+    }
+
+    /// `lookup` with a short query timeout, for networks with nodes offline
+    fn impatient<T: DhtTransport>(mut lookup: Lookup<'_, T>) -> Lookup<'_, T> {
+        lookup.query_timeout = Duration::from_millis(100);
+        lookup
+    }
+
+    fn brute_force_closest(network: &MemoryNetwork, from: u16, target: &NodeId, k: usize) -> Vec<NodeId> {
+        let mut ids: Vec<NodeId> = network.nodes.values().map(|d| d.local_id().clone()).collect();
+        ids.retain(|n| n != &id(from));
+        ids.sort_by_key(|n| Distance::between(n, target));
+        ids.truncate(k);
+        ids
+    }
+
+    #[tokio::test]
+    async fn test_find_node_converges_on_closest() {
+        let network = joined(80).await;
+        let origin = network.node(7);
+        let target = id(1000);
+
+        let found = Lookup::new(&origin, &network.transport(7)).find_node(&target).await;
+        let found_ids: Vec<NodeId> = found.into_iter().map(|p| p.node_id).collect();
+
+        assert_eq!(found_ids, brute_force_closest(&network, 7, &target, K_BUCKET_SIZE));
+    }
+
+    #[tokio::test]
+    async fn test_find_domain_across_nodes() {
+        let network = joined(40).await;
+        let holder = network.node(25);
+        let owner = DomainRecord::sign(&generate_identity(), "example.freedom", id(25), 1, DEFAULT_RECORD_TTL);
+        holder.register_domain(owner.clone()).unwrap();
+
+        let origin = network.node(3);
+        let result = Lookup::new(&origin, &network.transport(3)).find_domain("example.freedom").await;
+        assert_eq!(result.owner, Some(owner));
+
+        let missing = Lookup::new(&origin, &network.transport(3)).find_domain("missing.freedom").await;
+        assert!(missing.owner.is_none());
+        assert!(!missing.closest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_find_domain_prefers_newest_record() {
+        let network = joined(30).await;
+        let identity = generate_identity();
+        let stale = DomainRecord::sign(&identity, "example.freedom", id(5), 1, DEFAULT_RECORD_TTL);
+        let newer = DomainRecord::sign(&identity, "example.freedom", id(6), 2, DEFAULT_RECORD_TTL);
+
+        // Every node, the origin included, holds the stale copy except the
+        // one nearest the key, which has the newer one
+        let nearest = brute_force_closest(&network, 3, &domain_key("example.freedom"), 1)[0].clone();
+        for n in 0..30 {
+            let record = if id(n) == nearest { newer.clone() } else { stale.clone() };
+            network.node(n).register_domain(record).unwrap();
+        }
+
+        let origin = network.node(3);
+        let result = Lookup::new(&origin, &network.transport(3)).find_domain("example.freedom").await;
+        assert_eq!(result.owner, Some(newer.clone()));
+        assert_eq!(origin.lookup_domain("example.freedom"), Some(newer));
+    }
+
+    /// Passes requests through but redirects every domain record it relays
+    struct ForgingTransport<'a>(MemoryTransport<'a>);
+
+    impl DhtTransport for ForgingTransport<'_> {
+        async fn request(&self, addr: &str, message: DHTMessage) -> Result<DHTMessage> {
+            match self.0.request(addr, message).await? {
+                DHTMessage::DomainOwner { domain, owner: Some(mut record) } => {
+                    record.address.node_id = id(666);
+                    Ok(DHTMessage::DomainOwner { domain, owner: Some(record) })
+                }
+                reply => Ok(reply),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forged_records_are_rejected() {
+        let network = joined(30).await;
+        let record = DomainRecord::sign(&generate_identity(), "example.freedom", id(5), 1, DEFAULT_RECORD_TTL);
+        network.node(5).register_domain(record).unwrap();
+
+        let origin = network.node(3);
+        let transport = ForgingTransport(network.transport(3));
+        let result = Lookup::new(&origin, &transport).find_domain("example.freedom").await;
+        assert!(result.owner.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_publish_and_find_service() {
+        let network = joined(40).await;
+        let service = generate_identity();
+        let intro = crate::onion::CircuitHop { addr: addr(9), identity: generate_identity().public(), onion_key: [9u8; 32] };
+        let descriptor = ServiceDescriptor::sign(&service, [2u8; 32], vec![intro], DESCRIPTOR_TTL);
+
+        let stored = Lookup::new(&network.node(4), &network.transport(4)).publish_service(&descriptor).await;
+        assert_eq!(stored, K_BUCKET_SIZE);
+
+        let origin = network.node(31);
+        let found = Lookup::new(&origin, &network.transport(31)).find_service(&descriptor.domain()).await;
+        assert_eq!(found, Some(descriptor.clone()));
+        assert_eq!(origin.lookup_service(&descriptor.domain()), Some(descriptor));
+
+        let missing = generate_identity().public().to_domain();
+        assert_eq!(Lookup::new(&origin, &network.transport(31)).find_service(&missing).await, None);
+    }
+
+    fn holders(network: &MemoryNetwork, domain: &str) -> Vec<u16> {
+        let mut holders: Vec<u16> = (0..network.nodes.len() as u16)
+            .filter(|n| network.node(*n).lookup_domain(domain).is_some())
+            .collect();
+        holders.sort();
+        holders
+    }
+
+    #[tokio::test]
+    async fn test_publish_replicates_to_closest() {
+        let mut network = joined(60).await;
+        let owner = generate_identity();
+        let domain = owner.public().to_domain();
+        let record = DomainRecord::sign(&owner, &domain, id(4), 1, DEFAULT_RECORD_TTL);
+
+        let stored = Lookup::new(&network.node(4), &network.transport(4)).publish(&record).await;
+        assert_eq!(stored, K_BUCKET_SIZE);
+        let replicas = holders(&network, &domain);
+        assert_eq!(replicas.len(), K_BUCKET_SIZE);
+
+        // Still resolvable with the publisher and the nearest replicas gone
+        network.offline.insert(addr(4));
+        for n in replicas.iter().take(5) {
+            network.offline.insert(addr(*n));
+        }
+        let seeker = (0..60).find(|n| !replicas.contains(n) && *n != 4).unwrap();
+        let result = impatient(Lookup::new(&network.node(seeker), &network.transport(seeker)))
+            .find_domain(&domain)
+            .await;
+        assert_eq!(result.owner, Some(record));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_caches_on_path() {
+        let network = joined(40).await;
+        let owner = generate_identity();
+        let domain = owner.public().to_domain();
+        let record = DomainRecord::sign(&owner, &domain, id(4), 1, DEFAULT_RECORD_TTL);
+
+        // Only the K-th closest node to the key holds the record
+        let key = domain_key(&domain);
+        let mut by_distance: Vec<u16> = (0..40).collect();
+        by_distance.sort_by_key(|n| Distance::between(&id(*n), &key));
+        network.node(by_distance[K_BUCKET_SIZE - 1]).register_domain(record.clone()).unwrap();
+
+        // A newcomer that knows only the farthest node must pass through
+        // nodes without the record before reaching the holder
+        let seeker = DHT::new(id(500));
+        seeker.insert_peer(PeerInfo { node_id: id(by_distance[39]), addr: addr(by_distance[39]) });
+        let transport = network.transport(500);
+        let mut lookup = Lookup::new(&seeker, &transport);
+        lookup.alpha = 1;
+        let result = lookup.find_domain(&domain).await;
+        assert_eq!(result.owner, Some(record));
+        assert!(seeker.lookup_domain(&domain).is_some());
+
+        let nearest = result.closest.first().unwrap();
+        assert!(network.nodes[&nearest.addr].lookup_domain(&domain).is_some());
+        assert_eq!(holders(&network, &domain).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_offline_peers_are_skipped() {
+        let mut network = joined(30).await;
+        network.offline.insert(addr(1));
+        let origin = network.node(0);
+
+        let found = impatient(Lookup::new(&origin, &network.transport(0))).find_node(&id(1)).await;
+        assert!(!found.is_empty());
+        assert!(found.iter().all(|p| p.addr != addr(1)));
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_fills_routing_table() {
+        let network = joined(20).await;
+        assert!(network.nodes.values().all(|dht| dht.peer_count() > 1));
+
+        let loner = DHT::new(id(998));
+        assert!(bootstrap(&loner, &network.transport(998), &["10.9.9.9:1".to_string()]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_descriptor() {
+        let mut network = MemoryNetwork::default();
+        let relay = generate_identity();
+        let dht = Arc::new(DHT::new(relay.node_id()));
+        let capabilities = Capabilities { relay: true, exit_policy: Some(crate::exit::ExitPolicy::default()) };
+        let descriptor = NodeDescriptor::new(relay.public(), [1u8; 32], vec![addr(1)], capabilities).sign(&relay, DESCRIPTOR_TTL);
+        dht.register_descriptor(descriptor.clone()).unwrap();
+        network.nodes.insert(addr(1), dht.clone());
+        let transport = network.transport(0);
+
+        let peer = PeerInfo { node_id: relay.node_id(), addr: addr(1) };
+        assert_eq!(fetch_descriptor(&transport, &peer).await.unwrap(), descriptor);
+
+        // Asking under an ID the node has no descriptor for finds nothing
+        let wrong_id = PeerInfo { node_id: id(5), addr: addr(1) };
+        assert!(fetch_descriptor(&transport, &wrong_id).await.is_err());
+    }
+}
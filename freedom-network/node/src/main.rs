@@ -9,21 +9,93 @@ mod client;
 mod onion;
 mod proxy;
 mod web;
+mod lookup;
+mod rpc;
+mod wire;
+mod dispatch;
+mod config;
+mod handshake;
+mod relay;
+mod cell;
+mod stream;
+mod exit;
+mod path;
+mod circuits;
+mod rendezvous;
+mod http;
+mod privacy;
 
 use std::sync::Arc;
-use quinn::{Endpoint, ServerConfig};
-use rcgen::generate_simple_self_signed;
-use std::net::SocketAddr;
-use protocol::{DHT, NodeId, FreedomAddress};
-use routing::Router;
+use protocol::{Capabilities, DomainRecord, NodeDescriptor, DEFAULT_RECORD_TTL, DESCRIPTOR_TTL, DHT, REPUBLISH_INTERVAL};
 use std::collections::HashMap;
 use tokio::sync::RwLock;
-use sha3::Digest;
-use proxy::ProxyServer;
+use proxy::{ProxyServer, Socks5Server};
+use sites::SiteServer;
+use dispatch::Dispatcher;
 use web::WebDashboard;
+use config::{Cli, Command, IdentityCommand, NodeConfig};
+use clap::Parser;
+use anyhow::Context;
+
+/// What pooled circuits' exits must allow: HTTPS to any host
+const WEB_DESTINATION: &str = "*:443";
+
+/// Sign a record for a domain this node serves. Records are sequenced by
+/// signing time, so each renewal (and each restart) supersedes the last.
+fn sign_record(identity: &identity::Identity, domain: &str) -> DomainRecord {
+    DomainRecord::sign(identity, domain, identity.node_id(), protocol::unix_now(), DEFAULT_RECORD_TTL)
+}
+
+/// Sign this node's relay descriptor from its config, advertising
+/// `onion_key` for circuit handshakes
+fn sign_descriptor(identity: &identity::Identity, onion_key: [u8; 32], config: &NodeConfig) -> anyhow::Result<NodeDescriptor> {
+    let capabilities = Capabilities { relay: true, exit_policy: config.exit_policy()? };
+    Ok(NodeDescriptor::new(identity.public(), onion_key, vec![config.advertised_address().to_string()], capabilities)
+        .with_family(config.family()?)
+        .with_bandwidth(config.relay_bandwidth)
+        .sign(identity, DESCRIPTOR_TTL))
+}
+
+/// Run an `identity` subcommand against the configured data directory
+fn run_identity_command(command: IdentityCommand, config: &NodeConfig) -> anyhow::Result<()> {
+    let passphrase = identity::passphrase_from_env();
+    let data_dir = &config.data_dir;
+
+    let identity = match command {
+        IdentityCommand::Show => identity::Identity::load(&identity::identity_path(data_dir), &passphrase)?,
+        IdentityCommand::Export { file } => {
+            let identity = identity::export(data_dir, &file, &passphrase)?;
+            println!("✓ Exported identity to {}", file.display());
+            identity
+        }
+        IdentityCommand::Import { file, force } => {
+            let identity = identity::import(data_dir, &file, &passphrase, force)?;
+            println!("✓ Imported identity from {}", file.display());
+            identity
+        }
+        IdentityCommand::Rotate => {
+            let (identity, backup) = identity::rotate(data_dir, &passphrase)?;
+            if let Some(backup) = backup {
+                println!("✓ Previous identity saved to {}", backup.display());
+            }
+            println!("✓ Generated new identity");
+            identity
+        }
+    };
+
+    println!("📍 Node ID: {}", hex::encode(identity.node_id().0));
+    println!("🔑 Address: {}", identity.address());
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let config = NodeConfig::load(&cli)?;
+    if let Some(Command::Identity(command)) = cli.command {
+        return run_identity_command(command, &config);
+    }
+
     // Force stdout to flush immediately
     use std::io::{self, Write};
     let _ = io::stdout().flush();
@@ -33,25 +105,29 @@ async fn main() -> anyhow::Result<()> {
     println!("========================\n");
     let _ = io::stdout().flush();
 
+    let config = Arc::new(config);
+    std::fs::create_dir_all(&config.data_dir)?;
+    println!("📂 Data directory: {}", config.data_dir.display());
+
     // Initialize node infrastructure
-    let dht = Arc::new(DHT::new());
-    let router = Arc::new(Router::new());
-    let onion_router = Arc::new(onion::OnionRouter::new());
+    let onion_router = Arc::new(
+        onion::OnionRouter::new()
+            .with_guard_file(config.data_dir.join("guards.json"))
+            .with_path_rules(path::PathRules { distinct_subnets: config.distinct_subnets }),
+    );
     let _domain_cache: Arc<RwLock<HashMap<String, String>>> = Arc::new(RwLock::new(HashMap::new()));
-    // Generate node identity
-    let cert = generate_simple_self_signed(vec!["localhost".into()])?;
-    let cert_der = cert.serialize_der()?;
-    let key_der = cert.serialize_private_key_der();
-
-    // Create node ID from certificate
-    let mut hasher = sha3::Sha3_256::new();
-    hasher.update(&cert_der);
-    let node_id_hash = hasher.finalize();
-    let mut node_id_bytes = [0u8; 32];
-    node_id_bytes.copy_from_slice(&node_id_hash[..32]);
-    let node_id = NodeId(node_id_bytes);
+    // Load (or create on first start) the node identity
+    if std::env::var(identity::PASSPHRASE_ENV).is_err() {
+        eprintln!("⚠️  {} is not set; the identity key is stored without a passphrase", identity::PASSPHRASE_ENV);
+    }
+    let node_identity = Arc::new(identity::load_or_create(&config.data_dir, &identity::passphrase_from_env())?);
+    let (cert_der, key_der) = node_identity.tls_certificate()?;
+    let node_id = node_identity.node_id();
 
     println!("📍 Node ID: {}", hex::encode(&node_id.0[..8]));
+    println!("🔑 Address: {}", node_identity.address());
+
+    let dht = Arc::new(DHT::new(node_id.clone()));
 
     // Initialize onion routing
     println!("🧅 Onion Routing Layer: Initialized");
@@ -60,39 +136,212 @@ async fn main() -> anyhow::Result<()> {
     println!("   - Privacy-preserving routing\n");
 
     // Set up QUIC server
-    let mut server_config = ServerConfig::with_single_cert(
-        vec![rustls::Certificate(cert_der.clone())],
-        rustls::PrivateKey(key_der)
-    )?;
-    server_config.transport = Arc::new(quinn::TransportConfig::default());
-
-    let addr: SocketAddr = "127.0.0.1:5000".parse()?;
-    let endpoint = Endpoint::server(server_config, addr)?;
+    let addr = config.quic_address;
+    let endpoint = rpc::bind_endpoint(addr, cert_der.clone(), key_der)?;
+    let transport = Arc::new(rpc::QuicTransport::new(endpoint.clone()));
+    let exit_policy = config.exit_policy()?;
+    let relay = Arc::new(relay::Relay::new(node_identity.clone(), transport.clone()).with_exit_policy(exit_policy.clone()));
+    let dispatcher = Arc::new(Dispatcher::new(dht.clone(), relay.clone(), transport.clone()));
     println!("🚀 QUIC Server listening on {}", addr);
     println!("🔐 TLS Certificate: {} bytes\n", cert_der.len());
 
     // Register this node in the DHT
     println!("📝 Registering node in DHT...");
-    let freedom_address = FreedomAddress {
-        domain: "node.freedom".to_string(),
-        node_id: node_id.clone(),
-        ed25519_pubkey: cert_der.clone(),
+    let node_domain = node_identity.public().to_domain();
+    let published_domains = vec![node_domain.clone()];
+    dht.register_domain(sign_record(&node_identity, &node_domain))?;
+    println!("✓ Registered: {}", node_domain);
+    if exit_policy.is_some() {
+        println!("🚪 Exit relay: {} policy rules", config.exit_policy.len());
+    }
+    dht.register_descriptor(sign_descriptor(&node_identity, relay.onion_keys().public(), &config)?)?;
+
+    println!();
+
+    // Join the network through the configured bootstrap nodes, then keep our
+    // records alive: replicate them now and re-sign them every REPUBLISH_INTERVAL
+    {
+        let dht = dht.clone();
+        let transport = transport.clone();
+        let relay = relay.clone();
+        let bootstrap_nodes = config.bootstrap_nodes.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if !bootstrap_nodes.is_empty() {
+                match lookup::bootstrap(&dht, transport.as_ref(), &bootstrap_nodes).await {
+                    Ok(peers) => println!("🛰️  Bootstrapped: {} peers in routing table", peers),
+                    Err(e) => eprintln!("🔴 Bootstrap failed: {}", e),
+                }
+            }
+
+            let mut interval = tokio::time::interval(REPUBLISH_INTERVAL);
+            interval.tick().await;
+            loop {
+                for domain in &published_domains {
+                    // The newest record the network holds: ours, unless
+                    // another key got to the name first
+                    let lookup = lookup::Lookup::new(&dht, transport.as_ref());
+                    match lookup.find_domain(domain).await.owner {
+                        Some(record) if record.address.ed25519_pubkey != node_identity.public().as_bytes().as_slice() => {
+                            eprintln!("⚠️  {} is claimed by another key; not publishing it", domain);
+                        }
+                        Some(record) => {
+                            let stored = lookup.publish(&record).await;
+                            println!("📡 Published {} to {} peers", domain, stored);
+                        }
+                        None => {}
+                    }
+                }
+
+                interval.tick().await;
+                let expired = dht.expire_records(protocol::unix_now());
+                if expired > 0 {
+                    println!("🧹 Dropped {} expired records, {} domains held", expired, dht.record_count());
+                }
+                for domain in &published_domains {
+                    if let Err(e) = dht.register_domain(sign_record(&node_identity, domain)) {
+                        eprintln!("🔴 Failed to renew {}: {}", domain, e);
+                    }
+                }
+                // The onion key turns over here, so the new one is published
+                // as soon as it is in use
+                if relay.onion_keys().rotate(protocol::unix_now()) {
+                    println!("🔑 Rotated the onion key");
+                }
+                let descriptor = sign_descriptor(&node_identity, relay.onion_keys().public(), &config).expect("config was validated");
+                if let Err(e) = dht.register_descriptor(descriptor) {
+                    eprintln!("🔴 Failed to renew relay descriptor: {}", e);
+                }
+            }
+        });
+    }
+
+    // Keep the relays circuits are built through in line with the routing table
+    {
+        let onion_router = onion_router.clone();
+        let transport = transport.clone();
+        let dht = dht.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+            loop {
+                interval.tick().await;
+                onion_router.sync_routing_table(&dht, transport.as_ref()).await;
+            }
+        });
+    }
+
+    // Tear down circuits through this relay that clients have abandoned
+    {
+        let relay = relay.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let expired = relay.expire_idle_circuits(relay::CIRCUIT_IDLE_TIMEOUT).await;
+                if expired > 0 {
+                    println!("🧹 Closed {} idle relay circuits", expired);
+                }
+            }
+        });
+    }
+
+    // Keep clean circuits through exits that take web traffic built ahead
+    // of the proxy needing them, and retire them as they age
+    let circuit_manager = circuits::CircuitManager::new(
+        onion_router.clone(),
+        transport.clone(),
+        circuits::ManagerSettings {
+            pool_size: config.circuit_pool,
+            num_hops: config.hop_count,
+            destination: WEB_DESTINATION.to_string(),
+        },
+    );
+    let circuit_status = circuit_manager.status();
+    circuit_manager.spawn();
+
+    // Hosted sites are only reachable as a hidden service: the site server
+    // listens locally and the service forwards its streams there
+    let sites = Arc::new(SiteServer::new());
+    let service_target = if config.sites.is_empty() {
+        config.hidden_service
+    } else {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let target = listener.local_addr()?;
+        let sites = sites.clone();
+        tokio::spawn(async move {
+            if let Err(e) = sites.serve_http(listener).await {
+                eprintln!("🔴 Site server error: {}", e);
+            }
+        });
+        Some(target)
     };
-    dht.register_domain(freedom_address.clone());
-    println!("✓ Registered: {}\n", freedom_address.domain);
 
-    // Initialize HTTP Proxy Server (VPN-like interface)
-    let proxy_addr: SocketAddr = "127.0.0.1:8080".parse()?;
-    let proxy_server = Arc::new(ProxyServer::new(proxy_addr, onion_router.clone()).await?);
+    // Serve a hidden service: keep introduction circuits open and publish
+    // them under the service key, so the host's address never appears
+    if let Some(target) = service_target {
+        let service_identity = Arc::new(identity::load_or_create_service(&config.data_dir, &identity::passphrase_from_env())?);
+        let service = Arc::new(rendezvous::HiddenService::new(
+            service_identity,
+            onion_router.clone(),
+            transport.clone(),
+            target,
+            config.hop_count,
+        ));
+        println!("🕵️  Hidden service {} → {}", service.domain(), target);
+        for site in &config.sites {
+            if !site.domain.eq_ignore_ascii_case(&service.domain()) {
+                anyhow::bail!("Hosted site {} must be named {}, after the hidden service key", site.domain, service.domain());
+            }
+            sites.register_site(service.domain(), site.path.clone(), site.index.clone()).await?;
+            println!("✓ Hosting: {} from {}", service.domain(), site.path.display());
+        }
+        let dht = dht.clone();
+        let transport = transport.clone();
+        tokio::spawn(async move {
+            loop {
+                let descriptor = match service.refresh().await {
+                    Ok(descriptor) => descriptor,
+                    Err(e) => {
+                        eprintln!("🔴 Hidden service not reachable yet: {}", e);
+                        tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+                        continue;
+                    }
+                };
+                if let Err(e) = dht.register_service(descriptor.clone()) {
+                    eprintln!("🔴 Failed to register {}: {}", descriptor.domain(), e);
+                }
+                let stored = lookup::Lookup::new(&dht, transport.as_ref()).publish_service(&descriptor).await;
+                println!("📡 Published {} through {} introduction points to {} peers", descriptor.domain(), descriptor.intro_points.len(), stored);
+                tokio::time::sleep(REPUBLISH_INTERVAL).await;
+            }
+        });
+    }
+
+    // Initialize HTTP Proxy Server (VPN-like interface). It serves .freedom
+    // names itself, resolving them in the DHT and meeting the site at a
+    // rendezvous relay.
+    let resolver = Arc::new(resolver::FreedomResolver::new(dht.clone(), transport.clone()));
+    for (name, address) in &config.pet_names {
+        resolver.add_pet_name(name, address).await.with_context(|| format!("Invalid pet name '{}'", name))?;
+    }
+    let freedom_sites = client::FreedomClient::new(resolver, onion_router.clone(), transport.clone(), config.hop_count);
+    let proxy_server = Arc::new(
+        ProxyServer::new(config.proxy_address, onion_router.clone())
+            .await?
+            .with_freedom_sites(Arc::new(freedom_sites))
+            .with_privacy_policy(config.privacy_policy),
+    );
+    // The port actually bound, for when the config asks for any free one
+    let proxy_addr = proxy_server.local_addr()?;
     
     println!("\n╔════════════════════════════════════════════╗");
     println!("║     FREEDOM NETWORK VPN PROXY ACTIVE      ║");
     println!("╠════════════════════════════════════════════╣");
-    println!("║ 📍 Proxy: http://127.0.0.1:8080           ║");
+    println!("║ 📍 Proxy: http://{:<25}║", proxy_addr);
     println!("║ 🌐 Configure your browser:                 ║");
     println!("║    Firefox: Preferences → Network Settings ║");
     println!("║    Chrome: Settings → Advanced → Proxy     ║");
-    println!("║    Set HTTP proxy to: 127.0.0.1:8080       ║");
+    println!("║    Set HTTP proxy to: {:<21}║", proxy_addr);
     println!("╚════════════════════════════════════════════╝\n");
 
     // Spawn proxy server task
@@ -103,12 +352,26 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
-    // Initialize Web Dashboard with proxy metrics
+    // SOCKS5 for applications that don't speak HTTP proxying; it counts
+    // towards the same metrics as the HTTP proxy
     let proxy_metrics = proxy_server.get_metrics();
-    let dashboard_addr: SocketAddr = "127.0.0.1:9090".parse()?;
-    let web_dashboard = Arc::new(WebDashboard::new(dashboard_addr, proxy_metrics).await?);
+    if let Some(socks_addr) = config.socks_address {
+        let socks_server = Socks5Server::new(socks_addr, onion_router.clone(), proxy_metrics.clone())
+            .await?
+            .with_users(config.socks_users()?);
+        println!("🧦 SOCKS5 proxy on {}", socks_server.local_addr()?);
+        tokio::spawn(async move {
+            if let Err(e) = socks_server.run().await {
+                eprintln!("🔴 SOCKS5 server error: {}", e);
+            }
+        });
+    }
+
+    // Initialize Web Dashboard with proxy metrics
+    let dashboard_addr = config.dashboard_address;
+    let web_dashboard = Arc::new(WebDashboard::new(dashboard_addr, proxy_metrics, config.clone(), circuit_status).await?);
     
-    println!("🖥️  Dashboard: http://127.0.0.1:9090\n");
+    println!("🖥️  Dashboard: http://{}\n", dashboard_addr);
 
     // Spawn web dashboard task
     let web_clone = web_dashboard.clone();
@@ -122,36 +385,15 @@ async fn main() -> anyhow::Result<()> {
     println!("⏳ Waiting for connections...\n");
     loop {
         if let Some(conn) = endpoint.accept().await {
-            let _dht = dht.clone();
-            let _router = router.clone();
+            let dispatcher = dispatcher.clone();
 
             tokio::spawn(async move {
                 if let Ok(new_conn) = conn.await {
                     println!("🔗 New connection from {}", new_conn.remote_address());
-
-                    loop {
-                        match new_conn.accept_bi().await {
-                            Ok((mut send, mut recv)) => {
-                                let mut buf = vec![0; 8192];
-                                match recv.read(&mut buf).await {
-                                    Ok(Some(n)) => {
-                                        println!("📨 Received {} bytes", n);
-                                        let response = b"ACK";
-                                        let _ = send.write_all(response).await;
-                                    }
-                                    Ok(None) => break,
-                                    Err(e) => {
-                                        eprintln!("❌ Read error: {}", e);
-                                        break;
-                                    }
-                                }
-                            }
-                            Err(_) => break,
-                        }
-                    }
+                    dispatcher.serve_connection(new_conn).await;
                     println!("🔌 Connection closed");
                 }
             });
         }
     }
-}
\ No newline at end of file
+}
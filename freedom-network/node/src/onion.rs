@@ -1,20 +1,46 @@
 // Onion Routing Module for Freedom Network
 // Implements multi-hop routing similar to Tor but using our DHT substrate
 
-use rand::seq::SliceRandom;
+use anyhow::{anyhow, Result};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use futures::stream::{self, StreamExt};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
 use sha3::{Digest, Sha3_256};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use tokio::sync::RwLock;
+use crate::cell::{self, Cell, Reassembler};
+use crate::handshake::{ClientHandshake, HopKeys};
+use crate::identity::PublicIdentity;
+use crate::path::{self, Candidate, GuardSet, PathEnd, PathRules, RelayInfo};
+use crate::lookup::{self, DhtTransport};
+use crate::protocol::{pin_addr, NodeId, RoutingMessage, DHT};
+use crate::routing::{CircuitMessage, RelayPayload};
+use crate::stream::StreamMux;
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
-pub struct NodeId(String);
+/// Bytes each onion layer adds to a cell (the Poly1305 tag)
+pub const LAYER_OVERHEAD: usize = 16;
+
+/// How long a circuit is kept, used or not
+pub const CIRCUIT_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// How long after its first stream a circuit still takes new ones, so one
+/// circuit doesn't link a client's activity over a long session
+pub const MAX_CIRCUIT_DIRTINESS: Duration = Duration::from_secs(10 * 60);
+
+/// Descriptor fetches in flight at once while syncing the routing table
+const SYNC_CONCURRENCY: usize = 8;
 
 #[derive(Clone, Debug)]
 pub struct OnionRoute {
     pub route_id: String,
     pub hops: Vec<NodeId>,
-    pub symmetric_keys: Vec<Vec<u8>>, // One key per hop
     pub created_at: std::time::SystemTime,
     pub expires_at: std::time::SystemTime,
 }
@@ -24,6 +50,130 @@ pub struct OnionCircuit {
     pub circuit_id: String,
     pub route: OnionRoute,
     pub state: CircuitState,
+    /// Opens streams once the circuit is built
+    pub streams: Option<StreamMux>,
+    /// Entry relay and the circuit's ID on that link, once built
+    pub link: Option<(String, u32)>,
+    /// When the circuit was first handed out for a stream
+    pub dirty_since: Option<SystemTime>,
+    /// Isolation group the circuit was handed out to; streams from other
+    /// groups never share it
+    pub isolation: Option<String>,
+}
+
+impl OnionCircuit {
+    /// Built and its stream driver still running
+    pub fn is_alive(&self) -> bool {
+        self.state == CircuitState::Ready && self.streams.as_ref().is_some_and(|streams| !streams.is_closed())
+    }
+
+    /// Whether new streams may still go over this circuit
+    fn accepts_streams(&self, now: SystemTime, max_dirtiness: Duration) -> bool {
+        let dirty_for = |since: SystemTime| now.duration_since(since).unwrap_or_default();
+        self.is_alive()
+            && now < self.route.expires_at
+            && self.dirty_since.is_none_or(|since| dirty_for(since) < max_dirtiness)
+    }
+
+    fn stream_count(&self) -> usize {
+        self.streams.as_ref().map_or(0, StreamMux::stream_count)
+    }
+}
+
+/// A circuit as the dashboard shows it
+#[derive(Clone, Debug, Serialize)]
+pub struct CircuitSummary {
+    pub circuit_id: String,
+    pub state: String,
+    pub hops: usize,
+    pub age_secs: u64,
+    pub dirty_secs: Option<u64>,
+    pub streams: usize,
+}
+
+/// Which way a cell travels: forward is client -> exit, backward is exit -> client.
+/// The two directions use disjoint nonces under the same hop key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Forward = 0,
+    Backward = 1,
+}
+
+/// One hop's layer of a circuit: a ChaCha20-Poly1305 key and a cell counter
+/// per direction. Nonces are never sent; both ends derive them from the
+/// counters, so a replayed, dropped or reordered cell fails to open.
+#[derive(Clone)]
+pub struct HopLayer {
+    forward: ChaCha20Poly1305,
+    backward: ChaCha20Poly1305,
+    forward_counter: u64,
+    backward_counter: u64,
+}
+
+impl std::fmt::Debug for HopLayer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HopLayer")
+            .field("forward_counter", &self.forward_counter)
+            .field("backward_counter", &self.backward_counter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HopLayer {
+    /// Layer keyed by the output of the circuit handshake with this hop
+    pub fn new(keys: &HopKeys) -> Self {
+        HopLayer {
+            forward: ChaCha20Poly1305::new(Key::from_slice(&keys.forward)),
+            backward: ChaCha20Poly1305::new(Key::from_slice(&keys.backward)),
+            forward_counter: 0,
+            backward_counter: 0,
+        }
+    }
+
+    fn counter(&mut self, direction: Direction) -> &mut u64 {
+        match direction {
+            Direction::Forward => &mut self.forward_counter,
+            Direction::Backward => &mut self.backward_counter,
+        }
+    }
+
+    fn cipher(&self, direction: Direction) -> &ChaCha20Poly1305 {
+        match direction {
+            Direction::Forward => &self.forward,
+            Direction::Backward => &self.backward,
+        }
+    }
+
+    /// Nonce for the next cell: direction byte, three zero bytes, counter (BE)
+    fn nonce(direction: Direction, counter: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[0] = direction as u8;
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    /// Add this hop's layer to a cell
+    pub fn seal(&mut self, direction: Direction, data: &[u8]) -> Vec<u8> {
+        let counter = self.counter(direction);
+        let nonce = Self::nonce(direction, *counter);
+        *counter += 1;
+        self.cipher(direction)
+            .encrypt(Nonce::from_slice(&nonce), data)
+            .expect("ChaCha20-Poly1305 encryption cannot fail for in-memory buffers")
+    }
+
+    /// Remove this hop's layer. A cell that fails authentication is rejected
+    /// and the counter is left as it was; the circuit should be torn down.
+    pub fn open(&mut self, direction: Direction, data: &[u8]) -> Result<Vec<u8>, String> {
+        let counter = *self.counter(direction);
+        let nonce = Self::nonce(direction, counter);
+        let plaintext = self
+            .cipher(direction)
+            .decrypt(Nonce::from_slice(&nonce), data)
+            .map_err(|_| format!("Onion layer failed authentication (cell {})", counter))?;
+        *self.counter(direction) += 1;
+        Ok(plaintext)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -37,6 +187,13 @@ pub enum CircuitState {
 pub struct OnionRouter {
     circuits: Arc<RwLock<HashMap<String, OnionCircuit>>>,
     available_nodes: Arc<RwLock<Vec<NodeId>>>,
+    /// How to reach and authenticate the nodes circuits can be built
+    /// through, and what path selection weighs them by
+    relays: Arc<RwLock<HashMap<NodeId, RelayInfo>>>,
+    guards: Arc<RwLock<GuardSet>>,
+    rules: PathRules,
+    rng: Arc<Mutex<StdRng>>,
+    max_dirtiness: Duration,
     route_cache: Arc<RwLock<HashMap<String, OnionRoute>>>,
 }
 
@@ -45,9 +202,26 @@ impl OnionRouter {
         OnionRouter {
             circuits: Arc::new(RwLock::new(HashMap::new())),
             available_nodes: Arc::new(RwLock::new(Vec::new())),
+            relays: Arc::new(RwLock::new(HashMap::new())),
+            guards: Arc::new(RwLock::new(GuardSet::default())),
+            rules: PathRules::default(),
+            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
+            max_dirtiness: MAX_CIRCUIT_DIRTINESS,
             route_cache: Arc::new(RwLock::new(HashMap::new())),
         }
     }
+
+    /// Keep guards in `path` across restarts
+    pub fn with_guard_file(mut self, path: PathBuf) -> Self {
+        self.guards = Arc::new(RwLock::new(GuardSet::load(path)));
+        self
+    }
+
+    pub fn with_path_rules(mut self, rules: PathRules) -> Self {
+        self.rules = rules;
+        self
+    }
+
     
     /// Add a node to the available pool for routing
     pub async fn register_node(&self, node_id: NodeId) {
@@ -56,44 +230,97 @@ impl OnionRouter {
             nodes.push(node_id);
         }
     }
-    
-    /// Build a multi-hop onion route via available nodes
-    pub async fn build_route(&self, num_hops: usize) -> Result<OnionRoute, String> {
-        let nodes = self.available_nodes.read().await;
-        
-        if nodes.len() < num_hops {
-            return Err(format!(
-                "Not enough nodes available: have {}, need {}",
-                nodes.len(),
-                num_hops
-            ));
+
+    /// Add (or update) a relay that circuits can actually be built through
+    pub async fn register_relay(&self, relay: RelayInfo) {
+        let node_id = relay.node_id();
+        self.relays.write().await.insert(node_id.clone(), relay);
+        self.register_node(node_id).await;
+    }
+
+    /// Stop routing through a node. Guards are kept, so a guard that comes
+    /// back is used again.
+    pub async fn forget_node(&self, node_id: &NodeId) {
+        self.available_nodes.write().await.retain(|node| node != node_id);
+        self.relays.write().await.remove(node_id);
+    }
+
+    /// Bring the relays we route through in line with the DHT routing table:
+    /// fetch each peer's descriptor and register the relays among them, and
+    /// forget nodes that have left the table or stopped relaying. Returns how
+    /// many relays are registered.
+    pub async fn sync_routing_table<T: DhtTransport>(&self, dht: &DHT, transport: &T) -> usize {
+        let peers = dht.find_closest_peers(dht.local_id(), usize::MAX);
+        // A few fetches at a time, each bounded by the lookup query timeout
+        let fetched: Vec<_> = stream::iter(peers)
+            .map(|peer| async move {
+                let started = std::time::Instant::now();
+                let descriptor = lookup::fetch_descriptor(transport, &peer).await;
+                (peer, descriptor, started.elapsed())
+            })
+            .buffer_unordered(SYNC_CONCURRENCY)
+            .collect()
+            .await;
+
+        let mut relaying = Vec::new();
+        for (peer, descriptor, latency) in fetched {
+            let descriptor = match descriptor {
+                Ok(descriptor) if descriptor.capabilities.relay => descriptor,
+                Ok(_) => continue,
+                Err(e) => {
+                    eprintln!("⚠️  No descriptor from {}: {}", peer.addr, e);
+                    continue;
+                }
+            };
+            // Only dial where the relay's signed descriptor says it listens;
+            // the address the routing table learned it at is just a hint
+            let addr = descriptor
+                .addresses
+                .iter()
+                .find(|addr| **addr == peer.addr)
+                .or(descriptor.addresses.first());
+            let addr = match addr {
+                // A wildcard is where the relay binds, not where it can be
+                // reached; only the address it was found at will do
+                Some(addr) if addr.parse::<std::net::SocketAddr>().is_ok_and(|addr| addr.ip().is_unspecified()) => peer.addr.clone(),
+                Some(addr) => addr.clone(),
+                None => {
+                    eprintln!("⚠️  Descriptor from {} lists no addresses", peer.addr);
+                    continue;
+                }
+            };
+            let relay = RelayInfo::from_descriptor(&descriptor, addr).with_latency(Some(latency));
+            self.register_relay(relay).await;
+            relaying.push(peer.node_id.clone());
         }
-        
-        // Randomly select hops
-        let mut rng = rand::thread_rng();
-        let mut selected = nodes
+
+        let gone = self
+            .available_nodes
+            .read()
+            .await
             .iter()
+            .filter(|node| !relaying.contains(node))
             .cloned()
             .collect::<Vec<_>>();
-        selected.shuffle(&mut rng);
-        let hops = selected.into_iter().take(num_hops).collect::<Vec<_>>();
-        
-        // Generate symmetric keys for each hop (for encryption)
-        let symmetric_keys: Vec<Vec<u8>> = hops
-            .iter()
-            .map(|_| self.generate_symmetric_key())
-            .collect();
+        for node in &gone {
+            self.forget_node(node).await;
+        }
+        relaying.len()
+    }
+
+    /// Build a multi-hop onion route via available nodes: entry from our
+    /// guards, no two related hops, and with a `destination` (host:port) an
+    /// exit whose policy allows it
+    pub async fn build_route(&self, num_hops: usize, destination: Option<&str>) -> Result<OnionRoute, String> {
+        let hops = self.select_hops(num_hops, destination.map_or(PathEnd::Any, PathEnd::Exit)).await?;
         
         let route_id = self.generate_route_id(&hops);
         let now = std::time::SystemTime::now();
-        let expires_at = now
-            .checked_add(std::time::Duration::from_secs(3600))
-            .unwrap_or(now);
+        let expires_at = now.checked_add(CIRCUIT_LIFETIME).unwrap_or(now);
         
         let route = OnionRoute {
             route_id,
             hops,
-            symmetric_keys,
             created_at: now,
             expires_at,
         };
@@ -101,10 +328,37 @@ impl OnionRouter {
         Ok(route)
     }
     
+    /// Pick `num_hops` relays ending as `end` asks, entry from our guards
+    async fn select_hops(&self, num_hops: usize, end: PathEnd<'_>) -> Result<Vec<NodeId>, String> {
+        let nodes = self.available_nodes.read().await;
+        // A fixed last hop need not be one of ours
+        let needed = match end {
+            PathEnd::Fixed(_) => num_hops.saturating_sub(1),
+            _ => num_hops,
+        };
+        if nodes.len() < needed {
+            return Err(format!(
+                "Not enough nodes available: have {}, need {}",
+                nodes.len(),
+                needed
+            ));
+        }
+
+        let relays = self.relays.read().await;
+        let candidates = nodes
+            .iter()
+            .map(|id| Candidate { id, info: relays.get(id) })
+            .collect::<Vec<_>>();
+        let mut guards = self.guards.write().await;
+        let mut rng = self.rng.lock().unwrap();
+        guards.refresh(&candidates, crate::protocol::unix_now(), &mut *rng);
+        path::select_path(&candidates, &guards.usable(&candidates), num_hops, end, self.rules, &mut *rng)
+    }
+
     /// Establish an onion circuit
-    pub async fn establish_circuit(&self, num_hops: usize) -> Result<String, String> {
+    pub async fn establish_circuit(&self, num_hops: usize, destination: Option<&str>) -> Result<String, String> {
         // Build route
-        let route = self.build_route(num_hops).await?;
+        let route = self.build_route(num_hops, destination).await?;
         let circuit_id = self.generate_circuit_id();
         
         // Create circuit
@@ -112,6 +366,10 @@ impl OnionRouter {
             circuit_id: circuit_id.clone(),
             route: route.clone(),
             state: CircuitState::Building,
+            streams: None,
+            link: None,
+            dirty_since: None,
+            isolation: None,
         };
         
         // Store circuit
@@ -125,88 +383,211 @@ impl OnionRouter {
         Ok(circuit_id)
     }
     
-    /// Mark circuit as ready to use
-    pub async fn activate_circuit(&self, circuit_id: &str) -> Result<(), String> {
+    /// Establish a circuit and build it hop by hop over `transport`; once
+    /// built it is Ready and carries streams
+    pub async fn build_circuit<T: CircuitTransport + 'static>(
+        &self,
+        transport: Arc<T>,
+        num_hops: usize,
+        destination: Option<&str>,
+    ) -> Result<String, String> {
+        let circuit_id = self.establish_circuit(num_hops, destination).await?;
+        let route = self.route_cache.read().await[&circuit_id].clone();
+        let hops = {
+            let relays = self.relays.read().await;
+            route
+                .hops
+                .iter()
+                .map(|node| relays.get(node).map(|relay| relay.hop.clone()).ok_or_else(|| format!("No address known for node {}", node)))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        let built = match hops {
+            Ok(hops) => ClientCircuit::build(transport.as_ref(), &hops).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e),
+        };
+        match built {
+            Ok(circuit) => {
+                let link = (circuit.entry.clone(), circuit.circuit_id);
+                let streams = StreamMux::spawn(circuit, transport);
+                if let Some(circuit) = self.circuits.write().await.get_mut(&circuit_id) {
+                    circuit.streams = Some(streams);
+                    circuit.link = Some(link);
+                }
+                self.activate_circuit(&circuit_id).await?;
+                Ok(circuit_id)
+            }
+            Err(e) => {
+                self.close_circuit(transport.as_ref(), &circuit_id).await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Build a circuit outside the pool, for hidden service traffic. With
+    /// `last` the circuit ends at that relay, after `num_hops - 1` chosen
+    /// ones; otherwise every hop is chosen. Returns the circuit and its last hop.
+    pub async fn build_private_circuit<T: CircuitTransport>(
+        &self,
+        transport: &T,
+        num_hops: usize,
+        last: Option<&CircuitHop>,
+    ) -> Result<(ClientCircuit, CircuitHop), String> {
+        let (ids, last) = match last {
+            Some(last) => {
+                // The last hop was chosen elsewhere; the others must stay clear of it
+                let id = last.identity.node_id();
+                let mut info = self.relays.read().await.get(&id).cloned().unwrap_or_else(|| RelayInfo::new(last.clone()));
+                info.hop = last.clone();
+                let end = PathEnd::Fixed(Candidate { id: &id, info: Some(&info) });
+                let mut ids = self.select_hops(num_hops, end).await?;
+                ids.pop();
+                (ids, Some(last))
+            }
+            None => (self.select_hops(num_hops, PathEnd::Any).await?, None),
+        };
+        let mut hops = {
+            let relays = self.relays.read().await;
+            ids.iter()
+                .map(|node| relays.get(node).map(|relay| relay.hop.clone()).ok_or_else(|| format!("No address known for node {}", node)))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        hops.extend(last.cloned());
+        let circuit = ClientCircuit::build(transport, &hops).await.map_err(|e| e.to_string())?;
+        let last = hops.pop().expect("a built circuit has hops");
+        Ok((circuit, last))
+    }
+
+    /// Streams of a live circuit whose exit allows `target` (host:port).
+    /// Circuits already in use are preferred, and the one handed out counts
+    /// as used from now on.
+    pub async fn ready_streams(&self, target: &str) -> Option<StreamMux> {
+        self.isolated_streams(target, None).await
+    }
+
+    /// Like `ready_streams`, but only circuits that are clean or already
+    /// serve `isolation` qualify, so each group gets circuits of its own
+    pub async fn isolated_streams(&self, target: &str, isolation: Option<&str>) -> Option<StreamMux> {
+        let now = SystemTime::now();
         let mut circuits = self.circuits.write().await;
-        
-        if let Some(circuit) = circuits.get_mut(circuit_id) {
-            circuit.state = CircuitState::Ready;
-            Ok(())
-        } else {
-            Err(format!("Circuit {} not found", circuit_id))
+        let relays = self.relays.read().await;
+        let circuit = circuits
+            .values_mut()
+            .filter(|circuit| circuit.accepts_streams(now, self.max_dirtiness))
+            .filter(|circuit| circuit.dirty_since.is_none() || circuit.isolation.as_deref() == isolation)
+            .filter(|circuit| exit_allows(&relays, circuit, target))
+            .max_by_key(|circuit| circuit.dirty_since.is_some())?;
+        if circuit.dirty_since.is_none() {
+            circuit.dirty_since = Some(now);
+            circuit.isolation = isolation.map(str::to_string);
         }
+        circuit.streams.clone()
     }
-    
-    /// Get circuit information
-    pub async fn get_circuit(&self, circuit_id: &str) -> Option<OnionCircuit> {
+
+    /// Live circuits to `destination` that have never carried a stream
+    pub async fn clean_circuits(&self, destination: &str) -> usize {
+        let now = SystemTime::now();
         let circuits = self.circuits.read().await;
-        circuits.get(circuit_id).cloned()
+        let relays = self.relays.read().await;
+        circuits
+            .values()
+            .filter(|circuit| circuit.dirty_since.is_none() && circuit.accepts_streams(now, self.max_dirtiness))
+            .filter(|circuit| exit_allows(&relays, circuit, destination))
+            .count()
     }
-    
-    /// Tear down a circuit
-    pub async fn close_circuit(&self, circuit_id: &str) -> Result<(), String> {
+
+    /// Ready circuits that should be torn down: their streams failed, or
+    /// they take no new streams (expired or dirty too long) and have none open
+    pub async fn retirable_circuits(&self) -> Vec<String> {
+        let now = SystemTime::now();
+        let circuits = self.circuits.read().await;
+        circuits
+            .values()
+            .filter(|circuit| circuit.state == CircuitState::Ready)
+            .filter(|circuit| {
+                !circuit.is_alive() || (!circuit.accepts_streams(now, self.max_dirtiness) && circuit.stream_count() == 0)
+            })
+            .map(|circuit| circuit.circuit_id.clone())
+            .collect()
+    }
+
+    /// Forget circuits that have been closed; returns how many
+    pub async fn prune_closed(&self) -> usize {
+        let mut circuits = self.circuits.write().await;
+        let mut cache = self.route_cache.write().await;
+        let before = circuits.len();
+        circuits.retain(|circuit_id, circuit| {
+            let keep = circuit.state != CircuitState::Closed;
+            if !keep {
+                cache.remove(circuit_id);
+            }
+            keep
+        });
+        before - circuits.len()
+    }
+
+    pub async fn circuit_summaries(&self) -> Vec<CircuitSummary> {
+        let now = SystemTime::now();
+        let secs_since = |then: SystemTime| now.duration_since(then).unwrap_or_default().as_secs();
+        let circuits = self.circuits.read().await;
+        let mut summaries = circuits
+            .values()
+            .map(|circuit| CircuitSummary {
+                circuit_id: circuit.circuit_id.clone(),
+                state: format!("{:?}", circuit.state),
+                hops: circuit.route.hops.len(),
+                age_secs: secs_since(circuit.route.created_at),
+                dirty_secs: circuit.dirty_since.map(secs_since),
+                streams: circuit.stream_count(),
+            })
+            .collect::<Vec<_>>();
+        summaries.sort_by_key(|summary| summary.age_secs);
+        summaries
+    }
+
+    /// Mark circuit as ready to use
+    pub async fn activate_circuit(&self, circuit_id: &str) -> Result<(), String> {
         let mut circuits = self.circuits.write().await;
         
         if let Some(circuit) = circuits.get_mut(circuit_id) {
-            circuit.state = CircuitState::Closing;
-            // Async cleanup would happen here
-            circuit.state = CircuitState::Closed;
+            circuit.state = CircuitState::Ready;
             Ok(())
         } else {
             Err(format!("Circuit {} not found", circuit_id))
         }
     }
     
-    /// Layer encrypt data through all hops (onion-style)
-    pub fn encrypt_payload(
-        &self,
-        payload: &[u8],
-        symmetric_keys: &[Vec<u8>],
-    ) -> Vec<u8> {
-        let mut encrypted = payload.to_vec();
-        
-        // Encrypt in reverse order (exit node first, entry node last)
-        for key in symmetric_keys.iter().rev() {
-            encrypted = self.xor_encrypt(&encrypted, key);
+    /// Tear down a circuit: stop handing out its streams and send
+    /// DestroyCircuit to the entry, which passes it along to the other hops
+    pub async fn close_circuit<T: CircuitTransport>(&self, transport: &T, circuit_id: &str) -> Result<(), String> {
+        let link = {
+            let mut circuits = self.circuits.write().await;
+            let circuit = circuits
+                .get_mut(circuit_id)
+                .ok_or_else(|| format!("Circuit {} not found", circuit_id))?;
+            circuit.state = CircuitState::Closing;
+            circuit.streams = None;
+            circuit.link.take()
+        };
+
+        if let Some((entry, id)) = link {
+            if let Err(e) = transport.send(&entry, RoutingMessage::DestroyCircuit { circuit_id: id }).await {
+                eprintln!("🧅 Could not send DestroyCircuit to {}: {}", entry, e);
+            }
         }
-        
-        encrypted
-    }
-    
-    /// Layer decrypt data through all hops
-    pub fn decrypt_payload(
-        &self,
-        encrypted: &[u8],
-        symmetric_keys: &[Vec<u8>],
-    ) -> Vec<u8> {
-        let mut decrypted = encrypted.to_vec();
-        
-        // Decrypt in forward order (entry node first, exit node last)
-        for key in symmetric_keys.iter() {
-            decrypted = self.xor_encrypt(&decrypted, key);
+
+        if let Some(circuit) = self.circuits.write().await.get_mut(circuit_id) {
+            circuit.state = CircuitState::Closed;
         }
-        
-        decrypted
+        Ok(())
     }
     
     // ===== Private Helper Methods =====
     
-    fn generate_symmetric_key(&self) -> Vec<u8> {
-        use sha3::Digest;
-        let timestamp = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_nanos();
-        
-        let mut hasher = Sha3_256::new();
-        hasher.update(format!("{}", timestamp).as_bytes());
-        hasher.finalize().to_vec()
-    }
-    
     fn generate_route_id(&self, hops: &[NodeId]) -> String {
         let mut hasher = Sha3_256::new();
         for hop in hops {
-            hasher.update(hop.0.as_bytes());
+            hasher.update(hop.0);
         }
         hex::encode(hasher.finalize())
     }
@@ -217,33 +598,228 @@ impl OnionRouter {
         let random_bytes: Vec<u8> = (0..16).map(|_| rng.gen()).collect();
         hex::encode(random_bytes)
     }
-    
-    fn xor_encrypt(&self, data: &[u8], key: &[u8]) -> Vec<u8> {
-        data.iter()
-            .zip(key.iter().cycle())
-            .map(|(d, k)| d ^ k)
-            .collect()
+}
+
+fn exit_allows(relays: &HashMap<NodeId, RelayInfo>, circuit: &OnionCircuit, target: &str) -> bool {
+    let exit = circuit.route.hops.last().and_then(|exit| relays.get(exit));
+    exit.and_then(RelayInfo::exit_policy).is_some_and(|policy| policy.may_allow(target))
+}
+
+/// Carries circuit messages to a node. `None` means the node acknowledged
+/// the message without a reply.
+pub trait CircuitTransport: Send + Sync {
+    fn send(&self, addr: &str, message: RoutingMessage) -> impl Future<Output = Result<Option<RoutingMessage>>> + Send;
+}
+
+/// A relay to route through: where to reach it and the key it must prove
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CircuitHop {
+    pub addr: String,
+    pub identity: PublicIdentity,
+    /// The onion key from the relay's descriptor
+    pub onion_key: [u8; 32],
+}
+
+impl CircuitHop {
+    /// The address to dial the relay on, pinned to its identity
+    pub fn pinned_addr(&self) -> String {
+        pin_addr(&self.identity.node_id(), &self.addr)
+    }
+}
+
+/// Client end of a circuit, built one hop at a time. Each hop's keys come
+/// from a handshake tunnelled through the hops before it, so only the entry
+/// hop ever sees where the client is.
+pub struct ClientCircuit {
+    pub circuit_id: u32,
+    pub entry: String,
+    layers: Vec<HopLayer>,
+}
+
+impl ClientCircuit {
+    /// Open a circuit to `hops[0]` and telescope it out through the rest
+    pub async fn build<T: CircuitTransport>(transport: &T, hops: &[CircuitHop]) -> Result<Self> {
+        let entry = hops.first().ok_or_else(|| anyhow!("A circuit needs at least one hop"))?;
+        let circuit_id = rand::random::<u32>();
+
+        let (handshake, onion_skin) = ClientHandshake::start(&entry.identity.node_id(), &entry.onion_key);
+        let request = RoutingMessage::BuildCircuit { circuit_id, handshake: onion_skin };
+        let reply = match transport.send(&entry.pinned_addr(), request).await? {
+            Some(RoutingMessage::CircuitCreated { handshake: reply, .. }) => reply,
+            other => return Err(anyhow!("{} answered BuildCircuit with {:?}", entry.addr, other)),
+        };
+
+        let mut circuit = ClientCircuit {
+            circuit_id,
+            entry: entry.pinned_addr(),
+            layers: vec![HopLayer::new(&handshake.complete(&reply)?)],
+        };
+        for hop in &hops[1..] {
+            if let Err(e) = circuit.extend(transport, hop).await {
+                // Don't leave the hops built so far holding a dead circuit
+                let destroy = RoutingMessage::DestroyCircuit { circuit_id };
+                let _ = transport.send(&circuit.entry, destroy).await;
+                return Err(e);
+            }
+        }
+        Ok(circuit)
+    }
+
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// Add `hop` to the end of the circuit through the current last hop
+    pub async fn extend<T: CircuitTransport>(&mut self, transport: &T, hop: &CircuitHop) -> Result<()> {
+        let (handshake, onion_skin) = ClientHandshake::start(&hop.identity.node_id(), &hop.onion_key);
+        let extend = CircuitMessage::Extend { addr: hop.pinned_addr(), handshake: onion_skin };
+        let last = self.layers.len() - 1;
+        match self.send(transport, last, &extend).await? {
+            (from, CircuitMessage::Extended { handshake: reply }) if from == last => {
+                self.layers.push(HopLayer::new(&handshake.complete(&reply)?));
+                Ok(())
+            }
+            (from, other) => Err(anyhow!("Hop {} answered Extend with {:?}", from, other)),
+        }
+    }
+
+    /// Add the far end of a rendezvous as the last hop, keyed by the
+    /// end-to-end handshake with the hidden service
+    pub fn join(&mut self, keys: &HopKeys) {
+        self.layers.push(HopLayer::new(keys));
+    }
+
+    /// Tell the entry to tear the circuit down
+    pub async fn destroy<T: CircuitTransport>(&self, transport: &T) {
+        let destroy = RoutingMessage::DestroyCircuit { circuit_id: self.circuit_id };
+        if let Err(e) = transport.send(&self.entry, destroy).await {
+            eprintln!("🧅 Could not send DestroyCircuit to {}: {}", self.entry, e);
+        }
+    }
+
+    /// Send `message` to hop `target` (0 is the entry) and return the reply
+    /// along with the hop it came from
+    pub async fn send<T: CircuitTransport>(
+        &mut self,
+        transport: &T,
+        target: usize,
+        message: &CircuitMessage,
+    ) -> Result<(usize, CircuitMessage)> {
+        let mut replies = self.send_all(transport, target, std::slice::from_ref(message)).await?;
+        match replies.len() {
+            1 => Ok(replies.remove(0)),
+            n => Err(anyhow!("Expected one reply on circuit {}, got {}", self.circuit_id, n)),
+        }
+    }
+
+    /// Send several messages to hop `target` in one exchange and return
+    /// every reply, in order. Messages that need no answer (`Sendme`) get none.
+    pub async fn send_all<T: CircuitTransport>(
+        &mut self,
+        transport: &T,
+        target: usize,
+        messages: &[CircuitMessage],
+    ) -> Result<Vec<(usize, CircuitMessage)>> {
+        if target >= self.layers.len() {
+            return Err(anyhow!("Circuit has {} hops, no hop {}", self.layers.len(), target));
+        }
+        let cells = messages
+            .iter()
+            .flat_map(|message| cell::fragment(message.stream_id(), &message.encode()))
+            .map(|cell| self.wrap(target, &cell))
+            .collect();
+        self.exchange(transport, cells).await
+    }
+
+    /// Send hop `target` a padding cell, which every hop on the way counts
+    /// as activity and the target drops
+    pub async fn send_padding<T: CircuitTransport>(&mut self, transport: &T, target: usize) -> Result<()> {
+        if target >= self.layers.len() {
+            return Err(anyhow!("Circuit has {} hops, no hop {}", self.layers.len(), target));
+        }
+        let cells = vec![self.wrap(target, &Cell::padding())];
+        self.exchange(transport, cells).await?;
+        Ok(())
+    }
+
+    /// Send sealed cells to the entry and open the cells it answers with
+    async fn exchange<T: CircuitTransport>(&mut self, transport: &T, cells: Vec<Vec<u8>>) -> Result<Vec<(usize, CircuitMessage)>> {
+        let request = RoutingMessage::RelayData { circuit_id: self.circuit_id, cells };
+        let cells = match transport.send(&self.entry, request).await? {
+            Some(RoutingMessage::RelayData { cells, .. }) => cells,
+            other => return Err(anyhow!("{} answered RelayData with {:?}", self.entry, other)),
+        };
+
+        let mut reassembler = Reassembler::default();
+        let mut replies = Vec::new();
+        for cell in &cells {
+            let (hop, cell) = self.unwrap(cell)?;
+            if let Some((_, message)) = reassembler.push(cell).map_err(|e| anyhow!(e))? {
+                replies.push((hop, CircuitMessage::decode(&message).map_err(|e| anyhow!(e))?));
+            }
+        }
+        Ok(replies)
+    }
+
+    /// Seal a cell for hop `target` inside the layers of every hop before it.
+    /// The cell is padded as if it were going to the last hop, so cells on a
+    /// link look alike whichever hop they are for.
+    fn wrap(&mut self, target: usize, cell: &Cell) -> Vec<u8> {
+        let padding = (self.layers.len() - 1 - target) * (1 + LAYER_OVERHEAD);
+        let payload = RelayPayload::Recognized(cell.clone());
+        let plaintext = payload.encode_padded(1 + cell::CELL_LEN + padding);
+        let mut sealed = self.layers[target].seal(Direction::Forward, &plaintext);
+        for layer in self.layers[..target].iter_mut().rev() {
+            sealed = layer.seal(Direction::Forward, &RelayPayload::Relayed(sealed).encode());
+        }
+        sealed
+    }
+
+    /// Peel backward layers, entry first, until the hop that wrote the cell
+    fn unwrap(&mut self, sealed: &[u8]) -> Result<(usize, Cell)> {
+        let mut sealed = sealed.to_vec();
+        for (hop, layer) in self.layers.iter_mut().enumerate() {
+            let plaintext = layer.open(Direction::Backward, &sealed).map_err(|e| anyhow!(e))?;
+            match RelayPayload::decode(&plaintext).map_err(|e| anyhow!(e))? {
+                RelayPayload::Recognized(cell) => return Ok((hop, cell)),
+                RelayPayload::Relayed(inner) => sealed = inner,
+            }
+        }
+        Err(anyhow!("Reply cell was not recognized by any hop"))
+    }
+}
+
+/// Routers as other modules' tests need them
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    /// `router`, retiring circuits once they have been dirty for `max_dirtiness`
+    pub fn with_max_dirtiness(mut router: OnionRouter, max_dirtiness: Duration) -> OnionRouter {
+        router.max_dirtiness = max_dirtiness;
+        router
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::exit::ExitPolicy;
+    use crate::path::testing::guard_ids;
     
     #[tokio::test]
     async fn test_build_route() {
         let router = OnionRouter::new();
         
         // Register some nodes
-        router.register_node(NodeId("node1".to_string())).await;
-        router.register_node(NodeId("node2".to_string())).await;
-        router.register_node(NodeId("node3".to_string())).await;
+        router.register_node(NodeId([1; 32])).await;
+        router.register_node(NodeId([2; 32])).await;
+        router.register_node(NodeId([3; 32])).await;
         
         // Build a 3-hop route
-        let route = router.build_route(3).await.unwrap();
+        let route = router.build_route(3, None).await.unwrap();
         
         assert_eq!(route.hops.len(), 3);
-        assert_eq!(route.symmetric_keys.len(), 3);
         assert!(!route.route_id.is_empty());
     }
     
@@ -251,31 +827,237 @@ mod tests {
     async fn test_establish_circuit() {
         let router = OnionRouter::new();
         
-        router.register_node(NodeId("node1".to_string())).await;
-        router.register_node(NodeId("node2".to_string())).await;
-        router.register_node(NodeId("node3".to_string())).await;
+        router.register_node(NodeId([1; 32])).await;
+        router.register_node(NodeId([2; 32])).await;
+        router.register_node(NodeId([3; 32])).await;
         
-        let circuit_id = router.establish_circuit(3).await.unwrap();
+        let circuit_id = router.establish_circuit(3, None).await.unwrap();
         assert!(!circuit_id.is_empty());
         
-        let circuit = router.get_circuit(&circuit_id).await.unwrap();
-        assert_eq!(circuit.state, CircuitState::Building);
+        assert_eq!(router.circuits.read().await[&circuit_id].state, CircuitState::Building);
     }
     
     #[tokio::test]
-    async fn test_encrypt_decrypt() {
+    async fn test_route_ends_at_a_permitting_exit() {
+        // A fixed seed keeps path selection repeatable
+        let mut router = OnionRouter::new();
+        router.rng = Arc::new(Mutex::new(StdRng::seed_from_u64(1)));
+        let web = ExitPolicy::parse(&["accept *:80", "accept *:443", "reject *:*"]).unwrap();
+        let mail = ExitPolicy::parse(&["accept *:25", "reject *:*"]).unwrap();
+        let mut relays = Vec::new();
+        for (n, policy) in [None, None, None, Some(web), Some(mail)].into_iter().enumerate() {
+            let hop = CircuitHop { addr: format!("10.{}.0.1:5000", n), identity: crate::identity::generate_identity().public(), onion_key: [n as u8; 32] };
+            let mut relay = RelayInfo::new(hop);
+            relay.capabilities.exit_policy = policy;
+            relays.push(relay.node_id());
+            router.register_relay(relay).await;
+        }
+
+        for _ in 0..20 {
+            let route = router.build_route(3, Some("example.com:443")).await.unwrap();
+            assert_eq!(route.hops.len(), 3);
+            assert_eq!(route.hops[2], relays[3]);
+            assert!(!route.hops[..2].contains(&relays[3]));
+            assert!(guard_ids(&*router.guards.read().await).contains(&route.hops[0]));
+
+            let route = router.build_route(2, Some("10.0.0.1:25")).await.unwrap();
+            assert_eq!(route.hops[1], relays[4]);
+        }
+        assert!(router.build_route(3, Some("example.com:22")).await.is_err());
+
+        // Re-registering without a policy withdraws the exit
+        let mut relay = router.relays.read().await[&relays[4]].clone();
+        relay.capabilities.exit_policy = None;
+        router.register_relay(relay).await;
+        assert!(router.build_route(2, Some("10.0.0.1:25")).await.is_err());
+    }
+
+    /// Answers DHT requests straight from each address's node
+    struct MemoryDht(HashMap<String, DHT>);
+
+    impl DhtTransport for MemoryDht {
+        async fn request(&self, addr: &str, message: crate::protocol::DHTMessage) -> anyhow::Result<crate::protocol::DHTMessage> {
+            let (_, addr) = crate::protocol::unpin_addr(addr).map_err(|e| anyhow::anyhow!(e))?;
+            let dht = self.0.get(addr).ok_or_else(|| anyhow::anyhow!("Unknown peer {}", addr))?;
+            dht.handle_message(message).ok_or_else(|| anyhow::anyhow!("No reply"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_routing_table() {
+        use crate::protocol::{Capabilities, NodeDescriptor, PeerInfo, DESCRIPTOR_TTL};
+
+        let local = DHT::new(NodeId([0; 32]));
+        let mut network = HashMap::new();
+        let mut ids = Vec::new();
+        // An exit, a node that does not relay, a node with no descriptor and
+        // a relay that advertises the wildcard address it binds
+        let exit = ExitPolicy::parse(&["accept *:443", "reject *:*"]).unwrap();
+        for (n, capabilities) in [
+            Some(Capabilities { relay: true, exit_policy: Some(exit) }),
+            Some(Capabilities { relay: false, exit_policy: None }),
+            None,
+            Some(Capabilities { relay: true, exit_policy: None }),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let identity = crate::identity::generate_identity();
+            let addr = format!("10.{}.0.1:5000", n);
+            let node = DHT::new(identity.public().node_id());
+            if let Some(capabilities) = capabilities {
+                let listens = vec![if n == 3 { "0.0.0.0:5000".to_string() } else { format!("10.{}.0.9:5000", n) }];
+                let descriptor = NodeDescriptor::new(identity.public(), [n as u8; 32], listens, capabilities)
+                    .with_bandwidth(4 << 20)
+                    .sign(&identity, DESCRIPTOR_TTL);
+                node.register_descriptor(descriptor).unwrap();
+            }
+            local.insert_peer(PeerInfo { node_id: identity.public().node_id(), addr: addr.clone() });
+            ids.push(identity.public().node_id());
+            network.insert(addr, node);
+        }
+        let transport = MemoryDht(network);
+
         let router = OnionRouter::new();
-        
-        let payload = b"Secret message";
-        let keys = vec![
-            vec![0x42; 32],
-            vec![0x99; 32],
-            vec![0xAA; 32],
-        ];
-        
-        let encrypted = router.encrypt_payload(payload, &keys);
-        let decrypted = router.decrypt_payload(&encrypted, &keys);
-        
-        assert_eq!(decrypted, payload);
+        router.register_node(NodeId([9; 32])).await;
+        assert_eq!(router.sync_routing_table(&local, &transport).await, 2);
+
+        // Only the relays are routed through; the node that left is forgotten
+        let mut available = router.available_nodes.read().await.clone();
+        available.sort_by_key(|node| node.0);
+        let mut relaying = vec![ids[0].clone(), ids[3].clone()];
+        relaying.sort_by_key(|node| node.0);
+        assert_eq!(available, relaying);
+        // Reached where its descriptor says, not where the table heard of it,
+        // unless the descriptor only names a wildcard
+        assert_eq!(router.relays.read().await[&ids[3]].hop.addr, "10.3.0.1:5000");
+        let relay = router.relays.read().await[&ids[0]].clone();
+        assert_eq!(relay.hop.addr, "10.0.0.9:5000");
+        assert_eq!(relay.bandwidth, 4 << 20);
+        assert!(relay.exit_policy().unwrap().may_allow("*:443"));
+        assert!(relay.latency.is_some());
+
+        // A relay that drops out of the routing table is forgotten too
+        let local = DHT::new(NodeId([0; 32]));
+        assert_eq!(router.sync_routing_table(&local, &transport).await, 0);
+        assert!(router.available_nodes.read().await.is_empty());
+        assert!(router.relays.read().await.is_empty());
+    }
+
+    fn keys(forward: u8, backward: u8) -> HopKeys {
+        HopKeys { forward: [forward; 32], backward: [backward; 32] }
+    }
+
+    fn test_keys() -> Vec<HopKeys> {
+        vec![keys(0x42, 0x24), keys(0x99, 0x66), keys(0xAA, 0x55)]
+    }
+
+    fn test_circuit() -> ClientCircuit {
+        ClientCircuit {
+            circuit_id: 1,
+            entry: "10.0.0.1:5000".to_string(),
+            layers: test_keys().iter().map(HopLayer::new).collect(),
+        }
+    }
+
+    fn data_cell(data: &[u8]) -> Cell {
+        Cell { command: cell::CellCommand::End, stream_id: 7, data: data.to_vec() }
+    }
+
+    /// Open one forward layer the way a relay does
+    fn peel(layer: &mut HopLayer, sealed: &[u8]) -> RelayPayload {
+        RelayPayload::decode(&layer.open(Direction::Forward, sealed).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_wrap_unwrap() {
+        let mut circuit = test_circuit();
+        let mut relays: Vec<HopLayer> = test_keys().iter().map(HopLayer::new).collect();
+        let cell = data_cell(b"Secret message");
+
+        // Forward: each relay peels one layer, entry first, and the exit recognizes the cell
+        let mut sealed = circuit.wrap(2, &cell);
+        for relay in relays[..2].iter_mut() {
+            match peel(relay, &sealed) {
+                RelayPayload::Relayed(inner) => sealed = inner,
+                RelayPayload::Recognized(_) => panic!("Cell recognized too early"),
+            }
+        }
+        assert_eq!(peel(&mut relays[2], &sealed), RelayPayload::Recognized(cell.clone()));
+
+        // A cell for the entry hop is as long as one for the exit
+        assert_eq!(circuit.wrap(0, &cell).len(), circuit.wrap(2, &cell).len());
+
+        // Backward: the exit writes the reply, then each relay adds a layer
+        let reply = data_cell(b"Secret reply");
+        let mut sealed = relays[2].seal(Direction::Backward, &RelayPayload::Recognized(reply.clone()).encode());
+        for relay in relays[..2].iter_mut().rev() {
+            sealed = relay.seal(Direction::Backward, &RelayPayload::Relayed(sealed).encode());
+        }
+        assert_eq!(circuit.unwrap(&sealed).unwrap(), (2, reply));
+    }
+
+    #[test]
+    fn test_three_hop_vectors() {
+        let mut circuit = test_circuit();
+        let cell = data_cell(b"Secret message");
+        let first = circuit.wrap(2, &cell);
+        let second = circuit.wrap(2, &cell);
+
+        // Each layer is plain ChaCha20-Poly1305 under the hop's forward key,
+        // with the counter as nonce, so the same cell never encrypts the same way twice
+        assert_ne!(first, second);
+        for (sealed, counter) in [(first, 0u64), (second, 1)] {
+            let mut nonce = [0u8; 12];
+            nonce[4..].copy_from_slice(&counter.to_be_bytes());
+            let mut sealed = sealed;
+            for key in [0x42, 0x99] {
+                let hop = ChaCha20Poly1305::new(Key::from_slice(&[key; 32]));
+                let plaintext = hop.decrypt(Nonce::from_slice(&nonce), sealed.as_slice()).unwrap();
+                match RelayPayload::decode(&plaintext).unwrap() {
+                    RelayPayload::Relayed(inner) => sealed = inner,
+                    RelayPayload::Recognized(_) => panic!("Cell recognized too early"),
+                }
+            }
+            let exit = ChaCha20Poly1305::new(Key::from_slice(&[0xAA; 32]));
+            let plaintext = exit.decrypt(Nonce::from_slice(&nonce), sealed.as_slice()).unwrap();
+            assert_eq!(plaintext.len(), 1 + cell::CELL_LEN);
+            assert_eq!(RelayPayload::decode(&plaintext).unwrap(), RelayPayload::Recognized(cell.clone()));
+        }
+    }
+
+    #[test]
+    fn test_tampered_layer_is_rejected() {
+        let mut circuit = test_circuit();
+        let keys = test_keys();
+        let mut entry = HopLayer::new(&keys[0]);
+        let mut middle = HopLayer::new(&keys[1]);
+
+        let cell = circuit.wrap(2, &data_cell(b"Secret message"));
+        let mut tampered = cell.clone();
+        tampered[20] ^= 0x01;
+        assert!(entry.open(Direction::Forward, &tampered).is_err());
+
+        // A rejected cell does not advance the counter
+        let RelayPayload::Relayed(peeled) = peel(&mut entry, &cell) else {
+            panic!("Entry should relay the cell");
+        };
+
+        // Tampering with an inner layer is caught by the hop that owns it
+        let mut inner_tampered = peeled.clone();
+        let last = inner_tampered.len() - 1;
+        inner_tampered[last] ^= 0x80;
+        assert!(middle.open(Direction::Forward, &inner_tampered).is_err());
+
+        // Replays and wrong-direction cells fail too
+        assert!(entry.open(Direction::Forward, &cell).is_err());
+        assert!(middle.open(Direction::Backward, &peeled).is_err());
+        assert!(middle.open(Direction::Forward, &peeled).is_ok());
+
+        // A tampered reply is rejected by the client
+        let mut exit = HopLayer::new(&keys[2]);
+        let mut reply = exit.seal(Direction::Backward, &RelayPayload::Recognized(data_cell(b"Secret reply")).encode());
+        reply[0] ^= 0x01;
+        assert!(circuit.unwrap(&reply).is_err());
     }
 }
@@ -0,0 +1,473 @@
+// Path selection
+// Chooses the relays a circuit goes through. The entry is always one of a
+// few long-lived guards, so a hostile relay only ever gets to see a client
+// if it was picked as that client's guard. The exit must allow the
+// destination, no two hops may share a subnet or be declared one family,
+// and relays are otherwise picked in proportion to their bandwidth,
+// discounted by how slow they are to answer.
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use crate::exit::ExitPolicy;
+use crate::onion::CircuitHop;
+use crate::protocol::{Capabilities, NodeDescriptor, NodeId};
+
+/// Guards kept available for entry hops
+pub const GUARD_COUNT: usize = 3;
+
+/// Most guards ever kept at once, online or not. Once this many are
+/// sampled, losing guards to outages narrows the entry hops instead of
+/// exposing the client to new relays.
+pub const MAX_SAMPLED_GUARDS: usize = 2 * GUARD_COUNT;
+
+/// How long a guard is kept before another is picked in its place
+pub const GUARD_LIFETIME: Duration = Duration::from_secs(60 * 24 * 60 * 60);
+
+/// Bandwidth assumed for relays that haven't advertised one (bytes/second)
+pub const DEFAULT_BANDWIDTH: u64 = 1024 * 1024;
+
+/// Advertised bandwidth is capped here so no relay can claim most circuits
+pub const MAX_BANDWIDTH: u64 = 100 * 1024 * 1024;
+
+/// What path selection knows about a relay
+#[derive(Clone, Debug)]
+pub struct RelayInfo {
+    pub hop: CircuitHop,
+    pub capabilities: Capabilities,
+    /// Relays the operator says they also run; two relays are one family
+    /// only if each names the other
+    pub family: Vec<NodeId>,
+    /// Advertised bytes per second
+    pub bandwidth: u64,
+    /// Round trip last measured by this node
+    pub latency: Option<Duration>,
+}
+
+impl RelayInfo {
+    pub fn new(hop: CircuitHop) -> Self {
+        RelayInfo {
+            hop,
+            capabilities: Capabilities { relay: true, exit_policy: None },
+            family: Vec::new(),
+            bandwidth: DEFAULT_BANDWIDTH,
+            latency: None,
+        }
+    }
+
+    /// A relay as its descriptor describes it, reached at `addr`
+    pub fn from_descriptor(descriptor: &NodeDescriptor, addr: String) -> Self {
+        RelayInfo {
+            hop: CircuitHop { addr, identity: descriptor.identity, onion_key: descriptor.onion_key },
+            capabilities: descriptor.capabilities.clone(),
+            family: descriptor.family.clone(),
+            bandwidth: descriptor.bandwidth,
+            latency: None,
+        }
+    }
+
+    pub fn exit_policy(&self) -> Option<&ExitPolicy> {
+        self.capabilities.exit_policy.as_ref()
+    }
+
+    pub fn with_latency(mut self, latency: Option<Duration>) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.hop.identity.node_id()
+    }
+
+    fn ip(&self) -> Option<IpAddr> {
+        self.hop.addr.parse::<SocketAddr>().ok().map(|addr| addr.ip().to_canonical())
+    }
+}
+
+/// Restrictions on which relays may share a circuit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PathRules {
+    /// Keep hops in different /16 (IPv4) or /32 (IPv6) networks. Only worth
+    /// turning off for networks run on one machine.
+    pub distinct_subnets: bool,
+}
+
+impl Default for PathRules {
+    fn default() -> Self {
+        PathRules { distinct_subnets: true }
+    }
+}
+
+/// A node that could go into a path; nodes registered without relay details
+/// are treated as default-bandwidth relays on unknown addresses
+#[derive(Clone, Copy, Debug)]
+pub struct Candidate<'a> {
+    pub id: &'a NodeId,
+    pub info: Option<&'a RelayInfo>,
+}
+
+impl Candidate<'_> {
+    /// Selection weight: bandwidth, halved by 100ms of latency, a third by 200ms...
+    fn weight(&self) -> f64 {
+        let Some(info) = self.info else {
+            return DEFAULT_BANDWIDTH as f64;
+        };
+        let bandwidth = info.bandwidth.clamp(1, MAX_BANDWIDTH) as f64;
+        let latency = info.latency.map_or(0.0, |latency| latency.as_secs_f64());
+        bandwidth / (1.0 + latency * 10.0)
+    }
+
+    fn allows(&self, destination: Option<&str>) -> bool {
+        match destination {
+            None => true,
+            Some(destination) => self
+                .info
+                .and_then(RelayInfo::exit_policy)
+                .is_some_and(|policy| policy.may_allow(destination)),
+        }
+    }
+
+    fn related(&self, other: &Candidate, rules: PathRules) -> bool {
+        if self.id == other.id {
+            return true;
+        }
+        let (Some(a), Some(b)) = (self.info, other.info) else {
+            return false;
+        };
+        if rules.distinct_subnets {
+            if let (Some(x), Some(y)) = (a.ip(), b.ip()) {
+                if same_subnet(x, y) {
+                    return true;
+                }
+            }
+        }
+        a.family.contains(other.id) && b.family.contains(self.id)
+    }
+}
+
+fn same_subnet(a: IpAddr, b: IpAddr) -> bool {
+    match (a, b) {
+        (IpAddr::V4(a), IpAddr::V4(b)) => a.octets()[..2] == b.octets()[..2],
+        (IpAddr::V6(a), IpAddr::V6(b)) => a.octets()[..4] == b.octets()[..4],
+        _ => false,
+    }
+}
+
+/// Where a path has to end
+#[derive(Clone, Copy, Debug)]
+pub enum PathEnd<'a> {
+    Any,
+    /// At an exit whose policy may allow this destination (host:port)
+    Exit(&'a str),
+    /// At this relay, chosen elsewhere; no other hop may be related to it
+    Fixed(Candidate<'a>),
+}
+
+fn pick<'a, R: Rng>(rng: &mut R, choices: &[Candidate<'a>]) -> Option<Candidate<'a>> {
+    choices.choose_weighted(rng, |candidate| candidate.weight()).ok().copied()
+}
+
+/// Choose `num_hops` relays, entry first. The entry is the first usable
+/// guard that leaves room for a last hop as `end` asks and enough middle
+/// hops. A path that can't be filled is an error, never a shorter path.
+pub fn select_path<R: Rng>(
+    candidates: &[Candidate],
+    guards: &[NodeId],
+    num_hops: usize,
+    end: PathEnd,
+    rules: PathRules,
+    rng: &mut R,
+) -> Result<Vec<NodeId>, String> {
+    if num_hops == 0 {
+        return Err("A circuit needs at least one hop".to_string());
+    }
+    let destination = match end {
+        PathEnd::Exit(destination) => Some(destination),
+        _ => None,
+    };
+    // A one-hop path to a fixed relay is just that relay
+    if let (1, PathEnd::Fixed(last)) = (num_hops, end) {
+        return Ok(vec![last.id.clone()]);
+    }
+
+    for guard in guards.iter().filter_map(|id| candidates.iter().find(|c| c.id == id)) {
+        if num_hops == 1 {
+            if guard.allows(destination) {
+                return Ok(vec![guard.id.clone()]);
+            }
+            continue;
+        }
+
+        let exit = match end {
+            PathEnd::Fixed(last) if last.related(guard, rules) => continue,
+            PathEnd::Fixed(last) => last,
+            _ => {
+                let exits: Vec<_> = candidates
+                    .iter()
+                    .filter(|c| c.allows(destination) && !c.related(guard, rules))
+                    .copied()
+                    .collect();
+                let Some(exit) = pick(rng, &exits) else {
+                    continue;
+                };
+                exit
+            }
+        };
+
+        let mut path = vec![*guard];
+        for _ in 2..num_hops {
+            let middles: Vec<_> = candidates
+                .iter()
+                .filter(|c| !c.related(&exit, rules) && path.iter().all(|hop| !c.related(hop, rules)))
+                .copied()
+                .collect();
+            match pick(rng, &middles) {
+                Some(middle) => path.push(middle),
+                None => break,
+            }
+        }
+        if path.len() == num_hops - 1 {
+            path.push(exit);
+            return Ok(path.into_iter().map(|hop| hop.id.clone()).collect());
+        }
+    }
+
+    Err(match end {
+        PathEnd::Any => format!("No {}-hop path of unrelated relays", num_hops),
+        PathEnd::Exit(destination) => format!("No {}-hop path of unrelated relays ends at an exit allowing {}", num_hops, destination),
+        PathEnd::Fixed(last) => format!("No {}-hop path of relays unrelated to {} ends there", num_hops, last.id),
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GuardEntry {
+    pub node_id: NodeId,
+    /// Unix time in seconds the guard was picked
+    pub chosen_at: u64,
+}
+
+/// This node's guards, oldest first, saved so they outlive restarts
+#[derive(Debug, Default)]
+pub struct GuardSet {
+    entries: Vec<GuardEntry>,
+    path: Option<PathBuf>,
+}
+
+impl GuardSet {
+    /// Load the guards saved at `path`; a missing or unreadable file starts
+    /// an empty set that will be saved there
+    pub fn load(path: PathBuf) -> Self {
+        let entries = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                eprintln!("⚠️  Ignoring unreadable guard file {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        GuardSet { entries, path: Some(path) }
+    }
+
+    /// Guards that are currently available, in the order they were picked
+    pub fn usable(&self, candidates: &[Candidate]) -> Vec<NodeId> {
+        self.entries
+            .iter()
+            .filter(|guard| candidates.iter().any(|c| *c.id == guard.node_id))
+            .map(|guard| guard.node_id.clone())
+            .collect()
+    }
+
+    /// Retire guards older than GUARD_LIFETIME and pick new ones until
+    /// GUARD_COUNT are available, never holding more than
+    /// MAX_SAMPLED_GUARDS. Guards that are merely offline are kept, so an
+    /// attacker who knocks the current ones over only gets the client onto
+    /// a few new guards, not onto whichever relays it runs.
+    pub fn refresh<R: Rng>(&mut self, candidates: &[Candidate], now: u64, rng: &mut R) {
+        let before = self.entries.clone();
+        self.entries.retain(|guard| now.saturating_sub(guard.chosen_at) < GUARD_LIFETIME.as_secs());
+        while self.entries.len() < MAX_SAMPLED_GUARDS && self.usable(candidates).len() < GUARD_COUNT {
+            let fresh: Vec<_> = candidates
+                .iter()
+                .filter(|c| self.entries.iter().all(|guard| guard.node_id != *c.id))
+                .copied()
+                .collect();
+            let Some(guard) = pick(rng, &fresh) else {
+                break;
+            };
+            self.entries.push(GuardEntry { node_id: guard.id.clone(), chosen_at: now });
+        }
+        if self.entries != before {
+            self.save();
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let json = serde_json::to_vec_pretty(&self.entries).expect("guard entries always serialize");
+        if let Err(e) = std::fs::write(path, json) {
+            eprintln!("⚠️  Failed to save guards to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Guard sets as other modules' tests see them
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    /// The guards in `guards`, oldest first
+    pub fn guard_ids(guards: &GuardSet) -> Vec<NodeId> {
+        guards.entries.iter().map(|guard| guard.node_id.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::generate_identity;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn relay(addr: &str) -> RelayInfo {
+        RelayInfo::new(CircuitHop { addr: addr.to_string(), identity: generate_identity().public(), onion_key: [0u8; 32] })
+    }
+
+    fn candidates<'a>(relays: &'a [RelayInfo], ids: &'a [NodeId]) -> Vec<Candidate<'a>> {
+        ids.iter().zip(relays).map(|(id, info)| Candidate { id, info: Some(info) }).collect()
+    }
+
+    fn ids(relays: &[RelayInfo]) -> Vec<NodeId> {
+        relays.iter().map(RelayInfo::node_id).collect()
+    }
+
+    #[test]
+    fn test_no_two_hops_share_a_subnet_or_family() {
+        let mut relays: Vec<RelayInfo> = ["10.1.0.1:5000", "10.1.0.2:5000", "10.2.0.1:5000", "10.3.0.1:5000", "10.4.0.1:5000"]
+            .iter()
+            .map(|addr| relay(addr))
+            .collect();
+        let ids = ids(&relays);
+        // 10.3 and 10.4 are run by one operator who says so on both
+        relays[3].family = vec![ids[4].clone()];
+        relays[4].family = vec![ids[3].clone()];
+        let pool = candidates(&relays, &ids);
+
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..50 {
+            let path = select_path(&pool, &ids, 3, PathEnd::Any, PathRules::default(), &mut rng).unwrap();
+            assert!(!(path.contains(&ids[0]) && path.contains(&ids[1])), "same /16");
+            assert!(!(path.contains(&ids[3]) && path.contains(&ids[4])), "same family");
+        }
+        // Only three unrelated groups exist
+        assert!(select_path(&pool, &ids, 4, PathEnd::Any, PathRules::default(), &mut rng).is_err());
+        let local = PathRules { distinct_subnets: false };
+        assert!(select_path(&pool, &ids, 4, PathEnd::Any, local, &mut rng).is_ok());
+
+        // A family claim the other relay doesn't return counts for nothing
+        relays[4].family.clear();
+        let pool = candidates(&relays, &ids);
+        assert!(select_path(&pool, &ids, 4, PathEnd::Any, PathRules::default(), &mut rng).is_ok());
+    }
+
+    #[test]
+    fn test_fixed_last_hop() {
+        let relays: Vec<RelayInfo> = ["10.1.0.1:5000", "10.2.0.1:5000", "10.3.0.1:5000", "10.4.0.1:5000"]
+            .iter()
+            .map(|addr| relay(addr))
+            .collect();
+        let ids = ids(&relays);
+        let pool = candidates(&relays, &ids);
+        let mut rng = StdRng::seed_from_u64(5);
+
+        // A relay outside the pool, on the same /16 as the first guard
+        let outsider = relay("10.1.9.9:5000");
+        let outsider_id = outsider.node_id();
+        let last = Candidate { id: &outsider_id, info: Some(&outsider) };
+        for _ in 0..20 {
+            let path = select_path(&pool, &ids, 3, PathEnd::Fixed(last), PathRules::default(), &mut rng).unwrap();
+            assert_eq!(path.len(), 3);
+            assert_eq!(path[2], outsider_id);
+            assert!(!path.contains(&ids[0]), "same /16 as the last hop");
+        }
+
+        // One of the pool as the last hop is never picked twice
+        let path = select_path(&pool, &ids, 4, PathEnd::Fixed(pool[3]), PathRules::default(), &mut rng).unwrap();
+        assert_eq!(path.iter().filter(|id| **id == ids[3]).count(), 1);
+        assert_eq!(path[3], ids[3]);
+
+        // Too few unrelated relays is an error, not a shorter path
+        assert!(select_path(&pool, &ids, 5, PathEnd::Fixed(last), PathRules::default(), &mut rng).is_err());
+    }
+
+    #[test]
+    fn test_selection_is_seedable_and_weighted() {
+        let mut relays: Vec<RelayInfo> = (1..=6).map(|n| relay(&format!("10.{}.0.1:5000", n))).collect();
+        relays[5].bandwidth = MAX_BANDWIDTH;
+        relays[4].latency = Some(Duration::from_secs(2));
+        let ids = ids(&relays);
+        let pool = candidates(&relays, &ids);
+        let guards = vec![ids[0].clone()];
+
+        let run = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..20).map(|_| select_path(&pool, &guards, 3, PathEnd::Any, PathRules::default(), &mut rng).unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+
+        let paths = run(1);
+        assert!(paths.iter().all(|path| path[0] == ids[0]), "entry is always the guard");
+        let fast = paths.iter().filter(|path| path.contains(&ids[5])).count();
+        let slow = paths.iter().filter(|path| path.contains(&ids[4])).count();
+        assert!(fast >= 18, "high-bandwidth relay in {} of 20 paths", fast);
+        assert!(slow <= 2, "high-latency relay in {} of 20 paths", slow);
+    }
+
+    #[test]
+    fn test_guards_persist_and_expire() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("guards.json");
+        let relays: Vec<RelayInfo> = (1..=8).map(|n| relay(&format!("10.{}.0.1:5000", n))).collect();
+        let ids = ids(&relays);
+        let pool = candidates(&relays, &ids);
+        let mut rng = StdRng::seed_from_u64(3);
+
+        let mut guards = GuardSet::load(path.clone());
+        guards.refresh(&pool, 1000, &mut rng);
+        assert_eq!(guards.entries.len(), GUARD_COUNT);
+        let chosen = guards.entries.to_vec();
+
+        // Reloading keeps the same guards, and a refresh doesn't churn them
+        let mut reloaded = GuardSet::load(path.clone());
+        reloaded.refresh(&pool, 2000, &mut rng);
+        assert_eq!(reloaded.entries, chosen);
+
+        // An offline guard is kept but skipped, and another is added meanwhile
+        let online: Vec<_> = pool.iter().filter(|c| *c.id != chosen[0].node_id).copied().collect();
+        reloaded.refresh(&online, 3000, &mut rng);
+        assert_eq!(reloaded.entries.len(), GUARD_COUNT + 1);
+        assert_eq!(reloaded.usable(&online).len(), GUARD_COUNT);
+        assert_eq!(reloaded.entries[0], chosen[0]);
+
+        // Knocking guards offline adds only up to MAX_SAMPLED_GUARDS
+        let mut attacked = reloaded.entries.to_vec();
+        for round in 0..relays.len() {
+            let online: Vec<_> = pool.iter().filter(|c| attacked.iter().all(|guard| guard.node_id != *c.id)).copied().collect();
+            reloaded.refresh(&online, 4000 + round as u64, &mut rng);
+            assert!(reloaded.entries.len() <= MAX_SAMPLED_GUARDS);
+            attacked = reloaded.entries.to_vec();
+        }
+        assert_eq!(reloaded.entries.len(), MAX_SAMPLED_GUARDS);
+        assert!(reloaded.usable(&pool).len() >= GUARD_COUNT);
+
+        // Guards past their lifetime are replaced
+        let later = 1000 + GUARD_LIFETIME.as_secs();
+        reloaded.refresh(&pool, later, &mut rng);
+        assert!(reloaded.entries.iter().all(|guard| guard.chosen_at != 1000));
+        assert_eq!(reloaded.usable(&pool).len(), GUARD_COUNT);
+    }
+}
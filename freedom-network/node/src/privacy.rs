@@ -0,0 +1,141 @@
+// Privacy filtering for proxied requests
+// Browsers send headers that identify the user or the page they came from.
+// Everything leaves the network at an exit, so whatever the proxy forwards
+// is readable by the exit and the destination. A policy decides what is
+// removed or replaced with a value every user of the policy sends.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use crate::http::Headers;
+
+/// Sent in place of the browser's own User-Agent
+pub const COMMON_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; rv:128.0) Gecko/20100101 Firefox/128.0";
+
+/// Sent in place of the browser's own Accept-Language
+pub const COMMON_ACCEPT_LANGUAGE: &str = "en-US,en;q=0.5";
+
+/// Fields through which a client or an earlier proxy reveals an address
+const FORWARDING_FIELDS: &[&str] = &[
+    "forwarded",
+    "via",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-real-ip",
+    "client-ip",
+    "true-client-ip",
+    "x-client-ip",
+    "from",
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PrivacyPolicy {
+    /// Forward the browser's headers as they are
+    Off,
+    /// Drop forwarding addresses, send a common User-Agent and
+    /// Accept-Language, and cut Referer down to its origin
+    #[default]
+    Standard,
+    /// Standard, without Referer or client hints at all
+    Strict,
+}
+
+impl PrivacyPolicy {
+    /// Scrub the headers of a request about to leave through an exit
+    pub fn apply(self, headers: &mut Headers) {
+        if self == PrivacyPolicy::Off {
+            return;
+        }
+        headers.0.retain(|(name, _)| {
+            let name = name.to_ascii_lowercase();
+            let client_hint = self == PrivacyPolicy::Strict && name.starts_with("sec-ch-");
+            !FORWARDING_FIELDS.contains(&name.as_str()) && !client_hint
+        });
+        for (name, value) in headers.0.iter_mut() {
+            if name.eq_ignore_ascii_case("user-agent") {
+                *value = COMMON_USER_AGENT.to_string();
+            } else if name.eq_ignore_ascii_case("accept-language") {
+                *value = COMMON_ACCEPT_LANGUAGE.to_string();
+            }
+        }
+
+        match self {
+            PrivacyPolicy::Strict => headers.remove("referer"),
+            _ => {
+                let origin = headers.get("referer").and_then(referer_origin);
+                headers.remove("referer");
+                if let Some(origin) = origin {
+                    headers.0.push(("Referer".to_string(), origin));
+                }
+            }
+        }
+    }
+}
+
+/// The scheme and authority of a Referer, without the path that says which
+/// page the user was on
+fn referer_origin(referer: &str) -> Option<String> {
+    let (scheme, rest) = referer.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return None;
+    }
+    let authority = rest.split(['/', '?', '#']).next()?;
+    // Credentials never leave, even in a full Referer
+    let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    if host.is_empty() {
+        return None;
+    }
+    Some(format!("{}://{}/", scheme.to_ascii_lowercase(), host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn browser_headers() -> Headers {
+        let fields = [
+            ("Host", "example.com"),
+            ("User-Agent", "Mozilla/5.0 (X11; Linux x86_64; rv:115.0) Gecko/20100101 Firefox/115.0"),
+            ("Accept-Language", "de-CH,de;q=0.8"),
+            ("Referer", "https://user:pw@news.example/article/42?id=7"),
+            ("X-Forwarded-For", "192.0.2.7"),
+            ("Via", "1.1 corp-proxy"),
+            ("Sec-CH-UA-Platform", "\"Linux\""),
+            ("Cookie", "session=1"),
+        ];
+        Headers(fields.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect())
+    }
+
+    #[test]
+    fn test_policies() {
+        let mut headers = browser_headers();
+        PrivacyPolicy::Off.apply(&mut headers);
+        assert_eq!(headers, browser_headers());
+
+        let mut headers = browser_headers();
+        PrivacyPolicy::Standard.apply(&mut headers);
+        assert_eq!(headers.get("user-agent"), Some(COMMON_USER_AGENT));
+        assert_eq!(headers.get("accept-language"), Some(COMMON_ACCEPT_LANGUAGE));
+        assert_eq!(headers.get("referer"), Some("https://news.example/"));
+        assert_eq!(headers.get("x-forwarded-for"), None);
+        assert_eq!(headers.get("via"), None);
+        assert!(headers.get("sec-ch-ua-platform").is_some());
+        assert_eq!(headers.get("cookie"), Some("session=1"));
+
+        let mut headers = browser_headers();
+        PrivacyPolicy::Strict.apply(&mut headers);
+        assert_eq!(headers.get("referer"), None);
+        assert_eq!(headers.get("sec-ch-ua-platform"), None);
+        assert_eq!(headers.get("user-agent"), Some(COMMON_USER_AGENT));
+        assert_eq!(headers.get("host"), Some("example.com"));
+    }
+
+    #[test]
+    fn test_referer_origin() {
+        assert_eq!(referer_origin("HTTP://a.example:8080"), Some("http://a.example:8080/".to_string()));
+        assert_eq!(referer_origin("https://a.example?q=1"), Some("https://a.example/".to_string()));
+        assert_eq!(referer_origin("android-app://com.example/"), None);
+        assert_eq!(referer_origin("not a url"), None);
+    }
+}
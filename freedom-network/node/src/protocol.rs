@@ -1,21 +1,404 @@
 // Core Freedom Network Protocol
 // Handles DHT, routing, and .freedom domain resolution
 
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
 use sha3::{Sha3_256, Digest};
+use crate::exit::ExitPolicy;
+use crate::onion::CircuitHop;
+use crate::path::DEFAULT_BANDWIDTH;
+use crate::identity::{Identity, PublicIdentity, Signature};
 
+/// A node's identity on the network: the hash of its public key. The DHT
+/// routes on it and circuits are built through the nodes it names.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct NodeId(pub [u8; 32]);
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl NodeId {
+    /// Parse the hex form `Display` writes
+    pub fn from_hex(hex: &str) -> Option<NodeId> {
+        let bytes = hex::decode(hex).ok()?;
+        <[u8; 32]>::try_from(bytes).ok().map(NodeId)
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+/// Kademlia XOR distance between two node IDs. Stored big-endian, so the
+/// derived ordering is the ordering of the 256-bit integers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Distance(pub [u8; 32]);
+
+impl Distance {
+    pub const ZERO: Distance = Distance([0u8; 32]);
+
+    pub fn between(a: &NodeId, b: &NodeId) -> Self {
+        let mut out = [0u8; 32];
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = a.0[i] ^ b.0[i];
+        }
+        Distance(out)
+    }
+
+    /// Number of leading zero bits, i.e. the length of the shared ID prefix
+    pub fn leading_zeros(&self) -> u32 {
+        let mut zeros = 0;
+        for byte in self.0.iter() {
+            if *byte != 0 {
+                return zeros + byte.leading_zeros();
+            }
+            zeros += 8;
+        }
+        zeros
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Self::ZERO
+    }
+}
+
+/// Number of peers held in each k-bucket (Kademlia's `k`)
+pub const K_BUCKET_SIZE: usize = 20;
+
+/// Number of spare peers remembered per bucket for when a live entry drops out
+pub const REPLACEMENT_CACHE_SIZE: usize = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct FreedomAddress {
     pub domain: String, // e.g., "example.freedom"
     pub node_id: NodeId,
     pub ed25519_pubkey: Vec<u8>,
 }
 
+/// How long a domain record stays valid unless its owner says otherwise
+pub const DEFAULT_RECORD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often owners re-sign and re-store their records, and holders purge
+/// expired ones. Well inside the TTL so a live owner's names never lapse.
+pub const REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const RECORD_SIGNING_CONTEXT: &[u8] = b"freedom-domain-record-v1";
+
+/// A .freedom registration signed by the domain owner's key. Only a newer
+/// record (higher sequence) signed by the same key may replace a live one.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DomainRecord {
+    pub address: FreedomAddress,
+    pub sequence: u64,
+    /// Unix time in seconds after which the record is no longer valid
+    pub expires_at: u64,
+    pub signature: Signature,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordError {
+    InvalidKey,
+    BadSignature,
+    Expired,
+    /// A live record for the domain is held by a different key
+    OwnedByOtherKey,
+    /// A self-authenticating name signed by a key other than its own
+    NameMismatch,
+    /// The record is not newer than the one already stored
+    Stale { current: u64 },
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::InvalidKey => write!(f, "record carries an invalid public key"),
+            RecordError::BadSignature => write!(f, "record signature does not verify"),
+            RecordError::Expired => write!(f, "record has expired"),
+            RecordError::OwnedByOtherKey => write!(f, "domain is owned by a different key"),
+            RecordError::NameMismatch => write!(f, "name was not derived from the signing key"),
+            RecordError::Stale { current } => write!(f, "record is not newer than sequence {}", current),
+        }
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl DomainRecord {
+    /// Sign a record for `domain`, served by `node_id`, valid for `ttl`
+    pub fn sign(identity: &Identity, domain: &str, node_id: NodeId, sequence: u64, ttl: Duration) -> Self {
+        let mut record = DomainRecord {
+            address: FreedomAddress {
+                domain: domain.to_string(),
+                node_id,
+                ed25519_pubkey: identity.public().to_vec(),
+            },
+            sequence,
+            expires_at: unix_now().saturating_add(ttl.as_secs()),
+            signature: Signature([0u8; 64]),
+        };
+        record.signature = identity.sign(&record.signing_bytes());
+        record
+    }
+
+    /// Canonical bytes covered by the signature
+    fn signing_bytes(&self) -> Vec<u8> {
+        let mut out = RECORD_SIGNING_CONTEXT.to_vec();
+        out.extend_from_slice(&(self.address.domain.len() as u32).to_be_bytes());
+        out.extend_from_slice(self.address.domain.as_bytes());
+        out.extend_from_slice(&self.address.node_id.0);
+        out.extend_from_slice(&(self.address.ed25519_pubkey.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.address.ed25519_pubkey);
+        out.extend_from_slice(&self.sequence.to_be_bytes());
+        out.extend_from_slice(&self.expires_at.to_be_bytes());
+        out
+    }
+
+    pub fn owner(&self) -> Result<PublicIdentity, RecordError> {
+        PublicIdentity::from_bytes(&self.address.ed25519_pubkey).map_err(|_| RecordError::InvalidKey)
+    }
+
+    pub fn is_expired_at(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+
+    /// Check the signature and expiry as of `now` (unix seconds). A record
+    /// for a self-authenticating name must also be signed by that name's key.
+    pub fn verify_at(&self, now: u64) -> Result<(), RecordError> {
+        let owner = self.owner()?;
+        if !owner.verify(&self.signing_bytes(), &self.signature) {
+            return Err(RecordError::BadSignature);
+        }
+        if let Ok(named) = PublicIdentity::from_domain(&self.address.domain) {
+            if named != owner {
+                return Err(RecordError::NameMismatch);
+            }
+        }
+        if self.is_expired_at(now) {
+            return Err(RecordError::Expired);
+        }
+        Ok(())
+    }
+
+    pub fn verify(&self) -> Result<(), RecordError> {
+        self.verify_at(unix_now())
+    }
+}
+
+const DESCRIPTOR_SIGNING_CONTEXT: &[u8] = b"freedom-node-descriptor-v1";
+
+/// How long a node descriptor is valid; nodes re-sign theirs every
+/// REPUBLISH_INTERVAL
+pub const DESCRIPTOR_TTL: Duration = Duration::from_secs(3 * 60 * 60);
+
+/// What a node offers to others
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Capabilities {
+    /// Carries circuits for other nodes
+    pub relay: bool,
+    /// Set for exits: where it will open streams out of the network
+    pub exit_policy: Option<ExitPolicy>,
+}
+
+/// What a node tells the network about itself, signed by its identity key.
+/// The DHT serves it, path selection weighs relays by it, and circuits are
+/// built to the key in it.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct NodeDescriptor {
+    pub identity: PublicIdentity,
+    /// X25519 key circuit handshakes with the node are made to; it is
+    /// replaced every ONION_KEY_LIFETIME
+    pub onion_key: [u8; 32],
+    /// Where the node listens for peers (host:port)
+    pub addresses: Vec<String>,
+    pub capabilities: Capabilities,
+    /// Other nodes run by the same operator, kept off circuits together
+    pub family: Vec<NodeId>,
+    /// Bytes per second the node offers to carry
+    pub bandwidth: u64,
+    /// Unix time in seconds; a later descriptor replaces an earlier one
+    pub published_at: u64,
+    pub expires_at: u64,
+    pub signature: Signature,
+}
+
+impl NodeDescriptor {
+    /// An unsigned descriptor; fill it in, then `sign` it
+    pub fn new(identity: PublicIdentity, onion_key: [u8; 32], addresses: Vec<String>, capabilities: Capabilities) -> Self {
+        NodeDescriptor {
+            identity,
+            onion_key,
+            addresses,
+            capabilities,
+            family: Vec::new(),
+            bandwidth: DEFAULT_BANDWIDTH,
+            published_at: 0,
+            expires_at: 0,
+            signature: Signature([0u8; 64]),
+        }
+    }
+
+    pub fn with_family(mut self, family: Vec<NodeId>) -> Self {
+        self.family = family;
+        self
+    }
+
+    pub fn with_bandwidth(mut self, bandwidth: u64) -> Self {
+        self.bandwidth = bandwidth;
+        self
+    }
+
+    /// Publish as of now, valid for `ttl`
+    pub fn sign(mut self, identity: &Identity, ttl: Duration) -> Self {
+        assert_eq!(self.identity, identity.public(), "descriptor signed by another key");
+        self.published_at = unix_now();
+        self.expires_at = self.published_at.saturating_add(ttl.as_secs());
+        self.signature = identity.sign(&self.signing_bytes());
+        self
+    }
+
+    /// Canonical bytes covered by the signature; the policy is signed in its
+    /// rule syntax so the encoding doesn't depend on serde
+    fn signing_bytes(&self) -> Vec<u8> {
+        let mut out = DESCRIPTOR_SIGNING_CONTEXT.to_vec();
+        out.extend_from_slice(self.identity.as_bytes());
+        out.extend_from_slice(&self.onion_key);
+        out.extend_from_slice(&(self.addresses.len() as u32).to_be_bytes());
+        for address in &self.addresses {
+            out.extend_from_slice(&(address.len() as u32).to_be_bytes());
+            out.extend_from_slice(address.as_bytes());
+        }
+        out.push(self.capabilities.relay as u8);
+        match &self.capabilities.exit_policy {
+            None => out.push(0),
+            Some(policy) => {
+                out.push(1);
+                out.extend_from_slice(&(policy.rules().len() as u32).to_be_bytes());
+                for rule in policy.rules() {
+                    let rule = rule.to_string();
+                    out.extend_from_slice(&(rule.len() as u32).to_be_bytes());
+                    out.extend_from_slice(rule.as_bytes());
+                }
+            }
+        }
+        out.extend_from_slice(&(self.family.len() as u32).to_be_bytes());
+        for relative in &self.family {
+            out.extend_from_slice(&relative.0);
+        }
+        out.extend_from_slice(&self.bandwidth.to_be_bytes());
+        out.extend_from_slice(&self.published_at.to_be_bytes());
+        out.extend_from_slice(&self.expires_at.to_be_bytes());
+        out
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.identity.node_id()
+    }
+
+    pub fn is_expired_at(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+
+    pub fn verify_at(&self, now: u64) -> Result<(), RecordError> {
+        if !self.identity.verify(&self.signing_bytes(), &self.signature) {
+            return Err(RecordError::BadSignature);
+        }
+        if self.is_expired_at(now) {
+            return Err(RecordError::Expired);
+        }
+        Ok(())
+    }
+
+    pub fn verify(&self) -> Result<(), RecordError> {
+        self.verify_at(unix_now())
+    }
+}
+
+const SERVICE_SIGNING_CONTEXT: &[u8] = b"freedom-service-descriptor-v1";
+
+/// How to reach a hidden .freedom service without learning where it runs:
+/// the relays it keeps introduction circuits open to, signed by the service
+/// key its name is derived from
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ServiceDescriptor {
+    pub service: PublicIdentity,
+    /// X25519 key introductions are sealed to and the end-to-end handshake
+    /// is made with
+    pub onion_key: [u8; 32],
+    pub intro_points: Vec<CircuitHop>,
+    /// Unix time in seconds; a later descriptor replaces an earlier one
+    pub published_at: u64,
+    pub expires_at: u64,
+    pub signature: Signature,
+}
+
+impl ServiceDescriptor {
+    /// Publish `intro_points` and `onion_key` for the service `identity` as
+    /// of now, valid for `ttl`
+    pub fn sign(identity: &Identity, onion_key: [u8; 32], intro_points: Vec<CircuitHop>, ttl: Duration) -> Self {
+        let published_at = unix_now();
+        let mut descriptor = ServiceDescriptor {
+            service: identity.public(),
+            onion_key,
+            intro_points,
+            published_at,
+            expires_at: published_at.saturating_add(ttl.as_secs()),
+            signature: Signature([0u8; 64]),
+        };
+        descriptor.signature = identity.sign(&descriptor.signing_bytes());
+        descriptor
+    }
+
+    fn signing_bytes(&self) -> Vec<u8> {
+        let mut out = SERVICE_SIGNING_CONTEXT.to_vec();
+        out.extend_from_slice(self.service.as_bytes());
+        out.extend_from_slice(&self.onion_key);
+        out.extend_from_slice(&(self.intro_points.len() as u32).to_be_bytes());
+        for intro in &self.intro_points {
+            out.extend_from_slice(intro.identity.as_bytes());
+            out.extend_from_slice(&intro.onion_key);
+            out.extend_from_slice(&(intro.addr.len() as u32).to_be_bytes());
+            out.extend_from_slice(intro.addr.as_bytes());
+        }
+        out.extend_from_slice(&self.published_at.to_be_bytes());
+        out.extend_from_slice(&self.expires_at.to_be_bytes());
+        out
+    }
+
+    /// The self-authenticating name the service is reached by
+    pub fn domain(&self) -> String {
+        self.service.to_domain()
+    }
+
+    pub fn is_expired_at(&self, now: u64) -> bool {
+        now >= self.expires_at
+    }
+
+    pub fn verify_at(&self, now: u64) -> Result<(), RecordError> {
+        if !self.service.verify(&self.signing_bytes(), &self.signature) {
+            return Err(RecordError::BadSignature);
+        }
+        if self.is_expired_at(now) {
+            return Err(RecordError::Expired);
+        }
+        Ok(())
+    }
+
+    pub fn verify(&self) -> Result<(), RecordError> {
+        self.verify_at(unix_now())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ContentMetadata {
     pub hash: Vec<u8>,
@@ -30,11 +413,9 @@ pub enum DHTMessage {
         target: NodeId,
         requesting_node: NodeId,
     },
-    // Store a .freedom domain -> NodeId mapping
+    // Store a signed .freedom domain -> NodeId mapping
     StoreFreedomDomain {
-        domain: String,
-        owner_node: NodeId,
-        pubkey: Vec<u8>,
+        record: DomainRecord,
     },
     // Look up who owns a .freedom domain
     FindFreedomDomain {
@@ -44,30 +425,95 @@ pub enum DHTMessage {
     PeersFound {
         peers: Vec<PeerInfo>,
     },
-    // Response with domain owner
+    // Response with the signed domain record
     DomainOwner {
         domain: String,
-        owner: Option<FreedomAddress>,
+        owner: Option<DomainRecord>,
+    },
+    // Response to a store: whether the record was kept
+    Stored {
+        domain: String,
+        accepted: bool,
+    },
+    // Ask for a node's signed descriptor
+    FindDescriptor {
+        node_id: NodeId,
+    },
+    // Response with the descriptor, if the node holds it
+    Descriptor {
+        descriptor: Option<NodeDescriptor>,
+    },
+    // Store a hidden service's signed introduction points
+    StoreService {
+        descriptor: ServiceDescriptor,
+    },
+    // Look up a hidden service's introduction points by its domain
+    FindService {
+        domain: String,
+    },
+    // Response with the service descriptor
+    Service {
+        domain: String,
+        descriptor: Option<ServiceDescriptor>,
+    },
+    // Liveness check before evicting a peer from a full bucket
+    Ping {
+        sender: NodeId,
+    },
+    // Reply to a liveness check
+    Pong {
+        sender: NodeId,
     },
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct PeerInfo {
     pub node_id: NodeId,
     pub addr: String, // "127.0.0.1:5000"
 }
 
+impl PeerInfo {
+    /// The address to dial this peer on, pinned to its ID
+    pub fn pinned_addr(&self) -> String {
+        pin_addr(&self.node_id, &self.addr)
+    }
+}
+
+/// Pin `addr` to a node as `node_id@addr`. The transport only talks to a
+/// node there that proves it holds the key behind `node_id`.
+pub fn pin_addr(node_id: &NodeId, addr: &str) -> String {
+    format!("{}@{}", node_id, addr)
+}
+
+/// Split an address into the node it is pinned to, if any, and the plain
+/// `host:port` part
+pub fn unpin_addr(addr: &str) -> Result<(Option<NodeId>, &str), String> {
+    match addr.split_once('@') {
+        Some((id, plain)) => {
+            let id = NodeId::from_hex(id).ok_or_else(|| format!("Bad node ID in address {}", addr))?;
+            Ok((Some(id), plain))
+        }
+        None => Ok((None, addr)),
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum RoutingMessage {
-    // Build a multi-hop circuit
+    // Open a circuit to the receiving node: the client's handshake onion skin
     BuildCircuit {
-        hops: Vec<NodeId>,
         circuit_id: u32,
+        handshake: Vec<u8>,
+    },
+    // The relay's half of the handshake
+    CircuitCreated {
+        circuit_id: u32,
+        handshake: Vec<u8>,
     },
-    // Route data through circuit
+    // Route onion-layered cells through the circuit; the reply is the cells
+    // travelling back. Every cell on a link has the same size.
     RelayData {
         circuit_id: u32,
-        data: Vec<u8>,
+        cells: Vec<Vec<u8>>,
     },
     // Tear down circuit
     DestroyCircuit {
@@ -92,72 +538,312 @@ pub enum ContentMessage {
     NotFound,
 }
 
+/// Result of offering a peer to the routing table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// Peer was new and the bucket had room
+    Inserted,
+    /// Peer was already known and moved to the most-recently-seen end
+    Refreshed,
+    /// Bucket is full; the candidate went to the replacement cache and the
+    /// least-recently-seen entry must answer a ping to keep its slot
+    PingRequired { oldest: PeerInfo },
+    /// Peer is this node itself
+    Ignored,
+}
+
+/// A single k-bucket: live entries ordered least-recently-seen first,
+/// plus a bounded cache of candidates waiting for a free slot
+#[derive(Debug, Clone, Default)]
+pub struct KBucket {
+    entries: VecDeque<PeerInfo>,
+    replacements: VecDeque<PeerInfo>,
+}
+
+impl KBucket {
+    pub fn entries(&self) -> impl Iterator<Item = &PeerInfo> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.entries.len() >= K_BUCKET_SIZE
+    }
+
+    fn position(&self, node_id: &NodeId) -> Option<usize> {
+        self.entries.iter().position(|p| &p.node_id == node_id)
+    }
+
+    fn insert(&mut self, peer: PeerInfo) -> InsertOutcome {
+        if let Some(pos) = self.position(&peer.node_id) {
+            // Known peer: move to the tail, taking the freshest address
+            self.entries.remove(pos);
+            self.entries.push_back(peer);
+            return InsertOutcome::Refreshed;
+        }
+
+        if !self.is_full() {
+            self.entries.push_back(peer);
+            return InsertOutcome::Inserted;
+        }
+
+        self.add_replacement(peer);
+        InsertOutcome::PingRequired {
+            oldest: self.entries.front().cloned().expect("full bucket has entries"),
+        }
+    }
+
+    fn add_replacement(&mut self, peer: PeerInfo) {
+        self.replacements.retain(|p| p.node_id != peer.node_id);
+        if self.replacements.len() >= REPLACEMENT_CACHE_SIZE {
+            self.replacements.pop_front();
+        }
+        self.replacements.push_back(peer);
+    }
+
+    fn mark_alive(&mut self, node_id: &NodeId) {
+        if let Some(pos) = self.position(node_id) {
+            if let Some(peer) = self.entries.remove(pos) {
+                self.entries.push_back(peer);
+            }
+        }
+    }
+
+    /// Drop an entry and promote the most recently seen replacement
+    fn evict(&mut self, node_id: &NodeId) -> Option<PeerInfo> {
+        let removed = self.entries.remove(self.position(node_id)?)?;
+        if let Some(replacement) = self.replacements.pop_back() {
+            self.entries.push_back(replacement);
+        }
+        Some(removed)
+    }
+}
+
 // Kademlia-like DHT implementation
 pub struct DHT {
-    kbuckets: Arc<RwLock<Vec<Vec<PeerInfo>>>>,
-    domain_registry: Arc<RwLock<HashMap<String, FreedomAddress>>>,
-    content_store: Arc<RwLock<HashMap<String, Vec<u8>>>>,
+    local_id: NodeId,
+    kbuckets: Arc<RwLock<Vec<KBucket>>>,
+    domain_registry: Arc<RwLock<HashMap<String, DomainRecord>>>,
+    descriptors: Arc<RwLock<HashMap<NodeId, NodeDescriptor>>>,
+    services: Arc<RwLock<HashMap<String, ServiceDescriptor>>>,
 }
 
 impl DHT {
-    pub fn new() -> Self {
+    pub fn new(local_id: NodeId) -> Self {
         Self {
-            kbuckets: Arc::new(RwLock::new(vec![vec![]; 256])),
+            local_id,
+            kbuckets: Arc::new(RwLock::new(vec![KBucket::default(); 256])),
             domain_registry: Arc::new(RwLock::new(HashMap::new())),
-            content_store: Arc::new(RwLock::new(HashMap::new())),
+            descriptors: Arc::new(RwLock::new(HashMap::new())),
+            services: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
+    pub fn local_id(&self) -> &NodeId {
+        &self.local_id
+    }
+
     /// Calculate XOR distance between two node IDs
-    pub fn xor_distance(a: &NodeId, b: &NodeId) -> u64 {
-        let mut distance = 0u64;
-        for i in 0..8 {
-            distance ^= u64::from_le_bytes([
-                a.0[i],
-                a.0[i + 1],
-                a.0[i + 2],
-                a.0[i + 3],
-                a.0[i + 4],
-                a.0[i + 5],
-                a.0[i + 6],
-                a.0[i + 7],
-            ]) ^ u64::from_le_bytes([
-                b.0[i],
-                b.0[i + 1],
-                b.0[i + 2],
-                b.0[i + 3],
-                b.0[i + 4],
-                b.0[i + 5],
-                b.0[i + 6],
-                b.0[i + 7],
-            ]);
-        }
-        distance
-    }
-
-    /// Register a .freedom domain
-    pub fn register_domain(&self, address: FreedomAddress) -> bool {
+    pub fn xor_distance(a: &NodeId, b: &NodeId) -> Distance {
+        Distance::between(a, b)
+    }
+
+    /// Register a .freedom domain. The record must verify, and may only
+    /// replace a live record if it is newer and signed by the same key.
+    pub fn register_domain(&self, record: DomainRecord) -> Result<(), RecordError> {
+        let now = unix_now();
+        record.verify_at(now)?;
+
+        let mut registry = self.domain_registry.write().unwrap();
+        if let Some(current) = registry.get(&record.address.domain) {
+            if !current.is_expired_at(now) {
+                if current.address.ed25519_pubkey != record.address.ed25519_pubkey {
+                    return Err(RecordError::OwnedByOtherKey);
+                }
+                if record.sequence <= current.sequence {
+                    return Err(RecordError::Stale { current: current.sequence });
+                }
+            }
+        }
+        registry.insert(record.address.domain.clone(), record);
+        Ok(())
+    }
+
+    /// Look up a .freedom domain's record. Expired records are dropped.
+    pub fn lookup_domain(&self, domain: &str) -> Option<DomainRecord> {
+        let now = unix_now();
+        {
+            let registry = self.domain_registry.read().unwrap();
+            match registry.get(domain) {
+                Some(record) if record.verify_at(now).is_ok() => return Some(record.clone()),
+                Some(_) => {}
+                None => return None,
+            }
+        }
+        // A newer record may have been registered since the read lock was dropped
         let mut registry = self.domain_registry.write().unwrap();
-        registry.insert(address.domain.clone(), address);
-        true
+        match registry.entry(domain.to_string()) {
+            Entry::Occupied(entry) if entry.get().verify_at(now).is_ok() => Some(entry.get().clone()),
+            Entry::Occupied(entry) => {
+                entry.remove();
+                None
+            }
+            Entry::Vacant(_) => None,
+        }
+    }
+
+    /// Hold a node descriptor for others to fetch. It must verify and be
+    /// newer than the one already held for that node.
+    pub fn register_descriptor(&self, descriptor: NodeDescriptor) -> Result<(), RecordError> {
+        descriptor.verify()?;
+        let mut descriptors = self.descriptors.write().unwrap();
+        let node_id = descriptor.node_id();
+        if let Some(current) = descriptors.get(&node_id) {
+            if descriptor.published_at <= current.published_at {
+                return Err(RecordError::Stale { current: current.published_at });
+            }
+        }
+        descriptors.insert(node_id, descriptor);
+        Ok(())
+    }
+
+    /// A node's descriptor, unless it has expired
+    pub fn lookup_descriptor(&self, node_id: &NodeId) -> Option<NodeDescriptor> {
+        let descriptors = self.descriptors.read().unwrap();
+        descriptors
+            .get(node_id)
+            .filter(|descriptor| !descriptor.is_expired_at(unix_now()))
+            .cloned()
+    }
+
+    /// Hold a hidden service's descriptor under its domain. It must verify
+    /// and be newer than the one already held.
+    pub fn register_service(&self, descriptor: ServiceDescriptor) -> Result<(), RecordError> {
+        descriptor.verify()?;
+        let mut services = self.services.write().unwrap();
+        let domain = descriptor.domain();
+        if let Some(current) = services.get(&domain) {
+            if descriptor.published_at <= current.published_at {
+                return Err(RecordError::Stale { current: current.published_at });
+            }
+        }
+        services.insert(domain, descriptor);
+        Ok(())
+    }
+
+    /// A hidden service's descriptor, unless it has expired
+    pub fn lookup_service(&self, domain: &str) -> Option<ServiceDescriptor> {
+        let services = self.services.read().unwrap();
+        services
+            .get(domain)
+            .filter(|descriptor| !descriptor.is_expired_at(unix_now()))
+            .cloned()
+    }
+
+    /// Drop expired domain records and descriptors as of `now`;
+    /// returns how many went
+    pub fn expire_records(&self, now: u64) -> usize {
+        let mut removed = 0;
+
+        let mut registry = self.domain_registry.write().unwrap();
+        let before = registry.len();
+        registry.retain(|_, record| !record.is_expired_at(now));
+        removed += before - registry.len();
+        drop(registry);
+
+        let mut descriptors = self.descriptors.write().unwrap();
+        let before = descriptors.len();
+        descriptors.retain(|_, descriptor| !descriptor.is_expired_at(now));
+        removed += before - descriptors.len();
+        drop(descriptors);
+
+        let mut services = self.services.write().unwrap();
+        let before = services.len();
+        services.retain(|_, descriptor| !descriptor.is_expired_at(now));
+        removed += before - services.len();
+
+        removed
+    }
+
+    /// Number of domain records held, expired or not
+    pub fn record_count(&self) -> usize {
+        self.domain_registry.read().unwrap().len()
+    }
+
+    /// Bucket a peer belongs in: 255 minus the length of the shared ID prefix,
+    /// so bucket 255 covers the far half of the keyspace. `None` for ourselves.
+    pub fn bucket_index(&self, node_id: &NodeId) -> Option<usize> {
+        let distance = Self::xor_distance(&self.local_id, node_id);
+        if distance.is_zero() {
+            return None;
+        }
+        Some(255 - distance.leading_zeros() as usize)
+    }
+
+    /// Offer a peer to the routing table. Full buckets are never evicted here;
+    /// the caller gets `PingRequired` and reports back via `record_ping_result`.
+    pub fn insert_peer(&self, peer: PeerInfo) -> InsertOutcome {
+        let index = match self.bucket_index(&peer.node_id) {
+            Some(index) => index,
+            None => return InsertOutcome::Ignored,
+        };
+        let mut kbuckets = self.kbuckets.write().unwrap();
+        kbuckets[index].insert(peer)
+    }
+
+    /// Offer a peer and, if its bucket is full, ping the least-recently-seen
+    /// entry. A live entry keeps its slot (Kademlia favours long-lived peers);
+    /// a dead one is evicted in favour of the newest replacement.
+    pub async fn add_peer<F, Fut>(&self, peer: PeerInfo, ping: F) -> InsertOutcome
+    where
+        F: FnOnce(PeerInfo) -> Fut,
+        Fut: Future<Output = bool>,
+    {
+        let outcome = self.insert_peer(peer);
+        if let InsertOutcome::PingRequired { oldest } = &outcome {
+            let alive = ping(oldest.clone()).await;
+            self.record_ping_result(&oldest.node_id, alive);
+        }
+        outcome
+    }
+
+    /// Apply the result of a liveness ping to the routing table
+    pub fn record_ping_result(&self, node_id: &NodeId, alive: bool) {
+        if alive {
+            if let Some(index) = self.bucket_index(node_id) {
+                let mut kbuckets = self.kbuckets.write().unwrap();
+                kbuckets[index].mark_alive(node_id);
+            }
+        } else {
+            self.remove_peer(node_id);
+        }
     }
 
-    /// Look up a .freedom domain owner
-    pub fn lookup_domain(&self, domain: &str) -> Option<FreedomAddress> {
-        let registry = self.domain_registry.read().unwrap();
-        registry.get(domain).cloned()
+    /// Remove an unresponsive peer, promoting a replacement if one is cached
+    pub fn remove_peer(&self, node_id: &NodeId) -> Option<PeerInfo> {
+        let index = self.bucket_index(node_id)?;
+        let mut kbuckets = self.kbuckets.write().unwrap();
+        kbuckets[index].evict(node_id)
     }
 
-    /// Store content (file) keyed by domain
-    pub fn store_content(&self, domain: String, content: Vec<u8>) {
-        let mut store = self.content_store.write().unwrap();
-        store.insert(domain, content);
+    /// Whether the routing table holds this peer at this address
+    pub fn contains_peer(&self, peer: &PeerInfo) -> bool {
+        let index = match self.bucket_index(&peer.node_id) {
+            Some(index) => index,
+            None => return false,
+        };
+        let kbuckets = self.kbuckets.read().unwrap();
+        let known = kbuckets[index].entries().any(|known| known == peer);
+        known
     }
 
-    /// Retrieve content by domain
-    pub fn get_content(&self, domain: &str) -> Option<Vec<u8>> {
-        let store = self.content_store.read().unwrap();
-        store.get(domain).cloned()
+    /// Total number of live peers in the routing table
+    pub fn peer_count(&self) -> usize {
+        let kbuckets = self.kbuckets.read().unwrap();
+        kbuckets.iter().map(KBucket::len).sum()
     }
 
     /// Find peers closest to a target NodeId
@@ -166,12 +852,75 @@ impl DHT {
         let mut all_peers = Vec::new();
 
         for bucket in kbuckets.iter() {
-            all_peers.extend(bucket.clone());
+            all_peers.extend(bucket.entries().cloned());
         }
 
         all_peers.sort_by_key(|peer| Self::xor_distance(&peer.node_id, target));
         all_peers.into_iter().take(k).collect()
     }
+
+    /// Answer a DHT request from a peer. Returns `None` for messages that
+    /// carry no reply (stores) or are themselves replies.
+    pub fn handle_message(&self, message: DHTMessage) -> Option<DHTMessage> {
+        match message {
+            DHTMessage::FindNode { target, requesting_node } => {
+                let peers = self
+                    .find_closest_peers(&target, K_BUCKET_SIZE + 1)
+                    .into_iter()
+                    .filter(|p| p.node_id != requesting_node)
+                    .take(K_BUCKET_SIZE)
+                    .collect();
+                Some(DHTMessage::PeersFound { peers })
+            }
+            DHTMessage::FindFreedomDomain { domain } => match self.lookup_domain(&domain) {
+                Some(owner) => Some(DHTMessage::DomainOwner { domain, owner: Some(owner) }),
+                None => Some(DHTMessage::PeersFound {
+                    peers: self.find_closest_peers(&domain_key(&domain), K_BUCKET_SIZE),
+                }),
+            },
+            DHTMessage::StoreFreedomDomain { record } => {
+                let domain = record.address.domain.clone();
+                let accepted = match self.register_domain(record) {
+                    Ok(()) => true,
+                    // Already holding this record; a normal outcome of replication
+                    Err(RecordError::Stale { .. }) => false,
+                    Err(e) => {
+                        eprintln!("⚠️  Rejected record for {}: {}", domain, e);
+                        false
+                    }
+                };
+                Some(DHTMessage::Stored { domain, accepted })
+            }
+            DHTMessage::FindDescriptor { node_id } => Some(DHTMessage::Descriptor {
+                descriptor: self.lookup_descriptor(&node_id),
+            }),
+            DHTMessage::StoreService { descriptor } => {
+                let domain = descriptor.domain();
+                let accepted = match self.register_service(descriptor) {
+                    Ok(()) => true,
+                    Err(RecordError::Stale { .. }) => false,
+                    Err(e) => {
+                        eprintln!("⚠️  Rejected service descriptor for {}: {}", domain, e);
+                        false
+                    }
+                };
+                Some(DHTMessage::Stored { domain, accepted })
+            }
+            DHTMessage::FindService { domain } => match self.lookup_service(&domain) {
+                Some(descriptor) => Some(DHTMessage::Service { domain, descriptor: Some(descriptor) }),
+                None => Some(DHTMessage::PeersFound {
+                    peers: self.find_closest_peers(&domain_key(&domain), K_BUCKET_SIZE),
+                }),
+            },
+            DHTMessage::Ping { .. } => Some(DHTMessage::Pong { sender: self.local_id.clone() }),
+            DHTMessage::PeersFound { .. }
+            | DHTMessage::DomainOwner { .. }
+            | DHTMessage::Stored { .. }
+            | DHTMessage::Descriptor { .. }
+            | DHTMessage::Service { .. }
+            | DHTMessage::Pong { .. } => None,
+        }
+    }
 }
 
 /// Generate a NodeId from a public key
@@ -184,24 +933,217 @@ pub fn generate_node_id(pubkey: &[u8]) -> NodeId {
     NodeId(id)
 }
 
+/// DHT key under which a .freedom domain is stored
+pub fn domain_key(domain: &str) -> NodeId {
+    generate_node_id(domain.as_bytes())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::identity::generate_identity;
+    use proptest::prelude::*;
+
+    /// Snapshot of one of `dht`'s buckets
+    fn bucket(dht: &DHT, index: usize) -> KBucket {
+        dht.kbuckets.read().unwrap()[index].clone()
+    }
+
+    fn peer(first_byte: u8, last_byte: u8) -> PeerInfo {
+        let mut id = [0u8; 32];
+        id[0] = first_byte;
+        id[31] = last_byte;
+        PeerInfo {
+            node_id: NodeId(id),
+            addr: format!("127.0.0.1:{}", 5000 + last_byte as u16),
+        }
+    }
+
+    fn record(identity: &Identity, domain: &str, sequence: u64) -> DomainRecord {
+        DomainRecord::sign(identity, domain, identity.node_id(), sequence, DEFAULT_RECORD_TTL)
+    }
 
     #[test]
     fn test_domain_registration() {
-        let dht = DHT::new();
-        let node_id = NodeId([0u8; 32]);
-        let pubkey = vec![1, 2, 3];
+        let dht = DHT::new(NodeId([0u8; 32]));
+        let owner = generate_identity();
+        let rec = record(&owner, "example.freedom", 1);
 
-        let addr = FreedomAddress {
-            domain: "example.freedom".to_string(),
-            node_id: node_id.clone(),
-            ed25519_pubkey: pubkey,
-        };
+        assert!(dht.register_domain(rec.clone()).is_ok());
+        assert_eq!(dht.lookup_domain("example.freedom"), Some(rec));
+    }
+
+    #[test]
+    fn test_record_signature_covers_fields() {
+        let owner = generate_identity();
+        let rec = record(&owner, "example.freedom", 1);
+        assert!(rec.verify().is_ok());
+
+        let mut hijacked = rec.clone();
+        hijacked.address.node_id = NodeId([9u8; 32]);
+        assert_eq!(hijacked.verify(), Err(RecordError::BadSignature));
+
+        let mut extended = rec.clone();
+        extended.expires_at += 1;
+        assert_eq!(extended.verify(), Err(RecordError::BadSignature));
+
+        let mut rekeyed = rec.clone();
+        rekeyed.address.ed25519_pubkey = generate_identity().public().to_vec();
+        assert_eq!(rekeyed.verify(), Err(RecordError::BadSignature));
+
+        let mut garbage = rec;
+        garbage.address.ed25519_pubkey = vec![1, 2, 3];
+        assert_eq!(garbage.verify(), Err(RecordError::InvalidKey));
+    }
+
+    #[test]
+    fn test_self_authenticating_names() {
+        let owner = generate_identity();
+        let domain = owner.public().to_domain();
+        assert!(record(&owner, &domain, 1).verify().is_ok());
+
+        // Anyone can sign a record, but only the named key can sign for its name
+        let squatter = generate_identity();
+        let dht = DHT::new(NodeId([0u8; 32]));
+        assert_eq!(dht.register_domain(record(&squatter, &domain, 1)), Err(RecordError::NameMismatch));
+    }
+
+    #[test]
+    fn test_expire_records() {
+        let dht = DHT::new(NodeId([0u8; 32]));
+        let owner = generate_identity();
+        let rec = record(&owner, "example.freedom", 1);
+        dht.register_domain(rec.clone()).unwrap();
+        assert_eq!(dht.expire_records(unix_now()), 0);
+        assert_eq!(dht.expire_records(rec.expires_at), 1);
+        assert_eq!(dht.record_count(), 0);
+    }
+
+    #[test]
+    fn test_domain_cannot_be_hijacked() {
+        let dht = DHT::new(NodeId([0u8; 32]));
+        let owner = generate_identity();
+        let attacker = generate_identity();
+        dht.register_domain(record(&owner, "example.freedom", 5)).unwrap();
+
+        assert_eq!(
+            dht.register_domain(record(&attacker, "example.freedom", 100)),
+            Err(RecordError::OwnedByOtherKey)
+        );
+        assert_eq!(
+            dht.register_domain(record(&owner, "example.freedom", 5)),
+            Err(RecordError::Stale { current: 5 })
+        );
+
+        let newer = record(&owner, "example.freedom", 6);
+        dht.register_domain(newer.clone()).unwrap();
+        assert_eq!(dht.lookup_domain("example.freedom"), Some(newer));
+    }
 
-        assert!(dht.register_domain(addr.clone()));
-        assert_eq!(dht.lookup_domain("example.freedom"), Some(addr));
+    #[test]
+    fn test_expired_records() {
+        let dht = DHT::new(NodeId([0u8; 32]));
+        let owner = generate_identity();
+        let expired = DomainRecord::sign(&owner, "old.freedom", owner.node_id(), 1, Duration::ZERO);
+        assert_eq!(expired.verify(), Err(RecordError::Expired));
+        assert_eq!(dht.register_domain(expired), Err(RecordError::Expired));
+
+        // Once the owner's record lapses, the name is free to claim again
+        let rec = record(&owner, "old.freedom", 1);
+        assert!(rec.verify_at(rec.expires_at).is_err());
+        dht.register_domain(rec.clone()).unwrap();
+        {
+            let mut registry = dht.domain_registry.write().unwrap();
+            registry.get_mut("old.freedom").unwrap().expires_at = 0;
+        }
+        let newcomer = generate_identity();
+        assert!(dht.register_domain(record(&newcomer, "old.freedom", 1)).is_ok());
+
+        {
+            let mut registry = dht.domain_registry.write().unwrap();
+            registry.get_mut("old.freedom").unwrap().expires_at = 0;
+        }
+        assert_eq!(dht.lookup_domain("old.freedom"), None);
+    }
+
+    #[test]
+    fn test_node_descriptors() {
+        let relay = generate_identity();
+        let capabilities = Capabilities { relay: true, exit_policy: Some(ExitPolicy::parse(&["accept *:443", "reject *:*"]).unwrap()) };
+        let descriptor = NodeDescriptor::new(relay.public(), [5u8; 32], vec!["192.0.2.1:5000".to_string()], capabilities)
+            .with_family(vec![NodeId([3u8; 32])])
+            .with_bandwidth(1 << 20)
+            .sign(&relay, DESCRIPTOR_TTL);
+        assert!(descriptor.verify().is_ok());
+        assert_eq!(descriptor.node_id(), relay.node_id());
+
+        // Every field is covered by the signature
+        let mut widened = descriptor.clone();
+        widened.capabilities.exit_policy = Some(ExitPolicy::parse(&["accept *:*"]).unwrap());
+        assert_eq!(widened.verify(), Err(RecordError::BadSignature));
+        let mut dropped = descriptor.clone();
+        dropped.capabilities.exit_policy = None;
+        assert_eq!(dropped.verify(), Err(RecordError::BadSignature));
+        let mut moved = descriptor.clone();
+        moved.addresses = vec!["198.51.100.1:5000".to_string()];
+        assert_eq!(moved.verify(), Err(RecordError::BadSignature));
+        let mut retired = descriptor.clone();
+        retired.capabilities.relay = false;
+        assert_eq!(retired.verify(), Err(RecordError::BadSignature));
+        let mut boasting = descriptor.clone();
+        boasting.bandwidth *= 100;
+        assert_eq!(boasting.verify(), Err(RecordError::BadSignature));
+        let mut disowned = descriptor.clone();
+        disowned.family.clear();
+        assert_eq!(disowned.verify(), Err(RecordError::BadSignature));
+        let mut rekeyed = descriptor.clone();
+        rekeyed.onion_key = [6u8; 32];
+        assert_eq!(rekeyed.verify(), Err(RecordError::BadSignature));
+
+        let dht = DHT::new(NodeId([0u8; 32]));
+        assert_eq!(dht.register_descriptor(widened), Err(RecordError::BadSignature));
+        dht.register_descriptor(descriptor.clone()).unwrap();
+        assert!(matches!(dht.register_descriptor(descriptor.clone()), Err(RecordError::Stale { .. })));
+
+        let reply = dht.handle_message(DHTMessage::FindDescriptor { node_id: relay.node_id() });
+        assert!(matches!(reply, Some(DHTMessage::Descriptor { descriptor: Some(d) }) if d == descriptor));
+        let reply = dht.handle_message(DHTMessage::FindDescriptor { node_id: NodeId([7u8; 32]) });
+        assert!(matches!(reply, Some(DHTMessage::Descriptor { descriptor: None })));
+
+        assert_eq!(dht.expire_records(descriptor.expires_at), 1);
+        assert_eq!(dht.lookup_descriptor(&relay.node_id()), None);
+    }
+
+    #[test]
+    fn test_service_descriptors() {
+        let service = generate_identity();
+        let intro = CircuitHop { addr: "192.0.2.1:5000".to_string(), identity: generate_identity().public(), onion_key: [1u8; 32] };
+        let descriptor = ServiceDescriptor::sign(&service, [2u8; 32], vec![intro], DESCRIPTOR_TTL);
+        assert!(descriptor.verify().is_ok());
+        assert_eq!(descriptor.domain(), service.public().to_domain());
+
+        // Intro points can't be swapped for relays an attacker controls
+        let mut hijacked = descriptor.clone();
+        hijacked.intro_points[0].addr = "198.51.100.1:5000".to_string();
+        assert_eq!(hijacked.verify(), Err(RecordError::BadSignature));
+        let mut impostor = descriptor.clone();
+        impostor.service = generate_identity().public();
+        assert_eq!(impostor.verify(), Err(RecordError::BadSignature));
+
+        let dht = DHT::new(NodeId([0u8; 32]));
+        assert_eq!(dht.register_service(hijacked), Err(RecordError::BadSignature));
+        let reply = dht.handle_message(DHTMessage::StoreService { descriptor: descriptor.clone() });
+        assert!(matches!(reply, Some(DHTMessage::Stored { accepted: true, .. })));
+        let reply = dht.handle_message(DHTMessage::StoreService { descriptor: descriptor.clone() });
+        assert!(matches!(reply, Some(DHTMessage::Stored { accepted: false, .. })));
+
+        let reply = dht.handle_message(DHTMessage::FindService { domain: descriptor.domain() });
+        assert!(matches!(reply, Some(DHTMessage::Service { descriptor: Some(d), .. }) if d == descriptor));
+        let reply = dht.handle_message(DHTMessage::FindService { domain: "missing.freedom".to_string() });
+        assert!(matches!(reply, Some(DHTMessage::PeersFound { .. })));
+
+        assert_eq!(dht.expire_records(descriptor.expires_at), 1);
+        assert_eq!(dht.lookup_service(&descriptor.domain()), None);
     }
 
     #[test]
@@ -209,6 +1151,200 @@ mod tests {
         let a = NodeId([0x01; 32]);
         let b = NodeId([0x02; 32]);
         let distance = DHT::xor_distance(&a, &b);
-        assert!(distance > 0);
+        assert_eq!(distance, Distance([0x03; 32]));
+        assert_eq!(distance.leading_zeros(), 6);
+        assert_eq!(DHT::xor_distance(&a, &a), Distance::ZERO);
+        assert_eq!(Distance::ZERO.leading_zeros(), 256);
+    }
+
+    #[test]
+    fn test_distance_uses_all_bytes() {
+        let origin = NodeId([0u8; 32]);
+        let near = peer(0x00, 0x01).node_id;
+        let far = peer(0x00, 0x02).node_id;
+        assert!(DHT::xor_distance(&origin, &near) < DHT::xor_distance(&origin, &far));
+        assert!(DHT::xor_distance(&origin, &far) < DHT::xor_distance(&origin, &peer(0x01, 0).node_id));
+    }
+
+    fn wide_add(a: &Distance, b: &Distance) -> [u8; 33] {
+        let mut out = [0u8; 33];
+        let mut carry = 0u16;
+        for i in (0..32).rev() {
+            let sum = a.0[i] as u16 + b.0[i] as u16 + carry;
+            out[i + 1] = sum as u8;
+            carry = sum >> 8;
+        }
+        out[0] = carry as u8;
+        out
+    }
+
+    fn node_id() -> impl Strategy<Value = NodeId> {
+        any::<[u8; 32]>().prop_map(NodeId)
+    }
+
+    proptest! {
+        #[test]
+        fn prop_distance_identity(a in node_id()) {
+            prop_assert!(DHT::xor_distance(&a, &a).is_zero());
+        }
+
+        #[test]
+        fn prop_distance_symmetric(a in node_id(), b in node_id()) {
+            prop_assert_eq!(DHT::xor_distance(&a, &b), DHT::xor_distance(&b, &a));
+        }
+
+        #[test]
+        fn prop_distance_triangle(a in node_id(), b in node_id(), c in node_id()) {
+            let ac = DHT::xor_distance(&a, &c);
+            let mut lhs = [0u8; 33];
+            lhs[1..].copy_from_slice(&ac.0);
+            let rhs = wide_add(&DHT::xor_distance(&a, &b), &DHT::xor_distance(&b, &c));
+            prop_assert!(lhs <= rhs);
+        }
+
+        #[test]
+        fn prop_distance_unidirectional(a in node_id(), b in node_id(), c in node_id()) {
+            // For a fixed origin and distance there is exactly one point
+            prop_assume!(b != c);
+            prop_assert_ne!(DHT::xor_distance(&a, &b), DHT::xor_distance(&a, &c));
+        }
+
+        #[test]
+        fn prop_bucket_index_matches_prefix(a in node_id(), b in node_id()) {
+            let dht = DHT::new(a.clone());
+            let expected = (0..256)
+                .find(|bit| {
+                    let mask = 0x80u8 >> (bit % 8);
+                    (a.0[bit / 8] & mask) != (b.0[bit / 8] & mask)
+                })
+                .map(|bit| 255 - bit);
+            prop_assert_eq!(dht.bucket_index(&b), expected);
+        }
+    }
+
+    #[test]
+    fn test_handle_find_messages() {
+        let dht = DHT::new(NodeId([0u8; 32]));
+        dht.insert_peer(peer(0x80, 1));
+        dht.insert_peer(peer(0x01, 2));
+
+        let reply = dht.handle_message(DHTMessage::FindNode {
+            target: peer(0x01, 0).node_id,
+            requesting_node: peer(0x80, 1).node_id,
+        });
+        match reply {
+            Some(DHTMessage::PeersFound { peers }) => assert_eq!(peers, vec![peer(0x01, 2)]),
+            other => panic!("unexpected reply: {:?}", other),
+        }
+
+        let reply = dht.handle_message(DHTMessage::FindFreedomDomain {
+            domain: "missing.freedom".to_string(),
+        });
+        assert!(matches!(reply, Some(DHTMessage::PeersFound { peers }) if peers.len() == 2));
+
+        let store = DHTMessage::StoreFreedomDomain {
+            record: record(&generate_identity(), "example.freedom", 1),
+        };
+        let reply = dht.handle_message(store.clone());
+        assert!(matches!(reply, Some(DHTMessage::Stored { accepted: true, .. })));
+        let reply = dht.handle_message(store);
+        assert!(matches!(reply, Some(DHTMessage::Stored { accepted: false, .. })));
+        let reply = dht.handle_message(DHTMessage::FindFreedomDomain {
+            domain: "example.freedom".to_string(),
+        });
+        assert!(matches!(reply, Some(DHTMessage::DomainOwner { owner: Some(_), .. })));
+
+        let reply = dht.handle_message(DHTMessage::Ping { sender: peer(0x80, 1).node_id });
+        assert!(matches!(reply, Some(DHTMessage::Pong { sender }) if sender == NodeId([0u8; 32])));
+    }
+
+    #[test]
+    fn test_bucket_index() {
+        let dht = DHT::new(NodeId([0u8; 32]));
+        assert_eq!(dht.bucket_index(&NodeId([0u8; 32])), None);
+        assert_eq!(dht.bucket_index(&peer(0x80, 0).node_id), Some(255));
+        assert_eq!(dht.bucket_index(&peer(0x01, 0).node_id), Some(248));
+        assert_eq!(dht.bucket_index(&peer(0x00, 1).node_id), Some(0));
+    }
+
+    #[test]
+    fn test_insert_and_find_closest() {
+        let dht = DHT::new(NodeId([0u8; 32]));
+        assert_eq!(dht.insert_peer(peer(0x80, 1)), InsertOutcome::Inserted);
+        assert_eq!(dht.insert_peer(peer(0x01, 2)), InsertOutcome::Inserted);
+        assert_eq!(dht.insert_peer(peer(0x80, 1)), InsertOutcome::Refreshed);
+        assert_eq!(dht.insert_peer(PeerInfo {
+            node_id: NodeId([0u8; 32]),
+            addr: "127.0.0.1:5000".to_string(),
+        }), InsertOutcome::Ignored);
+
+        assert_eq!(dht.peer_count(), 2);
+        let closest = dht.find_closest_peers(&peer(0x01, 0).node_id, 1);
+        assert_eq!(closest, vec![peer(0x01, 2)]);
+    }
+
+    #[test]
+    fn test_refresh_moves_to_tail() {
+        let dht = DHT::new(NodeId([0u8; 32]));
+        dht.insert_peer(peer(0x80, 1));
+        dht.insert_peer(peer(0x80, 2));
+        dht.insert_peer(peer(0x80, 1));
+
+        let order: Vec<_> = bucket(&dht, 255).entries().map(|p| p.node_id.0[31]).collect();
+        assert_eq!(order, vec![2, 1]);
+    }
+
+    #[test]
+    fn test_full_bucket_requires_ping() {
+        let dht = DHT::new(NodeId([0u8; 32]));
+        for i in 0..K_BUCKET_SIZE as u8 {
+            assert_eq!(dht.insert_peer(peer(0x80, i)), InsertOutcome::Inserted);
+        }
+
+        let outcome = dht.insert_peer(peer(0x80, 100));
+        assert_eq!(outcome, InsertOutcome::PingRequired { oldest: peer(0x80, 0) });
+        assert_eq!(bucket(&dht, 255).len(), K_BUCKET_SIZE);
+        assert_eq!(bucket(&dht, 255).replacements.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_live_oldest_keeps_slot() {
+        let dht = DHT::new(NodeId([0u8; 32]));
+        for i in 0..K_BUCKET_SIZE as u8 {
+            dht.insert_peer(peer(0x80, i));
+        }
+
+        dht.add_peer(peer(0x80, 100), |_| async { true }).await;
+
+        let bucket = bucket(&dht, 255);
+        let ids: Vec<_> = bucket.entries().map(|p| p.node_id.0[31]).collect();
+        assert!(!ids.contains(&100));
+        assert_eq!(ids.last(), Some(&0));
+        assert_eq!(bucket.replacements.front(), Some(&peer(0x80, 100)));
+    }
+
+    #[tokio::test]
+    async fn test_dead_oldest_is_replaced() {
+        let dht = DHT::new(NodeId([0u8; 32]));
+        for i in 0..K_BUCKET_SIZE as u8 {
+            dht.insert_peer(peer(0x80, i));
+        }
+
+        dht.add_peer(peer(0x80, 100), |_| async { false }).await;
+
+        let bucket = bucket(&dht, 255);
+        let ids: Vec<_> = bucket.entries().map(|p| p.node_id.0[31]).collect();
+        assert!(!ids.contains(&0));
+        assert_eq!(ids.last(), Some(&100));
+        assert_eq!(bucket.replacements.len(), 0);
+    }
+
+    #[test]
+    fn test_replacement_cache_is_bounded() {
+        let dht = DHT::new(NodeId([0u8; 32]));
+        for i in 0..(K_BUCKET_SIZE + REPLACEMENT_CACHE_SIZE + 5) as u8 {
+            dht.insert_peer(peer(0x80, i));
+        }
+        assert_eq!(bucket(&dht, 255).replacements.len(), REPLACEMENT_CACHE_SIZE);
     }
 }
@@ -1,15 +1,49 @@
 /// HTTP/SOCKS5 Proxy server for routing traffic through Freedom Network
 /// This allows standard browsers to use the network via proxy configuration
 
-use std::net::SocketAddr;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, copy_bidirectional};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf, copy_bidirectional};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
+use crate::client::{FreedomClient, FreedomResponse};
+use crate::http::{BodyLength, Headers, HttpError, HttpReader, RequestHead, ResponseHead};
+use crate::identity::FREEDOM_TLD;
 use crate::onion::OnionRouter;
+use crate::privacy::PrivacyPolicy;
+use crate::rpc::QuicTransport;
+use crate::routing::EndReason;
+use crate::stream::{decode_datagrams, encode_datagram, StreamRefused, MAX_DATAGRAM_LEN};
 use anyhow::{Result, anyhow};
 
-#[derive(Clone, Debug)]
+/// How long a kept-alive client connection may sit idle between requests
+const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait for a stream to open, or for a server to start answering
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fetches .freedom sites for the proxy over rendezvous circuits
+pub type FreedomSites = FreedomClient<QuicTransport>;
+
+/// Request fields a .freedom site gets from the browser: what it accepts
+/// and what it already holds. The rest stay with the proxy.
+const FREEDOM_REQUEST_FIELDS: &[&str] = &[
+    "accept",
+    "accept-language",
+    "cache-control",
+    "if-match",
+    "if-modified-since",
+    "if-none-match",
+    "if-range",
+    "if-unmodified-since",
+    "range",
+];
+
+const NO_CIRCUIT: &str = "No onion circuit is ready with an exit that allows this destination. The node may still be joining the network; try again shortly.";
+
+#[derive(Clone, Debug, Default)]
 pub struct ProxyMetrics {
     pub bytes_sent: Arc<RwLock<u64>>,
     pub bytes_received: Arc<RwLock<u64>>,
@@ -17,10 +51,18 @@ pub struct ProxyMetrics {
     pub active_connections: Arc<RwLock<u64>>,
 }
 
+/// A server connection kept open for the next request to the same target
+struct Upstream {
+    target: String,
+    reader: HttpReader<DuplexStream>,
+}
+
 pub struct ProxyServer {
     listener: TcpListener,
     onion_router: Arc<OnionRouter>,
     metrics: ProxyMetrics,
+    freedom_sites: Option<Arc<FreedomSites>>,
+    privacy: PrivacyPolicy,
 }
 
 impl ProxyServer {
@@ -41,13 +83,32 @@ impl ProxyServer {
             listener,
             onion_router,
             metrics,
+            freedom_sites: None,
+            privacy: PrivacyPolicy::default(),
         })
     }
 
+    /// Serve .freedom hostnames from the Freedom Network through `client`
+    /// instead of sending them to an exit
+    pub fn with_freedom_sites(mut self, client: Arc<FreedomSites>) -> Self {
+        self.freedom_sites = Some(client);
+        self
+    }
+
+    /// Scrub identifying headers from requests according to `privacy`
+    pub fn with_privacy_policy(mut self, privacy: PrivacyPolicy) -> Self {
+        self.privacy = privacy;
+        self
+    }
+
     pub fn get_metrics(&self) -> ProxyMetrics {
         self.metrics.clone()
     }
 
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
     pub async fn run(&self) -> Result<()> {
         loop {
             let (socket, addr) = self.listener.accept().await?;
@@ -55,16 +116,24 @@ impl ProxyServer {
             
             let onion = self.onion_router.clone();
             let metrics = self.metrics.clone();
+            let freedom_sites = self.freedom_sites.clone();
+            let privacy = self.privacy;
             
             tokio::spawn(async move {
-                if let Err(e) = Self::handle_client(socket, onion, metrics).await {
+                if let Err(e) = Self::handle_client(socket, onion, freedom_sites, privacy, metrics).await {
                     eprintln!("❌ Proxy error: {}", e);
                 }
             });
         }
     }
 
-    async fn handle_client(mut socket: TcpStream, _onion_router: Arc<OnionRouter>, metrics: ProxyMetrics) -> Result<()> {
+    async fn handle_client(
+        socket: TcpStream,
+        onion_router: Arc<OnionRouter>,
+        freedom_sites: Option<Arc<FreedomSites>>,
+        privacy: PrivacyPolicy,
+        metrics: ProxyMetrics,
+    ) -> Result<()> {
         // Increment active connections
         let mut active = metrics.active_connections.write().await;
         *active += 1;
@@ -73,78 +142,384 @@ impl ProxyServer {
         drop(active);
         drop(total);
 
-        let session_result = async {
-            let mut buffer = vec![0u8; 8192];
-            let n = socket.read(&mut buffer).await?;
-            if n == 0 {
-                return Ok(());
-            }
+        let session_result = Self::serve(HttpReader::new(socket), &onion_router, freedom_sites.as_deref(), privacy, &metrics).await;
+
+        let mut active = metrics.active_connections.write().await;
+        *active = active.saturating_sub(1);
+        drop(active);
 
-            let mut recv = metrics.bytes_received.write().await;
-            *recv += n as u64;
-            drop(recv);
+        session_result
+    }
 
-            let request = String::from_utf8_lossy(&buffer[..n]).to_string();
-            let first_line = request.lines().next().unwrap_or("");
-            let parts: Vec<&str> = first_line.split_whitespace().collect();
-            if parts.len() < 3 {
-                return Err(anyhow!("Invalid HTTP request line"));
+    /// Answer requests on one client connection until either side closes it
+    async fn serve(
+        mut client: HttpReader<TcpStream>,
+        onion_router: &OnionRouter,
+        freedom_sites: Option<&FreedomSites>,
+        privacy: PrivacyPolicy,
+        metrics: &ProxyMetrics,
+    ) -> Result<()> {
+        let mut upstream = None;
+        loop {
+            let head = match tokio::time::timeout(CLIENT_IDLE_TIMEOUT, client.read_request()).await {
+                Ok(Ok(Some(head))) => head,
+                Ok(Ok(None)) | Err(_) => return Ok(()),
+                Ok(Err(e @ (HttpError::Malformed(_) | HttpError::TooLarge(_)))) => {
+                    Self::send_error(client.get_mut(), metrics, "400 Bad Request", "this address", &e.to_string()).await?;
+                    return Err(e.into());
+                }
+                Ok(Err(e)) => return Err(e.into()),
+            };
+            println!("📍 {} {}", head.method, head.target);
+
+            // .freedom names never reach an exit: they are hidden services,
+            // met through rendezvous circuits
+            if let Some(domain) = Self::freedom_domain(&head) {
+                if !Self::serve_freedom(&mut client, head, &domain, freedom_sites, privacy, metrics).await? {
+                    return Ok(());
+                }
+                continue;
             }
+            if head.method.eq_ignore_ascii_case("CONNECT") {
+                return Self::tunnel(client, &head, onion_router, metrics).await;
+            }
+            if !Self::forward(&mut client, &mut upstream, head, onion_router, privacy, metrics).await? {
+                return Ok(());
+            }
+        }
+    }
 
-            let method = parts[0];
-            let path = parts[1];
-            println!("📍 {} {}", method, path);
+    /// Open a CONNECT tunnel and join it to the client
+    async fn tunnel(mut client: HttpReader<TcpStream>, head: &RequestHead, onion_router: &OnionRouter, metrics: &ProxyMetrics) -> Result<()> {
+        let target = Self::normalize_connect_target(&head.target);
+        let mut upstream = match Self::open_upstream(onion_router, &target).await {
+            Ok(upstream) => upstream,
+            Err((status, reason)) => {
+                Self::send_error(client.get_mut(), metrics, status, &target, &reason).await?;
+                return Err(anyhow!("{} for {}: {}", status, target, reason));
+            }
+        };
 
-            if method.eq_ignore_ascii_case("CONNECT") {
-                let target = Self::normalize_connect_target(path);
-                let mut upstream = TcpStream::connect(&target).await?;
+        let response = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+        client.get_mut().write_all(response).await?;
+        count(&metrics.bytes_sent, response.len() as u64).await;
+        println!("🔐 CONNECT tunnel established to {} over the circuit", target);
+        Self::splice(&mut client, &mut upstream.reader, metrics).await
+    }
 
-                let response = b"HTTP/1.1 200 Connection Established\r\n\r\n";
-                socket.write_all(response).await?;
+    /// Pass one request to its server and the response back. Returns whether
+    /// the client connection can carry another request.
+    async fn forward(
+        client: &mut HttpReader<TcpStream>,
+        upstream: &mut Option<Upstream>,
+        mut head: RequestHead,
+        onion_router: &OnionRouter,
+        privacy: PrivacyPolicy,
+        metrics: &ProxyMetrics,
+    ) -> Result<bool> {
+        let framing = Self::extract_http_target(&head).and_then(|target| Ok((target, head.body()?)));
+        let (target, body) = match framing {
+            Ok(framing) => framing,
+            Err(e) => {
+                Self::send_error(client.get_mut(), metrics, "400 Bad Request", &head.target, &e.to_string()).await?;
+                return Ok(false);
+            }
+        };
+        if head.headers.has_token("expect", "100-continue") {
+            // Answer for the server rather than hold the body back for a
+            // round trip through the circuit
+            head.headers.remove("expect");
+            client.get_mut().write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        }
+        let request = Self::rewrite_request(&head, privacy);
+        count(&metrics.bytes_received, request.len() as u64).await;
 
-                let mut sent = metrics.bytes_sent.write().await;
-                *sent += response.len() as u64;
-                drop(sent);
+        // A kept-open server connection only takes requests for its own target
+        if upstream.as_ref().is_some_and(|upstream| upstream.target != target) {
+            *upstream = None;
+        }
+        let mut reused = upstream.is_some();
+        let response = loop {
+            if upstream.is_none() {
+                match Self::open_upstream(onion_router, &target).await {
+                    Ok(opened) => *upstream = Some(opened),
+                    Err((status, reason)) => {
+                        eprintln!("❌ {} for {}: {}", status, target, reason);
+                        Self::send_error(client.get_mut(), metrics, status, &target, &reason).await?;
+                        return Ok(false);
+                    }
+                }
+            }
+            let server = upstream.as_mut().expect("opened above");
+            match Self::send_request(client, server, &request, body, metrics).await {
+                Ok(response) => break response,
+                // The server may have closed a kept-open connection while it
+                // sat idle. Only a request that means the same sent twice,
+                // and whose body isn't half consumed, is safe to send again.
+                Err(HttpError::Closed) if reused && body == BodyLength::Empty && is_idempotent(&head.method) => {
+                    *upstream = None;
+                    reused = false;
+                }
+                Err(e) => {
+                    *upstream = None;
+                    let status = match &e {
+                        HttpError::Io(io) if io.kind() == std::io::ErrorKind::TimedOut => "504 Gateway Timeout",
+                        _ => "502 Bad Gateway",
+                    };
+                    eprintln!("❌ {} for {}: {}", status, target, e);
+                    Self::send_error(client.get_mut(), metrics, status, &target, &e.to_string()).await?;
+                    return Ok(false);
+                }
+            }
+        };
 
-                println!("🔐 CONNECT tunnel established to {}", target);
-                let (client_to_upstream, upstream_to_client) = copy_bidirectional(&mut socket, &mut upstream).await?;
+        let server = upstream.as_mut().expect("the response came from it");
+        let response_body = match response.body(&head.method) {
+            Ok(response_body) => response_body,
+            Err(e) => {
+                *upstream = None;
+                Self::send_error(client.get_mut(), metrics, "502 Bad Gateway", &target, &e.to_string()).await?;
+                return Ok(false);
+            }
+        };
+        // Connection fields are stripped, so the client hears from the proxy
+        // whether its connection stays open. It closes along with the server's.
+        let reusable = response.keep_alive() && response_body != BodyLength::UntilClose;
+        let keep_alive = head.keep_alive() && reusable;
+        let mut forwarded = response.clone();
+        forwarded.headers.strip_hop_by_hop();
+        if response.status != 101 {
+            match keep_alive {
+                true if head.version == "HTTP/1.0" => forwarded.headers.0.push(("Connection".to_string(), "keep-alive".to_string())),
+                true => {}
+                false => forwarded.headers.0.push(("Connection".to_string(), "close".to_string())),
+            }
+        }
+        let encoded = forwarded.encode();
+        client.get_mut().write_all(&encoded).await?;
+        count(&metrics.bytes_sent, encoded.len() as u64).await;
+        if response.status == 101 {
+            // Switching protocols (e.g. WebSocket): the connection is a tunnel now
+            Self::splice(client, &mut server.reader, metrics).await?;
+            return Ok(false);
+        }
+        let sent = server.reader.copy_body(response_body, client.get_mut()).await?;
+        count(&metrics.bytes_sent, sent).await;
+        println!("✓ Forwarded {} {} over the circuit ({})", head.method, target, response.status);
 
-                let mut recv = metrics.bytes_received.write().await;
-                *recv += client_to_upstream;
-                drop(recv);
+        if !reusable {
+            *upstream = None;
+        }
+        Ok(keep_alive)
+    }
 
-                let mut sent = metrics.bytes_sent.write().await;
-                *sent += upstream_to_client;
-                drop(sent);
-            } else {
-                let target = Self::extract_http_target(path, &request)?;
-                let rewritten = Self::rewrite_request_line(&request)?;
+    /// Answer a request for a .freedom site with the page fetched from it,
+    /// rendering .fdom documents to HTML. Returns whether the client
+    /// connection can carry another request.
+    async fn serve_freedom(
+        client: &mut HttpReader<TcpStream>,
+        head: RequestHead,
+        domain: &str,
+        freedom_sites: Option<&FreedomSites>,
+        privacy: PrivacyPolicy,
+        metrics: &ProxyMetrics,
+    ) -> Result<bool> {
+        let head_only = head.method.eq_ignore_ascii_case("HEAD");
+        if !head_only && !head.method.eq_ignore_ascii_case("GET") && !head.method.eq_ignore_ascii_case("CONNECT") {
+            let page = Self::error_page(
+                "405 Method Not Allowed",
+                domain,
+                "Freedom sites only answer GET and HEAD through the proxy.",
+                &[("Allow", "GET, HEAD")],
+            );
+            Self::send_page(client.get_mut(), metrics, &page).await?;
+            return Ok(false);
+        }
+        let freedom_sites = match freedom_sites {
+            _ if head.method.eq_ignore_ascii_case("CONNECT") => Err((
+                "400 Bad Request",
+                "Freedom sites are reached over http://; the rendezvous circuit already encrypts them end to end.",
+            )),
+            Some(freedom_sites) => Ok(freedom_sites),
+            None => Err(("503 Service Unavailable", "This node is not set up to reach .freedom sites.")),
+        };
+        let freedom_sites = match freedom_sites {
+            Ok(freedom_sites) => freedom_sites,
+            Err((status, reason)) => {
+                Self::send_error(client.get_mut(), metrics, status, domain, reason).await?;
+                return Ok(false);
+            }
+        };
 
-                let mut upstream = TcpStream::connect(&target).await?;
-                upstream.write_all(rewritten.as_bytes()).await?;
+        let path = origin_form(&head.target);
+        let mut headers = head.headers.clone();
+        headers.0.retain(|(name, _)| FREEDOM_REQUEST_FIELDS.contains(&name.to_ascii_lowercase().as_str()));
+        privacy.apply(&mut headers);
+        let fetched = freedom_sites.fetch(domain, &head.method, &path, headers).await;
+        let response = match fetched.and_then(|response| Self::render_fdom(response, &path, head_only)) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("❌ Failed to fetch {}{}: {}", domain, path, e);
+                Self::send_error(client.get_mut(), metrics, "502 Bad Gateway", domain, &e.to_string()).await?;
+                return Ok(false);
+            }
+        };
 
-                let (client_to_upstream, upstream_to_client) = copy_bidirectional(&mut socket, &mut upstream).await?;
+        // The body is whole, so the client gets fresh framing for it. A
+        // response without one keeps the length the server gave.
+        let keep_alive = head.keep_alive();
+        let bodiless = head_only || matches!(response.status, 204 | 304);
+        let mut headers: Vec<(String, String)> = response
+            .headers
+            .into_iter()
+            .filter(|(name, _)| !matches!(name.as_str(), "transfer-encoding" | "connection" | "keep-alive"))
+            .filter(|(name, _)| bodiless || name != "content-length")
+            .collect();
+        if !bodiless {
+            headers.push(("Content-Length".to_string(), response.body.len().to_string()));
+        }
+        if !keep_alive {
+            headers.push(("Connection".to_string(), "close".to_string()));
+        }
+        let mut encoded = ResponseHead {
+            version: "HTTP/1.1".to_string(),
+            status: response.status,
+            reason: reason_phrase(response.status).to_string(),
+            headers: Headers(headers),
+        }
+        .encode();
+        encoded.extend_from_slice(&response.body);
+        client.get_mut().write_all(&encoded).await?;
+        count(&metrics.bytes_sent, encoded.len() as u64).await;
+        println!("✓ Served {}{} from the Freedom Network ({})", domain, path, response.status);
+        Ok(keep_alive)
+    }
 
-                let mut recv = metrics.bytes_received.write().await;
-                *recv += client_to_upstream;
-                drop(recv);
+    /// Turn an .fdom document into the HTML a browser can show. Only a
+    /// whole document is rendered; for HEAD the length of the HTML isn't
+    /// known, so none is given.
+    fn render_fdom(mut response: FreedomResponse, path: &str, head_only: bool) -> Result<FreedomResponse> {
+        let is_fdom = match response.headers.get("content-type") {
+            Some(content_type) => content_type.trim().to_ascii_lowercase().starts_with("text/fdom"),
+            None => path.split(['?', '#']).next().unwrap_or("").ends_with(".fdom"),
+        };
+        if !is_fdom || response.status != 200 {
+            return Ok(response);
+        }
+        response.headers.insert("content-type".to_string(), "text/html; charset=utf-8".to_string());
+        if head_only {
+            response.headers.remove("content-length");
+            return Ok(response);
+        }
+        let source = String::from_utf8(response.body).map_err(|_| anyhow!("{} is not valid UTF-8", path))?;
+        let html = fdom::FdomProcessor::process(&source).map_err(|e| anyhow!("Could not render {}: {}", path, e))?;
+        response.body = html.into_bytes();
+        Ok(response)
+    }
 
-                let mut sent = metrics.bytes_sent.write().await;
-                *sent += upstream_to_client;
-                drop(sent);
+    /// The .freedom name a request is for, if it is for one
+    fn freedom_domain(head: &RequestHead) -> Option<String> {
+        let target = if head.method.eq_ignore_ascii_case("CONNECT") {
+            Self::normalize_connect_target(&head.target)
+        } else {
+            Self::extract_http_target(head).ok()?
+        };
+        let host = target.rsplit_once(':').map_or(target.as_str(), |(host, _)| host).to_ascii_lowercase();
+        host.ends_with(FREEDOM_TLD).then_some(host)
+    }
 
-                println!("✓ Forwarded HTTP request through {}", target);
+    /// Write a request and its body to the server and read the response
+    /// head, passing interim (1xx) responses straight back to the client
+    async fn send_request(
+        client: &mut HttpReader<TcpStream>,
+        server: &mut Upstream,
+        request: &[u8],
+        body: BodyLength,
+        metrics: &ProxyMetrics,
+    ) -> Result<ResponseHead, HttpError> {
+        server.reader.get_mut().write_all(request).await?;
+        let sent = client.copy_body(body, server.reader.get_mut()).await?;
+        count(&metrics.bytes_received, sent).await;
+        loop {
+            let response = match tokio::time::timeout(UPSTREAM_TIMEOUT, server.reader.read_response()).await {
+                Ok(response) => response?,
+                Err(_) => {
+                    let reason = format!("The server sent no response within {}s", UPSTREAM_TIMEOUT.as_secs());
+                    return Err(HttpError::Io(std::io::Error::new(std::io::ErrorKind::TimedOut, reason)));
+                }
+            };
+            if !(100..200).contains(&response.status) || response.status == 101 {
+                return Ok(response);
             }
+            let mut interim = response;
+            interim.headers.strip_hop_by_hop();
+            client.get_mut().write_all(&interim.encode()).await?;
+        }
+    }
 
-            Ok(())
+    /// A stream to `target` through a circuit's exit, or the error page status
+    /// and reason to show instead
+    async fn open_upstream(onion_router: &OnionRouter, target: &str) -> Result<Upstream, (&'static str, String)> {
+        // Everything leaves through the exit of a circuit, never directly
+        let Some(streams) = onion_router.ready_streams(target).await else {
+            return Err(("503 Service Unavailable", NO_CIRCUIT.to_string()));
+        };
+        match tokio::time::timeout(UPSTREAM_TIMEOUT, streams.open(target)).await {
+            Ok(Ok(stream)) => Ok(Upstream { target: target.to_string(), reader: HttpReader::new(stream) }),
+            Ok(Err(e)) => {
+                let status = match e.downcast_ref::<StreamRefused>().map(|refused| refused.reason) {
+                    Some(EndReason::Timeout) => "504 Gateway Timeout",
+                    _ => "502 Bad Gateway",
+                };
+                Err((status, e.to_string()))
+            }
+            Err(_) => Err(("504 Gateway Timeout", format!("The exit did not connect within {}s", UPSTREAM_TIMEOUT.as_secs()))),
         }
-        .await;
+    }
 
-        let mut active = metrics.active_connections.write().await;
-        *active = active.saturating_sub(1);
-        drop(active);
+    /// Join client and server until either closes, starting with whatever
+    /// each had sent past the last HTTP message
+    async fn splice(client: &mut HttpReader<TcpStream>, server: &mut HttpReader<DuplexStream>, metrics: &ProxyMetrics) -> Result<()> {
+        let to_server = client.take_buffered();
+        let to_client = server.take_buffered();
+        server.get_mut().write_all(&to_server).await?;
+        client.get_mut().write_all(&to_client).await?;
+        let (client_to_upstream, upstream_to_client) = copy_bidirectional(client.get_mut(), server.get_mut()).await?;
+        count(&metrics.bytes_received, client_to_upstream + to_server.len() as u64).await;
+        count(&metrics.bytes_sent, upstream_to_client + to_client.len() as u64).await;
+        Ok(())
+    }
 
-        session_result
+    /// Answer the browser with an HTML page explaining why `target` can't be reached
+    async fn send_error(socket: &mut TcpStream, metrics: &ProxyMetrics, status: &str, target: &str, reason: &str) -> Result<()> {
+        Self::send_page(socket, metrics, &Self::error_page(status, target, reason, &[])).await
+    }
+
+    async fn send_page(socket: &mut TcpStream, metrics: &ProxyMetrics, response: &str) -> Result<()> {
+        socket.write_all(response.as_bytes()).await?;
+
+        let mut sent = metrics.bytes_sent.write().await;
+        *sent += response.len() as u64;
+        Ok(())
+    }
+
+    fn error_page(status: &str, target: &str, reason: &str, headers: &[(&str, &str)]) -> String {
+        let body = format!(
+            "<!DOCTYPE html>\n<html><head><title>{status}</title></head>\n<body>\n<h1>Freedom Network: {status}</h1>\n\
+             <p>Could not reach <b>{target}</b> through the Freedom Network.</p>\n<p>{reason}</p>\n\
+             <p>Nothing was sent outside the network.</p>\n</body></html>\n",
+            status = html_escape(status),
+            target = html_escape(target),
+            reason = html_escape(reason),
+        );
+        let extra: String = headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            extra,
+            body
+        )
     }
 
     fn normalize_connect_target(path: &str) -> String {
@@ -155,93 +530,905 @@ impl ProxyServer {
         }
     }
 
-    fn extract_http_target(path: &str, request: &str) -> Result<String> {
-        if let Some(stripped) = path.strip_prefix("http://") {
-            let host_port = stripped.split('/').next().unwrap_or("");
-            if host_port.is_empty() {
-                return Err(anyhow!("Missing host in absolute URL"));
-            }
-            if host_port.contains(':') {
-                return Ok(host_port.to_string());
-            }
-            return Ok(format!("{}:80", host_port));
+    /// Where a proxied request goes: the authority of an absolute URL, or
+    /// the Host header for origin-form
+    fn extract_http_target(head: &RequestHead) -> Result<String> {
+        if head.target.starts_with("https://") {
+            // Sending it on would put the request in the clear on the exit
+            return Err(anyhow!("https:// URLs must be tunnelled with CONNECT"));
         }
-
-        if let Some(stripped) = path.strip_prefix("https://") {
-            let host_port = stripped.split('/').next().unwrap_or("");
+        if let Some(stripped) = head.target.strip_prefix("http://") {
+            let host_port = stripped.split(['/', '?']).next().unwrap_or("");
             if host_port.is_empty() {
                 return Err(anyhow!("Missing host in absolute URL"));
             }
-            if host_port.contains(':') {
-                return Ok(host_port.to_string());
-            }
-            return Ok(format!("{}:443", host_port));
+            return Ok(with_default_port(host_port, 80));
         }
 
-        for line in request.lines() {
-            if line.to_ascii_lowercase().starts_with("host:") {
-                let host = line[5..].trim();
-                if host.is_empty() {
-                    return Err(anyhow!("Host header is empty"));
-                }
-                if host.contains(':') {
-                    return Ok(host.to_string());
-                }
-                return Ok(format!("{}:80", host));
-            }
+        match head.headers.get("host") {
+            Some("") => Err(anyhow!("Host header is empty")),
+            Some(host) => Ok(with_default_port(host, 80)),
+            None => Err(anyhow!("Missing Host header for HTTP request")),
         }
-
-        Err(anyhow!("Missing Host header for HTTP request"))
     }
 
-    fn rewrite_request_line(request: &str) -> Result<String> {
-        let mut lines = request.splitn(2, "\r\n");
-        let first_line = lines.next().ok_or_else(|| anyhow!("Missing request line"))?;
-        let remainder = lines.next().unwrap_or("");
+    /// The head to send the server: the target in origin-form, without
+    /// the fields meant for the proxy alone, and scrubbed by `privacy`
+    fn rewrite_request(head: &RequestHead, privacy: PrivacyPolicy) -> Vec<u8> {
+        let mut head = head.clone();
+        head.target = origin_form(&head.target);
+        head.headers.strip_hop_by_hop();
+        privacy.apply(&mut head.headers);
+        head.encode()
+    }
+}
 
-        let parts: Vec<&str> = first_line.split_whitespace().collect();
-        if parts.len() < 3 {
-            return Err(anyhow!("Invalid request line"));
-        }
+/// A request target without the scheme and authority of an absolute URL
+fn origin_form(target: &str) -> String {
+    match target.strip_prefix("http://") {
+        Some(stripped) => match stripped.find(['/', '?']) {
+            Some(at) if stripped[at..].starts_with('/') => stripped[at..].to_string(),
+            Some(at) => format!("/{}", &stripped[at..]),
+            None => "/".to_string(),
+        },
+        None => target.to_string(),
+    }
+}
 
-        let method = parts[0];
-        let path = parts[1];
-        let version = parts[2];
+/// Whether sending the request twice has the same effect as sending it once
+fn is_idempotent(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "PUT" | "DELETE" | "OPTIONS" | "TRACE")
+}
 
-        let new_path = if let Some(stripped) = path.strip_prefix("http://") {
-            let suffix = stripped.split_once('/').map(|(_, tail)| tail).unwrap_or("");
-            if suffix.is_empty() { "/".to_string() } else { format!("/{}", suffix) }
-        } else if let Some(stripped) = path.strip_prefix("https://") {
-            let suffix = stripped.split_once('/').map(|(_, tail)| tail).unwrap_or("");
-            if suffix.is_empty() { "/".to_string() } else { format!("/{}", suffix) }
-        } else {
-            path.to_string()
-        };
+/// Reason phrase for the status codes sites commonly answer with
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        412 => "Precondition Failed",
+        416 => "Range Not Satisfiable",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
 
-        Ok(format!("{} {} {}\r\n{}", method, new_path, version, remainder))
+/// Add `port` to a host that doesn't name one. A bracketed IPv6 literal's
+/// own colons don't count.
+fn with_default_port(host: &str, port: u16) -> String {
+    let named_port = match host.rsplit_once(']') {
+        Some((_, rest)) => rest.starts_with(':'),
+        None => host.contains(':'),
+    };
+    if named_port {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, port)
     }
 }
 
-// Legacy SOCKS5 support (extended feature)
-#[allow(dead_code)]
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+async fn count(counter: &RwLock<u64>, bytes: u64) {
+    *counter.write().await += bytes;
+}
+
+const SOCKS_VERSION: u8 = 5;
+/// Version of the RFC 1929 username/password subnegotiation
+const AUTH_VERSION: u8 = 1;
+const METHOD_NONE: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xFF;
+const CMD_CONNECT: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+// Reply codes from RFC 1928 section 6
+const REPLY_SUCCEEDED: u8 = 0;
+const REPLY_GENERAL_FAILURE: u8 = 1;
+const REPLY_NOT_ALLOWED: u8 = 2;
+const REPLY_HOST_UNREACHABLE: u8 = 4;
+const REPLY_CONNECTION_REFUSED: u8 = 5;
+const REPLY_TTL_EXPIRED: u8 = 6;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 8;
+
+/// Bound address sent in replies that have none to report
+const UNSPECIFIED: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// SOCKS5 front end (RFC 1928) for applications that don't speak HTTP
+/// proxying. Names are passed to the exit unresolved, so lookups never
+/// leave the local machine. Each set of username/password credentials
+/// (RFC 1929) gets circuits of its own.
 pub struct Socks5Server {
     listener: TcpListener,
+    onion_router: Arc<OnionRouter>,
+    metrics: ProxyMetrics,
+    /// Accepted usernames and passwords. When empty, any credentials are
+    /// taken and only serve to keep applications on separate circuits.
+    users: Arc<HashMap<String, String>>,
 }
 
-#[allow(dead_code)]
 impl Socks5Server {
-    pub async fn new(addr: SocketAddr) -> Result<Self> {
+    pub async fn new(addr: SocketAddr, onion_router: Arc<OnionRouter>, metrics: ProxyMetrics) -> Result<Self> {
         let listener = TcpListener::bind(addr).await?;
         println!("🔷 SOCKS5 Server listening on {}", addr);
-        
-        Ok(Socks5Server { listener })
+
+        Ok(Socks5Server { listener, onion_router, metrics, users: Arc::new(HashMap::new()) })
+    }
+
+    /// Only accept clients that log in as one of `users`
+    pub fn with_users(mut self, users: HashMap<String, String>) -> Self {
+        self.users = Arc::new(users);
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
     }
 
     pub async fn run(&self) -> Result<()> {
         loop {
-            let (_socket, addr) = self.listener.accept().await?;
+            let (socket, addr) = self.listener.accept().await?;
             println!("🔗 SOCKS5 connection from {}", addr);
-            // SOCKS5 implementation would go here
+
+            let onion = self.onion_router.clone();
+            let metrics = self.metrics.clone();
+            let users = self.users.clone();
+            tokio::spawn(async move {
+                if let Err(e) = Self::handle_client(socket, onion, metrics, users).await {
+                    eprintln!("❌ SOCKS5 error: {}", e);
+                }
+            });
         }
     }
+
+    async fn handle_client(
+        mut socket: TcpStream,
+        onion_router: Arc<OnionRouter>,
+        metrics: ProxyMetrics,
+        users: Arc<HashMap<String, String>>,
+    ) -> Result<()> {
+        count(&metrics.active_connections, 1).await;
+        count(&metrics.total_connections, 1).await;
+
+        let session_result = async {
+            let isolation = Self::negotiate(&mut socket, &users).await?;
+
+            let mut header = [0u8; 3];
+            socket.read_exact(&mut header).await?;
+            if header[0] != SOCKS_VERSION {
+                return Err(anyhow!("Not a SOCKS5 request (version {})", header[0]));
+            }
+            let Some(target) = Self::read_target(&mut socket).await? else {
+                Self::reply(&mut socket, REPLY_ADDRESS_NOT_SUPPORTED, UNSPECIFIED).await?;
+                return Err(anyhow!("Unknown SOCKS address type"));
+            };
+            match header[1] {
+                CMD_CONNECT => Self::connect(&mut socket, &onion_router, &metrics, &target, isolation.as_deref()).await,
+                CMD_UDP_ASSOCIATE => Self::udp_associate(&mut socket, &onion_router, &metrics, &target, isolation.as_deref()).await,
+                command => {
+                    Self::reply(&mut socket, REPLY_COMMAND_NOT_SUPPORTED, UNSPECIFIED).await?;
+                    Err(anyhow!("Unsupported SOCKS command {}", command))
+                }
+            }
+        }
+        .await;
+
+        let mut active = metrics.active_connections.write().await;
+        *active = active.saturating_sub(1);
+        drop(active);
+
+        session_result
+    }
+
+    /// Agree on an auth method and run it; returns the isolation group for
+    /// the client's streams
+    async fn negotiate(socket: &mut TcpStream, users: &HashMap<String, String>) -> Result<Option<String>> {
+        let mut header = [0u8; 2];
+        socket.read_exact(&mut header).await?;
+        if header[0] != SOCKS_VERSION {
+            return Err(anyhow!("Not a SOCKS5 client (version {})", header[0]));
+        }
+        let mut methods = vec![0u8; header[1] as usize];
+        socket.read_exact(&mut methods).await?;
+
+        // Credentials are preferred even when not required, since they are
+        // how an application asks for circuits of its own
+        let method = if methods.contains(&METHOD_PASSWORD) {
+            METHOD_PASSWORD
+        } else if users.is_empty() && methods.contains(&METHOD_NONE) {
+            METHOD_NONE
+        } else {
+            METHOD_UNACCEPTABLE
+        };
+        socket.write_all(&[SOCKS_VERSION, method]).await?;
+        match method {
+            METHOD_NONE => Ok(None),
+            METHOD_PASSWORD => {
+                if socket.read_u8().await? != AUTH_VERSION {
+                    return Err(anyhow!("Unknown username/password auth version"));
+                }
+                let username = Self::read_field(socket).await?;
+                let password = Self::read_field(socket).await?;
+                let accepted = users.is_empty() || users.get(&username) == Some(&password);
+                socket.write_all(&[AUTH_VERSION, if accepted { 0 } else { 1 }]).await?;
+                if !accepted {
+                    return Err(anyhow!("Wrong SOCKS credentials for '{}'", username));
+                }
+                Ok(Some(format!("{}\0{}", username, password)))
+            }
+            _ => Err(anyhow!("Client offered no acceptable auth method")),
+        }
+    }
+
+    /// A length-prefixed username or password
+    async fn read_field(socket: &mut TcpStream) -> Result<String> {
+        let mut field = vec![0u8; socket.read_u8().await? as usize];
+        socket.read_exact(&mut field).await?;
+        Ok(String::from_utf8_lossy(&field).into_owned())
+    }
+
+    /// Read an address and port as "host:port"; `None` for an address type
+    /// SOCKS5 doesn't define
+    async fn read_target(socket: &mut TcpStream) -> Result<Option<String>> {
+        let atyp = socket.read_u8().await?;
+        let len = match atyp {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => socket.read_u8().await? as usize,
+            _ => return Ok(None),
+        };
+        let mut address = vec![0u8; len];
+        socket.read_exact(&mut address).await?;
+        let port = socket.read_u16().await?;
+        let host = format_host(atyp, &address).ok_or_else(|| anyhow!("Malformed SOCKS address"))?;
+        Ok(Some(format!("{}:{}", host, port)))
+    }
+
+    async fn reply(socket: &mut TcpStream, code: u8, bound: SocketAddr) -> Result<()> {
+        let mut reply = vec![SOCKS_VERSION, code, 0];
+        reply.extend(encode_address(bound));
+        socket.write_all(&reply).await?;
+        Ok(())
+    }
+
+    async fn connect(
+        socket: &mut TcpStream,
+        onion_router: &OnionRouter,
+        metrics: &ProxyMetrics,
+        target: &str,
+        isolation: Option<&str>,
+    ) -> Result<()> {
+        let Some(streams) = onion_router.isolated_streams(target, isolation).await else {
+            Self::reply(socket, REPLY_GENERAL_FAILURE, UNSPECIFIED).await?;
+            return Err(anyhow!("No circuit available for {}", target));
+        };
+        let mut upstream = match streams.open(target).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                Self::reply(socket, refusal_code(&e), UNSPECIFIED).await?;
+                return Err(e);
+            }
+        };
+        // The real bound address is the exit's, which stays private
+        Self::reply(socket, REPLY_SUCCEEDED, UNSPECIFIED).await?;
+        println!("🔷 SOCKS5 CONNECT to {} over the circuit", target);
+
+        let (client_to_upstream, upstream_to_client) = copy_bidirectional(socket, &mut upstream).await?;
+        count(&metrics.bytes_received, client_to_upstream).await;
+        count(&metrics.bytes_sent, upstream_to_client).await;
+        Ok(())
+    }
+
+    /// Relay the client's UDP datagrams over a datagram stream for as long as
+    /// the TCP connection that asked for them stays open. `declared` is where
+    /// the client said it would send from.
+    async fn udp_associate(
+        socket: &mut TcpStream,
+        onion_router: &OnionRouter,
+        metrics: &ProxyMetrics,
+        declared: &str,
+        isolation: Option<&str>,
+    ) -> Result<()> {
+        let peer_ip = socket.peer_addr()?.ip();
+        let declared_port = declared.rsplit_once(':').and_then(|(_, port)| port.parse::<u16>().ok()).filter(|port| *port != 0);
+        let relay = UdpSocket::bind(SocketAddr::new(socket.local_addr()?.ip(), 0)).await?;
+        Self::reply(socket, REPLY_SUCCEEDED, relay.local_addr()?).await?;
+        println!("🔷 SOCKS5 UDP ASSOCIATE on {}", relay.local_addr()?);
+
+        let mut client: Option<SocketAddr> = None;
+        let mut exit: Option<(ReadHalf<DuplexStream>, WriteHalf<DuplexStream>)> = None;
+        let mut from_exit = Vec::new();
+        let mut packet = vec![0u8; MAX_DATAGRAM_LEN + 512];
+        let mut chunk = vec![0u8; 16 * 1024];
+        let mut control = [0u8; 1];
+        loop {
+            tokio::select! {
+                read = socket.read(&mut control) => {
+                    if matches!(read, Ok(0) | Err(_)) {
+                        return Ok(());
+                    }
+                }
+                received = relay.recv_from(&mut packet) => {
+                    let (n, from) = received?;
+                    // Only the client that asked may use the relay
+                    if from.ip() != peer_ip
+                        || declared_port.is_some_and(|port| port != from.port())
+                        || client.is_some_and(|client| client != from)
+                    {
+                        continue;
+                    }
+                    client = Some(from);
+                    let Some((target, payload)) = parse_udp_request(&packet[..n]) else {
+                        continue;
+                    };
+                    // The circuit is picked for the first destination
+                    if exit.is_none() {
+                        let Some(streams) = onion_router.isolated_streams(&target, isolation).await else {
+                            eprintln!("❌ No circuit available for datagrams to {}", target);
+                            continue;
+                        };
+                        exit = Some(tokio::io::split(streams.open_datagrams().await?));
+                    }
+                    if let Some((_, writer)) = exit.as_mut() {
+                        writer.write_all(&encode_datagram(&target, payload)).await?;
+                        count(&metrics.bytes_received, payload.len() as u64).await;
+                    }
+                }
+                read = async {
+                    match exit.as_mut() {
+                        Some((reader, _)) => reader.read(&mut chunk).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    let n = read?;
+                    if n == 0 {
+                        return Err(anyhow!("The exit closed the datagram stream"));
+                    }
+                    from_exit.extend_from_slice(&chunk[..n]);
+                    for (source, payload) in decode_datagrams(&mut from_exit).map_err(|e| anyhow!(e))? {
+                        let (Some(client), Ok(source)) = (client, source.parse::<SocketAddr>()) else {
+                            continue;
+                        };
+                        let mut datagram = vec![0, 0, 0];
+                        datagram.extend(encode_address(source));
+                        datagram.extend_from_slice(&payload);
+                        relay.send_to(&datagram, client).await?;
+                        count(&metrics.bytes_sent, payload.len() as u64).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// RFC 1928 reply code for a stream the exit refused
+fn refusal_code(error: &anyhow::Error) -> u8 {
+    match error.downcast_ref::<StreamRefused>().map(|refused| refused.reason) {
+        Some(EndReason::NotAllowed) => REPLY_NOT_ALLOWED,
+        Some(EndReason::Unresolved) => REPLY_HOST_UNREACHABLE,
+        Some(EndReason::Timeout) => REPLY_TTL_EXPIRED,
+        Some(EndReason::Refused) => REPLY_CONNECTION_REFUSED,
+        _ => REPLY_GENERAL_FAILURE,
+    }
+}
+
+/// The host part of "host:port" for a SOCKS address
+fn format_host(atyp: u8, address: &[u8]) -> Option<String> {
+    match atyp {
+        ATYP_IPV4 => <[u8; 4]>::try_from(address).ok().map(|ip| Ipv4Addr::from(ip).to_string()),
+        ATYP_IPV6 => <[u8; 16]>::try_from(address).ok().map(|ip| format!("[{}]", Ipv6Addr::from(ip))),
+        ATYP_DOMAIN => std::str::from_utf8(address).ok().filter(|name| !name.is_empty()).map(str::to_string),
+        _ => None,
+    }
+}
+
+/// ATYP, address and port
+fn encode_address(addr: SocketAddr) -> Vec<u8> {
+    let mut out = Vec::with_capacity(19);
+    match addr {
+        SocketAddr::V4(v4) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&v4.ip().octets());
+        }
+        SocketAddr::V6(v6) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&v6.ip().octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+    out
+}
+
+/// Split a client's UDP datagram into its destination and payload.
+/// Fragments aren't supported and are dropped, as RFC 1928 allows.
+fn parse_udp_request(packet: &[u8]) -> Option<(String, &[u8])> {
+    let (header, rest) = packet.split_at_checked(4)?;
+    if header[2] != 0 {
+        return None;
+    }
+    let atyp = header[3];
+    let (len, rest) = match atyp {
+        ATYP_IPV4 => (4, rest),
+        ATYP_IPV6 => (16, rest),
+        ATYP_DOMAIN => rest.split_first().map(|(len, rest)| (*len as usize, rest))?,
+        _ => return None,
+    };
+    let (address, rest) = rest.split_at_checked(len)?;
+    let (port, payload) = rest.split_at_checked(2)?;
+    let host = format_host(atyp, address)?;
+    Some((format!("{}:{}", host, u16::from_be_bytes([port[0], port[1]])), payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::generate_identity;
+    use crate::rendezvous::HiddenService;
+    use crate::resolver::FreedomResolver;
+    use crate::rpc::testing::{echo_server, loopback_router, spawn_node, spawn_relays};
+
+    #[tokio::test]
+    async fn test_error_page_without_circuit() {
+        let proxy = ProxyServer::new("127.0.0.1:0".parse().unwrap(), Arc::new(OnionRouter::new())).await.unwrap();
+        let addr = proxy.local_addr().unwrap();
+        tokio::spawn(async move { proxy.run().await });
+
+        let mut browser = TcpStream::connect(addr).await.unwrap();
+        browser.write_all(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        browser.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+        assert!(response.contains("example.com:80"));
+        assert!(response.contains("No onion circuit is ready"));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_request_gets_400() {
+        let proxy = ProxyServer::new("127.0.0.1:0".parse().unwrap(), Arc::new(OnionRouter::new())).await.unwrap();
+        let addr = proxy.local_addr().unwrap();
+        tokio::spawn(async move { proxy.run().await });
+
+        let mut browser = TcpStream::connect(addr).await.unwrap();
+        browser.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        browser.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn test_request_target_rewriting() {
+        let head = |target: &str, host: Option<&str>| RequestHead {
+            method: "GET".to_string(),
+            target: target.to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: crate::http::Headers(host.map(|host| ("Host".to_string(), host.to_string())).into_iter().collect()),
+        };
+
+        let absolute = head("http://example.com?q=1", Some("example.com"));
+        assert_eq!(ProxyServer::extract_http_target(&absolute).unwrap(), "example.com:80");
+        assert!(ProxyServer::rewrite_request(&absolute, PrivacyPolicy::Off).starts_with(b"GET /?q=1 HTTP/1.1\r\n"));
+        let secure = head("https://[::1]/a/b", None);
+        assert!(ProxyServer::extract_http_target(&secure).is_err());
+        let bracketed = head("http://[::1]/a/b", None);
+        assert_eq!(ProxyServer::extract_http_target(&bracketed).unwrap(), "[::1]:80");
+        assert!(ProxyServer::rewrite_request(&bracketed, PrivacyPolicy::Off).starts_with(b"GET /a/b HTTP/1.1\r\n"));
+        let origin = head("/page", Some("example.com:8080"));
+        assert_eq!(ProxyServer::extract_http_target(&origin).unwrap(), "example.com:8080");
+        assert!(ProxyServer::rewrite_request(&origin, PrivacyPolicy::Off).starts_with(b"GET /page HTTP/1.1\r\n"));
+        assert!(ProxyServer::extract_http_target(&head("/page", None)).is_err());
+    }
+
+    #[test]
+    fn test_only_idempotent_methods_are_retried() {
+        assert!(is_idempotent("GET"));
+        assert!(is_idempotent("PUT"));
+        assert!(!is_idempotent("POST"));
+        assert!(!is_idempotent("PATCH"));
+    }
+
+    #[test]
+    fn test_rewritten_request_headers() {
+        let fields = [
+            ("Host", "example.com"),
+            ("User-Agent", "Mozilla/5.0 (X11; Linux x86_64) Chrome/126.0"),
+            ("Proxy-Connection", "keep-alive"),
+            ("Proxy-Authorization", "Basic dXNlcjpwYXNz"),
+            ("Connection", "keep-alive, X-Trace"),
+            ("X-Trace", "1"),
+            ("Keep-Alive", "300"),
+            ("TE", "trailers"),
+            ("X-Forwarded-For", "10.0.0.5"),
+            ("Referer", "http://example.com/private/page"),
+            ("Accept", "text/html"),
+        ];
+        let head = RequestHead {
+            method: "GET".to_string(),
+            target: "http://example.com/".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: crate::http::Headers(fields.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()),
+        };
+
+        let rewritten = ProxyServer::rewrite_request(&head, PrivacyPolicy::Off);
+        assert_eq!(
+            String::from_utf8(rewritten).unwrap(),
+            "GET / HTTP/1.1\r\nHost: example.com\r\nUser-Agent: Mozilla/5.0 (X11; Linux x86_64) Chrome/126.0\r\n\
+             X-Forwarded-For: 10.0.0.5\r\nReferer: http://example.com/private/page\r\nAccept: text/html\r\n\r\n"
+        );
+
+        let rewritten = ProxyServer::rewrite_request(&head, PrivacyPolicy::Standard);
+        assert_eq!(
+            String::from_utf8(rewritten).unwrap(),
+            format!(
+                "GET / HTTP/1.1\r\nHost: example.com\r\nUser-Agent: {}\r\nAccept: text/html\r\nReferer: http://example.com/\r\n\r\n",
+                crate::privacy::COMMON_USER_AGENT
+            )
+        );
+    }
+
+    #[test]
+    fn test_error_page_escapes_html() {
+        let page = ProxyServer::error_page("502 Bad Gateway", "<script>:80", "a & b", &[]);
+        assert!(page.contains("&lt;script&gt;:80"));
+        assert!(page.contains("a &amp; b"));
+        let (head, body) = page.split_once("\r\n\r\n").unwrap();
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+    }
+
+    async fn socks_server(users: &[(&str, &str)]) -> SocketAddr {
+        let users = users.iter().map(|(user, pass)| (user.to_string(), pass.to_string())).collect();
+        let server = Socks5Server::new("127.0.0.1:0".parse().unwrap(), Arc::new(OnionRouter::new()), ProxyMetrics::default())
+            .await
+            .unwrap()
+            .with_users(users);
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move { server.run().await });
+        addr
+    }
+
+    async fn read_reply(socket: &mut TcpStream) -> [u8; 10] {
+        let mut reply = [0u8; 10];
+        socket.read_exact(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn test_socks_login() {
+        let addr = socks_server(&[("alice", "secret")]).await;
+
+        // Logins are required, so a client that can't give one is turned away
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[5, 1, METHOD_NONE]).await.unwrap();
+        let mut choice = [0u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [5, METHOD_UNACCEPTABLE]);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[5, 2, METHOD_NONE, METHOD_PASSWORD]).await.unwrap();
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [5, METHOD_PASSWORD]);
+        client.write_all(&[1, 5, b'a', b'l', b'i', b'c', b'e', 5, b'g', b'u', b'e', b's', b's']).await.unwrap();
+        let mut status = [0u8; 2];
+        client.read_exact(&mut status).await.unwrap();
+        assert_eq!(status, [1, 1]);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[5, 1, METHOD_PASSWORD, 1, 5, b'a', b'l', b'i', b'c', b'e', 6, b's', b'e', b'c', b'r', b'e', b't']).await.unwrap();
+        client.read_exact(&mut choice).await.unwrap();
+        client.read_exact(&mut status).await.unwrap();
+        assert_eq!(status, [1, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_socks_requests_without_circuit() {
+        let addr = socks_server(&[]).await;
+
+        // A name goes to the exit as is; with no circuit the request fails cleanly
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[5, 1, METHOD_NONE]).await.unwrap();
+        let mut choice = [0u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [5, METHOD_NONE]);
+        let mut request = vec![5, CMD_CONNECT, 0, ATYP_DOMAIN, 11];
+        request.extend_from_slice(b"example.com");
+        request.extend_from_slice(&443u16.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        assert_eq!(read_reply(&mut client).await[..2], [5, REPLY_GENERAL_FAILURE]);
+
+        // BIND isn't offered
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[5, 1, METHOD_NONE, 5, 2, 0, ATYP_IPV4, 127, 0, 0, 1, 0, 80]).await.unwrap();
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(read_reply(&mut client).await[..2], [5, REPLY_COMMAND_NOT_SUPPORTED]);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[5, 1, METHOD_NONE, 5, CMD_CONNECT, 0, 9]).await.unwrap();
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(read_reply(&mut client).await[..2], [5, REPLY_ADDRESS_NOT_SUPPORTED]);
+    }
+
+    #[test]
+    fn test_refusal_codes() {
+        let refused = |reason| anyhow::Error::new(StreamRefused { reason, message: "Exit refused example.com:443".to_string() });
+        assert_eq!(refusal_code(&refused(EndReason::NotAllowed)), REPLY_NOT_ALLOWED);
+        assert_eq!(refusal_code(&refused(EndReason::Unresolved)), REPLY_HOST_UNREACHABLE);
+        assert_eq!(refusal_code(&refused(EndReason::Timeout)), REPLY_TTL_EXPIRED);
+        assert_eq!(refusal_code(&refused(EndReason::Refused)), REPLY_CONNECTION_REFUSED);
+        // Only the exit's reason counts, not what the message happens to say
+        assert_eq!(refusal_code(&anyhow!("Exit policy refuses; timed out")), REPLY_GENERAL_FAILURE);
+    }
+
+    #[test]
+    fn test_udp_request_parsing() {
+        let mut packet = vec![0, 0, 0, ATYP_IPV6];
+        packet.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        packet.extend_from_slice(&53u16.to_be_bytes());
+        packet.extend_from_slice(b"query");
+        assert_eq!(parse_udp_request(&packet), Some(("[::1]:53".to_string(), &b"query"[..])));
+
+        let mut packet = vec![0, 0, 0, ATYP_DOMAIN, 9];
+        packet.extend_from_slice(b"dns.local");
+        packet.extend_from_slice(&53u16.to_be_bytes());
+        assert_eq!(parse_udp_request(&packet), Some(("dns.local:53".to_string(), &b""[..])));
+
+        // Fragments and truncated headers are dropped
+        packet[2] = 1;
+        assert_eq!(parse_udp_request(&packet), None);
+        assert_eq!(parse_udp_request(&[0, 0, 0, ATYP_IPV4, 127, 0]), None);
+
+        let source: SocketAddr = "192.0.2.7:5353".parse().unwrap();
+        assert_eq!(encode_address(source), vec![ATYP_IPV4, 192, 0, 2, 7, 0x14, 0xe9]);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_goes_through_a_circuit() {
+        let (_, client, _) = spawn_node().await;
+        let router = Arc::new(loopback_router(&spawn_relays(3).await).await);
+        router.build_circuit(client.clone(), 3, None).await.unwrap();
+
+        let site = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let site_addr = site.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = site.accept().await.unwrap();
+            let mut request = vec![0u8; 1024];
+            let n = socket.read(&mut request).await.unwrap();
+            assert!(request[..n].starts_with(b"GET /page HTTP/1.1\r\n"));
+            socket.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello").await.unwrap();
+        });
+
+        let proxy = ProxyServer::new("127.0.0.1:0".parse().unwrap(), router).await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        tokio::spawn(async move { proxy.run().await });
+
+        let mut browser = tokio::net::TcpStream::connect(proxy_addr).await.unwrap();
+        let request = format!("GET http://{}/page HTTP/1.1\r\nHost: {}\r\n\r\n", site_addr, site_addr);
+        browser.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        browser.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("hello"));
+    }
+
+    /// Log in to a SOCKS5 server as `user` and send a request for `target`
+    async fn socks_request(proxy: SocketAddr, user: &str, command: u8, target: SocketAddr) -> (tokio::net::TcpStream, u8, SocketAddr) {
+        let mut socket = tokio::net::TcpStream::connect(proxy).await.unwrap();
+        let mut hello = vec![5, 1, 2, 1, user.len() as u8];
+        hello.extend_from_slice(user.as_bytes());
+        hello.extend_from_slice(&[1, b'x']);
+        socket.write_all(&hello).await.unwrap();
+        let mut answers = [0u8; 4];
+        socket.read_exact(&mut answers).await.unwrap();
+        assert_eq!(answers, [5, 2, 1, 0]);
+
+        let SocketAddr::V4(target) = target else { panic!("IPv4 targets only") };
+        let mut request = vec![5, command, 0, 1];
+        request.extend_from_slice(&target.ip().octets());
+        request.extend_from_slice(&target.port().to_be_bytes());
+        socket.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        socket.read_exact(&mut reply).await.unwrap();
+        let bound = SocketAddr::from(([reply[4], reply[5], reply[6], reply[7]], u16::from_be_bytes([reply[8], reply[9]])));
+        (socket, reply[1], bound)
+    }
+
+    #[tokio::test]
+    async fn test_socks_streams_and_datagrams() {
+        let (_, client, _) = spawn_node().await;
+        let router = Arc::new(loopback_router(&spawn_relays(3).await).await);
+        router.build_circuit(client.clone(), 3, None).await.unwrap();
+        router.build_circuit(client.clone(), 3, None).await.unwrap();
+
+        let echo_addr = echo_server().await;
+        let udp_echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_echo_addr = udp_echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            while let Ok((n, from)) = udp_echo.recv_from(&mut buf).await {
+                let _ = udp_echo.send_to(&buf[..n], from).await;
+            }
+        });
+
+        let proxy = Socks5Server::new("127.0.0.1:0".parse().unwrap(), router.clone(), ProxyMetrics::default()).await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        tokio::spawn(async move { proxy.run().await });
+
+        let (mut alice, status, _) = socks_request(proxy_addr, "alice", 1, echo_addr).await;
+        assert_eq!(status, 0);
+        alice.write_all(b"over socks").await.unwrap();
+        let mut reply = [0u8; 10];
+        alice.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"over socks");
+
+        // Each login gets a circuit of its own, so a third finds none left
+        let (_bob, status, _) = socks_request(proxy_addr, "bob", 1, echo_addr).await;
+        assert_eq!(status, 0);
+        let dirty = router.circuit_summaries().await.iter().filter(|circuit| circuit.dirty_secs.is_some()).count();
+        assert_eq!(dirty, 2);
+        let (_, status, _) = socks_request(proxy_addr, "carol", 1, echo_addr).await;
+        assert_eq!(status, 1);
+
+        // UDP goes out from the exit too, over alice's circuit
+        let (_control, status, relay) = socks_request(proxy_addr, "alice", 3, "0.0.0.0:0".parse().unwrap()).await;
+        assert_eq!(status, 0);
+        let app = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let SocketAddr::V4(target) = udp_echo_addr else { unreachable!() };
+        let mut datagram = vec![0, 0, 0, 1];
+        datagram.extend_from_slice(&target.ip().octets());
+        datagram.extend_from_slice(&target.port().to_be_bytes());
+        datagram.extend_from_slice(b"datagram");
+        app.send_to(&datagram, relay).await.unwrap();
+        let mut buf = [0u8; 1500];
+        let (n, _) = tokio::time::timeout(std::time::Duration::from_secs(10), app.recv_from(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(buf[..n], datagram[..]);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_keeps_connections_alive() {
+        let (_, client, _) = spawn_node().await;
+        let router = Arc::new(loopback_router(&spawn_relays(3).await).await);
+        router.build_circuit(client.clone(), 3, None).await.unwrap();
+
+        // Each site answers every request on one connection with its name
+        // and the body it was sent
+        let mut sites = Vec::new();
+        for name in ["first", "second"] {
+            let site = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            sites.push(site.local_addr().unwrap());
+            tokio::spawn(async move {
+                let (socket, _) = site.accept().await.unwrap();
+                let mut socket = crate::http::HttpReader::new(socket);
+                while let Some(head) = socket.read_request().await.unwrap() {
+                    let mut body = Vec::new();
+                    socket.copy_body(head.body().unwrap(), &mut body).await.unwrap();
+                    let reply = format!("{} {} {}", name, head.target, String::from_utf8(body).unwrap());
+                    let response = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", reply.len(), reply);
+                    socket.get_mut().write_all(response.as_bytes()).await.unwrap();
+                }
+            });
+        }
+
+        let proxy = ProxyServer::new("127.0.0.1:0".parse().unwrap(), router).await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        tokio::spawn(async move { proxy.run().await });
+
+        let mut browser = crate::http::HttpReader::new(tokio::net::TcpStream::connect(proxy_addr).await.unwrap());
+        let requests = [
+            (sites[0], "/a", "one"),
+            (sites[0], "/b", "two"),
+            (sites[1], "/c", "three"),
+        ];
+        for (site, path, body) in requests {
+            let request = format!("POST http://{}{} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}", site, path, site, body.len(), body);
+            // Dribble the request in pieces to the proxy
+            let (head, rest) = request.split_at(request.len() - 2);
+            let (start, end) = head.split_at(9);
+            for piece in [start, end, rest] {
+                browser.get_mut().write_all(piece.as_bytes()).await.unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            let response = browser.read_response().await.unwrap();
+            assert_eq!(response.status, 200);
+            let mut reply = Vec::new();
+            browser.copy_body(response.body("POST").unwrap(), &mut reply).await.unwrap();
+            let name = if site == sites[0] { "first" } else { "second" };
+            assert_eq!(String::from_utf8(reply).unwrap(), format!("{} {} {}", name, path, body));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_serves_freedom_sites() {
+        let relays = spawn_relays(4).await;
+        let client_router = Arc::new(loopback_router(&relays).await);
+        let service_router = Arc::new(loopback_router(&relays).await);
+        let (client_dht, client, _) = spawn_node().await;
+        let (_, host, _) = spawn_node().await;
+
+        // The site answers every request with an .fdom page
+        let site = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = site.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = site.accept().await {
+                tokio::spawn(async move {
+                    let mut socket = crate::http::HttpReader::new(socket);
+                    let head = socket.read_request().await.unwrap().unwrap();
+                    assert_eq!(head.target, "/index.fdom");
+                    // What the browser accepts comes along; its cookies don't
+                    assert_eq!(head.headers.get("accept"), Some("text/fdom"));
+                    assert_eq!(head.headers.get("cookie"), None);
+                    let page = r#"@page { title = "Hidden" } @section { @paragraph { "Served over a rendezvous" } }"#;
+                    let mut response = format!("HTTP/1.0 200 OK\r\nContent-Type: text/fdom\r\nContent-Length: {}\r\n\r\n", page.len());
+                    if head.method == "GET" {
+                        response.push_str(page);
+                    }
+                    socket.get_mut().write_all(response.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        let service = Arc::new(HiddenService::new(Arc::new(generate_identity()), service_router, host, target, 2));
+        client_dht.register_service(service.refresh().await.unwrap()).unwrap();
+
+        let resolver = Arc::new(FreedomResolver::new(client_dht, client.clone()));
+        let freedom_sites = Arc::new(FreedomClient::new(resolver, client_router.clone(), client, 2));
+        let proxy = ProxyServer::new("127.0.0.1:0".parse().unwrap(), client_router).await.unwrap().with_freedom_sites(freedom_sites);
+        let proxy_addr = proxy.local_addr().unwrap();
+        tokio::spawn(async move { proxy.run().await });
+
+        // Twice over one connection; the second request reuses the rendezvous
+        let mut browser = crate::http::HttpReader::new(tokio::net::TcpStream::connect(proxy_addr).await.unwrap());
+        for _ in 0..2 {
+            let request = format!(
+                "GET http://{}/index.fdom HTTP/1.1\r\nHost: {}\r\nAccept: text/fdom\r\nCookie: id=1\r\n\r\n",
+                service.domain(),
+                service.domain()
+            );
+            browser.get_mut().write_all(request.as_bytes()).await.unwrap();
+            let response = browser.read_response().await.unwrap();
+            assert_eq!(response.status, 200);
+            assert_eq!(response.headers.get("content-type"), Some("text/html; charset=utf-8"));
+            let mut page = Vec::new();
+            browser.copy_body(response.body("GET").unwrap(), &mut page).await.unwrap();
+            let page = String::from_utf8(page).unwrap();
+            assert!(page.contains("<title>Hidden</title>"), "{}", page);
+            assert!(page.contains("Served over a rendezvous"));
+        }
+
+        // HEAD reaches the site as HEAD and comes back without a body
+        let request = format!("HEAD http://{}/index.fdom HTTP/1.1\r\nAccept: text/fdom\r\n\r\n", service.domain());
+        browser.get_mut().write_all(request.as_bytes()).await.unwrap();
+        let response = browser.read_response().await.unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("content-type"), Some("text/html; charset=utf-8"));
+        assert_eq!(response.body("HEAD").unwrap(), BodyLength::Empty);
+
+        // Other methods are turned away, saying which ones would do
+        let request = format!("POST http://{}/form HTTP/1.1\r\nContent-Length: 0\r\n\r\n", service.domain());
+        browser.get_mut().write_all(request.as_bytes()).await.unwrap();
+        let response = browser.read_response().await.unwrap();
+        assert_eq!(response.status, 405);
+        assert_eq!(response.headers.get("allow"), Some("GET, HEAD"));
+
+        // CONNECT can't reach a .freedom name
+        let mut socket = tokio::net::TcpStream::connect(proxy_addr).await.unwrap();
+        let request = format!("CONNECT {}:443 HTTP/1.1\r\n\r\n", service.domain());
+        socket.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request"), "{}", response);
+    }
 }
@@ -0,0 +1,704 @@
+// Relay side of onion circuits
+// Answers circuit handshakes, removes this node's layer from incoming cells,
+// extends circuits to the next hop when a client asks and switches cells
+// between the inbound and outbound circuit once extended. Relays also serve
+// as introduction and rendezvous points for hidden services.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot, Mutex};
+use crate::cell::{self, Reassembler};
+use crate::handshake::{self, OnionKeys};
+use crate::exit::ExitPolicy;
+use crate::stream::ExitStreams;
+use crate::identity::Identity;
+use crate::onion::{CircuitTransport, Direction, HopLayer};
+use crate::protocol::{unpin_addr, NodeId, RoutingMessage};
+use crate::rendezvous::{self, MAX_PENDING_INTRODUCTIONS, POLL_WAIT, SERVICE_ANSWER_TIMEOUT};
+use crate::routing::{CircuitMessage, EndReason, RelayPayload, RendezvousCookie};
+use crate::wire::{ErrorCode, Frame, WireError};
+
+/// How long a circuit may go without cells before the relay tears it down
+pub const CIRCUIT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Most circuits one connection may have open through this relay
+pub const MAX_CIRCUITS_PER_REMOTE: usize = 1024;
+
+/// A circuit passing through this node, as seen from the inbound side
+struct RelayCircuit {
+    layer: HopLayer,
+    /// When a cell last came through
+    last_active: Instant,
+    /// Cells addressed to this hop that don't yet make up a whole message
+    reassembler: Reassembler,
+    /// Streams opened through this hop when it is the exit
+    streams: ExitStreams,
+    /// Where the circuit continues, once extended
+    next: Option<CircuitKey>,
+    /// Introductions for the hidden service that set this hop up as an
+    /// introduction point
+    introductions: Option<mpsc::Receiver<Vec<u8>>>,
+    rendezvous: Option<Rendezvous>,
+}
+
+/// Client cells on their way to a hidden service, and where its answer goes
+type Exchange = (Vec<Vec<u8>>, oneshot::Sender<Vec<Vec<u8>>>);
+
+/// What a waiting client circuit gets when the service joins: the service's
+/// handshake and the way to its circuit
+type Joined = (Vec<u8>, mpsc::Sender<Exchange>);
+
+/// This hop's part in a rendezvous between a client and a hidden service
+enum Rendezvous {
+    /// A client circuit waiting for the service to join
+    Waiting(oneshot::Receiver<Joined>),
+    /// A client circuit joined to a service: cells for later hops go to it
+    Client(mpsc::Sender<Exchange>),
+    /// A service circuit joined to a client
+    Service {
+        exchanges: mpsc::Receiver<Exchange>,
+        /// The exchange the service answers next
+        pending: Option<oneshot::Sender<Vec<Vec<u8>>>>,
+    },
+}
+
+/// Circuits are keyed by the connection they arrived on plus the id the
+/// previous hop chose, so two peers can't collide on an id
+type CircuitKey = (SocketAddr, u32);
+
+pub struct Relay<T: CircuitTransport> {
+    identity: Arc<Identity>,
+    onion_keys: OnionKeys,
+    transport: Arc<T>,
+    circuits: RwLock<HashMap<CircuitKey, Arc<Mutex<RelayCircuit>>>>,
+    /// Outbound circuit -> the inbound circuit it continues
+    outbound: RwLock<HashMap<CircuitKey, CircuitKey>>,
+    next_circuit_id: AtomicU32,
+    /// Where clients may open streams to from this node, if anywhere
+    exit_policy: Option<ExitPolicy>,
+    /// Hidden services introduced here -> their introduction circuit
+    intro_points: RwLock<HashMap<NodeId, mpsc::Sender<Vec<u8>>>>,
+    /// Client circuits waiting here for a hidden service, by cookie
+    rendezvous_points: RwLock<HashMap<RendezvousCookie, oneshot::Sender<Joined>>>,
+}
+
+impl<T: CircuitTransport> Relay<T> {
+    pub fn new(identity: Arc<Identity>, transport: Arc<T>) -> Self {
+        Self {
+            identity,
+            onion_keys: OnionKeys::generate(),
+            transport,
+            circuits: RwLock::new(HashMap::new()),
+            outbound: RwLock::new(HashMap::new()),
+            next_circuit_id: AtomicU32::new(rand::random()),
+            exit_policy: None,
+            intro_points: RwLock::new(HashMap::new()),
+            rendezvous_points: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Let clients open streams through this relay to whatever `policy` allows
+    pub fn with_exit_policy(mut self, policy: Option<ExitPolicy>) -> Self {
+        self.exit_policy = policy;
+        self
+    }
+
+    /// The keys circuit handshakes are answered with; the current one goes
+    /// in the node's descriptor
+    pub fn onion_keys(&self) -> &OnionKeys {
+        &self.onion_keys
+    }
+
+    /// Handle a circuit message from `remote` and build the reply frame
+    pub async fn handle(&self, message: RoutingMessage, remote: SocketAddr) -> Frame {
+        let result = match message {
+            RoutingMessage::BuildCircuit { circuit_id, handshake } => self.create(remote, circuit_id, &handshake),
+            RoutingMessage::RelayData { circuit_id, cells } => self.relay(remote, circuit_id, &cells).await,
+            RoutingMessage::DestroyCircuit { circuit_id } => {
+                self.destroy((remote, circuit_id)).await;
+                Ok(Frame::Ack)
+            }
+            RoutingMessage::CircuitCreated { .. } => Err(WireError::new(
+                ErrorCode::UnexpectedMessage,
+                "CircuitCreated is a reply, not a request",
+            )),
+        };
+        result.unwrap_or_else(Frame::Error)
+    }
+
+    fn create(&self, remote: SocketAddr, circuit_id: u32, onion_skin: &[u8]) -> Result<Frame, WireError> {
+        let (reply, keys) = handshake::respond(&self.identity.node_id(), &self.onion_keys, onion_skin)
+            .map_err(|e| WireError::new(ErrorCode::Malformed, e.to_string()))?;
+
+        let mut circuits = self.circuits.write().unwrap();
+        if circuits.contains_key(&(remote, circuit_id)) {
+            return Err(WireError::new(ErrorCode::Internal, format!("Circuit {} already exists", circuit_id)));
+        }
+        if circuits.keys().filter(|(addr, _)| *addr == remote).count() >= MAX_CIRCUITS_PER_REMOTE {
+            return Err(WireError::new(ErrorCode::Internal, format!("Too many circuits from {}", remote)));
+        }
+        circuits.insert((remote, circuit_id), Arc::new(Mutex::new(RelayCircuit {
+            layer: HopLayer::new(&keys),
+            last_active: Instant::now(),
+            reassembler: Reassembler::default(),
+            streams: ExitStreams::default(),
+            next: None,
+            introductions: None,
+            rendezvous: None,
+        })));
+        Ok(Frame::Routing(RoutingMessage::CircuitCreated { circuit_id, handshake: reply }))
+    }
+
+    async fn relay(&self, remote: SocketAddr, circuit_id: u32, cells: &[Vec<u8>]) -> Result<Frame, WireError> {
+        let circuit = self
+            .circuits
+            .read()
+            .unwrap()
+            .get(&(remote, circuit_id))
+            .cloned()
+            .ok_or_else(|| WireError::new(ErrorCode::NotFound, format!("Unknown circuit {}", circuit_id)))?;
+
+        // Cells on one circuit are handled in order so the nonce counters line up
+        let mut circuit = circuit.lock().await;
+        circuit.last_active = Instant::now();
+        let mut replies = Vec::new();
+        let mut relayed = Vec::new();
+        // Replies get the same padding as the cells they answer, so both
+        // directions on a link carry cells of one size
+        let mut reply_len = 0;
+        for cell in cells {
+            let plaintext = circuit.layer.open(Direction::Forward, cell).map_err(malformed)?;
+            match RelayPayload::decode(&plaintext).map_err(malformed)? {
+                RelayPayload::Recognized(cell) => {
+                    reply_len = reply_len.max(plaintext.len());
+                    if let Some((_, message)) = circuit.reassembler.push(cell).map_err(malformed)? {
+                        let message = CircuitMessage::decode(&message).map_err(malformed)?;
+                        if let Some(reply) = self.handle_cell(&mut circuit, (remote, circuit_id), message).await? {
+                            let cells = cell::fragment(reply.stream_id(), &reply.encode());
+                            replies.extend(cells.into_iter().map(RelayPayload::Recognized));
+                        }
+                    }
+                }
+                RelayPayload::Relayed(inner) => relayed.push(inner),
+            }
+        }
+        // As the exit, answer with whatever the streams have ready
+        if reply_len > 0 {
+            for message in circuit.streams.poll() {
+                let cells = cell::fragment(message.stream_id(), &message.encode());
+                replies.extend(cells.into_iter().map(RelayPayload::Recognized));
+            }
+        }
+        if !relayed.is_empty() {
+            replies.extend(self.switch(&circuit, relayed).await?.into_iter().map(RelayPayload::Relayed));
+        }
+
+        let cells = replies
+            .iter()
+            .map(|reply| circuit.layer.seal(Direction::Backward, &reply.encode_padded(reply_len)))
+            .collect();
+        Ok(Frame::Routing(RoutingMessage::RelayData { circuit_id, cells }))
+    }
+
+    /// Pass cells meant for later hops down the outbound circuit and return
+    /// the reply cells, which still carry the later hops' layers
+    async fn switch(&self, circuit: &RelayCircuit, cells: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, WireError> {
+        if let Some(Rendezvous::Client(service)) = &circuit.rendezvous {
+            return Self::exchange(service, cells).await;
+        }
+        let (next_addr, next_id) = circuit
+            .next
+            .ok_or_else(|| WireError::new(ErrorCode::UnexpectedMessage, "Circuit ends at this hop"))?;
+        let request = RoutingMessage::RelayData { circuit_id: next_id, cells };
+        match self.transport.send(&next_addr.to_string(), request).await {
+            Ok(Some(RoutingMessage::RelayData { cells, .. })) => Ok(cells),
+            Ok(other) => Err(WireError::new(
+                ErrorCode::Internal,
+                format!("{} answered RelayData with {:?}", next_addr, other),
+            )),
+            Err(e) => Err(WireError::new(ErrorCode::Internal, format!("Relay to {} failed: {}", next_addr, e))),
+        }
+    }
+
+    /// Hand a joined client's cells to the service and wait for its answer
+    async fn exchange(service: &mpsc::Sender<Exchange>, cells: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, WireError> {
+        let gone = || WireError::new(ErrorCode::NotFound, "The service has left the rendezvous");
+        let (answer, answered) = oneshot::channel();
+        service.send((cells, answer)).await.map_err(|_| gone())?;
+        match tokio::time::timeout(SERVICE_ANSWER_TIMEOUT, answered).await {
+            Ok(Ok(cells)) => Ok(cells),
+            Ok(Err(_)) => Err(gone()),
+            Err(_) => Err(WireError::new(ErrorCode::Internal, "The service did not answer in time")),
+        }
+    }
+
+    /// Tear down circuits that have carried no cells for `max_idle`, telling
+    /// the next hop of each. A circuit busy with a cell is never idle.
+    pub async fn expire_idle_circuits(&self, max_idle: Duration) -> usize {
+        let idle: Vec<CircuitKey> = self
+            .circuits
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, circuit)| circuit.try_lock().is_ok_and(|circuit| circuit.last_active.elapsed() > max_idle))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in &idle {
+            self.destroy(*key).await;
+        }
+        idle.len()
+    }
+
+    /// Tear down the circuit `key` belongs to and tell the hop on the other
+    /// side. `key` may name either the inbound or the outbound circuit.
+    async fn destroy(&self, key: CircuitKey) {
+        let removed = self.circuits.write().unwrap().remove(&key);
+        let notify = match removed {
+            // Destroy from upstream: carry it on downstream
+            Some(circuit) => {
+                let next = circuit.lock().await.next;
+                if let Some(next) = next {
+                    self.outbound.write().unwrap().remove(&next);
+                }
+                next
+            }
+            // Destroy from downstream: carry it back upstream
+            None => {
+                let inbound = self.outbound.write().unwrap().remove(&key);
+                if let Some(inbound) = inbound {
+                    self.circuits.write().unwrap().remove(&inbound);
+                }
+                inbound
+            }
+        };
+
+        if let Some((addr, circuit_id)) = notify {
+            let message = RoutingMessage::DestroyCircuit { circuit_id };
+            if let Err(e) = self.transport.send(&addr.to_string(), message).await {
+                eprintln!("Could not pass DestroyCircuit {} on to {}: {}", circuit_id, addr, e);
+            }
+        }
+    }
+
+    /// Act on a message addressed to this hop; some messages take no reply
+    async fn handle_cell(
+        &self,
+        circuit: &mut RelayCircuit,
+        inbound: CircuitKey,
+        message: CircuitMessage,
+    ) -> Result<Option<CircuitMessage>, WireError> {
+        match message {
+            CircuitMessage::Extend { addr, handshake } => {
+                if circuit.next.is_some() {
+                    return Err(WireError::new(ErrorCode::UnexpectedMessage, "Circuit is already extended"));
+                }
+                let next_addr: SocketAddr = unpin_addr(&addr)
+                    .ok()
+                    .and_then(|(_, plain)| plain.parse().ok())
+                    .ok_or_else(|| WireError::new(ErrorCode::Malformed, format!("Bad relay address {}", addr)))?;
+                let next_id = self.next_circuit_id.fetch_add(1, Ordering::Relaxed);
+                let request = RoutingMessage::BuildCircuit { circuit_id: next_id, handshake };
+                match self.transport.send(&addr, request).await {
+                    Ok(Some(RoutingMessage::CircuitCreated { handshake, .. })) => {
+                        circuit.next = Some((next_addr, next_id));
+                        self.outbound.write().unwrap().insert((next_addr, next_id), inbound);
+                        Ok(Some(CircuitMessage::Extended { handshake }))
+                    }
+                    Ok(other) => Err(WireError::new(
+                        ErrorCode::Internal,
+                        format!("{} answered BuildCircuit with {:?}", addr, other),
+                    )),
+                    Err(e) => Err(WireError::new(ErrorCode::Internal, format!("Extend to {} failed: {}", addr, e))),
+                }
+            }
+            CircuitMessage::EstablishIntro { service, signature } => {
+                let signed = rendezvous::intro_signing_bytes(&self.identity.public(), &service);
+                if !service.verify(&signed, &signature) {
+                    return Err(WireError::new(ErrorCode::Malformed, "Introduction point request is not signed by the service"));
+                }
+                if circuit.introductions.is_some() {
+                    return Err(WireError::new(ErrorCode::UnexpectedMessage, "Circuit is already an introduction circuit"));
+                }
+                // A newer circuit for the same service takes over from the old one
+                let (sender, receiver) = mpsc::channel(MAX_PENDING_INTRODUCTIONS);
+                self.intro_points.write().unwrap().insert(service.node_id(), sender);
+                circuit.introductions = Some(receiver);
+                Ok(Some(CircuitMessage::IntroEstablished))
+            }
+            CircuitMessage::Introduce { service, request } => {
+                let mut intro_points = self.intro_points.write().unwrap();
+                intro_points.retain(|_, sender| !sender.is_closed());
+                let accepted = intro_points.get(&service).is_some_and(|sender| sender.try_send(request).is_ok());
+                Ok(Some(CircuitMessage::IntroduceAck { accepted }))
+            }
+            CircuitMessage::FetchIntroductions => {
+                let introductions = circuit
+                    .introductions
+                    .as_mut()
+                    .ok_or_else(|| WireError::new(ErrorCode::UnexpectedMessage, "Circuit is not an introduction circuit"))?;
+                let mut requests = Vec::new();
+                match tokio::time::timeout(POLL_WAIT, introductions.recv()).await {
+                    Ok(Some(request)) => requests.push(request),
+                    Ok(None) => return Err(WireError::new(ErrorCode::NotFound, "A newer circuit took over these introductions")),
+                    Err(_) => {}
+                }
+                while let Ok(request) = introductions.try_recv() {
+                    requests.push(request);
+                }
+                Ok(Some(CircuitMessage::Introductions { requests }))
+            }
+            CircuitMessage::EstablishRendezvous { cookie } => {
+                if circuit.rendezvous.is_some() {
+                    return Err(WireError::new(ErrorCode::UnexpectedMessage, "Circuit is already at a rendezvous"));
+                }
+                let mut rendezvous_points = self.rendezvous_points.write().unwrap();
+                // Forget cookies whose client circuit has gone
+                rendezvous_points.retain(|_, waiting| !waiting.is_closed());
+                if rendezvous_points.contains_key(&cookie) {
+                    return Err(WireError::new(ErrorCode::UnexpectedMessage, "Rendezvous cookie is already in use"));
+                }
+                let (sender, joined) = oneshot::channel();
+                rendezvous_points.insert(cookie, sender);
+                circuit.rendezvous = Some(Rendezvous::Waiting(joined));
+                Ok(Some(CircuitMessage::RendezvousEstablished))
+            }
+            CircuitMessage::AwaitRendezvous => {
+                let Some(Rendezvous::Waiting(mut joined)) = circuit.rendezvous.take() else {
+                    return Err(WireError::new(ErrorCode::UnexpectedMessage, "No rendezvous is waiting on this circuit"));
+                };
+                match tokio::time::timeout(POLL_WAIT, &mut joined).await {
+                    Ok(Ok((handshake, service))) => {
+                        circuit.rendezvous = Some(Rendezvous::Client(service));
+                        Ok(Some(CircuitMessage::RendezvousJoined { handshake: Some(handshake) }))
+                    }
+                    Ok(Err(_)) => Err(WireError::new(ErrorCode::NotFound, "The rendezvous was abandoned")),
+                    Err(_) => {
+                        circuit.rendezvous = Some(Rendezvous::Waiting(joined));
+                        Ok(Some(CircuitMessage::RendezvousJoined { handshake: None }))
+                    }
+                }
+            }
+            CircuitMessage::JoinRendezvous { cookie, handshake } => {
+                if circuit.rendezvous.is_some() {
+                    return Err(WireError::new(ErrorCode::UnexpectedMessage, "Circuit is already at a rendezvous"));
+                }
+                let waiting = self
+                    .rendezvous_points
+                    .write()
+                    .unwrap()
+                    .remove(&cookie)
+                    .ok_or_else(|| WireError::new(ErrorCode::NotFound, "No client is waiting under that cookie"))?;
+                let (sender, exchanges) = mpsc::channel(1);
+                waiting
+                    .send((handshake, sender))
+                    .map_err(|_| WireError::new(ErrorCode::NotFound, "The client has left the rendezvous"))?;
+                circuit.rendezvous = Some(Rendezvous::Service { exchanges, pending: None });
+                Ok(Some(CircuitMessage::RendezvousEstablished))
+            }
+            CircuitMessage::RendezvousData { cells } => {
+                let Some(Rendezvous::Service { exchanges, pending }) = &mut circuit.rendezvous else {
+                    return Err(WireError::new(ErrorCode::UnexpectedMessage, "Circuit has not joined a rendezvous"));
+                };
+                if let Some(answer) = pending.take() {
+                    let _ = answer.send(cells);
+                }
+                match tokio::time::timeout(POLL_WAIT, exchanges.recv()).await {
+                    Ok(Some((cells, answer))) => {
+                        *pending = Some(answer);
+                        Ok(Some(CircuitMessage::RendezvousData { cells }))
+                    }
+                    Ok(None) => Err(WireError::new(ErrorCode::NotFound, "The client has left the rendezvous")),
+                    Err(_) => Ok(Some(CircuitMessage::RendezvousData { cells: Vec::new() })),
+                }
+            }
+            message @ (CircuitMessage::Begin { .. }
+            | CircuitMessage::BeginDatagrams { .. }
+            | CircuitMessage::Data { .. }
+            | CircuitMessage::End { .. }
+            | CircuitMessage::Sendme { .. }) => match &self.exit_policy {
+                Some(policy) => circuit
+                    .streams
+                    .handle(message, policy)
+                    .map_err(|e| WireError::new(ErrorCode::UnexpectedMessage, e)),
+                None => Ok(Some(CircuitMessage::End {
+                    stream_id: message.stream_id(),
+                    reason: EndReason::NotAllowed,
+                    detail: "This relay is not an exit".to_string(),
+                })),
+            },
+            other => Err(WireError::new(
+                ErrorCode::UnexpectedMessage,
+                format!("Relays do not handle {:?}", other),
+            )),
+        }
+    }
+}
+
+fn malformed(reason: String) -> WireError {
+    WireError::new(ErrorCode::Malformed, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::generate_identity;
+    use crate::onion::{CircuitHop, ClientCircuit, OnionRouter};
+    use crate::path::{PathRules, RelayInfo};
+    use anyhow::{anyhow, Result};
+    use std::future::Future;
+    use std::pin::Pin;
+
+    /// Relays wired together in memory; each link knows its own address so
+    /// the receiving relay sees where a message came from
+    #[derive(Default)]
+    struct MemoryNetwork {
+        relays: RwLock<HashMap<String, Arc<Relay<MemoryLink>>>>,
+        /// Size of every relay cell sent, by destination
+        cell_sizes: RwLock<HashMap<String, Vec<usize>>>,
+    }
+
+    type BoxedReply<'a> = Pin<Box<dyn Future<Output = Result<Option<RoutingMessage>>> + Send + 'a>>;
+
+    struct MemoryLink {
+        network: Arc<MemoryNetwork>,
+        from: SocketAddr,
+    }
+
+    impl CircuitTransport for MemoryLink {
+        // Relays call back into the network when extending, so the future is
+        // boxed to give the recursion a concrete type
+        #[allow(refining_impl_trait)]
+        fn send(&self, addr: &str, message: RoutingMessage) -> BoxedReply<'_> {
+            let addr = match unpin_addr(addr) {
+                Ok((_, plain)) => plain,
+                Err(e) => return Box::pin(async move { Err(anyhow!(e)) }),
+            };
+            let relay = self.network.relays.read().unwrap().get(addr).cloned();
+            if let RoutingMessage::RelayData { cells, .. } = &message {
+                let mut sizes = self.network.cell_sizes.write().unwrap();
+                sizes.entry(addr.to_string()).or_default().extend(cells.iter().map(Vec::len));
+            }
+            let addr = addr.to_string();
+            Box::pin(async move {
+                let relay = relay.ok_or_else(|| anyhow!("Unknown relay {}", addr))?;
+                match relay.handle(message, self.from).await {
+                    Frame::Routing(reply) => Ok(Some(reply)),
+                    Frame::Ack => Ok(None),
+                    Frame::Error(e) => Err(e.into()),
+                    other => Err(anyhow!("Unexpected reply {:?}", other)),
+                }
+            })
+        }
+    }
+
+    fn addr(n: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], n))
+    }
+
+    fn link(network: &Arc<MemoryNetwork>, n: u16) -> MemoryLink {
+        MemoryLink { network: network.clone(), from: addr(n) }
+    }
+
+    fn add_relay(network: &Arc<MemoryNetwork>, n: u16) -> CircuitHop {
+        let identity = Arc::new(generate_identity());
+        let public = identity.public();
+        let relay = Arc::new(Relay::new(identity, Arc::new(link(network, n))));
+        let onion_key = relay.onion_keys().public();
+        network.relays.write().unwrap().insert(addr(n).to_string(), relay);
+        CircuitHop { addr: addr(n).to_string(), identity: public, onion_key }
+    }
+
+    fn relay(network: &Arc<MemoryNetwork>, hop: &CircuitHop) -> Arc<Relay<MemoryLink>> {
+        network.relays.read().unwrap()[&hop.addr].clone()
+    }
+
+    fn circuit_count<T: CircuitTransport>(relay: &Relay<T>) -> usize {
+        relay.circuits.read().unwrap().len()
+    }
+
+    #[tokio::test]
+    async fn test_extend_once() {
+        let network = Arc::new(MemoryNetwork::default());
+        let hops = vec![add_relay(&network, 1), add_relay(&network, 2)];
+        let third = add_relay(&network, 3);
+        let client = link(&network, 100);
+
+        let mut circuit = ClientCircuit::build(&client, &hops).await.unwrap();
+        assert_eq!(circuit.len(), 2);
+        assert_eq!(circuit_count(&relay(&network, &hops[0])), 1);
+
+        // The guard already extended this circuit and refuses to do it again
+        let (_, onion_skin) = crate::handshake::ClientHandshake::start(&third.identity.node_id(), &third.onion_key);
+        let extend = CircuitMessage::Extend { addr: third.addr.clone(), handshake: onion_skin };
+        assert!(circuit.send(&client, 0, &extend).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cells_switch_through_middle_hops() {
+        let network = Arc::new(MemoryNetwork::default());
+        let hops = vec![add_relay(&network, 1), add_relay(&network, 2), add_relay(&network, 3)];
+        let client = link(&network, 100);
+
+        // Reaching the exit means the entry and middle switched the Extend cells
+        let mut circuit = ClientCircuit::build(&client, &hops).await.unwrap();
+        assert_eq!(circuit.len(), 3);
+        for hop in &hops {
+            assert_eq!(circuit_count(&relay(&network, hop)), 1);
+        }
+
+        // A cell for the middle hop passes the entry and its reply comes back
+        let (_, onion_skin) = crate::handshake::ClientHandshake::start(&hops[2].identity.node_id(), &hops[2].onion_key);
+        let extend = CircuitMessage::Extend { addr: hops[2].addr.clone(), handshake: onion_skin };
+        let err = circuit.send(&client, 1, &extend).await.unwrap_err();
+        assert!(err.to_string().contains("already extended"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_closing_a_circuit_destroys_every_hop() {
+        let network = Arc::new(MemoryNetwork::default());
+        let router = OnionRouter::new().with_path_rules(PathRules { distinct_subnets: false });
+        let hops = vec![add_relay(&network, 1), add_relay(&network, 2), add_relay(&network, 3)];
+        for hop in &hops {
+            router.register_relay(RelayInfo::new(hop.clone())).await;
+        }
+        let client = Arc::new(link(&network, 100));
+
+        let circuit_id = router.build_circuit(client.clone(), 3, None).await.unwrap();
+        assert!(hops.iter().all(|hop| circuit_count(&relay(&network, hop)) == 1));
+        router.close_circuit(client.as_ref(), &circuit_id).await.unwrap();
+        assert!(hops.iter().all(|hop| circuit_count(&relay(&network, hop)) == 0));
+        let summaries = router.circuit_summaries().await;
+        assert_eq!(summaries.iter().find(|summary| summary.circuit_id == circuit_id).unwrap().state, "Closed");
+        assert_eq!(router.prune_closed().await, 1);
+
+        // A build that fails part way tears down the hops it got through
+        let unreachable = CircuitHop { addr: addr(9).to_string(), identity: generate_identity().public(), onion_key: [9; 32] };
+        let partial = vec![hops[0].clone(), hops[1].clone(), unreachable];
+        assert!(ClientCircuit::build(client.as_ref(), &partial).await.is_err());
+        assert!(hops.iter().all(|hop| circuit_count(&relay(&network, hop)) == 0));
+    }
+
+    #[tokio::test]
+    async fn test_destroy_travels_forward() {
+        let network = Arc::new(MemoryNetwork::default());
+        let hops = vec![add_relay(&network, 1), add_relay(&network, 2), add_relay(&network, 3)];
+        let client = link(&network, 100);
+        let circuit = ClientCircuit::build(&client, &hops).await.unwrap();
+
+        let destroy = RoutingMessage::DestroyCircuit { circuit_id: circuit.circuit_id };
+        assert!(client.send(&circuit.entry, destroy).await.unwrap().is_none());
+        for hop in &hops {
+            assert_eq!(circuit_count(&relay(&network, hop)), 0);
+            assert!(relay(&network, hop).outbound.read().unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_destroy_travels_backward() {
+        let network = Arc::new(MemoryNetwork::default());
+        let hops = vec![add_relay(&network, 1), add_relay(&network, 2), add_relay(&network, 3)];
+        let client = link(&network, 100);
+        ClientCircuit::build(&client, &hops).await.unwrap();
+
+        // The exit tears down its end; the middle carries it back to the entry
+        let middle = relay(&network, &hops[1]);
+        let (exit_addr, exit_id) = *middle.outbound.read().unwrap().keys().next().unwrap();
+        assert_eq!(exit_addr, addr(3));
+        let exit_link = link(&network, 3);
+        let destroy = RoutingMessage::DestroyCircuit { circuit_id: exit_id };
+        assert!(exit_link.send(&hops[1].addr, destroy).await.unwrap().is_none());
+
+        assert_eq!(circuit_count(&relay(&network, &hops[0])), 0);
+        assert_eq!(circuit_count(&middle), 0);
+        assert_eq!(circuit_count(&relay(&network, &hops[2])), 1);
+    }
+
+    #[tokio::test]
+    async fn test_idle_circuits_are_destroyed() {
+        let network = Arc::new(MemoryNetwork::default());
+        let hops = vec![add_relay(&network, 1), add_relay(&network, 2), add_relay(&network, 3)];
+        let client = link(&network, 100);
+        ClientCircuit::build(&client, &hops).await.unwrap();
+
+        let entry = relay(&network, &hops[0]);
+        assert_eq!(entry.expire_idle_circuits(CIRCUIT_IDLE_TIMEOUT).await, 0);
+        assert!(hops.iter().all(|hop| circuit_count(&relay(&network, hop)) == 1));
+
+        // The entry's sweep carries the teardown on to the later hops
+        assert_eq!(entry.expire_idle_circuits(Duration::ZERO).await, 1);
+        assert!(hops.iter().all(|hop| circuit_count(&relay(&network, hop)) == 0));
+    }
+
+    #[tokio::test]
+    async fn test_padding_keeps_circuits_alive() {
+        let network = Arc::new(MemoryNetwork::default());
+        let hops = vec![add_relay(&network, 1), add_relay(&network, 2), add_relay(&network, 3)];
+        let client = link(&network, 100);
+        let mut circuit = ClientCircuit::build(&client, &hops).await.unwrap();
+        network.cell_sizes.write().unwrap().clear();
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        circuit.send_padding(&client, 2).await.unwrap();
+
+        // Every hop passed the cell on, so none of them counts the circuit as idle
+        let sizes = network.cell_sizes.read().unwrap().clone();
+        for hop in &hops {
+            assert_eq!(sizes[&hop.addr].len(), 1);
+            assert_eq!(relay(&network, hop).expire_idle_circuits(Duration::from_millis(40)).await, 0);
+        }
+        assert!(circuit.send_padding(&client, 3).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_circuits_per_remote_are_capped() {
+        let network = Arc::new(MemoryNetwork::default());
+        let hop = add_relay(&network, 1);
+        let relay = relay(&network, &hop);
+        let build = |circuit_id| {
+            let (_, handshake) = crate::handshake::ClientHandshake::start(&hop.identity.node_id(), &hop.onion_key);
+            RoutingMessage::BuildCircuit { circuit_id, handshake }
+        };
+
+        for circuit_id in 0..MAX_CIRCUITS_PER_REMOTE as u32 {
+            assert!(matches!(relay.handle(build(circuit_id), addr(100)).await, Frame::Routing(_)));
+        }
+        match relay.handle(build(u32::MAX), addr(100)).await {
+            Frame::Error(e) => assert!(e.message.contains("Too many circuits"), "{}", e.message),
+            other => panic!("unexpected reply: {:?}", other),
+        }
+
+        // Other connections are not held back by a busy one
+        assert!(matches!(relay.handle(build(u32::MAX), addr(101)).await, Frame::Routing(_)));
+    }
+
+    #[tokio::test]
+    async fn test_large_messages_span_fixed_size_cells() {
+        let network = Arc::new(MemoryNetwork::default());
+        let hops = vec![add_relay(&network, 1), add_relay(&network, 2), add_relay(&network, 3)];
+        let client = link(&network, 100);
+        let mut circuit = ClientCircuit::build(&client, &hops).await.unwrap();
+        network.cell_sizes.write().unwrap().clear();
+
+        // Cells for different hops, small and large
+        let (_, onion_skin) = crate::handshake::ClientHandshake::start(&hops[2].identity.node_id(), &hops[2].onion_key);
+        let extend = CircuitMessage::Extend { addr: hops[2].addr.clone(), handshake: onion_skin };
+        assert!(circuit.send(&client, 0, &extend).await.is_err());
+
+        // An oversized handshake only fails once the exit has reassembled
+        // every cell of it and passed it on
+        let extend = CircuitMessage::Extend { addr: hops[0].addr.clone(), handshake: vec![7; 4 * cell::CELL_LEN] };
+        let err = circuit.send(&client, 2, &extend).await.unwrap_err();
+        assert!(err.to_string().contains("Onion skin must be"), "{}", err);
+
+        // Whatever was sent, every cell on a given link had the same size
+        let sizes = network.cell_sizes.read().unwrap();
+        for hop in &hops {
+            let link = &sizes[&hop.addr];
+            assert!(link.iter().all(|&size| size == link[0]), "{}: {:?}", hop.addr, link);
+        }
+        assert!(sizes[&hops[0].addr].len() > 4);
+    }
+}
@@ -0,0 +1,506 @@
+// Hidden services
+// A host keeps introduction circuits open to a few relays and publishes
+// them, signed by its service key, in the DHT. A client picks a rendezvous
+// relay, asks the host through an introduction point to meet it there, and
+// the rendezvous relay joins the two circuits. Each side only talks to its
+// own circuit, so neither learns where the other is. Streams then run end
+// to end under keys from a handshake with the service key.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, Result};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+use crate::cell::{self, Reassembler};
+use crate::exit::ExitPolicy;
+use crate::handshake::{self, ClientHandshake, HopKeys, OnionKeys};
+use crate::identity::{Identity, PublicIdentity};
+use crate::onion::{CircuitHop, CircuitTransport, ClientCircuit, Direction, HopLayer, OnionRouter};
+use crate::protocol::{unix_now, RoutingMessage, ServiceDescriptor, DESCRIPTOR_TTL};
+use crate::routing::{CircuitMessage, EndReason, RelayPayload, RendezvousCookie};
+use crate::stream::{ExitStreams, StreamMux};
+
+/// Introduction points a service keeps open
+pub const INTRO_POINTS: usize = 3;
+
+/// Introductions a relay holds for a service that hasn't collected them
+pub const MAX_PENDING_INTRODUCTIONS: usize = 16;
+
+/// How long a relay holds a poll open waiting for something to answer with
+pub const POLL_WAIT: Duration = Duration::from_secs(5);
+
+/// How long a rendezvous relay waits for the service to answer a client
+pub const SERVICE_ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a client waits for the service to come to the rendezvous
+pub const RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A rendezvous with no open streams is dropped after this long without
+/// hearing from the client
+pub const SESSION_IDLE: Duration = Duration::from_secs(5 * 60);
+
+/// How far the time in an introduction may be from the service's clock
+pub const INTRO_MAX_SKEW: Duration = Duration::from_secs(5 * 60);
+
+/// Rendezvous circuits a service builds at once; introductions that arrive
+/// while this many are under way are dropped
+pub const MAX_RENDEZVOUS_BUILDS: usize = 8;
+
+const INTRO_SIGNING_CONTEXT: &[u8] = b"freedom-intro-point-v1";
+
+/// What a service signs to make the relay `intro` its introduction point
+pub fn intro_signing_bytes(intro: &PublicIdentity, service: &PublicIdentity) -> Vec<u8> {
+    let mut out = INTRO_SIGNING_CONTEXT.to_vec();
+    out.extend_from_slice(intro.as_bytes());
+    out.extend_from_slice(service.as_bytes());
+    out
+}
+
+/// Sealed to the service key and passed on by the introduction point,
+/// which can't read it
+#[derive(Debug, Serialize, Deserialize)]
+struct IntroRequest {
+    rendezvous: CircuitHop,
+    cookie: RendezvousCookie,
+    /// Client half of the end-to-end handshake with the service key
+    handshake: Vec<u8>,
+    /// Unix time the client sent it; the service only remembers recent
+    /// requests, so it refuses the rest as possible replays
+    sent_at: u64,
+}
+
+/// Meet the service `descriptor` describes at a rendezvous relay and return
+/// a way to open streams to it
+pub async fn connect<T: CircuitTransport + 'static>(
+    router: &OnionRouter,
+    transport: Arc<T>,
+    descriptor: &ServiceDescriptor,
+    num_hops: usize,
+) -> Result<StreamMux> {
+    descriptor.verify()?;
+    let (mut circuit, rendezvous) = router
+        .build_private_circuit(transport.as_ref(), num_hops, None)
+        .await
+        .map_err(|e| anyhow!(e))?;
+    match meet(router, transport.as_ref(), descriptor, num_hops, &mut circuit, rendezvous).await {
+        Ok(()) => Ok(StreamMux::spawn(circuit, transport)),
+        Err(e) => {
+            circuit.destroy(transport.as_ref()).await;
+            Err(e)
+        }
+    }
+}
+
+async fn meet<T: CircuitTransport>(
+    router: &OnionRouter,
+    transport: &T,
+    descriptor: &ServiceDescriptor,
+    num_hops: usize,
+    circuit: &mut ClientCircuit,
+    rendezvous: CircuitHop,
+) -> Result<()> {
+    let last = circuit.len() - 1;
+    let cookie: RendezvousCookie = rand::random();
+    match circuit.send(transport, last, &CircuitMessage::EstablishRendezvous { cookie }).await? {
+        (_, CircuitMessage::RendezvousEstablished) => {}
+        (_, other) => return Err(anyhow!("{} answered EstablishRendezvous with {:?}", rendezvous.addr, other)),
+    }
+
+    let (handshake, onion_skin) = ClientHandshake::start(&descriptor.service.node_id(), &descriptor.onion_key);
+    let request = IntroRequest { rendezvous, cookie, handshake: onion_skin, sent_at: unix_now() };
+    let request = handshake::seal_to(&descriptor.onion_key, &bincode::serialize(&request)?);
+    introduce(router, transport, descriptor, num_hops, request).await?;
+
+    let deadline = Instant::now() + RENDEZVOUS_TIMEOUT;
+    loop {
+        match circuit.send(transport, last, &CircuitMessage::AwaitRendezvous).await? {
+            (_, CircuitMessage::RendezvousJoined { handshake: Some(reply) }) => {
+                circuit.join(&handshake.complete(&reply)?);
+                return Ok(());
+            }
+            (_, CircuitMessage::RendezvousJoined { handshake: None }) if Instant::now() < deadline => {}
+            (_, CircuitMessage::RendezvousJoined { handshake: None }) => {
+                return Err(anyhow!("{} did not come to the rendezvous", descriptor.domain()));
+            }
+            (_, other) => return Err(anyhow!("Unexpected answer to AwaitRendezvous: {:?}", other)),
+        }
+    }
+}
+
+/// Pass `request` to the service through its introduction points, in
+/// random order, until one takes it
+async fn introduce<T: CircuitTransport>(
+    router: &OnionRouter,
+    transport: &T,
+    descriptor: &ServiceDescriptor,
+    num_hops: usize,
+    request: Vec<u8>,
+) -> Result<()> {
+    let mut intro_points = descriptor.intro_points.clone();
+    intro_points.shuffle(&mut rand::thread_rng());
+
+    let mut last_error = anyhow!("{} lists no introduction points", descriptor.domain());
+    for intro in &intro_points {
+        let mut circuit = match router.build_private_circuit(transport, num_hops, Some(intro)).await {
+            Ok((circuit, _)) => circuit,
+            Err(e) => {
+                last_error = anyhow!("No circuit to introduction point {}: {}", intro.addr, e);
+                continue;
+            }
+        };
+        let message = CircuitMessage::Introduce { service: descriptor.service.node_id(), request: request.clone() };
+        let reply = circuit.send(transport, circuit.len() - 1, &message).await;
+        circuit.destroy(transport).await;
+        match reply {
+            Ok((_, CircuitMessage::IntroduceAck { accepted: true })) => return Ok(()),
+            Ok((_, other)) => last_error = anyhow!("Introduction point {} answered {:?}", intro.addr, other),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// An introduction circuit, by entry address and circuit id, and the task
+/// polling it
+type IntroCircuit = ((String, u32), JoinHandle<()>);
+
+/// The host side of a hidden service. Every stream a client opens to the
+/// service is forwarded to `target`, a local address.
+pub struct HiddenService<T> {
+    identity: Arc<Identity>,
+    /// Rotated as the descriptor is republished
+    onion_keys: OnionKeys,
+    router: Arc<OnionRouter>,
+    transport: Arc<T>,
+    target: SocketAddr,
+    num_hops: usize,
+    intros: Mutex<Vec<IntroCircuit>>,
+    /// Introductions already acted on, by digest, until they are too old
+    /// to be accepted anyway
+    seen: std::sync::Mutex<HashMap<[u8; 32], u64>>,
+    builds: Arc<Semaphore>,
+}
+
+impl<T: CircuitTransport + 'static> HiddenService<T> {
+    pub fn new(identity: Arc<Identity>, router: Arc<OnionRouter>, transport: Arc<T>, target: SocketAddr, num_hops: usize) -> Self {
+        HiddenService {
+            identity,
+            onion_keys: OnionKeys::generate(),
+            router,
+            transport,
+            target,
+            num_hops,
+            intros: Mutex::new(Vec::new()),
+            seen: std::sync::Mutex::new(HashMap::new()),
+            builds: Arc::new(Semaphore::new(MAX_RENDEZVOUS_BUILDS)),
+        }
+    }
+
+    /// The name clients reach the service by
+    pub fn domain(&self) -> String {
+        self.identity.public().to_domain()
+    }
+
+    /// Open fresh introduction circuits, retire the old ones, and return a
+    /// signed descriptor listing the new introduction points
+    pub async fn refresh(self: &Arc<Self>) -> Result<ServiceDescriptor, String> {
+        let mut established: Vec<(CircuitHop, ClientCircuit)> = Vec::new();
+        for _ in 0..INTRO_POINTS * 2 {
+            if established.len() == INTRO_POINTS {
+                break;
+            }
+            match self.establish_intro().await {
+                // The relay hands introductions to the newest circuit, so that's the one to keep
+                Ok((intro, circuit)) => match established.iter_mut().find(|(other, _)| *other == intro) {
+                    Some((_, older)) => std::mem::replace(older, circuit).destroy(self.transport.as_ref()).await,
+                    None => established.push((intro, circuit)),
+                },
+                Err(e) => eprintln!("🕵️  Could not open an introduction circuit: {}", e),
+            }
+        }
+        if established.is_empty() {
+            return Err("Could not open any introduction circuits".to_string());
+        }
+
+        let mut intros = self.intros.lock().await;
+        for ((entry, circuit_id), task) in intros.drain(..) {
+            task.abort();
+            let _ = self.transport.send(&entry, RoutingMessage::DestroyCircuit { circuit_id }).await;
+        }
+        let mut intro_points = Vec::new();
+        for (intro, circuit) in established {
+            let link = (circuit.entry.clone(), circuit.circuit_id);
+            intros.push((link, tokio::spawn(self.clone().collect_introductions(circuit))));
+            intro_points.push(intro);
+        }
+        if self.onion_keys.rotate(unix_now()) {
+            println!("🕵️  Rotated the onion key of {}", self.domain());
+        }
+        Ok(ServiceDescriptor::sign(&self.identity, self.onion_keys.public(), intro_points, DESCRIPTOR_TTL))
+    }
+
+    async fn establish_intro(&self) -> Result<(CircuitHop, ClientCircuit), String> {
+        let transport = self.transport.as_ref();
+        let (mut circuit, intro) = self.router.build_private_circuit(transport, self.num_hops, None).await?;
+        let service = self.identity.public();
+        let signature = self.identity.sign(&intro_signing_bytes(&intro.identity, &service));
+        let establish = CircuitMessage::EstablishIntro { service, signature };
+        match circuit.send(transport, circuit.len() - 1, &establish).await {
+            Ok((_, CircuitMessage::IntroEstablished)) => Ok((intro, circuit)),
+            reply => {
+                circuit.destroy(transport).await;
+                Err(format!("{} would not introduce: {:?}", intro.addr, reply))
+            }
+        }
+    }
+
+    /// Poll an introduction point until its circuit fails, meeting every
+    /// client that asks
+    async fn collect_introductions(self: Arc<Self>, mut circuit: ClientCircuit) {
+        let last = circuit.len() - 1;
+        loop {
+            match circuit.send(self.transport.as_ref(), last, &CircuitMessage::FetchIntroductions).await {
+                Ok((_, CircuitMessage::Introductions { requests })) => {
+                    for request in requests {
+                        let Ok(building) = self.builds.clone().try_acquire_owned() else {
+                            eprintln!("🕵️  Dropped an introduction: {} rendezvous already under way", MAX_RENDEZVOUS_BUILDS);
+                            continue;
+                        };
+                        let service = self.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = service.rendezvous(&request, building).await {
+                                eprintln!("🕵️  Rendezvous failed: {}", e);
+                            }
+                        });
+                    }
+                }
+                Ok((_, other)) => {
+                    eprintln!("🕵️  Unexpected answer to FetchIntroductions: {:?}", other);
+                    return;
+                }
+                Err(e) => {
+                    eprintln!("🕵️  Introduction circuit closed: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Meet a client at the rendezvous relay it asked for and serve its
+    /// streams. `building` counts against MAX_RENDEZVOUS_BUILDS until the
+    /// circuit has joined the client's.
+    async fn rendezvous(&self, request: &[u8], building: OwnedSemaphorePermit) -> Result<()> {
+        let request: IntroRequest = bincode::deserialize(&handshake::open_sealed(&self.onion_keys, request)?)?;
+        let now = unix_now();
+        if request.sent_at.abs_diff(now) > INTRO_MAX_SKEW.as_secs() {
+            return Err(anyhow!("Introduction sent at {} is too far from now ({})", request.sent_at, now));
+        }
+        if !self.first_sighting(&request, now) {
+            return Err(anyhow!("Introduction was replayed"));
+        }
+        let (reply, keys) = handshake::respond(&self.identity.node_id(), &self.onion_keys, &request.handshake)?;
+        let transport = self.transport.as_ref();
+        let (mut circuit, _) = self
+            .router
+            .build_private_circuit(transport, self.num_hops, Some(&request.rendezvous))
+            .await
+            .map_err(|e| anyhow!(e))?;
+        let served = self.serve(&mut circuit, request.cookie, reply, &keys, building).await;
+        circuit.destroy(transport).await;
+        served
+    }
+
+    /// Record an introduction by its cookie and handshake; false if it was
+    /// already seen
+    fn first_sighting(&self, request: &IntroRequest, now: u64) -> bool {
+        let mut hasher = Sha3_256::new();
+        hasher.update(request.cookie);
+        hasher.update(&request.handshake);
+        let digest: [u8; 32] = hasher.finalize().into();
+
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, forget_at| *forget_at > now);
+        let forget_at = request.sent_at + INTRO_MAX_SKEW.as_secs() + 1;
+        seen.insert(digest, forget_at).is_none()
+    }
+
+    async fn serve(
+        &self,
+        circuit: &mut ClientCircuit,
+        cookie: RendezvousCookie,
+        handshake: Vec<u8>,
+        keys: &HopKeys,
+        building: OwnedSemaphorePermit,
+    ) -> Result<()> {
+        let transport = self.transport.as_ref();
+        let last = circuit.len() - 1;
+        match circuit.send(transport, last, &CircuitMessage::JoinRendezvous { cookie, handshake }).await? {
+            (_, CircuitMessage::RendezvousEstablished) => {}
+            (_, other) => return Err(anyhow!("Unexpected answer to JoinRendezvous: {:?}", other)),
+        }
+        drop(building);
+
+        let mut session = ServiceSession::new(keys, self.target);
+        let mut answers = Vec::new();
+        let mut heard = Instant::now();
+        loop {
+            let cells = match circuit.send(transport, last, &CircuitMessage::RendezvousData { cells: answers }).await? {
+                (_, CircuitMessage::RendezvousData { cells }) => cells,
+                (_, other) => return Err(anyhow!("Unexpected answer to RendezvousData: {:?}", other)),
+            };
+            if !cells.is_empty() {
+                heard = Instant::now();
+            } else if session.streams.len() == 0 && heard.elapsed() > SESSION_IDLE {
+                return Ok(());
+            }
+            answers = session.answer(&cells).await.map_err(|e| anyhow!(e))?;
+        }
+    }
+}
+
+/// The service's end of a rendezvous: the last layer of the client's
+/// circuit, with streams that all go to the service's local address
+struct ServiceSession {
+    layer: HopLayer,
+    reassembler: Reassembler,
+    streams: ExitStreams,
+    target: String,
+    policy: ExitPolicy,
+}
+
+impl ServiceSession {
+    fn new(keys: &HopKeys, target: SocketAddr) -> Self {
+        let policy = ExitPolicy::parse(&[format!("accept {}", target)]).expect("a socket address is a valid rule");
+        ServiceSession {
+            layer: HopLayer::new(keys),
+            reassembler: Reassembler::default(),
+            streams: ExitStreams::default(),
+            target: target.to_string(),
+            policy,
+        }
+    }
+
+    /// Open the client's cells and answer them as the exit of its circuit
+    async fn answer(&mut self, cells: &[Vec<u8>]) -> Result<Vec<Vec<u8>>, String> {
+        let mut replies = Vec::new();
+        let mut reply_len = 0;
+        for cell in cells {
+            let plaintext = self.layer.open(Direction::Forward, cell)?;
+            let RelayPayload::Recognized(cell) = RelayPayload::decode(&plaintext)? else {
+                return Err("The service is the last hop of a rendezvous".to_string());
+            };
+            reply_len = reply_len.max(plaintext.len());
+            if let Some((_, message)) = self.reassembler.push(cell)? {
+                let reply = match CircuitMessage::decode(&message)? {
+                    // Whatever the client names, its streams reach the service
+                    CircuitMessage::Begin { stream_id, .. } => {
+                        let begin = CircuitMessage::Begin { stream_id, target: self.target.clone() };
+                        self.streams.handle(begin, &self.policy)?
+                    }
+                    CircuitMessage::BeginDatagrams { stream_id } => {
+                        let detail = "Hidden services only take TCP streams".to_string();
+                        Some(CircuitMessage::End { stream_id, reason: EndReason::NotAllowed, detail })
+                    }
+                    message => self.streams.handle(message, &self.policy)?,
+                };
+                if let Some(reply) = reply {
+                    let cells = cell::fragment(reply.stream_id(), &reply.encode());
+                    replies.extend(cells.into_iter().map(RelayPayload::Recognized));
+                }
+            }
+        }
+        if reply_len > 0 {
+            for message in self.streams.poll() {
+                let cells = cell::fragment(message.stream_id(), &message.encode());
+                replies.extend(cells.into_iter().map(RelayPayload::Recognized));
+            }
+        }
+        Ok(replies
+            .iter()
+            .map(|reply| self.layer.seal(Direction::Backward, &reply.encode_padded(reply_len)))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::testing::{echo_server, loopback_router, spawn_node, spawn_relays};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::identity::generate_identity;
+
+    struct NoTransport;
+
+    impl CircuitTransport for NoTransport {
+        async fn send(&self, addr: &str, _message: RoutingMessage) -> Result<Option<RoutingMessage>> {
+            Err(anyhow!("{} unreachable", addr))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_introductions_are_fresh_and_used_once() {
+        let identity = Arc::new(generate_identity());
+        let service = HiddenService::new(
+            identity.clone(),
+            Arc::new(OnionRouter::new()),
+            Arc::new(NoTransport),
+            "127.0.0.1:8080".parse().unwrap(),
+            3,
+        );
+        let sealed = |sent_at| {
+            let rendezvous = CircuitHop { addr: "10.0.0.1:5000".to_string(), identity: generate_identity().public(), onion_key: [1; 32] };
+            let (_, handshake) = ClientHandshake::start(&identity.node_id(), &service.onion_keys.public());
+            let request = IntroRequest { rendezvous, cookie: rand::random(), handshake, sent_at };
+            handshake::seal_to(&service.onion_keys.public(), &bincode::serialize(&request).unwrap())
+        };
+        let permit = || service.builds.clone().try_acquire_owned().unwrap();
+
+        let stale = sealed(unix_now() - INTRO_MAX_SKEW.as_secs() - 60);
+        let error = service.rendezvous(&stale, permit()).await.unwrap_err();
+        assert!(error.to_string().contains("too far from now"), "{}", error);
+
+        // The first attempt gets as far as building a circuit; a replay doesn't
+        let fresh = sealed(unix_now());
+        let error = service.rendezvous(&fresh, permit()).await.unwrap_err();
+        assert!(error.to_string().contains("Not enough nodes"), "{}", error);
+        let error = service.rendezvous(&fresh, permit()).await.unwrap_err();
+        assert!(error.to_string().contains("replayed"), "{}", error);
+
+        let _held: Vec<_> = (0..MAX_RENDEZVOUS_BUILDS).map(|_| permit()).collect();
+        assert!(service.builds.clone().try_acquire_owned().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hidden_service_rendezvous() {
+        // Client and service each pick their own paths through the same relays
+        let relays = spawn_relays(4).await;
+        let client_router = loopback_router(&relays).await;
+        let service_router = Arc::new(loopback_router(&relays).await);
+        let (_, client, _) = spawn_node().await;
+        let (_, host, host_addr) = spawn_node().await;
+        let target = echo_server().await;
+
+        let service = Arc::new(HiddenService::new(Arc::new(generate_identity()), service_router, host, target, 2));
+        let descriptor = service.refresh().await.unwrap();
+        assert_eq!(descriptor.domain(), service.domain());
+        assert!(!descriptor.intro_points.is_empty());
+        assert!(descriptor.intro_points.iter().all(|intro| intro.addr != host_addr.to_string()));
+
+        // Whatever the client asks for, the stream reaches the service's target
+        let mux = connect(&client_router, client.clone(), &descriptor, 2).await.unwrap();
+        let mut stream = mux.open("anything.example:80").await.unwrap();
+        stream.write_all(b"hidden hello").await.unwrap();
+        let mut reply = [0u8; 12];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"hidden hello");
+
+        // A descriptor for a service nobody hosts leads nowhere
+        let stranger = ServiceDescriptor::sign(&generate_identity(), descriptor.onion_key, descriptor.intro_points.clone(), DESCRIPTOR_TTL);
+        assert!(connect(&client_router, client, &stranger, 2).await.is_err());
+    }
+}
@@ -1,61 +1,131 @@
 // .freedom resolver - finds and connects to .freedom sites
+// Names are self-authenticating (`<address>.freedom`, derived from the owner's
+// key); human-readable pet names are a local mapping onto those.
 
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::RwLock;
-use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use anyhow::{anyhow, Result};
+use crate::identity::{PublicIdentity, FREEDOM_TLD};
+use crate::lookup::{DhtTransport, Lookup};
+use crate::protocol::{unix_now, ServiceDescriptor, DHT};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
 pub struct FreedomSiteMetadata {
     pub domain: String,
     pub owner_node_id: Vec<u8>,
-    pub ipv4: Option<String>,
-    pub ipv6: Option<String>,
-    pub port: u16,
+    /// Ed25519 key of the site owner; must match the key in `domain`
+    pub owner_pubkey: Vec<u8>,
+    /// Relays that pass introductions on to the site, signed by its key;
+    /// its own address is never published
+    pub service: ServiceDescriptor,
     pub protocol_version: u32,
 }
 
-pub struct FreedomResolver {
+impl FreedomSiteMetadata {
+    /// Metadata for `domain` as its service descriptor describes it
+    pub fn from_descriptor(domain: &str, service: ServiceDescriptor) -> Self {
+        FreedomSiteMetadata {
+            domain: domain.to_string(),
+            owner_node_id: service.service.node_id().0.to_vec(),
+            owner_pubkey: service.service.to_vec(),
+            service,
+            protocol_version: 1,
+        }
+    }
+}
+
+/// Lowercase a name and add the .freedom suffix if it is missing
+fn normalize(domain: &str) -> String {
+    let domain = domain.to_ascii_lowercase();
+    if domain.ends_with(FREEDOM_TLD) {
+        domain
+    } else {
+        format!("{}{}", domain, FREEDOM_TLD)
+    }
+}
+
+/// Check that a site's key is the one its self-authenticating name encodes,
+/// and that the key signed its descriptor
+pub fn verify_site_key(metadata: &FreedomSiteMetadata) -> Result<()> {
+    let named = PublicIdentity::from_domain(&metadata.domain)?;
+    if named.as_bytes().as_slice() != metadata.owner_pubkey.as_slice() || metadata.service.service != named {
+        return Err(anyhow!("Site key does not match {}", metadata.domain));
+    }
+    metadata.service.verify().map_err(|e| anyhow!("Descriptor for {} is invalid: {}", metadata.domain, e))
+}
+
+pub struct FreedomResolver<T> {
     cache: Arc<RwLock<HashMap<String, FreedomSiteMetadata>>>,
-    bootstrap_nodes: Vec<String>,
+    pet_names: Arc<RwLock<HashMap<String, PublicIdentity>>>,
+    dht: Arc<DHT>,
+    transport: Arc<T>,
 }
 
-impl FreedomResolver {
-    pub fn new(bootstrap_nodes: Vec<String>) -> Self {
+impl<T: DhtTransport> FreedomResolver<T> {
+    /// Resolve names through the DHT, asking peers over `transport` for
+    /// descriptors the local table doesn't hold
+    pub fn new(dht: Arc<DHT>, transport: Arc<T>) -> Self {
         Self {
             cache: Arc::new(RwLock::new(HashMap::new())),
-            bootstrap_nodes,
+            pet_names: Arc::new(RwLock::new(HashMap::new())),
+            dht,
+            transport,
         }
     }
 
-    /// Resolve a .freedom domain to site metadata
+    /// Map a human-readable name onto a self-authenticating one
+    pub async fn add_pet_name(&self, name: &str, target: &str) -> Result<()> {
+        let name = normalize(name);
+        if PublicIdentity::from_domain(&name).is_ok() {
+            return Err(anyhow!("{} is already a self-authenticating name", name));
+        }
+        let owner = PublicIdentity::from_domain(&normalize(target))?;
+        let mut pet_names = self.pet_names.write().await;
+        pet_names.insert(name, owner);
+        Ok(())
+    }
+
+    /// Turn a name into its canonical self-authenticating form
+    pub async fn canonical_name(&self, domain: &str) -> Result<String> {
+        let domain = normalize(domain);
+        if PublicIdentity::from_domain(&domain).is_ok() {
+            return Ok(domain);
+        }
+        let pet_names = self.pet_names.read().await;
+        pet_names
+            .get(&domain)
+            .map(|owner| owner.to_domain())
+            .ok_or_else(|| anyhow!("{} is neither a key-derived name nor a known pet name", domain))
+    }
+
+    /// Resolve a .freedom name to site metadata whose key matches the name
     pub async fn resolve(&self, domain: &str) -> Result<FreedomSiteMetadata> {
-        // Normalize domain
-        let domain = if domain.ends_with(".freedom") {
-            domain.to_string()
-        } else {
-            format!("{}.freedom", domain)
-        };
-
-        // Check cache first
+        let domain = self.canonical_name(domain).await?;
+
+        // Check cache first; an expired descriptor is looked up again
         {
-            let cache = self.cache.read().await;
-            if let Some(metadata) = cache.get(&domain) {
-                return Ok(metadata.clone());
+            let mut cache = self.cache.write().await;
+            match cache.get(&domain) {
+                Some(metadata) if metadata.service.is_expired_at(unix_now()) => {
+                    cache.remove(&domain);
+                }
+                Some(metadata) => {
+                    verify_site_key(metadata)?;
+                    return Ok(metadata.clone());
+                }
+                None => {}
             }
         }
 
-        // Try to resolve through bootstrap nodes
-        // For now, return a mock response
-        let metadata = FreedomSiteMetadata {
-            domain: domain.clone(),
-            owner_node_id: vec![0x01; 32],
-            ipv4: Some("127.0.0.1".to_string()),
-            ipv6: None,
-            port: 5000,
-            protocol_version: 1,
-        };
+        // Find the site's descriptor in the DHT. Only the key its name was
+        // derived from can have signed it.
+        let descriptor = Lookup::new(&self.dht, self.transport.as_ref())
+            .find_service(&domain)
+            .await
+            .ok_or_else(|| anyhow!("{} is not published in the DHT", domain))?;
+        let metadata = FreedomSiteMetadata::from_descriptor(&domain, descriptor);
+        verify_site_key(&metadata)?;
 
         // Cache the result
         {
@@ -65,59 +135,106 @@ impl FreedomResolver {
 
         Ok(metadata)
     }
+}
 
-    /// Clear the resolution cache
-    pub async fn clear_cache(&self) {
-        let mut cache = self.cache.write().await;
-        cache.clear();
-    }
-
-    /// Get cached domains
-    pub async fn list_cached(&self) -> Vec<String> {
-        let cache = self.cache.read().await;
-        cache.keys().cloned().collect()
-    }
+/// Resolvers holding entries the DHT would never hand out
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
 
-    /// Add custom domain mapping (for local testing)
-    pub async fn add_mapping(&self, domain: String, metadata: FreedomSiteMetadata) {
-        let mut cache = self.cache.write().await;
-        cache.insert(domain, metadata);
+    /// Cache `metadata` for `domain` as is; its key is checked when the
+    /// name is resolved, not here
+    pub async fn cache_site<T: DhtTransport>(resolver: &FreedomResolver<T>, domain: &str, metadata: FreedomSiteMetadata) {
+        resolver.cache.write().await.insert(normalize(domain), metadata);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::testing::cache_site;
+    use crate::identity::{generate_identity, Identity};
+    use crate::onion::CircuitHop;
+    use crate::protocol::{generate_node_id, DHTMessage, DEFAULT_RECORD_TTL};
+
+    struct NoTransport;
+
+    impl DhtTransport for NoTransport {
+        async fn request(&self, addr: &str, _message: DHTMessage) -> Result<DHTMessage> {
+            Err(anyhow!("{} unreachable", addr))
+        }
+    }
+
+    fn resolver() -> FreedomResolver<NoTransport> {
+        FreedomResolver::new(Arc::new(DHT::new(generate_node_id(b"resolver"))), Arc::new(NoTransport))
+    }
+
+    fn descriptor(owner: &Identity) -> ServiceDescriptor {
+        let intro = CircuitHop { addr: "192.0.2.1:5000".to_string(), identity: generate_identity().public(), onion_key: [1u8; 32] };
+        ServiceDescriptor::sign(owner, [2u8; 32], vec![intro], DEFAULT_RECORD_TTL)
+    }
 
     #[tokio::test]
     async fn test_domain_resolution() {
-        let resolver = FreedomResolver::new(vec!["127.0.0.1:5000".to_string()]);
-        let result = resolver.resolve("example").await;
-        
-        assert!(result.is_ok());
-        let metadata = result.unwrap();
-        assert_eq!(metadata.domain, "example.freedom");
-        assert_eq!(metadata.port, 5000);
+        let resolver = resolver();
+        let owner = generate_identity();
+        let domain = owner.public().to_domain();
+
+        // Nobody has published the site yet
+        assert!(resolver.resolve(&domain).await.is_err());
+
+        resolver.dht.register_service(descriptor(&owner)).unwrap();
+        let metadata = resolver.resolve(&owner.public().to_address()).await.unwrap();
+        assert_eq!(metadata.domain, domain);
+        assert_eq!(metadata.owner_pubkey, owner.public().to_vec());
+        assert_eq!(metadata.service.intro_points.len(), 1);
+
+        // Arbitrary names no longer resolve on their own
+        assert!(resolver.resolve("example").await.is_err());
     }
 
     #[tokio::test]
     async fn test_cache() {
-        let resolver = FreedomResolver::new(vec![]);
-        let domain = "test".to_string();
+        let resolver = resolver();
+        let owner = generate_identity();
+        let domain = owner.public().to_domain();
 
         // Add to cache
-        let metadata = FreedomSiteMetadata {
-            domain: "test.freedom".to_string(),
-            owner_node_id: vec![0x02; 32],
-            ipv4: Some("192.168.1.1".to_string()),
-            ipv6: None,
-            port: 8000,
-            protocol_version: 1,
-        };
-        resolver.add_mapping(domain, metadata).await;
+        cache_site(&resolver, &domain, FreedomSiteMetadata::from_descriptor(&domain, descriptor(&owner))).await;
 
         // Check it's cached
-        let cached = resolver.list_cached().await;
-        assert_eq!(cached.len(), 1);
+        assert_eq!(resolver.cache.read().await.len(), 1);
+        assert_eq!(resolver.resolve(&domain).await.unwrap().service.intro_points.len(), 1);
+
+        // An expired descriptor is looked up again rather than used
+        let expired = ServiceDescriptor::sign(&owner, [2u8; 32], Vec::new(), std::time::Duration::ZERO);
+        cache_site(&resolver, &domain, FreedomSiteMetadata::from_descriptor(&domain, expired)).await;
+        assert!(resolver.resolve(&domain).await.is_err());
+        assert!(resolver.cache.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_mismatched_key() {
+        let resolver = resolver();
+        let domain = generate_identity().public().to_domain();
+        let impostor = generate_identity();
+
+        cache_site(&resolver, &domain, FreedomSiteMetadata::from_descriptor(&domain, descriptor(&impostor))).await;
+        assert!(resolver.resolve(&domain).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pet_names() {
+        let resolver = resolver();
+        let owner = generate_identity();
+        let domain = owner.public().to_domain();
+        resolver.dht.register_service(descriptor(&owner)).unwrap();
+
+        resolver.add_pet_name("blog", &domain).await.unwrap();
+        assert_eq!(resolver.canonical_name("Blog.freedom").await.unwrap(), domain);
+        assert_eq!(resolver.resolve("blog").await.unwrap().owner_pubkey, owner.public().to_vec());
+
+        assert!(resolver.add_pet_name("chat", "not-a-key.freedom").await.is_err());
+        assert!(resolver.add_pet_name(&domain, &domain).await.is_err());
     }
 }
@@ -1,111 +1,194 @@
-// Multi-hop routing and circuit building (onion routing style)
+// Messages carried end to end over onion circuits
+// Relays switch cells in relay.rs; clients build circuits in onion.rs
 
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
 use serde::{Deserialize, Serialize};
+use crate::cell::{Cell, CELL_LEN};
+use crate::identity::{PublicIdentity, Signature};
+use crate::protocol::NodeId;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Copy)]
-pub struct NodeId([u8; 32]);
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Copy)]
-pub struct CircuitId(pub u32);
-
-#[derive(Debug, Clone)]
-pub struct Circuit {
-    pub id: CircuitId,
-    pub hops: Vec<NodeId>,
-    pub current_hop: usize,
-}
+/// Random value naming a rendezvous, chosen by the client
+pub type RendezvousCookie = [u8; 20];
 
+/// Messages carried inside relay cells, readable only by the hop they are for
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum CircuitMessage {
+    /// Ask the last hop to open the circuit onward to `addr`, pinned to the
+    /// next relay's ID
     Extend {
-        circuit_id: CircuitId,
-        next_hop: NodeId,
+        addr: String,
+        handshake: Vec<u8>,
     },
+    /// The next hop's half of the handshake
     Extended {
-        circuit_id: CircuitId,
+        handshake: Vec<u8>,
+    },
+    /// Ask the exit to open a TCP stream to `target` (host:port). The exit
+    /// answers `Connected` or `End` in a later reply, once it knows.
+    Begin {
+        stream_id: u16,
+        target: String,
     },
+    /// Ask the exit to relay UDP for the client. `Data` on the stream then
+    /// carries datagrams framed with `stream::encode_datagram`.
+    BeginDatagrams {
+        stream_id: u16,
+    },
+    Connected {
+        stream_id: u16,
+    },
+    /// Stream bytes. Every reply from the exit carries what its streams
+    /// have read since, so an empty `Data` from the client is a poll.
     Data {
-        circuit_id: CircuitId,
-        encrypted_data: Vec<u8>,
+        stream_id: u16,
+        data: Vec<u8>,
+    },
+    /// Either side closing a stream, or the exit refusing to open it
+    End {
+        stream_id: u16,
+        reason: EndReason,
+        /// What went wrong, for logs and error pages
+        detail: String,
+    },
+    /// A window's worth of cells was taken: from the exit, delivered by the
+    /// client to the application; from the client, written out by the exit.
+    /// `None` is for the circuit as a whole and only comes from the client.
+    Sendme {
+        stream_id: Option<u16>,
+    },
+    /// From a hidden service: accept introductions for `service` at this
+    /// hop. The signature shows the service key holder asked for it.
+    EstablishIntro {
+        service: PublicIdentity,
+        signature: Signature,
+    },
+    IntroEstablished,
+    /// From a client: pass `request`, sealed to the service key, on to the
+    /// service introduced here
+    Introduce {
+        service: NodeId,
+        request: Vec<u8>,
+    },
+    /// Whether the introduction reached a service's circuit
+    IntroduceAck {
+        accepted: bool,
+    },
+    /// From a hidden service: collect the introductions waiting for it
+    FetchIntroductions,
+    Introductions {
+        requests: Vec<Vec<u8>>,
+    },
+    /// From a client: wait at this hop for a service to join under `cookie`
+    EstablishRendezvous {
+        cookie: RendezvousCookie,
+    },
+    /// Answers both EstablishRendezvous and JoinRendezvous
+    RendezvousEstablished,
+    /// From a client: has the service joined yet?
+    AwaitRendezvous,
+    /// The service's half of the end-to-end handshake once it has joined;
+    /// `None` if it hasn't yet
+    RendezvousJoined {
+        handshake: Option<Vec<u8>>,
+    },
+    /// From a hidden service: join the client circuit waiting under `cookie`
+    JoinRendezvous {
+        cookie: RendezvousCookie,
+        handshake: Vec<u8>,
     },
-    Destroy {
-        circuit_id: CircuitId,
+    /// From a hidden service: answers to the client's last cells. The reply
+    /// carries the client's next cells, if any arrive in time.
+    RendezvousData {
+        cells: Vec<Vec<u8>>,
     },
 }
 
-pub struct Router {
-    circuits: Arc<RwLock<HashMap<CircuitId, Circuit>>>,
-    next_circuit_id: Arc<RwLock<u32>>,
+/// Why a stream ended; this, not the detail text, is what the other side
+/// acts on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EndReason {
+    /// Closed by either side once done
+    Done,
+    /// The exit's policy, or the far end of a rendezvous, doesn't take the stream
+    NotAllowed,
+    /// The destination's name didn't resolve
+    Unresolved,
+    /// The destination couldn't be connected to
+    Refused,
+    /// Resolving, connecting or writing took too long
+    Timeout,
+    /// The stream id was reserved, in use or unknown
+    Protocol,
+    /// The connection failed once open, or the exit couldn't serve the stream
+    Failed,
 }
 
-impl Router {
-    pub fn new() -> Self {
-        Self {
-            circuits: Arc::new(RwLock::new(HashMap::new())),
-            next_circuit_id: Arc::new(RwLock::new(1)),
+impl CircuitMessage {
+    /// Stream a message belongs to, 0 for circuit-level messages
+    pub fn stream_id(&self) -> u16 {
+        match self {
+            CircuitMessage::Begin { stream_id, .. }
+            | CircuitMessage::BeginDatagrams { stream_id }
+            | CircuitMessage::Connected { stream_id }
+            | CircuitMessage::Data { stream_id, .. }
+            | CircuitMessage::End { stream_id, .. } => *stream_id,
+            CircuitMessage::Sendme { stream_id } => stream_id.unwrap_or(0),
+            _ => 0,
         }
     }
 
-    /// Create a new circuit through specified hops
-    pub fn build_circuit(&self, hops: Vec<NodeId>) -> CircuitId {
-        let mut id_counter = self.next_circuit_id.write().unwrap();
-        let circuit_id = CircuitId(*id_counter);
-        *id_counter = id_counter.wrapping_add(1);
-
-        let circuit = Circuit {
-            id: circuit_id,
-            hops,
-            current_hop: 0,
-        };
-
-        let mut circuits = self.circuits.write().unwrap();
-        circuits.insert(circuit_id, circuit);
-
-        circuit_id
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("circuit messages always serialize")
     }
 
-    /// Get circuit by ID
-    pub fn get_circuit(&self, circuit_id: CircuitId) -> Option<Circuit> {
-        let circuits = self.circuits.read().unwrap();
-        circuits.get(&circuit_id).cloned()
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        bincode::deserialize(bytes).map_err(|e| format!("Malformed circuit message: {}", e))
     }
+}
 
-    /// Advance to next hop in circuit
-    pub fn advance_hop(&self, circuit_id: CircuitId) -> bool {
-        let mut circuits = self.circuits.write().unwrap();
-        if let Some(circuit) = circuits.get_mut(&circuit_id) {
-            circuit.current_hop += 1;
-            circuit.current_hop < circuit.hops.len()
-        } else {
-            false
-        }
-    }
+const PAYLOAD_RELAYED: u8 = 0;
+const PAYLOAD_RECOGNIZED: u8 = 1;
 
-    /// Destroy a circuit
-    pub fn destroy_circuit(&self, circuit_id: CircuitId) {
-        let mut circuits = self.circuits.write().unwrap();
-        circuits.remove(&circuit_id);
+/// A relay cell with one layer removed: either a cell for this hop, or
+/// another hop's layer to pass along. A recognized cell may be followed by
+/// padding so it is as long as a cell still carrying more layers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayPayload {
+    Relayed(Vec<u8>),
+    Recognized(Cell),
+}
+
+impl RelayPayload {
+    pub fn encode(&self) -> Vec<u8> {
+        let (tag, body) = match self {
+            RelayPayload::Relayed(cell) => (PAYLOAD_RELAYED, cell.clone()),
+            RelayPayload::Recognized(cell) => (PAYLOAD_RECOGNIZED, cell.encode()),
+        };
+        let mut out = Vec::with_capacity(1 + body.len());
+        out.push(tag);
+        out.extend(body);
+        out
     }
 
-    /// Get the next hop node in a circuit
-    pub fn get_next_hop(&self, circuit_id: CircuitId) -> Option<NodeId> {
-        let circuits = self.circuits.read().unwrap();
-        circuits.get(&circuit_id).and_then(|circuit| {
-            if circuit.current_hop < circuit.hops.len() {
-                Some(circuit.hops[circuit.current_hop])
-            } else {
-                None
+    /// Encode and pad with zeros to at least `len` bytes; only recognized
+    /// cells can be padded, since a relayed cell must keep its exact length
+    pub fn encode_padded(&self, len: usize) -> Vec<u8> {
+        let mut out = self.encode();
+        if let RelayPayload::Recognized(_) = self {
+            if out.len() < len {
+                out.resize(len, 0);
             }
-        })
+        }
+        out
     }
 
-    /// Get exit node (final node) of circuit
-    pub fn get_exit_node(&self, circuit_id: CircuitId) -> Option<NodeId> {
-        let circuits = self.circuits.read().unwrap();
-        circuits.get(&circuit_id).and_then(|circuit| {
-            circuit.hops.last().copied()
-        })
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        match bytes.split_first() {
+            Some((&PAYLOAD_RELAYED, cell)) => Ok(RelayPayload::Relayed(cell.to_vec())),
+            Some((&PAYLOAD_RECOGNIZED, cell)) => {
+                Cell::decode(cell.get(..CELL_LEN).unwrap_or(cell)).map(RelayPayload::Recognized)
+            }
+            Some((tag, _)) => Err(format!("Unknown relay payload tag {}", tag)),
+            None => Err("Empty relay payload".to_string()),
+        }
     }
-}
\ No newline at end of file
+}
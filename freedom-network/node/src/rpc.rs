@@ -0,0 +1,367 @@
+// QUIC transport for requests to other nodes
+// Each request opens a bidirectional stream, writes one frame and reads one reply
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+use anyhow::{anyhow, Result};
+use quinn::{ClientConfig, Connection, Endpoint, ServerConfig};
+use tokio::sync::RwLock;
+use crate::lookup::DhtTransport;
+use crate::onion::CircuitTransport;
+use crate::protocol::{generate_node_id, unpin_addr, DHTMessage, NodeId, RoutingMessage};
+use crate::wire::{self, Frame};
+
+/// Contents of an Ed25519 SubjectPublicKeyInfo up to the key itself: the
+/// algorithm identifier and the bit string header
+const ED25519_SPKI_PREFIX: [u8; 10] = [0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+/// Split the DER element at the front of `der` into its tag, its contents
+/// and whatever follows it
+fn der_element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count].iter().fold(0usize, |len, &byte| len << 8 | byte as usize);
+        (len, &rest[count..])
+    };
+    (rest.len() >= len).then(|| (tag, &rest[..len], &rest[len..]))
+}
+
+/// The NodeId behind the Ed25519 key a node certificate is issued to
+pub fn certificate_node_id(cert_der: &[u8]) -> Option<NodeId> {
+    let (_, cert, _) = der_element(cert_der)?;
+    let (_, mut fields, _) = der_element(cert)?;
+    // Skip the optional version, then the serial number, signature
+    // algorithm, issuer, validity and subject
+    if fields.first() == Some(&0xa0) {
+        fields = der_element(fields)?.2;
+    }
+    for _ in 0..5 {
+        fields = der_element(fields)?.2;
+    }
+    let (_, spki, _) = der_element(fields)?;
+    let key = spki.strip_prefix(&ED25519_SPKI_PREFIX[..])?;
+    (key.len() == 32).then(|| generate_node_id(key))
+}
+
+/// Node certificates are self-signed, so there is no CA chain to check.
+/// A node is its Ed25519 key instead: TLS proves the peer holds the key in
+/// its certificate, and this checks the key hashes to the NodeId dialled.
+struct NodeCertificate {
+    /// The node the address was pinned to; `None` takes any node
+    expected: Option<NodeId>,
+}
+
+impl rustls::client::ServerCertVerifier for NodeCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        let node_id = certificate_node_id(&end_entity.0)
+            .ok_or(rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding))?;
+        match &self.expected {
+            Some(expected) if *expected != node_id => {
+                Err(rustls::Error::InvalidCertificate(rustls::CertificateError::NotValidForName))
+            }
+            _ => Ok(rustls::client::ServerCertVerified::assertion()),
+        }
+    }
+}
+
+/// Client configuration for dialing other nodes, accepting only the node
+/// `expected` names when it is given
+pub fn client_config(expected: Option<NodeId>) -> ClientConfig {
+    let crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(NodeCertificate { expected }))
+        .with_no_client_auth();
+    ClientConfig::new(Arc::new(crypto))
+}
+
+/// The NodeId a connected peer's certificate was issued to
+fn peer_node_id(conn: &Connection) -> Option<NodeId> {
+    let certs = conn.peer_identity()?.downcast::<Vec<rustls::Certificate>>().ok()?;
+    certificate_node_id(&certs.first()?.0)
+}
+
+/// Bind a QUIC endpoint that both accepts peers and dials out to them
+pub fn bind_endpoint(addr: SocketAddr, cert_der: Vec<u8>, key_der: Vec<u8>) -> Result<Endpoint> {
+    let mut server_config = ServerConfig::with_single_cert(
+        vec![rustls::Certificate(cert_der)],
+        rustls::PrivateKey(key_der),
+    )?;
+    server_config.transport = Arc::new(quinn::TransportConfig::default());
+
+    let mut endpoint = Endpoint::server(server_config, addr)?;
+    endpoint.set_default_client_config(client_config(None));
+    Ok(endpoint)
+}
+
+pub struct QuicTransport {
+    endpoint: Endpoint,
+    /// Open connections and the node each one reached
+    connections: RwLock<HashMap<SocketAddr, (Connection, NodeId)>>,
+}
+
+impl QuicTransport {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            connections: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Reuse an open connection to `addr` or dial a new one. An address
+    /// pinned to a node only ever gets a connection to that node.
+    async fn connection(&self, addr: &str) -> Result<Connection> {
+        let (pinned, plain) = unpin_addr(addr).map_err(|e| anyhow!(e))?;
+        let socket: SocketAddr = plain.parse()?;
+        {
+            let connections = self.connections.read().await;
+            if let Some((conn, node_id)) = connections.get(&socket) {
+                if conn.close_reason().is_none() {
+                    return match pinned {
+                        Some(pinned) if pinned != *node_id => {
+                            Err(anyhow!("{} is node {}, not {}", plain, node_id, pinned))
+                        }
+                        _ => Ok(conn.clone()),
+                    };
+                }
+            }
+        }
+
+        let conn = self.endpoint.connect_with(client_config(pinned), socket, "localhost")?.await?;
+        let node_id = peer_node_id(&conn).ok_or_else(|| anyhow!("{} sent no node certificate", plain))?;
+        let mut connections = self.connections.write().await;
+        connections.insert(socket, (conn.clone(), node_id));
+        Ok(conn)
+    }
+
+    /// Send one request frame to `addr` and wait for the reply frame.
+    /// An `Error` frame from the peer comes back as `Err(WireError)`.
+    pub async fn call(&self, addr: &str, request: &Frame) -> Result<Frame> {
+        let conn = self.connection(addr).await?;
+        let (mut send, mut recv) = conn.open_bi().await?;
+
+        wire::write_frame(&mut send, request).await?;
+        send.finish().await?;
+        wire::read_reply(&mut recv).await
+    }
+}
+
+impl DhtTransport for QuicTransport {
+    async fn request(&self, addr: &str, message: DHTMessage) -> Result<DHTMessage> {
+        match self.call(addr, &Frame::Dht(message)).await? {
+            Frame::Dht(reply) => Ok(reply),
+            other => Err(anyhow!("{} sent {:?} to a DHT request", addr, other)),
+        }
+    }
+}
+
+impl CircuitTransport for QuicTransport {
+    async fn send(&self, addr: &str, message: RoutingMessage) -> Result<Option<RoutingMessage>> {
+        match self.call(addr, &Frame::Routing(message)).await? {
+            Frame::Routing(reply) => Ok(Some(reply)),
+            Frame::Ack => Ok(None),
+            other => Err(anyhow!("{} sent {:?} to a circuit request", addr, other)),
+        }
+    }
+}
+
+/// Nodes on loopback for tests that need real QUIC peers and relays
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use crate::dispatch::Dispatcher;
+    use crate::exit::ExitPolicy;
+    use crate::identity::{generate_identity, Identity};
+    use crate::onion::{CircuitHop, OnionRouter};
+    use crate::path::{PathRules, RelayInfo};
+    use crate::protocol::DHT;
+    use crate::relay::Relay;
+
+    /// A node with a DHT of its own, listening at the returned address
+    pub async fn spawn_node() -> (Arc<DHT>, Arc<QuicTransport>, SocketAddr) {
+        let (dht, transport, relay) = spawn(Arc::new(generate_identity())).await;
+        (dht, transport, relay.hop.addr.parse().unwrap())
+    }
+
+    /// `n` relays that let streams out anywhere, as path selection sees them
+    pub async fn spawn_relays(n: usize) -> Vec<RelayInfo> {
+        let mut relays = Vec::with_capacity(n);
+        for _ in 0..n {
+            relays.push(spawn(Arc::new(generate_identity())).await.2);
+        }
+        relays
+    }
+
+    /// A router that knows `relays`; they all share loopback, so it doesn't
+    /// keep them apart by subnet
+    pub async fn loopback_router(relays: &[RelayInfo]) -> OnionRouter {
+        let router = OnionRouter::new().with_path_rules(PathRules { distinct_subnets: false });
+        for relay in relays {
+            router.register_relay(relay.clone()).await;
+        }
+        router
+    }
+
+    /// A TCP server that sends back whatever each connection sends it
+    pub async fn echo_server() -> SocketAddr {
+        let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = socket.into_split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        addr
+    }
+
+    async fn spawn(identity: Arc<Identity>) -> (Arc<DHT>, Arc<QuicTransport>, RelayInfo) {
+        let (cert_der, key_der) = identity.tls_certificate().unwrap();
+        let endpoint = bind_endpoint("127.0.0.1:0".parse().unwrap(), cert_der, key_der).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+
+        let dht = Arc::new(DHT::new(identity.node_id()));
+        let transport = Arc::new(QuicTransport::new(endpoint.clone()));
+
+        let public = identity.public();
+        let policy = ExitPolicy::parse(&["accept *:*"]).unwrap();
+        let relay = Arc::new(Relay::new(identity, transport.clone()).with_exit_policy(Some(policy.clone())));
+        let hop = CircuitHop { addr: addr.to_string(), identity: public, onion_key: relay.onion_keys().public() };
+        let dispatcher = Arc::new(Dispatcher::new(dht.clone(), relay, transport.clone()));
+        tokio::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                if let Ok(conn) = connecting.await {
+                    tokio::spawn(dispatcher.clone().serve_connection(conn));
+                }
+            }
+        });
+
+        let mut info = RelayInfo::new(hop);
+        info.capabilities.exit_policy = Some(policy);
+        (dht, transport, info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::*;
+    use crate::lookup::{self, Lookup};
+    use crate::identity::generate_identity;
+    use crate::onion::{CircuitHop, ClientCircuit};
+    use crate::protocol::{pin_addr, DomainRecord, PeerInfo, DEFAULT_RECORD_TTL};
+    use crate::wire::ErrorCode;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_lookup_over_quic() {
+        let (a, a_transport, _) = spawn_node().await;
+        let (b, _, b_addr) = spawn_node().await;
+        let (c, _, c_addr) = spawn_node().await;
+
+        // b knows c; a only knows b
+        b.insert_peer(PeerInfo { node_id: c.local_id().clone(), addr: c_addr.to_string() });
+        let owner = generate_identity();
+        c.register_domain(DomainRecord::sign(&owner, "example.freedom", c.local_id().clone(), 1, DEFAULT_RECORD_TTL))
+            .unwrap();
+
+        let peers = lookup::bootstrap(&a, a_transport.as_ref(), &[b_addr.to_string()]).await.unwrap();
+        assert_eq!(peers, 2);
+
+        let result = Lookup::new(&a, a_transport.as_ref()).find_domain("example.freedom").await;
+        assert_eq!(result.owner.map(|o| o.address.node_id), Some(c.local_id().clone()));
+
+        // b learned a from its requests once a answered a ping as itself
+        let learned = || b.find_closest_peers(a.local_id(), 1).iter().any(|p| &p.node_id == a.local_id());
+        for _ in 0..50 {
+            if learned() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(learned());
+
+        // A request claiming someone else's NodeId doesn't get them added
+        let claimed = NodeId([7; 32]);
+        a_transport.request(&b_addr.to_string(), DHTMessage::Ping { sender: claimed.clone() }).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(b.find_closest_peers(&claimed, 1).iter().all(|p| p.node_id != claimed));
+    }
+
+    #[tokio::test]
+    async fn test_telescoping_circuit_over_quic() {
+        let (_, client, _) = spawn_node().await;
+        let hops: Vec<_> = spawn_relays(3).await.into_iter().map(|relay| relay.hop).collect();
+
+        let circuit = ClientCircuit::build(client.as_ref(), &hops).await.unwrap();
+        assert_eq!(circuit.len(), 3);
+        assert_eq!(circuit.entry, hops[0].pinned_addr());
+
+        // A relay that can't prove the expected key is refused
+        let impostor = CircuitHop { identity: generate_identity().public(), ..hops[2].clone() };
+        let mut path = hops[..2].to_vec();
+        path.push(impostor);
+        assert!(ClientCircuit::build(client.as_ref(), &path).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_remote_errors_are_typed() {
+        let (_, a_transport, _) = spawn_node().await;
+        let (_, _, b_addr) = spawn_node().await;
+
+        let relay = Frame::Routing(RoutingMessage::RelayData { circuit_id: 9, cells: vec![vec![1, 2, 3]] });
+        let err = a_transport.call(&b_addr.to_string(), &relay).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<wire::WireError>().map(|e| e.code), Some(ErrorCode::NotFound));
+    }
+
+    #[test]
+    fn test_certificate_node_id() {
+        let identity = generate_identity();
+        let (cert_der, _) = identity.tls_certificate().unwrap();
+        assert_eq!(certificate_node_id(&cert_der), Some(identity.node_id()));
+
+        // Not an Ed25519 key, so not a node
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        assert_eq!(certificate_node_id(&other.serialize_der().unwrap()), None);
+        assert_eq!(certificate_node_id(&cert_der[..cert_der.len() / 2]), None);
+    }
+
+    #[tokio::test]
+    async fn test_pinned_addresses_check_the_node_key() {
+        let (_, a_transport, _) = spawn_node().await;
+        let (b, _, b_addr) = spawn_node().await;
+        let (c, _, c_addr) = spawn_node().await;
+        let ping = DHTMessage::Ping { sender: NodeId([0; 32]) };
+
+        // Dialled fresh, the handshake itself refuses the wrong node
+        let wrong = pin_addr(b.local_id(), &c_addr.to_string());
+        assert!(a_transport.request(&wrong, ping.clone()).await.is_err());
+
+        let right = pin_addr(b.local_id(), &b_addr.to_string());
+        assert!(matches!(a_transport.request(&right, ping.clone()).await, Ok(DHTMessage::Pong { .. })));
+
+        // An open connection to c is not handed out under b's pin either
+        assert!(a_transport.request(&c_addr.to_string(), ping.clone()).await.is_ok());
+        assert!(a_transport.request(&wrong, ping.clone()).await.is_err());
+        let c_pin = pin_addr(c.local_id(), &c_addr.to_string());
+        assert!(a_transport.request(&c_pin, ping).await.is_ok());
+    }
+}
@@ -3,8 +3,11 @@
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::RwLock;
 use anyhow::Result;
+use crate::http::{Headers, HttpReader, ResponseHead};
 
 #[derive(Debug, Clone)]
 pub struct Site {
@@ -72,6 +75,67 @@ impl SiteServer {
         Ok(tokio::fs::read(&canonical).await?)
     }
 
+    /// Answer HTTP requests on `listener`, a local address the hidden
+    /// service forwards its streams to. The Host header picks the site.
+    pub async fn serve_http(self: Arc<Self>, listener: TcpListener) -> Result<()> {
+        loop {
+            let (socket, _) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = server.answer(socket).await {
+                    eprintln!("❌ Site request failed: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Answer one request, then close the connection
+    async fn answer(&self, socket: TcpStream) -> Result<()> {
+        let mut socket = HttpReader::new(socket);
+        let Some(head) = socket.read_request().await? else {
+            return Ok(());
+        };
+        let domain = head.headers.get("host").unwrap_or("").split(':').next().unwrap_or("").to_ascii_lowercase();
+        let path = head.target.split(['?', '#']).next().unwrap_or("/");
+        let head_only = head.method.eq_ignore_ascii_case("HEAD");
+
+        let (status, reason, content_type, body) = if !head_only && !head.method.eq_ignore_ascii_case("GET") {
+            (405, "Method Not Allowed", "text/plain", b"Only GET and HEAD are served\n".to_vec())
+        } else {
+            match self.serve_file(&domain, path).await {
+                Ok(data) => {
+                    // The index is served for the site root, and typed by its own name
+                    let file = match path {
+                        "" | "/" => self.get_site(&domain).await.map(|site| site.index_file).unwrap_or_default(),
+                        path => path.to_string(),
+                    };
+                    (200, "OK", content_type(&file), data)
+                }
+                Err(_) => (404, "Not Found", "text/plain", b"Not found\n".to_vec()),
+            }
+        };
+        let mut headers = vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+            ("Connection".to_string(), "close".to_string()),
+        ];
+        if status == 405 {
+            headers.push(("Allow".to_string(), "GET, HEAD".to_string()));
+        }
+        let mut response = ResponseHead {
+            version: "HTTP/1.0".to_string(),
+            status,
+            reason: reason.to_string(),
+            headers: Headers(headers),
+        }
+        .encode();
+        if !head_only {
+            response.extend_from_slice(&body);
+        }
+        socket.get_mut().write_all(&response).await?;
+        Ok(())
+    }
+
     /// Get site metadata
     pub async fn get_site_info(&self, domain: &str) -> Option<SiteInfo> {
         let sites = self.sites.read().await;
@@ -82,6 +146,23 @@ impl SiteServer {
     }
 }
 
+/// MIME type for a site file, by extension
+pub fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map(|(_, ext)| ext.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("fdom") => "text/fdom",
+        Some("html") | Some("htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js") => "application/javascript",
+        Some("json") => "application/json",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("svg") => "image/svg+xml",
+        Some("txt") => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct SiteInfo {
     pub domain: String,
@@ -127,4 +208,35 @@ mod tests {
         let content = server.serve_file("test.freedom", "/").await.unwrap();
         assert_eq!(content, b"<html>Hello Freedom</html>");
     }
+
+    #[tokio::test]
+    async fn test_serve_http() {
+        use tokio::io::AsyncReadExt;
+
+        let server = Arc::new(SiteServer::new());
+        let temp_dir = tempfile::tempdir().unwrap();
+        std::fs::write(temp_dir.path().join("index.fdom"), b"page Hello").unwrap();
+        server.register_site("test.freedom".to_string(), temp_dir.path().to_path_buf(), "index.fdom".to_string()).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server.serve_http(listener));
+
+        let fetch = |request: &'static str| async move {
+            let mut socket = TcpStream::connect(addr).await.unwrap();
+            socket.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            socket.read_to_string(&mut response).await.unwrap();
+            response
+        };
+        let index = fetch("GET / HTTP/1.0\r\nHost: test.freedom:80\r\n\r\n").await;
+        assert!(index.starts_with("HTTP/1.0 200 OK\r\n"), "{}", index);
+        assert!(index.contains("Content-Type: text/fdom\r\n"));
+        assert!(index.ends_with("\r\n\r\npage Hello"));
+
+        let other = fetch("GET / HTTP/1.0\r\nHost: other.freedom\r\n\r\n").await;
+        assert!(other.starts_with("HTTP/1.0 404 Not Found\r\n"), "{}", other);
+        let post = fetch("POST / HTTP/1.0\r\nHost: test.freedom\r\nContent-Length: 0\r\n\r\n").await;
+        assert!(post.starts_with("HTTP/1.0 405 Method Not Allowed\r\n"), "{}", post);
+        assert!(post.contains("Allow: GET, HEAD\r\n"));
+    }
 }
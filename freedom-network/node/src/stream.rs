@@ -0,0 +1,1042 @@
+// Streams multiplexed over onion circuits
+// The exit opens a TCP connection per stream and carries its bytes in Data
+// messages. A datagram stream instead gets a UDP socket at the exit, with
+// each datagram framed inside the stream's bytes. Every stream has a task
+// of its own at the exit that connects, writes and reads ahead into a
+// buffer, so a slow destination holds up nothing but its own stream.
+//
+// Circuits are request/response: the exit can only answer. Whatever its
+// streams have ready rides back on the reply to the client's next cells,
+// and a client with nothing to send polls, backing off while its streams
+// stay idle.
+//
+// Flow control follows Tor's SENDME scheme. The exit may send a limited
+// number of cells per stream and per circuit, and only refills once the
+// client has handed them to the application. The client likewise sends a
+// stream no more than the exit has written out. A stalled reader therefore
+// stops its own stream without taking the other streams down with it.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use futures::FutureExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::task::JoinHandle;
+use crate::cell::CELL_DATA_LEN;
+use crate::exit::ExitPolicy;
+use crate::onion::{CircuitTransport, ClientCircuit};
+use crate::relay;
+use crate::routing::{CircuitMessage, EndReason};
+
+/// Cells the exit may send on a circuit before the client acknowledges them
+pub const CIRCUIT_WINDOW: u32 = 1000;
+/// Cells acknowledged by one circuit-level Sendme
+pub const CIRCUIT_SENDME: u32 = 100;
+/// Cells the exit may send on one stream before the client acknowledges them
+pub const STREAM_WINDOW: u32 = 500;
+/// Cells acknowledged by one stream-level Sendme
+pub const STREAM_SENDME: u32 = 50;
+
+/// Most cells of stream data in one reply
+const MAX_DATA_CELLS: u32 = 16;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a destination may leave the client's bytes unread before the
+/// stream ends
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Reads an exit stream's task may have waiting for the client
+const EXIT_READ_AHEAD: usize = 8;
+/// Pause before the first poll once no stream has moved any bytes
+const IDLE_POLL: Duration = Duration::from_millis(20);
+/// The pause doubles while streams stay idle, up to this
+const MAX_IDLE_POLL: Duration = Duration::from_millis(500);
+/// How often a circuit with no streams sends a padding cell, so the relays
+/// on it don't tear it down as abandoned
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(relay::CIRCUIT_IDLE_TIMEOUT.as_secs() / 2);
+/// Buffer between the driver and the application, per stream
+const STREAM_BUFFER: usize = 64 * 1024;
+/// Largest UDP payload carried on a datagram stream
+pub const MAX_DATAGRAM_LEN: usize = 65507;
+
+/// Window cost of a Data message carrying `len` bytes
+pub fn data_cells(len: usize) -> u32 {
+    len.div_ceil(CELL_DATA_LEN) as u32
+}
+
+fn credit(window: u32, increment: u32, max: u32) -> Result<u32, String> {
+    let window = window + increment;
+    if window > max {
+        return Err(format!("Sendme would open the window to {} cells, past {}", window, max));
+    }
+    Ok(window)
+}
+
+/// Frame one datagram for a datagram stream: the peer ("host:port") and
+/// the payload, each behind a big-endian u16 length
+pub fn encode_datagram(peer: &str, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(4 + peer.len() + payload.len());
+    frame.extend_from_slice(&(peer.len() as u16).to_be_bytes());
+    frame.extend_from_slice(peer.as_bytes());
+    frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Take every complete datagram off the front of `buffer`, leaving a
+/// partial one for the next read
+pub fn decode_datagrams(buffer: &mut Vec<u8>) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut datagrams = Vec::new();
+    let mut offset = 0;
+    loop {
+        let rest = &buffer[offset..];
+        let Some(peer_len) = rest.get(..2).map(|len| u16::from_be_bytes([len[0], len[1]]) as usize) else {
+            break;
+        };
+        let Some(payload_len) = rest.get(2 + peer_len..4 + peer_len).map(|len| u16::from_be_bytes([len[0], len[1]]) as usize) else {
+            break;
+        };
+        let Some(payload) = rest.get(4 + peer_len..4 + peer_len + payload_len) else {
+            break;
+        };
+        let peer = String::from_utf8(rest[2..2 + peer_len].to_vec()).map_err(|_| "Datagram peer is not UTF-8".to_string())?;
+        datagrams.push((peer, payload.to_vec()));
+        offset += 4 + peer_len + payload_len;
+    }
+    buffer.drain(..offset);
+    Ok(datagrams)
+}
+
+/// What a stream's task at the exit has for the client
+enum ExitEvent {
+    Connected,
+    Data(Vec<u8>),
+    End(EndReason, String),
+}
+
+/// The task's ends of an exit stream
+struct StreamTask {
+    /// Client bytes to write out
+    writes: mpsc::Receiver<Vec<u8>>,
+    events: mpsc::Sender<ExitEvent>,
+    /// Cells written out, for the exit to acknowledge
+    written: Arc<AtomicU32>,
+}
+
+/// The exit's handle on one stream, whose task does the waiting
+struct ExitStream {
+    writes: mpsc::Sender<Vec<u8>>,
+    events: mpsc::Receiver<ExitEvent>,
+    /// Data taken from `events` that the windows haven't let through yet
+    held: Vec<u8>,
+    written: Arc<AtomicU32>,
+    /// Cells written out since the client was last sent a Sendme
+    unacknowledged: u32,
+    window: u32,
+    task: JoinHandle<()>,
+}
+
+impl Drop for ExitStream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// The exit's side of the streams on one circuit. Nothing here waits, so it
+/// is safe to use while the circuit is locked.
+pub struct ExitStreams {
+    streams: HashMap<u16, ExitStream>,
+    window: u32,
+}
+
+impl Default for ExitStreams {
+    fn default() -> Self {
+        Self { streams: HashMap::new(), window: CIRCUIT_WINDOW }
+    }
+}
+
+impl ExitStreams {
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Handle a stream message from the client. Errors are protocol
+    /// violations that should fail the circuit; a stream that can't be
+    /// served, or that `policy` forbids, is answered with `End` instead.
+    /// A new stream's `Connected` or `End` comes later, from `poll`.
+    pub fn handle(&mut self, message: CircuitMessage, policy: &ExitPolicy) -> Result<Option<CircuitMessage>, String> {
+        match message {
+            CircuitMessage::Begin { stream_id, target } => {
+                let policy = policy.clone();
+                Ok(self.begin(stream_id, |task| run_tcp(target, policy, task)))
+            }
+            CircuitMessage::BeginDatagrams { stream_id } => {
+                let policy = policy.clone();
+                Ok(self.begin(stream_id, |task| run_udp(policy, task)))
+            }
+            CircuitMessage::Data { stream_id, data } => {
+                let Some(stream) = self.streams.get_mut(&stream_id) else {
+                    let detail = "Unknown stream".to_string();
+                    return Ok(Some(CircuitMessage::End { stream_id, reason: EndReason::Protocol, detail }));
+                };
+                if data.is_empty() {
+                    return Ok(None);
+                }
+                match stream.writes.try_send(data) {
+                    Err(mpsc::error::TrySendError::Full(_)) => Err(format!("Stream {} overran its window", stream_id)),
+                    // The task has ended, and its End is on the way
+                    Ok(()) | Err(mpsc::error::TrySendError::Closed(_)) => Ok(None),
+                }
+            }
+            CircuitMessage::End { stream_id, .. } => {
+                self.streams.remove(&stream_id);
+                Ok(None)
+            }
+            CircuitMessage::Sendme { stream_id: None } => {
+                self.window = credit(self.window, CIRCUIT_SENDME, CIRCUIT_WINDOW)?;
+                Ok(None)
+            }
+            CircuitMessage::Sendme { stream_id: Some(stream_id) } => {
+                // The stream may have ended while the Sendme was on its way
+                if let Some(stream) = self.streams.get_mut(&stream_id) {
+                    stream.window = credit(stream.window, STREAM_SENDME, STREAM_WINDOW)?;
+                }
+                Ok(None)
+            }
+            other => Err(format!("Not a stream message: {:?}", other)),
+        }
+    }
+
+    /// Start a stream's task, unless `stream_id` can't be used
+    fn begin<F>(&mut self, stream_id: u16, run: impl FnOnce(StreamTask) -> F) -> Option<CircuitMessage>
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let refusal = if stream_id == 0 {
+            Some("Stream id 0 is reserved".to_string())
+        } else if self.streams.contains_key(&stream_id) {
+            Some(format!("Stream {} is already open", stream_id))
+        } else {
+            None
+        };
+        if let Some(detail) = refusal {
+            return Some(CircuitMessage::End { stream_id, reason: EndReason::Protocol, detail });
+        }
+
+        // The client sends no more than a window ahead, and every Data
+        // message costs at least a cell
+        let (writes, writes_receiver) = mpsc::channel(STREAM_WINDOW as usize);
+        let (events_sender, events) = mpsc::channel(EXIT_READ_AHEAD);
+        let written = Arc::new(AtomicU32::new(0));
+        let task = tokio::spawn(run(StreamTask { writes: writes_receiver, events: events_sender, written: written.clone() }));
+        self.streams.insert(stream_id, ExitStream {
+            writes,
+            events,
+            held: Vec::new(),
+            written,
+            unacknowledged: 0,
+            window: STREAM_WINDOW,
+            task,
+        });
+        None
+    }
+
+    /// Collect what the streams' tasks have ready, as far as the windows
+    /// allow, along with Sendmes for what they have written out
+    pub fn poll(&mut self) -> Vec<CircuitMessage> {
+        let mut messages = Vec::new();
+        let mut ids: Vec<u16> = self.streams.keys().copied().collect();
+        ids.sort_unstable();
+        for stream_id in ids {
+            let stream = self.streams.get_mut(&stream_id).expect("ids come from the map");
+            stream.unacknowledged += stream.written.swap(0, Ordering::Relaxed);
+            while stream.unacknowledged >= STREAM_SENDME {
+                stream.unacknowledged -= STREAM_SENDME;
+                messages.push(CircuitMessage::Sendme { stream_id: Some(stream_id) });
+            }
+
+            let limit = MAX_DATA_CELLS.min(stream.window).min(self.window) as usize * CELL_DATA_LEN;
+            let mut data = Vec::new();
+            let mut end = None;
+            loop {
+                // An End waits until the data before it has gone
+                if stream.held.is_empty() {
+                    match stream.events.try_recv() {
+                        Ok(ExitEvent::Connected) => {
+                            messages.push(CircuitMessage::Connected { stream_id });
+                            continue;
+                        }
+                        Ok(ExitEvent::Data(bytes)) => stream.held = bytes,
+                        Ok(ExitEvent::End(reason, detail)) => {
+                            end = Some((reason, detail));
+                            break;
+                        }
+                        Err(mpsc::error::TryRecvError::Disconnected) => {
+                            end = Some((EndReason::Done, "Closed".to_string()));
+                            break;
+                        }
+                        Err(mpsc::error::TryRecvError::Empty) => break,
+                    }
+                }
+                let take = stream.held.len().min(limit - data.len());
+                if take == 0 {
+                    break;
+                }
+                data.extend(stream.held.drain(..take));
+            }
+
+            if !data.is_empty() {
+                stream.window -= data_cells(data.len());
+                self.window -= data_cells(data.len());
+                messages.push(CircuitMessage::Data { stream_id, data });
+            }
+            if let Some((reason, detail)) = end {
+                self.streams.remove(&stream_id);
+                messages.push(CircuitMessage::End { stream_id, reason, detail });
+            }
+        }
+        messages
+    }
+}
+
+/// Resolve `target` to an address the policy allows. The policy applies to
+/// where a name resolves, not to the name itself.
+async fn resolve_allowed(target: &str, policy: &ExitPolicy) -> Result<SocketAddr, (EndReason, String)> {
+    let addrs = match tokio::time::timeout(CONNECT_TIMEOUT, tokio::net::lookup_host(target)).await {
+        Ok(Ok(addrs)) => addrs.collect::<Vec<_>>(),
+        Ok(Err(e)) => return Err((EndReason::Unresolved, format!("Could not resolve {}: {}", target, e))),
+        Err(_) => return Err((EndReason::Timeout, format!("Timed out resolving {}", target))),
+    };
+    addrs
+        .into_iter()
+        .find(|addr| policy.allows(addr))
+        .ok_or_else(|| (EndReason::NotAllowed, format!("Exit policy refuses {}", target)))
+}
+
+async fn connect(target: &str, policy: &ExitPolicy) -> Result<TcpStream, (EndReason, String)> {
+    let addr = resolve_allowed(target, policy).await?;
+    match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(socket)) => Ok(socket),
+        Ok(Err(e)) => Err((EndReason::Refused, format!("Could not connect to {}: {}", target, e))),
+        Err(_) => Err((EndReason::Timeout, format!("Timed out connecting to {}", target))),
+    }
+}
+
+/// Connect to `target`, then carry bytes both ways until either side stops
+async fn run_tcp(target: String, policy: ExitPolicy, task: StreamTask) {
+    let StreamTask { mut writes, events, written } = task;
+    let socket = match connect(&target, &policy).await {
+        Ok(socket) => socket,
+        Err((reason, detail)) => {
+            let _ = events.send(ExitEvent::End(reason, detail)).await;
+            return;
+        }
+    };
+    if events.send(ExitEvent::Connected).await.is_err() {
+        return;
+    }
+
+    let (mut reader, mut writer) = socket.into_split();
+    let read = async {
+        loop {
+            let mut buf = vec![0u8; MAX_DATA_CELLS as usize * CELL_DATA_LEN];
+            match reader.read(&mut buf).await {
+                Ok(0) => return (EndReason::Done, "Closed".to_string()),
+                Ok(n) => {
+                    buf.truncate(n);
+                    if events.send(ExitEvent::Data(buf)).await.is_err() {
+                        return (EndReason::Done, "Closed".to_string());
+                    }
+                }
+                Err(e) => return (EndReason::Failed, e.to_string()),
+            }
+        }
+    };
+    let write = async {
+        while let Some(data) = writes.recv().await {
+            match tokio::time::timeout(WRITE_TIMEOUT, writer.write_all(&data)).await {
+                Ok(Ok(())) => written.fetch_add(data_cells(data.len()), Ordering::Relaxed),
+                Ok(Err(e)) => return (EndReason::Failed, e.to_string()),
+                Err(_) => return (EndReason::Timeout, format!("{} stopped reading", target)),
+            };
+        }
+        (EndReason::Done, "Closed".to_string())
+    };
+    let (reason, detail) = tokio::select! {
+        end = read => end,
+        end = write => end,
+    };
+    let _ = events.send(ExitEvent::End(reason, detail)).await;
+}
+
+/// Send the client's datagrams from a UDP socket of the stream's own, and
+/// pass back what the peers it has sent to answer. UDP is best effort:
+/// datagrams the policy refuses or that can't be sent are dropped, and so
+/// are replies that arrive while the client is behind.
+async fn run_udp(policy: ExitPolicy, task: StreamTask) {
+    let StreamTask { mut writes, events, written } = task;
+    // A dual-stack socket reaches both families; fall back to IPv4 only
+    let socket = match UdpSocket::bind("[::]:0").await {
+        Ok(socket) => socket,
+        Err(_) => match UdpSocket::bind("0.0.0.0:0").await {
+            Ok(socket) => socket,
+            Err(e) => {
+                let _ = events.send(ExitEvent::End(EndReason::Failed, format!("Could not open a UDP socket: {}", e))).await;
+                return;
+            }
+        },
+    };
+    if events.send(ExitEvent::Connected).await.is_err() {
+        return;
+    }
+
+    let dual_stack = socket.local_addr().is_ok_and(|addr| addr.is_ipv6());
+    // Start of a datagram whose remaining bytes haven't arrived
+    let mut partial = Vec::new();
+    // Where the client has sent datagrams; only they may answer
+    let mut peers = HashSet::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+    loop {
+        tokio::select! {
+            data = writes.recv() => {
+                let Some(data) = data else {
+                    return;
+                };
+                written.fetch_add(data_cells(data.len()), Ordering::Relaxed);
+                partial.extend_from_slice(&data);
+                let datagrams = match decode_datagrams(&mut partial) {
+                    Ok(datagrams) => datagrams,
+                    Err(detail) => {
+                        let _ = events.send(ExitEvent::End(EndReason::Protocol, detail)).await;
+                        return;
+                    }
+                };
+                for (target, payload) in datagrams {
+                    let Ok(addr) = resolve_allowed(&target, &policy).await else {
+                        continue;
+                    };
+                    let to = match addr {
+                        SocketAddr::V4(v4) if dual_stack => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+                        addr => addr,
+                    };
+                    if socket.send_to(&payload, to).await.is_ok() {
+                        peers.insert(addr);
+                    }
+                }
+            }
+            received = socket.recv_from(&mut buf) => {
+                // A refused port reported back through ICMP doesn't end the stream
+                let Ok((n, from)) = received else {
+                    continue;
+                };
+                let from = SocketAddr::new(from.ip().to_canonical(), from.port());
+                if peers.contains(&from) {
+                    let _ = events.try_send(ExitEvent::Data(encode_datagram(&from.to_string(), &buf[..n])));
+                }
+            }
+        }
+    }
+}
+
+/// An exit's answer to a stream it wouldn't open, for callers to find
+/// with `downcast_ref`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamRefused {
+    pub reason: EndReason,
+    pub message: String,
+}
+
+impl std::fmt::Display for StreamRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for StreamRefused {}
+
+struct OpenRequest {
+    /// Where to connect, or `None` for a datagram stream
+    target: Option<String>,
+    reply: oneshot::Sender<Result<DuplexStream>>,
+}
+
+/// Opens streams over one circuit. The circuit itself belongs to a driver
+/// task; this handle can be cloned and shared between connections.
+#[derive(Clone, Debug)]
+pub struct StreamMux {
+    requests: mpsc::Sender<OpenRequest>,
+    open_streams: Arc<AtomicUsize>,
+}
+
+impl StreamMux {
+    /// Take over `circuit` and start serving streams through its last hop
+    pub fn spawn<T: CircuitTransport + Send + Sync + 'static>(circuit: ClientCircuit, transport: Arc<T>) -> Self {
+        let (requests, receiver) = mpsc::channel(16);
+        let (outgoing_sender, outgoing) = mpsc::unbounded_channel();
+        let open_streams = Arc::new(AtomicUsize::new(0));
+        let driver = Driver {
+            open_streams: open_streams.clone(),
+            exit: circuit.len() - 1,
+            circuit,
+            transport,
+            streams: BTreeMap::new(),
+            opening: BTreeMap::new(),
+            next_stream_id: 1,
+            delivered: 0,
+            outgoing,
+            outgoing_sender,
+        };
+        tokio::spawn(driver.run(receiver));
+        StreamMux { requests, open_streams }
+    }
+
+    /// Open a stream to `target` (host:port) through the exit
+    pub async fn open(&self, target: &str) -> Result<DuplexStream> {
+        self.request(Some(target.to_string())).await
+    }
+
+    /// Open a datagram stream: frames written with `encode_datagram` are
+    /// sent as UDP from the exit, and replies come back the same way
+    pub async fn open_datagrams(&self) -> Result<DuplexStream> {
+        self.request(None).await
+    }
+
+    async fn request(&self, target: Option<String>) -> Result<DuplexStream> {
+        let (reply, response) = oneshot::channel();
+        let request = OpenRequest { target, reply };
+        self.requests.send(request).await.map_err(|_| anyhow!("Circuit is closed"))?;
+        response.await.map_err(|_| anyhow!("Circuit is closed"))?
+    }
+
+    /// True once the circuit has failed or shut down
+    pub fn is_closed(&self) -> bool {
+        self.requests.is_closed()
+    }
+
+    /// Streams currently open over the circuit
+    pub fn stream_count(&self) -> usize {
+        self.open_streams.load(Ordering::Relaxed)
+    }
+}
+
+/// Application bytes for the exit, or `None` once the application has
+/// closed its end
+type Outgoing = (u16, Option<Vec<u8>>);
+
+struct ClientStream {
+    /// Driver's end of the pipe to the application
+    io: WriteHalf<DuplexStream>,
+    /// Data from the exit the application hasn't taken yet, with its window cost
+    pending: VecDeque<(u32, Vec<u8>)>,
+    /// Cells delivered since the last stream Sendme
+    delivered: u32,
+    remote_closed: bool,
+    /// Cells the exit will take before it next sends a Sendme
+    send_window: Arc<Semaphore>,
+    /// Reads what the application writes
+    reader: JoinHandle<()>,
+}
+
+impl Drop for ClientStream {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl ClientStream {
+    /// Hand as much pending data to the application as it will take without
+    /// waiting; returns the window cost of what was fully delivered
+    fn deliver(&mut self) -> u32 {
+        let mut cells = 0;
+        while let Some((cost, chunk)) = self.pending.front_mut() {
+            match self.io.write(chunk).now_or_never() {
+                Some(Ok(n)) if n > 0 => {
+                    chunk.drain(..n);
+                    if chunk.is_empty() {
+                        cells += *cost;
+                        self.pending.pop_front();
+                    }
+                }
+                // The application went away; the next read sees it
+                Some(_) => {
+                    self.pending.clear();
+                    break;
+                }
+                None => break,
+            }
+        }
+        cells
+    }
+}
+
+/// Read what the application writes, never more than the send window allows
+async fn read_application(
+    stream_id: u16,
+    mut io: ReadHalf<DuplexStream>,
+    window: Arc<Semaphore>,
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+) {
+    loop {
+        let Ok(permit) = window.acquire().await else {
+            return;
+        };
+        permit.forget();
+        // Only this task takes permits, so the rest are still there after the read
+        let room = (window.available_permits() + 1).min(MAX_DATA_CELLS as usize);
+        let mut buf = vec![0u8; room * CELL_DATA_LEN];
+        match io.read(&mut buf).await {
+            Ok(n) if n > 0 => {
+                buf.truncate(n);
+                window.forget_permits(data_cells(n) as usize - 1);
+                if outgoing.send((stream_id, Some(buf))).is_err() {
+                    return;
+                }
+            }
+            _ => {
+                let _ = outgoing.send((stream_id, None));
+                return;
+            }
+        }
+    }
+}
+
+struct Driver<T> {
+    circuit: ClientCircuit,
+    transport: Arc<T>,
+    exit: usize,
+    streams: BTreeMap<u16, ClientStream>,
+    /// Streams the exit has yet to answer Begin for
+    opening: BTreeMap<u16, OpenRequest>,
+    next_stream_id: u16,
+    /// Cells delivered on any stream since the last circuit Sendme
+    delivered: u32,
+    open_streams: Arc<AtomicUsize>,
+    outgoing: mpsc::UnboundedReceiver<Outgoing>,
+    outgoing_sender: mpsc::UnboundedSender<Outgoing>,
+}
+
+impl<T: CircuitTransport> Driver<T> {
+    async fn run(mut self, mut requests: mpsc::Receiver<OpenRequest>) {
+        let mut accepting = true;
+        // How long to wait before polling; `None` polls straight away
+        let mut pause: Option<Duration> = None;
+        loop {
+            self.open_streams.store(self.streams.len() + self.opening.len(), Ordering::Relaxed);
+            let mut messages = Vec::new();
+            if self.streams.is_empty() && self.opening.is_empty() {
+                if !accepting {
+                    return;
+                }
+                match tokio::time::timeout(KEEPALIVE_INTERVAL, requests.recv()).await {
+                    Ok(Some(request)) => self.begin(request, &mut messages),
+                    Ok(None) => return,
+                    Err(_) => {
+                        if let Err(e) = self.circuit.send_padding(self.transport.as_ref(), self.exit).await {
+                            eprintln!("Circuit {} failed: {}", self.circuit.circuit_id, e);
+                            return;
+                        }
+                        continue;
+                    }
+                }
+            } else if let Some(pause) = pause {
+                tokio::select! {
+                    request = requests.recv(), if accepting => match request {
+                        Some(request) => self.begin(request, &mut messages),
+                        None => accepting = false,
+                    },
+                    Some((stream_id, data)) = self.outgoing.recv() => self.send_data(stream_id, data, &mut messages),
+                    _ = tokio::time::sleep(pause) => {}
+                }
+            }
+
+            while accepting {
+                match requests.try_recv() {
+                    Ok(request) => self.begin(request, &mut messages),
+                    Err(mpsc::error::TryRecvError::Empty) => break,
+                    Err(mpsc::error::TryRecvError::Disconnected) => accepting = false,
+                }
+            }
+            while let Ok((stream_id, data)) = self.outgoing.try_recv() {
+                self.send_data(stream_id, data, &mut messages);
+            }
+            let delivered = self.deliver(&mut messages);
+            let mut moved = delivered || !messages.is_empty();
+
+            // With nothing to say, ask the exit what its streams have read
+            if messages.is_empty() {
+                match self.opening.keys().chain(self.streams.keys()).next() {
+                    Some(&stream_id) => messages.push(CircuitMessage::Data { stream_id, data: Vec::new() }),
+                    None => continue,
+                }
+            }
+            match self.exchange(&messages).await {
+                Ok(answered) => moved |= answered,
+                Err(e) => {
+                    eprintln!("Circuit {} failed: {}", self.circuit.circuit_id, e);
+                    return;
+                }
+            }
+            pause = if moved { None } else { Some(pause.map_or(IDLE_POLL, |pause| (pause * 2).min(MAX_IDLE_POLL))) };
+        }
+    }
+
+    fn allocate_stream_id(&mut self) -> u16 {
+        loop {
+            let stream_id = self.next_stream_id;
+            self.next_stream_id = self.next_stream_id.checked_add(1).unwrap_or(1);
+            if !self.streams.contains_key(&stream_id) && !self.opening.contains_key(&stream_id) {
+                return stream_id;
+            }
+        }
+    }
+
+    /// Ask the exit for a stream; the requester hears back once it answers
+    fn begin(&mut self, request: OpenRequest, messages: &mut Vec<CircuitMessage>) {
+        let stream_id = self.allocate_stream_id();
+        messages.push(match &request.target {
+            Some(target) => CircuitMessage::Begin { stream_id, target: target.clone() },
+            None => CircuitMessage::BeginDatagrams { stream_id },
+        });
+        self.opening.insert(stream_id, request);
+    }
+
+    fn send_data(&mut self, stream_id: u16, data: Option<Vec<u8>>, messages: &mut Vec<CircuitMessage>) {
+        if !self.streams.contains_key(&stream_id) {
+            return;
+        }
+        match data {
+            Some(data) => messages.push(CircuitMessage::Data { stream_id, data }),
+            None => {
+                messages.push(CircuitMessage::End { stream_id, reason: EndReason::Done, detail: "Closed".to_string() });
+                self.streams.remove(&stream_id);
+            }
+        }
+    }
+
+    /// Hand the applications what has arrived, acknowledge it, and finish
+    /// the streams the exit has closed; returns whether anything was delivered
+    fn deliver(&mut self, messages: &mut Vec<CircuitMessage>) -> bool {
+        let mut moved = false;
+        let mut finished = Vec::new();
+        for (&stream_id, stream) in self.streams.iter_mut() {
+            let cells = stream.deliver();
+            moved |= cells > 0;
+            stream.delivered += cells;
+            self.delivered += cells;
+            while stream.delivered >= STREAM_SENDME {
+                stream.delivered -= STREAM_SENDME;
+                messages.push(CircuitMessage::Sendme { stream_id: Some(stream_id) });
+            }
+            if stream.remote_closed && stream.pending.is_empty() {
+                let _ = stream.io.shutdown().now_or_never();
+                finished.push(stream_id);
+            }
+        }
+        while self.delivered >= CIRCUIT_SENDME {
+            self.delivered -= CIRCUIT_SENDME;
+            messages.push(CircuitMessage::Sendme { stream_id: None });
+        }
+        for stream_id in finished {
+            self.streams.remove(&stream_id);
+        }
+        moved
+    }
+
+    /// Send `messages` to the exit and take in its answers. Errors are
+    /// circuit failures; returns whether the exit sent anything.
+    async fn exchange(&mut self, messages: &[CircuitMessage]) -> Result<bool> {
+        let replies = self.circuit.send_all(self.transport.as_ref(), self.exit, messages).await?;
+        let answered = !replies.is_empty();
+        for (_, reply) in replies {
+            match reply {
+                CircuitMessage::Connected { stream_id } => {
+                    if let Some(request) = self.opening.remove(&stream_id) {
+                        self.connected(stream_id, request);
+                    }
+                }
+                CircuitMessage::Data { stream_id, data } => {
+                    if let Some(stream) = self.streams.get_mut(&stream_id) {
+                        if !data.is_empty() {
+                            stream.pending.push_back((data_cells(data.len()), data));
+                        }
+                    }
+                }
+                CircuitMessage::End { stream_id, reason, detail } => {
+                    if let Some(request) = self.opening.remove(&stream_id) {
+                        let target = request.target.as_deref().unwrap_or("datagrams");
+                        let refused = StreamRefused { reason, message: format!("Exit refused {}: {}", target, detail) };
+                        let _ = request.reply.send(Err(refused.into()));
+                    } else if let Some(stream) = self.streams.get_mut(&stream_id) {
+                        stream.remote_closed = true;
+                    }
+                }
+                CircuitMessage::Sendme { stream_id: Some(stream_id) } => {
+                    if let Some(stream) = self.streams.get(&stream_id) {
+                        let window = stream.send_window.available_permits() as u32;
+                        credit(window, STREAM_SENDME, STREAM_WINDOW).map_err(|e| anyhow!(e))?;
+                        stream.send_window.add_permits(STREAM_SENDME as usize);
+                    }
+                }
+                other => return Err(anyhow!("Unexpected {:?} from the exit", other)),
+            }
+        }
+        Ok(answered)
+    }
+
+    fn connected(&mut self, stream_id: u16, request: OpenRequest) {
+        let (application, io) = tokio::io::duplex(STREAM_BUFFER);
+        let (read, write) = tokio::io::split(io);
+        let send_window = Arc::new(Semaphore::new(STREAM_WINDOW as usize));
+        let reader = tokio::spawn(read_application(stream_id, read, send_window.clone(), self.outgoing_sender.clone()));
+        self.streams.insert(stream_id, ClientStream {
+            io: write,
+            pending: VecDeque::new(),
+            delivered: 0,
+            remote_closed: false,
+            send_window,
+            reader,
+        });
+        // A requester that has given up drops its end, which closes the stream
+        let _ = request.reply.send(Ok(application));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::testing::{echo_server, spawn_node, spawn_relays};
+    use tokio::net::TcpListener;
+
+    /// A destination that writes `len` bytes as soon as a connection arrives
+    async fn firehose(len: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let _ = socket.write_all(&vec![0x5A; len]).await;
+                    let mut sink = Vec::new();
+                    let _ = socket.read_to_end(&mut sink).await;
+                });
+            }
+        });
+        addr
+    }
+
+    fn open_policy() -> ExitPolicy {
+        ExitPolicy::parse(&["accept *:*"]).unwrap()
+    }
+
+    /// Poll until the exit has had nothing to send for a while; returns
+    /// everything it sent
+    async fn settle(streams: &mut ExitStreams) -> Vec<CircuitMessage> {
+        let mut messages = Vec::new();
+        let mut quiet = 0;
+        while quiet < 20 {
+            let polled = streams.poll();
+            if polled.is_empty() {
+                quiet += 1;
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            } else {
+                quiet = 0;
+                messages.extend(polled);
+            }
+        }
+        messages
+    }
+
+    fn cells_sent(messages: &[CircuitMessage]) -> u32 {
+        messages
+            .iter()
+            .map(|message| match message {
+                CircuitMessage::Data { data, .. } => data_cells(data.len()),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    fn begin(streams: &mut ExitStreams, stream_id: u16, target: &str, policy: &ExitPolicy) {
+        let begin = CircuitMessage::Begin { stream_id, target: target.to_string() };
+        assert!(streams.handle(begin, policy).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_stream_window_stops_the_exit() {
+        let target = firehose(STREAM_WINDOW as usize * CELL_DATA_LEN * 2).await;
+        let mut streams = ExitStreams::default();
+        begin(&mut streams, 1, &target, &open_policy());
+
+        // The exit sends one stream window and then waits for a Sendme
+        let sent = settle(&mut streams).await;
+        assert!(matches!(sent.first(), Some(CircuitMessage::Connected { stream_id: 1 })), "{:?}", sent.first());
+        assert_eq!(cells_sent(&sent), STREAM_WINDOW);
+        let sendme = CircuitMessage::Sendme { stream_id: Some(1) };
+        assert!(streams.handle(sendme.clone(), &open_policy()).unwrap().is_none());
+        assert_eq!(cells_sent(&settle(&mut streams).await), STREAM_SENDME);
+
+        // Acknowledging cells that were never sent is a protocol violation
+        for _ in 0..STREAM_WINDOW / STREAM_SENDME {
+            streams.handle(sendme.clone(), &open_policy()).unwrap();
+        }
+        assert!(streams.handle(sendme, &open_policy()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_circuit_window_is_shared() {
+        let target = firehose(STREAM_WINDOW as usize * CELL_DATA_LEN * 2).await;
+        let mut streams = ExitStreams::default();
+        for stream_id in [1, 2, 3] {
+            begin(&mut streams, stream_id, &target, &open_policy());
+        }
+
+        // Three streams with room for more share out the one circuit window
+        assert_eq!(cells_sent(&settle(&mut streams).await), CIRCUIT_WINDOW);
+        streams.handle(CircuitMessage::Sendme { stream_id: None }, &open_policy()).unwrap();
+        assert_eq!(cells_sent(&settle(&mut streams).await), CIRCUIT_SENDME);
+    }
+
+    #[tokio::test]
+    async fn test_client_data_is_written_and_acknowledged() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let received = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            socket.read_to_end(&mut received).await.unwrap();
+            received
+        });
+
+        let mut streams = ExitStreams::default();
+        begin(&mut streams, 1, &target, &open_policy());
+        let chunk = vec![7u8; CELL_DATA_LEN];
+        for _ in 0..STREAM_SENDME {
+            let data = CircuitMessage::Data { stream_id: 1, data: chunk.clone() };
+            assert!(streams.handle(data, &open_policy()).unwrap().is_none());
+        }
+        let sent = settle(&mut streams).await;
+        assert_eq!(sent.iter().filter(|m| matches!(m, CircuitMessage::Sendme { stream_id: Some(1) })).count(), 1);
+
+        // A client that ignores the window fails the circuit
+        for _ in 0..STREAM_WINDOW {
+            streams.handle(CircuitMessage::Data { stream_id: 1, data: vec![1] }, &open_policy()).unwrap();
+        }
+        let data = CircuitMessage::Data { stream_id: 1, data: vec![1] };
+        let overran = (0..STREAM_WINDOW).any(|_| streams.handle(data.clone(), &open_policy()).is_err());
+        assert!(overran);
+
+        let end = CircuitMessage::End { stream_id: 1, reason: EndReason::Done, detail: "Closed".to_string() };
+        streams.handle(end, &open_policy()).unwrap();
+        assert!(received.await.unwrap().len() >= STREAM_SENDME as usize * CELL_DATA_LEN);
+    }
+
+    #[tokio::test]
+    async fn test_begin_refusals() {
+        let mut streams = ExitStreams::default();
+        let reserved = CircuitMessage::Begin { stream_id: 0, target: firehose(1).await };
+        assert!(matches!(streams.handle(reserved, &open_policy()).unwrap(), Some(CircuitMessage::End { .. })));
+
+        // Nothing listens on a port we just released
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        begin(&mut streams, 1, &closed, &open_policy());
+        let refused = settle(&mut streams).await;
+        assert!(matches!(refused.as_slice(), [CircuitMessage::End { stream_id: 1, reason: EndReason::Refused, .. }]), "{:?}", refused);
+        assert_eq!(streams.len(), 0);
+
+        // The default policy keeps clients off the exit's own network
+        begin(&mut streams, 2, &firehose(1).await, &ExitPolicy::default());
+        match settle(&mut streams).await.as_slice() {
+            [CircuitMessage::End { reason, detail, .. }] => assert_eq!(*reason, EndReason::NotAllowed, "{}", detail),
+            other => panic!("unexpected reply: {:?}", other),
+        }
+
+        let unknown = CircuitMessage::Data { stream_id: 9, data: vec![1] };
+        assert!(matches!(streams.handle(unknown, &open_policy()).unwrap(), Some(CircuitMessage::End { stream_id: 9, .. })));
+    }
+
+    /// Send `data` on a datagram stream and collect the datagrams that come back
+    async fn datagram_exchange(streams: &mut ExitStreams, stream_id: u16, data: Vec<u8>) -> Vec<(String, Vec<u8>)> {
+        streams.handle(CircuitMessage::Data { stream_id, data }, &open_policy()).unwrap();
+        let mut received = Vec::new();
+        for message in settle(streams).await {
+            match message {
+                CircuitMessage::Data { data, .. } => received.extend(data),
+                CircuitMessage::Sendme { .. } => {}
+                other => panic!("unexpected reply: {:?}", other),
+            }
+        }
+        decode_datagrams(&mut received).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_datagram_stream() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+            while let Ok((n, from)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..n], from).await;
+            }
+        });
+
+        // The default policy keeps datagrams off the exit's own network
+        let mut streams = ExitStreams::default();
+        for (stream_id, policy) in [(1, open_policy()), (2, ExitPolicy::default())] {
+            assert!(streams.handle(CircuitMessage::BeginDatagrams { stream_id }, &policy).unwrap().is_none());
+        }
+        assert_eq!(settle(&mut streams).await.len(), 2);
+
+        // A datagram split across Data messages goes out once it is complete
+        let mut frame = encode_datagram(&echo_addr, b"ping");
+        let tail = frame.split_off(3);
+        assert!(datagram_exchange(&mut streams, 1, frame).await.is_empty());
+        let replies = datagram_exchange(&mut streams, 1, tail).await;
+        assert_eq!(replies, vec![(echo_addr.clone(), b"ping".to_vec())]);
+
+        let refused = encode_datagram(&echo_addr, b"local");
+        assert!(datagram_exchange(&mut streams, 2, refused).await.is_empty());
+
+        let mut garbled = vec![0, 2, 0xff, 0xfe, 0, 0];
+        assert!(decode_datagrams(&mut garbled).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_streams_share_a_circuit() {
+        let (_, client, _) = spawn_node().await;
+        let hops: Vec<_> = spawn_relays(3).await.into_iter().map(|relay| relay.hop).collect();
+        let circuit = ClientCircuit::build(client.as_ref(), &hops).await.unwrap();
+        let mux = StreamMux::spawn(circuit, client.clone());
+        let target = echo_server().await.to_string();
+
+        // A download bigger than the circuit window runs next to a small exchange
+        let big: Vec<u8> = (0..CIRCUIT_WINDOW as usize * CELL_DATA_LEN + 65536).map(|i| (i % 251) as u8).collect();
+        let (bulk, small) = tokio::join!(mux.open(&target), mux.open(&target));
+        let (bulk, mut small) = (bulk.unwrap(), small.unwrap());
+        let expected = big.clone();
+        let bulk_task = tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(bulk);
+            let write = async move { writer.write_all(&big).await.unwrap() };
+            let mut echoed = vec![0u8; expected.len()];
+            let read = reader.read_exact(&mut echoed);
+            let (_, read) = tokio::join!(write, read);
+            read.unwrap();
+            assert!(echoed == expected);
+        });
+
+        small.write_all(b"hello").await.unwrap();
+        let mut reply = [0u8; 5];
+        small.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"hello");
+        drop(small);
+        bulk_task.await.unwrap();
+
+        // Nothing listens on the exit's port 1
+        assert!(mux.open("127.0.0.1:1").await.is_err());
+        assert!(!mux.is_closed());
+    }
+}
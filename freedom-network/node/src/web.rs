@@ -6,16 +6,21 @@ use std::time::SystemTime;
 use tokio::net::{TcpListener, TcpStream};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use anyhow::Result;
+use std::sync::Arc;
+use crate::circuits::CircuitStatus;
+use crate::config::NodeConfig;
 use crate::proxy::ProxyMetrics;
 
 pub struct WebDashboard {
     listener: TcpListener,
     proxy_metrics: ProxyMetrics,
+    config: Arc<NodeConfig>,
+    circuits: CircuitStatus,
     start_time: SystemTime,
 }
 
 impl WebDashboard {
-    pub async fn new(addr: SocketAddr, proxy_metrics: ProxyMetrics) -> Result<Self> {
+    pub async fn new(addr: SocketAddr, proxy_metrics: ProxyMetrics, config: Arc<NodeConfig>, circuits: CircuitStatus) -> Result<Self> {
         let listener = TcpListener::bind(addr).await?;
         println!("🖥️  Web Dashboard available at http://{}", addr);
         println!("   View stats, manage VPN, configure proxy\n");
@@ -23,6 +28,8 @@ impl WebDashboard {
         Ok(WebDashboard { 
             listener,
             proxy_metrics,
+            config,
+            circuits,
             start_time: SystemTime::now(),
         })
     }
@@ -33,10 +40,12 @@ impl WebDashboard {
             println!("🖥️  Dashboard connection from {}", addr);
             
             let proxy_metrics = self.proxy_metrics.clone();
+            let config = self.config.clone();
+            let circuits = self.circuits.clone();
             let start_time = self.start_time;
             
             tokio::spawn(async move {
-                if let Err(e) = Self::handle_request(socket, proxy_metrics, start_time).await {
+                if let Err(e) = Self::handle_request(socket, proxy_metrics, config, circuits, start_time).await {
                     eprintln!("❌ Dashboard error: {}", e);
                 }
             });
@@ -46,6 +55,8 @@ impl WebDashboard {
     async fn handle_request(
         mut socket: TcpStream,
         proxy_metrics: ProxyMetrics,
+        config: Arc<NodeConfig>,
+        circuits: CircuitStatus,
         start_time: SystemTime,
     ) -> Result<()> {
         let mut buffer = vec![0u8; 4096];
@@ -74,8 +85,9 @@ impl WebDashboard {
         let response = match (method, path) {
             ("GET", "/") => Self::html_dashboard(&proxy_metrics, start_time).await,
             ("GET", "/api/status") => Self::api_status(&proxy_metrics, start_time).await,
-            ("GET", "/api/config") => Self::api_config().await,
+            ("GET", "/api/config") => Self::api_config(&config).await,
             ("GET", "/api/stats") => Self::api_stats(&proxy_metrics).await,
+            ("GET", "/api/circuits") => Self::cors_json_response(&circuits.to_json().await.to_string()),
             ("OPTIONS", _) => "HTTP/1.1 204 No Content\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: GET, OPTIONS\r\nAccess-Control-Allow-Headers: Content-Type\r\nContent-Length: 0\r\n\r\n".to_string(),
             _ => Self::not_found().await,
         };
@@ -110,7 +122,7 @@ impl WebDashboard {
         .power-ring { width: 200px; height: 200px; border-radius: 50%; background: conic-gradient(from 0deg, var(--bad), #632525 70%); display: grid; place-items: center; }
         .power-ring.online { background: conic-gradient(from 0deg, var(--ok), #216132 70%); }
         .power-inner { width: 156px; height: 156px; border-radius: 50%; background: var(--surface); border: 1px solid var(--border); display: grid; place-items: center; font-size: 18px; font-weight: 700; }
-        .stats-row { display: grid; grid-template-columns: repeat(5, minmax(0, 1fr)); gap: 10px; margin-bottom: 20px; }
+        .stats-row { display: grid; grid-template-columns: repeat(6, minmax(0, 1fr)); gap: 10px; margin-bottom: 20px; }
         .stat-card { background: var(--surface); border: 1px solid var(--border); border-radius: 12px; padding: 12px; display: flex; flex-direction: column; gap: 6px; }
         .stat-label { color: var(--muted); font-size: 11px; text-transform: uppercase; letter-spacing: 0.4px; }
         .stat-value { color: var(--accent); font-size: 17px; font-weight: 700; }
@@ -142,6 +154,7 @@ impl WebDashboard {
             <article class="stat-card"><span class="stat-label">Total</span><span class="stat-value" id="totalconns">—</span></article>
             <article class="stat-card"><span class="stat-label">Sent</span><span class="stat-value" id="sent">—</span></article>
             <article class="stat-card"><span class="stat-label">Received</span><span class="stat-value" id="recv">—</span></article>
+            <article class="stat-card"><span class="stat-label">Circuits</span><span class="stat-value" id="circuits">—</span></article>
         </section>
 
         <section class="proxy-card">
@@ -193,13 +206,14 @@ impl WebDashboard {
 
         async function refresh() {
             try {
-                const [sr, dr] = await Promise.all([fetch('/api/status'), fetch('/api/stats')]);
-                const s = await sr.json(), d = await dr.json();
+                const [sr, dr, cr] = await Promise.all([fetch('/api/status'), fetch('/api/stats'), fetch('/api/circuits')]);
+                const s = await sr.json(), d = await dr.json(), c = await cr.json();
                 document.getElementById('uptime').textContent = formatUptime(s.uptime_ms);
                 document.getElementById('connections').textContent = s.connections_active;
                 document.getElementById('totalconns').textContent = s.connections_total;
                 document.getElementById('sent').textContent = formatBytes(d.bytes_sent);
                 document.getElementById('recv').textContent = formatBytes(d.bytes_received);
+                document.getElementById('circuits').textContent = c.clean + '/' + c.pool_size + ' clean';
                 setOnline(true);
             } catch(e) {
                 setOnline(false);
@@ -240,9 +254,25 @@ impl WebDashboard {
         Self::cors_json_response(&json)
     }
 
-    async fn api_config() -> String {
-        let json = r#"{"proxy_enabled":true,"proxy_address":"127.0.0.1:8080","quic_address":"127.0.0.1:5000","dashboard_address":"127.0.0.1:9090","dht_enabled":true,"onion_routing":true}"#;
-        Self::cors_json_response(json)
+    async fn api_config(config: &NodeConfig) -> String {
+        let sites: Vec<&str> = config.sites.iter().map(|site| site.domain.as_str()).collect();
+        let json = serde_json::json!({
+            "proxy_enabled": true,
+            "proxy_address": config.proxy_address.to_string(),
+            "quic_address": config.quic_address.to_string(),
+            "advertised_address": config.advertised_address().to_string(),
+            "dashboard_address": config.dashboard_address.to_string(),
+            "bootstrap_nodes": config.bootstrap_nodes,
+            "hop_count": config.hop_count,
+            "circuit_pool": config.circuit_pool,
+            "data_dir": config.data_dir.display().to_string(),
+            "sites": sites,
+            "exit_relay": config.exit_relay,
+            "exit_policy": config.exit_policy,
+            "dht_enabled": true,
+            "onion_routing": true,
+        });
+        Self::cors_json_response(&json.to_string())
     }
 
     async fn api_stats(proxy_metrics: &ProxyMetrics) -> String {
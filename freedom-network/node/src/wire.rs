@@ -0,0 +1,261 @@
+// Wire protocol for node-to-node QUIC streams
+// Frame layout: [u32 length, big-endian][u8 version][u8 kind][bincode body]
+// The length covers version, kind and body.
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use crate::protocol::{ContentMessage, DHTMessage, RoutingMessage};
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Largest frame a peer may send, header included
+pub const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+const HEADER_LEN: usize = 2;
+
+/// Type tag carried in every frame header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageKind {
+    Dht = 1,
+    Routing = 2,
+    Content = 3,
+    Ack = 4,
+    Error = 5,
+}
+
+impl TryFrom<u8> for MessageKind {
+    type Error = WireError;
+
+    fn try_from(tag: u8) -> Result<Self, WireError> {
+        match tag {
+            1 => Ok(MessageKind::Dht),
+            2 => Ok(MessageKind::Routing),
+            3 => Ok(MessageKind::Content),
+            4 => Ok(MessageKind::Ack),
+            5 => Ok(MessageKind::Error),
+            other => Err(WireError::new(ErrorCode::UnknownKind, format!("Unknown message kind {}", other))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    UnsupportedVersion,
+    UnknownKind,
+    Malformed,
+    UnexpectedMessage,
+    NotFound,
+    Internal,
+}
+
+/// Error reported to the peer in an `Error` frame
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WireError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl WireError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+impl std::fmt::Display for WireError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for WireError {}
+
+/// A decoded message
+#[derive(Debug, Clone)]
+pub enum Frame {
+    Dht(DHTMessage),
+    Routing(RoutingMessage),
+    Content(ContentMessage),
+    /// Request handled, nothing to return
+    Ack,
+    Error(WireError),
+}
+
+impl Frame {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Frame::Dht(_) => MessageKind::Dht,
+            Frame::Routing(_) => MessageKind::Routing,
+            Frame::Content(_) => MessageKind::Content,
+            Frame::Ack => MessageKind::Ack,
+            Frame::Error(_) => MessageKind::Error,
+        }
+    }
+
+    /// Serialize to a complete frame, length prefix included
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let body = match self {
+            Frame::Dht(message) => bincode::serialize(message)?,
+            Frame::Routing(message) => bincode::serialize(message)?,
+            Frame::Content(message) => bincode::serialize(message)?,
+            Frame::Ack => Vec::new(),
+            Frame::Error(error) => bincode::serialize(error)?,
+        };
+
+        let len = HEADER_LEN + body.len();
+        if len > MAX_FRAME_SIZE {
+            return Err(anyhow!("Frame of {} bytes exceeds limit of {}", len, MAX_FRAME_SIZE));
+        }
+
+        let mut out = Vec::with_capacity(4 + len);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+        out.push(PROTOCOL_VERSION);
+        out.push(self.kind() as u8);
+        out.extend_from_slice(&body);
+        Ok(out)
+    }
+}
+
+/// A frame as read off the wire, before its body is decoded. Keeping the two
+/// steps apart lets a receiver answer a bad body without losing stream sync.
+#[derive(Debug, Clone)]
+pub struct RawFrame {
+    pub version: u8,
+    pub kind: u8,
+    pub body: Vec<u8>,
+}
+
+impl RawFrame {
+    pub fn decode(&self) -> Result<Frame, WireError> {
+        if self.version != PROTOCOL_VERSION {
+            return Err(WireError::new(
+                ErrorCode::UnsupportedVersion,
+                format!("Unsupported protocol version {} (expected {})", self.version, PROTOCOL_VERSION),
+            ));
+        }
+
+        let malformed = |e: bincode::Error| WireError::new(ErrorCode::Malformed, e.to_string());
+        match MessageKind::try_from(self.kind)? {
+            MessageKind::Dht => bincode::deserialize(&self.body).map(Frame::Dht).map_err(malformed),
+            MessageKind::Routing => bincode::deserialize(&self.body).map(Frame::Routing).map_err(malformed),
+            MessageKind::Content => bincode::deserialize(&self.body).map(Frame::Content).map_err(malformed),
+            MessageKind::Ack => Ok(Frame::Ack),
+            MessageKind::Error => bincode::deserialize(&self.body).map(Frame::Error).map_err(malformed),
+        }
+    }
+}
+
+/// Read the next frame. Returns `None` on a clean end of stream, which is
+/// one that ends before the first byte of a length prefix.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Option<RawFrame>> {
+    let mut len_bytes = [0u8; 4];
+    if reader.read(&mut len_bytes[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len_bytes[1..]).await?;
+
+    let len = u32::from_be_bytes(len_bytes) as usize;
+    if !(HEADER_LEN..=MAX_FRAME_SIZE).contains(&len) {
+        return Err(anyhow!("Invalid frame length {}", len));
+    }
+
+    let mut frame = vec![0u8; len];
+    reader.read_exact(&mut frame).await?;
+    Ok(Some(RawFrame {
+        version: frame[0],
+        kind: frame[1],
+        body: frame.split_off(HEADER_LEN),
+    }))
+}
+
+/// Read and decode the next frame, treating a remote `Error` frame as an error
+pub async fn read_reply<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Frame> {
+    let raw = read_frame(reader).await?.ok_or_else(|| anyhow!("Stream closed before reply"))?;
+    match raw.decode()? {
+        Frame::Error(error) => Err(error.into()),
+        frame => Ok(frame),
+    }
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &Frame) -> Result<()> {
+    writer.write_all(&frame.encode()?).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::NodeId;
+
+    async fn roundtrip(frame: Frame) -> Frame {
+        let bytes = frame.encode().unwrap();
+        let raw = read_frame(&mut bytes.as_slice()).await.unwrap().unwrap();
+        raw.decode().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let frame = roundtrip(Frame::Dht(DHTMessage::Ping { sender: NodeId([9u8; 32]) })).await;
+        assert!(matches!(frame, Frame::Dht(DHTMessage::Ping { sender }) if sender == NodeId([9u8; 32])));
+
+        let frame = roundtrip(Frame::Routing(RoutingMessage::DestroyCircuit { circuit_id: 7 })).await;
+        assert!(matches!(frame, Frame::Routing(RoutingMessage::DestroyCircuit { circuit_id: 7 })));
+
+        assert!(matches!(roundtrip(Frame::Ack).await, Frame::Ack));
+
+        let error = WireError::new(ErrorCode::NotFound, "nope");
+        assert!(matches!(roundtrip(Frame::Error(error.clone())).await, Frame::Error(e) if e == error));
+    }
+
+    #[tokio::test]
+    async fn test_multiple_frames_on_one_stream() {
+        let mut bytes = Frame::Ack.encode().unwrap();
+        bytes.extend(Frame::Content(ContentMessage::NotFound).encode().unwrap());
+
+        let mut reader = bytes.as_slice();
+        assert!(matches!(read_frame(&mut reader).await.unwrap().unwrap().decode(), Ok(Frame::Ack)));
+        assert!(matches!(
+            read_frame(&mut reader).await.unwrap().unwrap().decode(),
+            Ok(Frame::Content(ContentMessage::NotFound))
+        ));
+        assert!(read_frame(&mut reader).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_bad_frames() {
+        let mut bytes = Frame::Ack.encode().unwrap();
+        bytes[4] = PROTOCOL_VERSION + 1;
+        let raw = read_frame(&mut bytes.as_slice()).await.unwrap().unwrap();
+        assert_eq!(raw.decode().unwrap_err().code, ErrorCode::UnsupportedVersion);
+
+        let mut bytes = Frame::Ack.encode().unwrap();
+        bytes[5] = 99;
+        let raw = read_frame(&mut bytes.as_slice()).await.unwrap().unwrap();
+        assert_eq!(raw.decode().unwrap_err().code, ErrorCode::UnknownKind);
+
+        let mut bytes = vec![0, 0, 0, 5, PROTOCOL_VERSION, MessageKind::Dht as u8];
+        bytes.extend_from_slice(&[0xff, 0xff, 0xff]);
+        let raw = read_frame(&mut bytes.as_slice()).await.unwrap().unwrap();
+        assert_eq!(raw.decode().unwrap_err().code, ErrorCode::Malformed);
+
+        let oversized = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes();
+        assert!(read_frame(&mut oversized.as_slice()).await.is_err());
+
+        let truncated = [0u8, 0, 0, 10, PROTOCOL_VERSION];
+        assert!(read_frame(&mut truncated.as_slice()).await.is_err());
+
+        // Only an end before the prefix starts is clean
+        let cut_prefix = [0u8, 0];
+        let err = read_frame(&mut cut_prefix.as_slice()).await.unwrap_err();
+        let io = err.downcast_ref::<std::io::Error>().map(|e| e.kind());
+        assert_eq!(io, Some(std::io::ErrorKind::UnexpectedEof));
+    }
+
+    #[tokio::test]
+    async fn test_read_reply_surfaces_remote_errors() {
+        let bytes = Frame::Error(WireError::new(ErrorCode::NotFound, "Unknown circuit 3")).encode().unwrap();
+        let err = read_reply(&mut bytes.as_slice()).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<WireError>().map(|e| e.code), Some(ErrorCode::NotFound));
+    }
+}